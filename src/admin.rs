@@ -0,0 +1,169 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::num_to_network_name;
+
+const COMMAND_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    // role, term, commit index and peers
+    Status,
+    TransferLeadership(u32),
+    SnapshotNow,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().ok_or("no command given")?;
+        let mut node = || {
+            let name = words.next().ok_or_else(|| format!("{} needs a node id", command))?;
+            u32::from_str_radix(name, 16).map_err(|_| format!("invalid node id {}, expected hex like 0001", name))
+        };
+        let parsed = match command {
+            "status" => AdminCommand::Status,
+            "transfer-leadership" => AdminCommand::TransferLeadership(node()?),
+            "snapshot-now" => AdminCommand::SnapshotNow,
+            other => return Err(format!("unknown command {}, expected status, transfer-leadership or snapshot-now", other)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected {} after {}", extra, command)),
+            None => Ok(parsed),
+        }
+    }
+}
+
+impl AdminCommand {
+    pub fn name(&self) -> String {
+        match self {
+            AdminCommand::Status => "status".to_string(),
+            AdminCommand::TransferLeadership(id) => format!("transfer-leadership {}", num_to_network_name(*id)),
+            AdminCommand::SnapshotNow => "snapshot-now".to_string(),
+        }
+    }
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+// A second socket for operators, separate from the one clients and peers share, taking one command per connection as a
+// line of text and answering with a line of JSON, like `echo status | nc -U admin.sock`. Polled from the network's
+// receive loop like the HTTP server, so commands can read and change node state without any locking.
+pub struct AdminServer {
+    listener: Listener,
+}
+
+pub struct AdminRequest {
+    stream: Box<dyn Stream>,
+    pub command: Result<AdminCommand, String>,
+}
+
+impl AdminServer {
+    // a host:port listens over TCP, anything else is the path of a Unix socket, replacing one left by a previous run
+    pub fn bind(address: &str) -> io::Result<AdminServer> {
+        let listener = match address.parse::<SocketAddr>() {
+            Ok(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+            Err(_) => {
+                match std::fs::remove_file(address) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                let listener = UnixListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener)
+            }
+        };
+        Ok(AdminServer { listener })
+    }
+
+    // None if there's no connection waiting, or it didn't send a line in time
+    pub fn accept(&self) -> Option<AdminRequest> {
+        let stream: Box<dyn Stream> = match &self.listener {
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().ok()?;
+                stream.set_nonblocking(false).ok()?;
+                stream.set_read_timeout(Some(COMMAND_READ_TIMEOUT)).ok()?;
+                Box::new(stream)
+            }
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().ok()?;
+                stream.set_nonblocking(false).ok()?;
+                stream.set_read_timeout(Some(COMMAND_READ_TIMEOUT)).ok()?;
+                Box::new(stream)
+            }
+        };
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        Some(AdminRequest { stream: reader.into_inner(), command: line.parse() })
+    }
+}
+
+impl AdminRequest {
+    pub fn respond(mut self, body: &serde_json::Value) {
+        let mut line = body.to_string();
+        line.push('\n');
+        let _ = self.stream.write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    use serde_json::json;
+
+    use crate::admin::{AdminCommand, AdminServer};
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("status\n".parse(), Ok(AdminCommand::Status));
+        assert_eq!("transfer-leadership 000A".parse(), Ok(AdminCommand::TransferLeadership(10)));
+        assert!("transfer-leadership".parse::<AdminCommand>().is_err());
+        assert!("transfer-leadership zz".parse::<AdminCommand>().is_err());
+        assert!("snapshot-now please".parse::<AdminCommand>().is_err());
+        assert!("reboot".parse::<AdminCommand>().is_err());
+        assert!("add-node 0003".parse::<AdminCommand>().is_err());
+        assert_eq!(AdminCommand::TransferLeadership(3).name(), "transfer-leadership 0003");
+    }
+
+    #[test]
+    fn answers_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("admin_test_{}.sock", std::process::id()));
+        let server = AdminServer::bind(path.to_str().unwrap()).unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"status\n").unwrap();
+        let req = loop {
+            if let Some(req) = server.accept() {
+                break req;
+            }
+        };
+        assert_eq!(req.command, Ok(AdminCommand::Status));
+        req.respond(&json!({ "role": "leader" }));
+
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&line).unwrap(), json!({ "role": "leader" }));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Subsystem {
+    Other = 0,
+    Log = 1,
+    StateMachine = 2,
+    Buffers = 3,
+}
+
+#[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+#[derive(Serialize, Default, Debug)]
+pub struct SubsystemStats {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+#[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+#[derive(Serialize, Default, Debug)]
+pub struct AllocStats {
+    pub live_bytes: u64,
+    pub other: SubsystemStats,
+    pub log: SubsystemStats,
+    pub state_machine: SubsystemStats,
+    pub buffers: SubsystemStats,
+}
+
+pub struct SubsystemGuard {
+    #[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+    previous: Subsystem,
+}
+
+// attributes allocations made on this thread to the subsystem until the returned guard is dropped
+#[must_use]
+pub fn enter(subsystem: Subsystem) -> SubsystemGuard {
+    SubsystemGuard { previous: imp::swap_current(subsystem) }
+}
+
+impl Drop for SubsystemGuard {
+    fn drop(&mut self) {
+        imp::swap_current(self.previous);
+    }
+}
+
+// None unless built with the alloc-stats feature
+pub fn snapshot() -> Option<AllocStats> {
+    imp::snapshot()
+}
+
+#[cfg(feature = "alloc-stats")]
+mod imp {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::alloc_stats::{AllocStats, Subsystem, SubsystemStats};
+
+    const NUM_SUBSYSTEMS: usize = 4;
+
+    struct Counters {
+        allocations: [AtomicU64; NUM_SUBSYSTEMS],
+        bytes: [AtomicU64; NUM_SUBSYSTEMS],
+        live_bytes: AtomicU64,
+    }
+
+    static COUNTERS: Counters = Counters {
+        allocations: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        bytes: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        live_bytes: AtomicU64::new(0),
+    };
+
+    thread_local! {
+        static CURRENT: Cell<Subsystem> = Cell::new(Subsystem::Other);
+    }
+
+    struct CountingAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record_alloc(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            COUNTERS.live_bytes.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            record_alloc(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            COUNTERS.live_bytes.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+            record_alloc(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    fn record_alloc(size: usize) {
+        // try_with so allocations during thread teardown still get counted
+        let subsystem = CURRENT.try_with(|c| c.get()).unwrap_or(Subsystem::Other) as usize;
+        COUNTERS.allocations[subsystem].fetch_add(1, Ordering::Relaxed);
+        COUNTERS.bytes[subsystem].fetch_add(size as u64, Ordering::Relaxed);
+        COUNTERS.live_bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn swap_current(subsystem: Subsystem) -> Subsystem {
+        CURRENT.try_with(|c| c.replace(subsystem)).unwrap_or(Subsystem::Other)
+    }
+
+    fn subsystem_stats(subsystem: Subsystem) -> SubsystemStats {
+        SubsystemStats {
+            allocations: COUNTERS.allocations[subsystem as usize].load(Ordering::Relaxed),
+            bytes: COUNTERS.bytes[subsystem as usize].load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn snapshot() -> Option<AllocStats> {
+        Some(AllocStats {
+            live_bytes: COUNTERS.live_bytes.load(Ordering::Relaxed),
+            other: subsystem_stats(Subsystem::Other),
+            log: subsystem_stats(Subsystem::Log),
+            state_machine: subsystem_stats(Subsystem::StateMachine),
+            buffers: subsystem_stats(Subsystem::Buffers),
+        })
+    }
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+mod imp {
+    use crate::alloc_stats::{AllocStats, Subsystem};
+
+    pub fn swap_current(_subsystem: Subsystem) -> Subsystem {
+        Subsystem::Other
+    }
+
+    pub fn snapshot() -> Option<AllocStats> {
+        None
+    }
+}
@@ -0,0 +1,151 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Messages are signed by appending ,"mac":"<hex HMAC-SHA256>" as the last field of their JSON object, where the MAC
+// covers every byte of the message before it. That way nothing has to agree on how to re-serialize the JSON.
+const MAC_FIELD: &[u8] = b",\"mac\":\"";
+const MAC_HEX_LEN: usize = 64;
+const SUFFIX_LEN: usize = MAC_FIELD.len() + MAC_HEX_LEN + 2;
+// Messages between peers also carry the sender's term as ,"term":<term> just before the MAC, and are signed with a key
+// derived from the secret for that term, so a MAC can't be moved onto a message claiming some other term.
+const TERM_FIELD: &[u8] = b",\"term\":";
+
+fn new_mac(secret: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(body);
+    mac
+}
+
+fn term_key(secret: &[u8], term: u32) -> Vec<u8> {
+    let mut derive = new_mac(secret, b"term:");
+    derive.update(&term.to_be_bytes());
+    derive.finalize().into_bytes().to_vec()
+}
+
+// signs the JSON object in buffer[..len] in place, giving its new length
+pub fn sign(secret: &[u8], buffer: &mut [u8], len: usize) -> usize {
+    let body_len = len - 1;
+    let mac = new_mac(secret, &buffer[..body_len]).finalize().into_bytes();
+
+    let mut suffix = MAC_FIELD.to_vec();
+    for b in mac.iter() {
+        suffix.extend_from_slice(format!("{:02x}", b).as_bytes());
+    }
+    suffix.extend_from_slice(b"\"}");
+
+    buffer[body_len..body_len + SUFFIX_LEN].copy_from_slice(&suffix);
+    body_len + SUFFIX_LEN
+}
+
+// adds the term to the JSON object in buffer[..len] and signs it with the term's key, giving its new length
+pub fn sign_for_term(secret: &[u8], term: u32, buffer: &mut [u8], len: usize) -> usize {
+    let mut field = TERM_FIELD.to_vec();
+    field.extend_from_slice(term.to_string().as_bytes());
+    field.push(b'}');
+
+    let body_len = len - 1;
+    buffer[body_len..body_len + field.len()].copy_from_slice(&field);
+    sign(&term_key(secret, term), buffer, body_len + field.len())
+}
+
+// Like verify, but also takes messages signed for a term, giving the term along with the length of the message with
+// both the term and the MAC stripped off.
+pub fn verify_with_term(secret: &[u8], buffer: &mut [u8], len: usize) -> Option<(usize, Option<u32>)> {
+    let (term, field_start) = match signed_term(buffer, len) {
+        Some(signed) => signed,
+        None => return verify(secret, buffer, len).map(|len| (len, None)),
+    };
+    verify(&term_key(secret, term), buffer, len)?;
+
+    buffer[field_start] = b'}';
+    Some((field_start + 1, Some(term)))
+}
+
+// the term in a ,"term":<term> just before the MAC, if there is one, and where that field starts
+fn signed_term(buffer: &[u8], len: usize) -> Option<(u32, usize)> {
+    let body = &buffer[..len.checked_sub(SUFFIX_LEN)?];
+    let digits = body.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let term_start = body.len() - digits;
+    if digits == 0 || !body[..term_start].ends_with(TERM_FIELD) {
+        return None;
+    }
+    let term = std::str::from_utf8(&body[term_start..]).ok()?.parse().ok()?;
+    Some((term, term_start - TERM_FIELD.len()))
+}
+
+// checks the MAC on the JSON object in buffer[..len] and strips it off, giving the length of the unsigned message, or
+// None if it's unsigned or the MAC is wrong
+pub fn verify(secret: &[u8], buffer: &mut [u8], len: usize) -> Option<usize> {
+    let body_len = len.checked_sub(SUFFIX_LEN)?;
+    let suffix = &buffer[body_len..len];
+    if !suffix.starts_with(MAC_FIELD) || !suffix.ends_with(b"\"}") {
+        return None;
+    }
+
+    let hex = &suffix[MAC_FIELD.len()..MAC_FIELD.len() + MAC_HEX_LEN];
+    let mut expected = [0u8; MAC_HEX_LEN / 2];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        expected[i] = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    new_mac(secret, &buffer[..body_len]).verify_slice(&expected).ok()?;
+
+    buffer[body_len] = b'}';
+    Some(body_len + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{sign, sign_for_term, verify, verify_with_term};
+
+    #[test]
+    fn sign_and_verify() {
+        let message = br#"{"src":"0001","dst":"0002","leader":"FFFF","type":"get","MID":"a","key":"k"}"#;
+        let mut buffer = [0u8; 256];
+        buffer[..message.len()].copy_from_slice(message);
+
+        let signed_len = sign(b"secret", &mut buffer, message.len());
+        assert!(std::str::from_utf8(&buffer[..signed_len]).unwrap().contains(r#""key":"k","mac":""#));
+        let signed = buffer;
+
+        assert_eq!(verify(b"secret", &mut buffer, signed_len), Some(message.len()));
+        assert_eq!(&buffer[..message.len()], &message[..]);
+
+        buffer = signed;
+        assert_eq!(verify(b"other secret", &mut buffer, signed_len), None);
+
+        buffer = signed;
+        buffer[30] ^= 1;
+        assert_eq!(verify(b"secret", &mut buffer, signed_len), None);
+
+        buffer[..message.len()].copy_from_slice(message);
+        assert_eq!(verify(b"secret", &mut buffer, message.len()), None);
+    }
+
+    #[test]
+    fn term_bound() {
+        let message = br#"{"src":"0001","dst":"0002","leader":"FFFF","type":"raft","data":[1,2]}"#;
+        let mut buffer = [0u8; 256];
+        buffer[..message.len()].copy_from_slice(message);
+
+        let signed_len = sign_for_term(b"secret", 7, &mut buffer, message.len());
+        assert!(std::str::from_utf8(&buffer[..signed_len]).unwrap().contains(r#""data":[1,2],"term":7,"mac":""#));
+        let signed = buffer;
+
+        assert_eq!(verify_with_term(b"secret", &mut buffer, signed_len), Some((message.len(), Some(7))));
+        assert_eq!(&buffer[..message.len()], &message[..]);
+
+        // a MAC for one term doesn't check out for another
+        buffer = signed;
+        let term_at = std::str::from_utf8(&buffer[..signed_len]).unwrap().find(r#""term":7"#).unwrap() + 7;
+        buffer[term_at] = b'8';
+        assert_eq!(verify_with_term(b"secret", &mut buffer, signed_len), None);
+
+        buffer = signed;
+        assert_eq!(verify(b"secret", &mut buffer, signed_len), None);
+
+        buffer[..message.len()].copy_from_slice(message);
+        let signed_len = sign(b"secret", &mut buffer, message.len());
+        assert_eq!(verify_with_term(b"secret", &mut buffer, signed_len), Some((message.len(), None)));
+    }
+}
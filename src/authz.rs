@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// One thing a client asked for. A request touching several keys is several operations, and one that isn't about any key,
+// like defrag or register, has none. Scans give their prefix or start.
+#[derive(Debug)]
+pub struct Operation<'a> {
+    // None for HTTP clients, which don't have one
+    pub client_id: Option<u32>,
+    // the message type, as on the wire
+    pub kind: &'static str,
+    pub access: Access,
+    pub key: Option<&'a str>,
+}
+
+// Decides which client operations the network hands to the core, the rest are answered with a fail. Admin and status
+// messages aren't asked about.
+pub trait Authorizer {
+    fn authorize(&self, op: &Operation) -> bool;
+}
+
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _: &Operation) -> bool {
+        true
+    }
+}
+
+// A policy from a JSON file given with KV_AUTHZ_RULES, e.g. {"read_only": ["0004"], "prefixes": {"billing/": ["0002"]}}.
+// Read-only clients can't write anything, and keys under an owned prefix can only be written by its owners.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    read_only: Vec<String>,
+    #[serde(default)]
+    prefixes: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug)]
+pub struct Rules {
+    read_only: HashSet<u32>,
+    prefix_owners: Vec<(String, HashSet<u32>)>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Rules, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read authorization rules {}: {}", path.display(), e))?;
+        let file: RulesFile = serde_json::from_str(&text).map_err(|e| format!("invalid authorization rules {}: {}", path.display(), e))?;
+        Rules::from_file(file).map_err(|e| format!("invalid authorization rules {}: {}", path.display(), e))
+    }
+
+    fn from_file(file: RulesFile) -> Result<Rules, String> {
+        let ids = |names: Vec<String>| names.into_iter()
+            .map(|name| u32::from_str_radix(&name, 16).map_err(|_| format!("{} isn't a client id", name)))
+            .collect::<Result<HashSet<u32>, String>>();
+        Ok(Rules {
+            read_only: ids(file.read_only)?,
+            prefix_owners: file.prefixes.into_iter().map(|(prefix, owners)| Ok((prefix, ids(owners)?))).collect::<Result<_, String>>()?,
+        })
+    }
+}
+
+impl Authorizer for Rules {
+    fn authorize(&self, op: &Operation) -> bool {
+        if op.access == Access::Read {
+            return true;
+        }
+        if op.client_id.map_or(false, |id| self.read_only.contains(&id)) {
+            return false;
+        }
+        let key = match op.key {
+            Some(key) => key,
+            None => return true,
+        };
+        self.prefix_owners.iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .all(|(_, owners)| op.client_id.map_or(false, |id| owners.contains(&id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authz::{Access, Authorizer, Operation, Rules, RulesFile};
+
+    #[test]
+    fn rules() {
+        let rules = Rules::from_file(serde_json::from_str::<RulesFile>(r#"{
+            "read_only": ["0004"],
+            "prefixes": { "billing/": ["0002"], "billing/audit/": ["0003"] }
+        }"#).unwrap()).unwrap();
+        let allowed = |client_id, access, key| rules.authorize(&Operation { client_id, kind: "put", access, key });
+
+        assert!(allowed(Some(1), Access::Write, Some("users/1")));
+        assert!(allowed(Some(4), Access::Read, Some("users/1")));
+        assert!(!allowed(Some(4), Access::Write, Some("users/1")));
+        assert!(!allowed(Some(4), Access::Write, None));
+
+        assert!(allowed(Some(2), Access::Write, Some("billing/1")));
+        assert!(!allowed(Some(1), Access::Write, Some("billing/1")));
+        assert!(!allowed(None, Access::Write, Some("billing/1")));
+        assert!(allowed(None, Access::Read, Some("billing/1")));
+        // under both prefixes, so it takes an owner of each
+        assert!(!allowed(Some(2), Access::Write, Some("billing/audit/1")));
+
+        assert!(Rules::from_file(serde_json::from_str::<RulesFile>(r#"{ "read_only": ["client"] }"#).unwrap()).is_err());
+        assert!(serde_json::from_str::<RulesFile>(r#"{ "readonly": [] }"#).is_err());
+    }
+}
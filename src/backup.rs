@@ -0,0 +1,528 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::{Compression, Crc};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use hmac::{Hmac, Mac};
+use my_raft::bytes::{TryFromBytes, WriteBytes};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const MANIFEST: &str = "manifest.json";
+
+const S3_TIMEOUT: Duration = Duration::from_secs(30);
+// how far back point-in-time restores can go, in snapshots
+const MAX_RETAINED_SNAPSHOTS: usize = 24;
+// tagged snapshots are kept apart from the rest, up to this many of the newest tags
+const MAX_RETAINED_TAGS: usize = 32;
+// compressed segments start with this in place of the entry count, followed by the deflated segment
+const COMPRESSED_SEGMENT_MARKER: u32 = u32::MAX;
+// segments written now start with this, then the entry count, and give each entry's checksum after its length
+const CHECKSUMMED_SEGMENT_MARKER: u32 = u32::MAX - 1;
+
+// Somewhere to keep backups. Objects are only ever written whole, and the manifest is written last, so a backup is
+// never half-visible.
+pub trait BackupTarget: Send {
+    fn put(&mut self, name: &str, data: &[u8]) -> io::Result<()>;
+    fn get(&mut self, name: &str) -> io::Result<Vec<u8>>;
+}
+
+// Every retained snapshot and log segment, each in upload order. A segment replaces whatever earlier segments had from
+// its first index on, since it was only uploaded because the log was truncated and rewritten from there.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub snapshots: Vec<SnapshotInfo>,
+    pub segments: Vec<SegmentInfo>,
+    #[serde(default)]
+    pub tags: Vec<TagInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub last_index: u32,
+    pub last_term: u32,
+    // unix seconds
+    pub uploaded_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SegmentInfo {
+    pub name: String,
+    pub first_index: u32,
+    pub last_index: u32,
+    pub uploaded_at: u64,
+}
+
+// a snapshot named by an operator, e.g. before a risky change, which stays restorable after it's aged out of snapshots
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TagInfo {
+    pub tag: String,
+    pub snapshot: SnapshotInfo,
+    pub tagged_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestorePoint {
+    Latest,
+    // the log is cut off after this index
+    Index(u32),
+    // only what had been uploaded by this unix time is used, so it's as precise as the backup interval
+    Timestamp(u64),
+    // exactly the tagged snapshot
+    Tag(String),
+}
+
+// which objects to restore from: the snapshot, then the segments applied in order, then the log cut off at last_index
+#[derive(Debug, PartialEq)]
+pub struct RestorePlan {
+    pub snapshot: Option<SnapshotInfo>,
+    pub segments: Vec<SegmentInfo>,
+    pub last_index: u32,
+}
+
+pub enum Upload {
+    Snapshot { last_index: u32, last_term: u32, bytes: Vec<u8> },
+    Segment { first_index: u32, last_index: u32, last_term: u32, bytes: Vec<u8> },
+    // tags the newest uploaded snapshot, replacing any tag with the same name
+    Tag { tag: String },
+}
+
+// The uploader's queue, and its manifest as of the last upload. Cloned for everything that sends it uploads.
+#[derive(Clone)]
+pub struct Backups {
+    pub uploads: Sender<Upload>,
+    pub manifest: Arc<Mutex<Manifest>>,
+}
+
+// Uploads happen on their own thread so a slow bucket never holds up Raft. Segments are closed once they're uploaded,
+// so compressing them there costs the main loop nothing.
+pub fn spawn_uploader(mut target: Box<dyn BackupTarget>, compress_segments: bool) -> Backups {
+    let (uploads, receiver) = mpsc::channel();
+    let manifest: Arc<Mutex<Manifest>> = Arc::new(Mutex::new(target.get(MANIFEST).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()));
+
+    let shared = manifest.clone();
+    thread::spawn(move || {
+        let mut manifest = shared.lock().unwrap().clone();
+        for upload in receiver {
+            let upload = match upload {
+                Upload::Segment { first_index, last_index, last_term, bytes } if compress_segments =>
+                    Upload::Segment { first_index, last_index, last_term, bytes: compress_segment(&bytes) },
+                upload => upload,
+            };
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            if let Err(e) = upload_and_update_manifest(target.as_mut(), &mut manifest, upload, now) {
+                eprintln!("backup failed: {}", e);
+            }
+            *shared.lock().unwrap() = manifest.clone();
+        }
+    });
+    Backups { uploads, manifest }
+}
+
+pub fn upload_and_update_manifest(target: &mut dyn BackupTarget, manifest: &mut Manifest, upload: Upload, uploaded_at: u64) -> io::Result<()> {
+    match upload {
+        Upload::Snapshot { last_index, last_term, bytes } => {
+            let name = format!("snapshot-{}-{}", last_index, last_term);
+            target.put(&name, &bytes)?;
+            manifest.snapshots.push(SnapshotInfo { name, last_index, last_term, uploaded_at });
+
+            // the manifest forgets about old objects, they're left in the bucket
+            if manifest.snapshots.len() > MAX_RETAINED_SNAPSHOTS {
+                manifest.snapshots.remove(0);
+                let oldest = manifest.snapshots[0].last_index;
+                manifest.segments.retain(|s| s.last_index > oldest);
+            }
+        }
+        Upload::Segment { first_index, last_index, last_term, bytes } => {
+            let name = format!("segment-{}-{}-{}", first_index, last_index, last_term);
+            target.put(&name, &bytes)?;
+            manifest.segments.push(SegmentInfo { name, first_index, last_index, uploaded_at });
+        }
+        Upload::Tag { tag } => {
+            let snapshot = manifest.snapshots.last().cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no snapshot has been uploaded to tag {}", tag)))?;
+            manifest.tags.retain(|t| t.tag != tag);
+            manifest.tags.push(TagInfo { tag, snapshot, tagged_at: uploaded_at });
+            if manifest.tags.len() > MAX_RETAINED_TAGS {
+                manifest.tags.remove(0);
+            }
+        }
+    }
+    target.put(MANIFEST, serde_json::to_string(manifest).unwrap().as_bytes())
+}
+
+pub fn plan_restore(manifest: &Manifest, point: &RestorePoint) -> Result<RestorePlan, String> {
+    let (max_index, uploaded_by) = match point {
+        RestorePoint::Latest => (u32::MAX, u64::MAX),
+        RestorePoint::Index(index) => (*index, u64::MAX),
+        RestorePoint::Timestamp(time) => (u32::MAX, *time),
+        RestorePoint::Tag(tag) => {
+            let tagged = manifest.tags.iter().find(|t| &t.tag == tag).ok_or_else(|| format!("no snapshot is tagged {}", tag))?;
+            return Ok(RestorePlan { snapshot: Some(tagged.snapshot.clone()), segments: vec![], last_index: tagged.snapshot.last_index });
+        }
+    };
+
+    let snapshot = manifest.snapshots.iter()
+        .rev()
+        .find(|s| s.last_index <= max_index && s.uploaded_at <= uploaded_by)
+        .cloned();
+    let base = snapshot.as_ref().map_or(0, |s| s.last_index);
+
+    // last index covered by the segments so far
+    let mut end = base;
+    let mut segments = vec![];
+    for segment in manifest.segments.iter().filter(|s| s.uploaded_at <= uploaded_by) {
+        if segment.last_index <= base || segment.first_index > max_index {
+            continue;
+        }
+        if segment.first_index > end + 1 {
+            eprintln!("backup is missing entries {} to {}", end + 1, segment.first_index - 1);
+            break;
+        }
+        end = segment.last_index;
+        segments.push(segment.clone());
+    }
+
+    let last_index = end.min(max_index);
+    if let RestorePoint::Index(index) = point {
+        if last_index < *index {
+            return Err(format!("backup only goes up to index {}", last_index));
+        }
+    }
+    Ok(RestorePlan { snapshot, segments, last_index })
+}
+
+pub fn encode_segment<T: WriteBytes>(entries: &[T]) -> Vec<u8> {
+    let mut bytes = CHECKSUMMED_SEGMENT_MARKER.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    let mut entry_bytes = vec![];
+    for entry in entries {
+        entry_bytes.clear();
+        entry.write_bytes_with_writer(&mut entry_bytes).unwrap();
+        bytes.extend_from_slice(&(entry_bytes.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&checksum(&entry_bytes).to_be_bytes());
+        bytes.extend_from_slice(&entry_bytes);
+    }
+    bytes
+}
+
+fn compress_segment(segment: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(COMPRESSED_SEGMENT_MARKER.to_be_bytes().to_vec(), Compression::default());
+    encoder.write_all(segment).unwrap();
+    encoder.finish().unwrap()
+}
+
+// reads segments whether they were compressed or not
+pub fn decode_segment<T: TryFromBytes>(bytes: &[u8]) -> Option<Vec<T>> {
+    let mut rest = bytes;
+    if take_u32(&mut rest)? != COMPRESSED_SEGMENT_MARKER {
+        return decode_entries(bytes);
+    }
+    let mut segment = vec![];
+    DeflateDecoder::new(rest).read_to_end(&mut segment).ok()?;
+    decode_entries(&segment)
+}
+
+fn decode_entries<T: TryFromBytes>(mut bytes: &[u8]) -> Option<Vec<T>> {
+    let mut len = take_u32(&mut bytes)?;
+    let checksummed = len == CHECKSUMMED_SEGMENT_MARKER;
+    if checksummed {
+        len = take_u32(&mut bytes)?;
+    }
+    let mut entries = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let entry_len = take_u32(&mut bytes)? as usize;
+        let sum = if checksummed { Some(take_u32(&mut bytes)?) } else { None };
+        if bytes.len() < entry_len {
+            return None;
+        }
+        let (entry, rest) = bytes.split_at(entry_len);
+        if sum.map_or(false, |sum| sum != checksum(entry)) {
+            return None;
+        }
+        entries.push(T::try_from_slice(entry)?);
+        bytes = rest;
+    }
+    Some(entries)
+}
+
+// the CRC32 that entries and snapshots are written with
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+pub fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (n, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Some(u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+// An S3-compatible bucket reached over plain HTTP with path-style addressing and SigV4 signing, e.g. MinIO or an S3
+// endpoint behind a TLS-terminating proxy.
+pub struct S3Target {
+    // host:port
+    pub endpoint: String,
+    pub bucket: String,
+    // prepended to every object name
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Target {
+    fn request(&self, method: &str, name: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+        let path = format!("/{}/{}{}", self.bucket, self.prefix, name);
+        let (date, time) = utc_date_time(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        let payload_hash = hex(&Sha256::digest(body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!("{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+                                        method, path, self.endpoint, payload_hash, time, signed_headers, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", time, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let mut stream = TcpStream::connect(&self.endpoint)?;
+        stream.set_read_timeout(Some(S3_TIMEOUT))?;
+        stream.set_write_timeout(Some(S3_TIMEOUT))?;
+        write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nx-amz-content-sha256: {}\r\nx-amz-date: {}\r\n\
+                        Authorization: AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n",
+               method, path, self.endpoint, payload_hash, time, self.access_key, scope, signed_headers, signature, body.len())?;
+        stream.write_all(body)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status: u32 = status_line.split_whitespace().nth(1).and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad HTTP status line"))?;
+
+        let mut content_length = None;
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            let mut parts = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse::<usize>().ok();
+                }
+            }
+            header.clear();
+        }
+
+        let mut response = vec![];
+        match content_length {
+            Some(len) => {
+                response.resize(len, 0);
+                reader.read_exact(&mut response)?;
+            }
+            None => {
+                reader.read_to_end(&mut response)?;
+            }
+        }
+
+        if !(200..300).contains(&status) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{} {} returned {}", method, path, status)));
+        }
+        Ok(response)
+    }
+}
+
+impl BackupTarget for S3Target {
+    fn put(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.request("PUT", name, data).map(|_| ())
+    }
+
+    fn get(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        self.request("GET", name, &[])
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// (YYYYMMDD, YYYYMMDDTHHMMSSZ) for a unix timestamp, as SigV4 wants them
+fn utc_date_time(secs: u64) -> (String, String) {
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = format!("{}T{:02}{:02}{:02}Z", date, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60);
+    (date, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+
+    use crate::backup::{BackupTarget, compress_segment, decode_segment, encode_segment, Manifest, plan_restore, RestorePoint, upload_and_update_manifest, Upload, utc_date_time};
+    use crate::state_machine::{DeleteValueCommand, KvCommand, KvOp};
+
+    #[derive(Default)]
+    struct MemoryTarget(HashMap<String, Vec<u8>>);
+
+    impl BackupTarget for MemoryTarget {
+        fn put(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+            self.0.insert(name.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn get(&mut self, name: &str) -> io::Result<Vec<u8>> {
+            self.0.get(name).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_string()))
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(utc_date_time(0), ("19700101".to_string(), "19700101T000000Z".to_string()));
+        assert_eq!(utc_date_time(1_700_000_000), ("20231114".to_string(), "20231114T221320Z".to_string()));
+        assert_eq!(utc_date_time(951_782_400), ("20000229".to_string(), "20000229T000000Z".to_string()));
+    }
+
+    #[test]
+    fn segments() {
+        let entries = vec![
+            KvCommand { mid: "a".to_string(), op: KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None }) },
+            KvCommand { mid: "b".to_string(), op: KvOp::Defrag },
+        ];
+        let bytes = encode_segment(&entries);
+        assert_eq!(decode_segment::<KvCommand>(&bytes), Some(entries));
+        assert_eq!(decode_segment::<KvCommand>(&bytes[..bytes.len() - 1]), None);
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(decode_segment::<KvCommand>(&flipped), None);
+
+        let repeated: Vec<KvCommand> = (0..100).map(|i| KvCommand { mid: i.to_string(), op: KvOp::Defrag }).collect();
+        let plain = encode_segment(&repeated);
+        let compressed = compress_segment(&plain);
+        assert!(compressed.len() < plain.len() / 2);
+        assert_eq!(decode_segment::<KvCommand>(&compressed), Some(repeated));
+        assert_eq!(decode_segment::<KvCommand>(&compressed[..compressed.len() / 2]), None);
+    }
+
+    #[test]
+    fn manifest_keeps_history() {
+        let mut target = MemoryTarget::default();
+        let mut manifest = Manifest::default();
+
+        let segment = |first_index, last_index| Upload::Segment { first_index, last_index, last_term: 1, bytes: vec![] };
+        let snapshot = |last_index| Upload::Snapshot { last_index, last_term: 1, bytes: vec![] };
+        upload_and_update_manifest(&mut target, &mut manifest, segment(1, 10), 100).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, segment(11, 20), 200).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, snapshot(12), 250).unwrap();
+        // truncated back to 15 and rewritten
+        upload_and_update_manifest(&mut target, &mut manifest, segment(15, 25), 300).unwrap();
+
+        let stored: Manifest = serde_json::from_slice(&target.get("manifest.json").unwrap()).unwrap();
+        assert_eq!(stored, manifest);
+
+        for i in 0..30 {
+            upload_and_update_manifest(&mut target, &mut manifest, snapshot(100 + i), 400).unwrap();
+        }
+        assert_eq!(manifest.snapshots.len(), 24);
+        assert!(manifest.segments.is_empty());
+    }
+
+    #[test]
+    fn restore_plans() {
+        let mut target = MemoryTarget::default();
+        let mut manifest = Manifest::default();
+
+        let segment = |first_index, last_index| Upload::Segment { first_index, last_index, last_term: 1, bytes: vec![] };
+        upload_and_update_manifest(&mut target, &mut manifest, segment(1, 10), 100).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, segment(11, 20), 200).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, Upload::Snapshot { last_index: 12, last_term: 1, bytes: vec![] }, 250).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, segment(15, 25), 300).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, segment(30, 40), 400).unwrap();
+
+        let ranges = |plan: &crate::backup::RestorePlan| plan.segments.iter().map(|s| (s.first_index, s.last_index)).collect::<Vec<_>>();
+
+        // stops at the gap after 25
+        let latest = plan_restore(&manifest, &RestorePoint::Latest).unwrap();
+        assert_eq!(latest.snapshot.as_ref().map(|s| s.last_index), Some(12));
+        assert_eq!(ranges(&latest), vec![(11, 20), (15, 25)]);
+        assert_eq!(latest.last_index, 25);
+
+        let before_snapshot = plan_restore(&manifest, &RestorePoint::Index(8)).unwrap();
+        assert_eq!(before_snapshot.snapshot, None);
+        assert_eq!(ranges(&before_snapshot), vec![(1, 10)]);
+        assert_eq!(before_snapshot.last_index, 8);
+
+        let by_time = plan_restore(&manifest, &RestorePoint::Timestamp(220)).unwrap();
+        assert_eq!(by_time.snapshot, None);
+        assert_eq!(ranges(&by_time), vec![(1, 10), (11, 20)]);
+        assert_eq!(by_time.last_index, 20);
+
+        assert!(plan_restore(&manifest, &RestorePoint::Index(35)).is_err());
+    }
+
+    #[test]
+    fn tags() {
+        let mut target = MemoryTarget::default();
+        let mut manifest = Manifest::default();
+
+        let tag = |tag: &str| Upload::Tag { tag: tag.to_string() };
+        assert!(upload_and_update_manifest(&mut target, &mut manifest, tag("too-early"), 50).is_err());
+
+        upload_and_update_manifest(&mut target, &mut manifest, Upload::Snapshot { last_index: 12, last_term: 1, bytes: vec![] }, 100).unwrap();
+        upload_and_update_manifest(&mut target, &mut manifest, tag("pre-migration"), 150).unwrap();
+        for i in 0..30 {
+            upload_and_update_manifest(&mut target, &mut manifest, Upload::Snapshot { last_index: 100 + i, last_term: 2, bytes: vec![] }, 200).unwrap();
+        }
+
+        // still restorable after its snapshot aged out
+        let plan = plan_restore(&manifest, &RestorePoint::Tag("pre-migration".to_string())).unwrap();
+        assert_eq!(plan.snapshot.map(|s| s.last_index), Some(12));
+        assert!(plan.segments.is_empty());
+        assert_eq!(plan.last_index, 12);
+        assert!(plan_restore(&manifest, &RestorePoint::Tag("other".to_string())).is_err());
+
+        // retagging moves the tag
+        upload_and_update_manifest(&mut target, &mut manifest, tag("pre-migration"), 300).unwrap();
+        assert_eq!(manifest.tags.len(), 1);
+        assert_eq!(manifest.tags[0].snapshot.last_index, 129);
+
+        for i in 0..40 {
+            upload_and_update_manifest(&mut target, &mut manifest, tag(&i.to_string()), 400).unwrap();
+        }
+        assert_eq!(manifest.tags.len(), 32);
+        assert_eq!(manifest.tags[0].tag, "8");
+    }
+}
@@ -0,0 +1,43 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use my_project6::client::{Client, Member};
+use serde_json::json;
+
+use crate::metrics::Histogram;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Puts and then gets `requests` keys one at a time through the HTTP client port (KV_HTTP_CLIENTS), giving the rate and
+// latencies in microseconds of each.
+pub fn run(members: Vec<Member>, requests: usize, value_bytes: usize) -> serde_json::Value {
+    let mut client = Client::new(members, TIMEOUT);
+    let value = "x".repeat(value_bytes);
+    let puts = time(requests, |i| client.put(&format!("bench-{}", i), &value));
+    let gets = time(requests, |i| client.get(&format!("bench-{}", i)).map(|_| ()));
+    json!({
+        "requests": requests,
+        "value_bytes": value_bytes,
+        "leader": client.leader(),
+        "puts": puts,
+        "gets": gets,
+    })
+}
+
+fn time(requests: usize, mut request: impl FnMut(usize) -> io::Result<()>) -> serde_json::Value {
+    let mut latency_us = Histogram::default();
+    let mut failures = 0;
+    let started = Instant::now();
+    for i in 0..requests {
+        let sent = Instant::now();
+        match request(i) {
+            Ok(()) => latency_us.record(sent.elapsed().as_micros() as u64),
+            Err(_) => failures += 1,
+        }
+    }
+    json!({
+        "per_sec": requests as f64 / started.elapsed().as_secs_f64(),
+        "failures": failures,
+        "latency_us": latency_us.to_json(),
+    })
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use my_project6::client;
+use my_project6::client::Member;
+use my_project6::drain;
+
+// Maintenance commands for a running cluster, over the members' HTTP ports (KV_HTTP_PORT).
+//
+// usage: kvctl drain <node> <name>=<host:port>...
+//        kvctl metrics dump|reset|growth <host:port>
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: kvctl drain <node> <name>=<host:port>...\n       kvctl metrics dump|reset|growth <host:port>";
+
+    match args.next().as_deref() {
+        Some("drain") => {
+            let node = args.next().expect(usage);
+            let members: Vec<Member> = args.map(|arg| parse_member(&arg).unwrap_or_else(|| panic!("invalid member {}, {}", arg, usage))).collect();
+            let report = drain::drain(&node, &members, TIMEOUT);
+            for step in &report.steps {
+                println!("{}", step);
+            }
+            if report.problems.is_empty() {
+                println!("{} is safe to stop", node);
+            } else {
+                for problem in &report.problems {
+                    eprintln!("not safe to stop: {}", problem);
+                }
+                std::process::exit(1);
+            }
+        }
+        // a reset answers with what was recorded up until then, so a benchmark run is bracketed by two resets
+        Some("metrics") => {
+            let (method, path) = match args.next().as_deref() {
+                Some("dump") => ("GET", "/metrics/dump"),
+                Some("reset") => ("POST", "/metrics/reset"),
+                Some("growth") => ("GET", "/storage/growth"),
+                _ => panic!("{}", usage),
+            };
+            let addr = args.next().and_then(|addr| addr.parse().ok()).expect(usage);
+            match client::admin(addr, method, path, TIMEOUT) {
+                Ok(metrics) => println!("{}", serde_json::to_string_pretty(&metrics).unwrap()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_member(arg: &str) -> Option<Member> {
+    let separator = arg.find('=')?;
+    Some(Member { name: arg[..separator].to_string(), addr: arg[separator + 1..].parse().ok()? })
+}
@@ -0,0 +1,316 @@
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockAddr, SockFlag, SockType};
+use serde_json::Value;
+
+use my_project6::protocol::{Message, Request as ClientRequest, Response};
+
+// Drives a local cluster the same way the CS3700 simulator does (one SeqPacket socket per replica, messages routed
+// by dst), while generating a randomized get/put workload and checking that every get observes the latest
+// acknowledged put.
+//
+// usage: soak <replica binary> [replicas] [seconds, 0 runs forever]
+
+const PACKET_SIZE: usize = 65527;
+const CLIENT_ID: &str = "C000";
+const NUM_KEYS: u64 = 32;
+const REQUEST_INTERVAL: Duration = Duration::from_millis(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const PUT_HISTORY: usize = 64;
+
+struct Replica {
+    name: String,
+    fd: RawFd,
+    process: Child,
+}
+
+enum Request {
+    Get { key: String, sent_at: Instant },
+    Put { key: String, value: String, sent_at: Instant },
+}
+
+struct Put {
+    value: String,
+    sent_at: Instant,
+    acked_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    ok_gets: u64,
+    ok_puts: u64,
+    redirects: u64,
+    fails: u64,
+    timeouts: u64,
+    violations: u64,
+    latencies: Vec<Duration>,
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+struct Soak {
+    replicas: Vec<Replica>,
+    leader: Option<String>,
+    pending: HashMap<String, Request>,
+    puts: HashMap<String, VecDeque<Put>>,
+    next_mid: u64,
+    rng: Rng,
+    stats: Stats,
+    buffer: Vec<u8>,
+}
+
+fn main() {
+    let mut args = std::env::args();
+    args.next();
+
+    let binary = PathBuf::from(args.next().expect("usage: soak <replica binary> [replicas] [seconds]"))
+        .canonicalize()
+        .expect("replica binary not found");
+    let num_replicas: u32 = args.next().map(|s| s.parse().expect("invalid replica count")).unwrap_or(5);
+    let seconds: u64 = args.next().map(|s| s.parse().expect("invalid duration")).unwrap_or(0);
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 | 1;
+    println!("soak: {} replicas, seed {}", num_replicas, seed);
+
+    let mut soak = Soak {
+        replicas: start_replicas(&binary, num_replicas),
+        leader: None,
+        pending: HashMap::new(),
+        puts: HashMap::new(),
+        next_mid: 0,
+        rng: Rng(seed),
+        stats: Stats::default(),
+        buffer: vec![0u8; PACKET_SIZE],
+    };
+
+    let start = Instant::now();
+    let mut last_request = start;
+    let mut last_report = start;
+
+    while seconds == 0 || start.elapsed() < Duration::from_secs(seconds) {
+        soak.route_messages(REQUEST_INTERVAL);
+
+        if last_request.elapsed() >= REQUEST_INTERVAL {
+            soak.send_request();
+            last_request = Instant::now();
+        }
+
+        soak.expire_requests();
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            soak.report(start.elapsed());
+            last_report = Instant::now();
+        }
+    }
+
+    soak.report(start.elapsed());
+
+    for replica in &mut soak.replicas {
+        let _ = replica.process.kill();
+    }
+
+    if soak.stats.violations > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn start_replicas(binary: &PathBuf, num_replicas: u32) -> Vec<Replica> {
+    let dir = std::env::temp_dir().join(format!("soak-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let names: Vec<String> = (0..num_replicas).map(|n| format!("{:0>4X}", n)).collect();
+
+    let listeners: Vec<RawFd> = names.iter().map(|name| {
+        let fd = socket::socket(AddressFamily::Unix, SockType::SeqPacket, SockFlag::empty(), None).unwrap();
+        socket::bind(fd, &SockAddr::new_unix(dir.join(name).as_path()).unwrap()).unwrap();
+        socket::listen(fd, 1).unwrap();
+        fd
+    }).collect();
+
+    names.iter().zip(listeners).map(|(name, listener)| {
+        let process = Command::new(binary)
+            .arg(name)
+            .args(names.iter().filter(|n| *n != name))
+            .current_dir(&dir)
+            .spawn()
+            .unwrap();
+        let fd = socket::accept(listener).unwrap();
+        Replica { name: name.clone(), fd, process }
+    }).collect()
+}
+
+impl Soak {
+    fn route_messages(&mut self, timeout: Duration) {
+        let mut fds: Vec<PollFd> = self.replicas.iter().map(|r| PollFd::new(r.fd, PollFlags::POLLIN)).collect();
+        if poll(&mut fds, timeout.as_millis() as i32).unwrap_or(0) <= 0 {
+            return;
+        }
+
+        let ready: Vec<RawFd> = fds.iter()
+            .zip(&self.replicas)
+            .filter(|(fd, _)| fd.revents().map_or(false, |r| r.contains(PollFlags::POLLIN)))
+            .map(|(_, r)| r.fd)
+            .collect();
+
+        for fd in ready {
+            let amt = match socket::recv(fd, &mut self.buffer, MsgFlags::empty()) {
+                Ok(amt) if amt > 0 => amt,
+                _ => continue,
+            };
+
+            let message: Value = serde_json::from_slice(&self.buffer[..amt]).expect("replica sent invalid JSON");
+            let dst = message["dst"].as_str().unwrap_or("").to_string();
+
+            if dst == CLIENT_ID {
+                // anything that isn't a response to a client request is ignored
+                if let Ok(response) = serde_json::from_value(message) {
+                    self.handle_response(response);
+                }
+            } else if let Some(replica) = self.replicas.iter().find(|r| r.name == dst) {
+                let _ = socket::send(replica.fd, &self.buffer[..amt], MsgFlags::empty());
+            }
+        }
+    }
+
+    fn send_request(&mut self) {
+        let key = format!("key{}", self.rng.next() % NUM_KEYS);
+        let mid = format!("{:X}", self.next_mid);
+        self.next_mid += 1;
+
+        let dst = match &self.leader {
+            Some(leader) => leader.clone(),
+            None => self.replicas[(self.rng.next() % self.replicas.len() as u64) as usize].name.clone(),
+        };
+
+        let now = Instant::now();
+        let request = if self.rng.next() % 2 == 0 {
+            self.pending.insert(mid.clone(), Request::Get { key: key.clone(), sent_at: now });
+            ClientRequest::Get { mid, key, min_index: 0 }
+        } else {
+            let value = format!("value-{}", mid);
+            let puts = self.puts.entry(key.clone()).or_default();
+            puts.push_back(Put { value: value.clone(), sent_at: now, acked_at: None });
+            if puts.len() > PUT_HISTORY {
+                puts.pop_front();
+            }
+            self.pending.insert(mid.clone(), Request::Put { key: key.clone(), value: value.clone(), sent_at: now });
+            ClientRequest::Put { mid, key, value, sensitive: false }
+        };
+        let message = serde_json::to_vec(&Message::new(CLIENT_ID, &dst, self.leader.as_deref(), request)).unwrap();
+
+        if let Some(replica) = self.replicas.iter().find(|r| r.name == dst) {
+            let _ = socket::send(replica.fd, &message, MsgFlags::empty());
+            self.stats.sent += 1;
+        }
+    }
+
+    fn handle_response(&mut self, message: Message<Response>) {
+        self.leader = message.leader().map(|leader| leader.to_string());
+
+        let request = match self.pending.remove(message.body.mid()) {
+            Some(request) => request,
+            None => return,
+        };
+
+        let value = match message.body {
+            Response::Ok { value, .. } => value.unwrap_or_default(),
+            Response::Redirect { .. } => {
+                self.stats.redirects += 1;
+                return;
+            }
+            Response::Fail { .. } => {
+                self.stats.fails += 1;
+                return;
+            }
+            Response::Expired { .. } => return,
+        };
+
+        let now = Instant::now();
+        match request {
+            Request::Get { key, sent_at } => {
+                self.stats.ok_gets += 1;
+                self.stats.latencies.push(now - sent_at);
+
+                if !self.get_is_valid(&key, &value, sent_at) {
+                    self.stats.violations += 1;
+                    eprintln!("soak: VIOLATION get {} returned {:?}, which was overwritten before the get was sent", key, value);
+                }
+            }
+            Request::Put { key, value, sent_at } => {
+                self.stats.ok_puts += 1;
+                self.stats.latencies.push(now - sent_at);
+
+                if let Some(put) = self.puts.get_mut(&key).and_then(|puts| puts.iter_mut().find(|p| p.value == value)) {
+                    put.acked_at = Some(now);
+                }
+            }
+        }
+    }
+
+    // a get may return any put that wasn't overwritten before the get was sent, i.e. no other put was both sent after
+    // it was acknowledged and acknowledged itself before the get was sent. Unacknowledged puts may land at any time.
+    fn get_is_valid(&self, key: &str, value: &str, get_sent_at: Instant) -> bool {
+        let puts = match self.puts.get(key) {
+            Some(puts) => puts,
+            None => return value.is_empty(),
+        };
+
+        let overwritten_after = |after: Option<Instant>| puts.iter().any(|q|
+            q.acked_at.map_or(false, |q_acked| q_acked < get_sent_at)
+                && after.map_or(true, |after| q.sent_at > after));
+
+        if value.is_empty() {
+            return !overwritten_after(None) || puts.len() == PUT_HISTORY;
+        }
+
+        match puts.iter().find(|p| p.value == value) {
+            Some(Put { acked_at: Some(acked_at), .. }) => !overwritten_after(Some(*acked_at)),
+            Some(Put { acked_at: None, .. }) => true,
+            // fell out of the history window, so it's too old to judge
+            None => puts.len() == PUT_HISTORY,
+        }
+    }
+
+    fn expire_requests(&mut self) {
+        let before = self.pending.len();
+        self.pending.retain(|_, request| match request {
+            Request::Get { sent_at, .. } | Request::Put { sent_at, .. } => sent_at.elapsed() < REQUEST_TIMEOUT,
+        });
+        self.stats.timeouts += (before - self.pending.len()) as u64;
+    }
+
+    fn report(&mut self, elapsed: Duration) {
+        self.stats.latencies.sort();
+        let median = self.stats.latencies.get(self.stats.latencies.len() / 2).copied().unwrap_or_default();
+
+        println!("soak: {}s sent={} ok_gets={} ok_puts={} redirects={} fails={} timeouts={} violations={} median_latency={:?}",
+                 elapsed.as_secs(),
+                 self.stats.sent,
+                 self.stats.ok_gets,
+                 self.stats.ok_puts,
+                 self.stats.redirects,
+                 self.stats.fails,
+                 self.stats.timeouts,
+                 self.stats.violations,
+                 median);
+
+        self.stats.latencies.clear();
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use my_project6::client::Member;
+
+use crate::local_cluster;
+
+// Without a subcommand the arguments are run's, so the testbed's `3700kvstore <id> <peer id>...` still starts a node.
+#[derive(Debug, Parser)]
+#[clap(name = "3700kvstore", about = "A key-value store replicated with Raft", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    #[clap(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[clap(about = "Run a node, the default")]
+    Run(RunArgs),
+    #[clap(about = "Print what's in a stopped node's write-ahead log as JSON")]
+    InspectLog {
+        #[clap(help = "The node's KV_WAL_DIR")]
+        wal_dir: PathBuf,
+    },
+    #[clap(about = "Rewrite a stopped node's snapshot in KV_SNAPSHOT_FORMAT and delete the log segments it covers")]
+    Compact {
+        #[clap(help = "The node's KV_WAL_DIR")]
+        wal_dir: PathBuf,
+    },
+    #[clap(about = "Time puts and gets against a running cluster's HTTP client port")]
+    Bench {
+        #[clap(long, default_value = "1000", help = "How many keys to put and then get")]
+        requests: usize,
+        #[clap(long, default_value = "16", help = "How long each value is")]
+        value_bytes: usize,
+        #[clap(required = true, value_name = "NAME=HOST:PORT", value_parser = parse_member, help = "Members of the cluster")]
+        members: Vec<Member>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[clap(long, value_name = "FILE", help = "Settings and nodes from a JSON or TOML file, also KV_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[clap(long, value_name = "NODES", help = "Run a whole cluster of this many nodes in this process")]
+    pub local_cluster: Option<usize>,
+    #[clap(long, value_name = "PORT", default_value_t = local_cluster::DEFAULT_CLIENT_PORT, help = "The local cluster's first HTTP client port")]
+    pub client_port: u16,
+    #[clap(long, help = "Make this node the only member of a new cluster, keeping its data, once a quorum is lost for good")]
+    pub force_new_cluster: bool,
+    #[clap(long, requires = "force_new_cluster", help = "Don't ask before forcing a new cluster")]
+    pub yes: bool,
+    #[clap(value_name = "NODE", help = "Our id then our peers', or <id>=<host:port> for each member with KV_TRANSPORT")]
+    pub nodes: Vec<String>,
+}
+
+fn parse_member(arg: &str) -> Result<Member, String> {
+    let separator = arg.find('=').ok_or_else(|| format!("expected <name>=<host:port>, got {}", arg))?;
+    let addr = arg[separator + 1..].parse().map_err(|e| format!("invalid address in {}: {}", arg, e))?;
+    Ok(Member { name: arg[..separator].to_string(), addr })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::cli::{Cli, Command};
+
+    #[test]
+    fn parses() {
+        let cli = Cli::try_parse_from(&["3700kvstore", "0000", "0001", "0002"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.run.nodes, vec!["0000", "0001", "0002"]);
+
+        let cli = Cli::try_parse_from(&["3700kvstore", "run", "--config", "kv.toml"]).unwrap();
+        match cli.command {
+            Some(Command::Run(args)) => assert_eq!(args.config.unwrap().to_str(), Some("kv.toml")),
+            command => panic!("expected run, got {:?}", command),
+        }
+
+        let cli = Cli::try_parse_from(&["3700kvstore", "bench", "--requests", "10", "0000=127.0.0.1:7000"]).unwrap();
+        match cli.command {
+            Some(Command::Bench { members, requests, value_bytes }) => {
+                assert_eq!((members[0].name.as_str(), requests, value_bytes), ("0000", 10, 16));
+            }
+            command => panic!("expected bench, got {:?}", command),
+        }
+
+        assert!(Cli::try_parse_from(&["3700kvstore", "--local-cluster", "three"]).is_err());
+        assert!(Cli::try_parse_from(&["3700kvstore", "bench", "127.0.0.1:7000"]).is_err());
+        assert!(Cli::try_parse_from(&["3700kvstore", "inspect-log"]).is_err());
+        assert!(Cli::try_parse_from(&["3700kvstore", "--force-new-cluster", "--yes", "0000"]).unwrap().run.force_new_cluster);
+        assert!(Cli::try_parse_from(&["3700kvstore", "--yes", "0000"]).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::protocol::Response;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// a replica's name, which is what redirects point at, and its HTTP client port
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+struct MemberState {
+    member: Member,
+    // how long to stay away after the latest of a run of connection errors, and until when
+    backoff: Duration,
+    down_until: Option<Instant>,
+}
+
+impl MemberState {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.map_or(true, |t| now >= t)
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.backoff = (self.backoff * 2).max(INITIAL_BACKOFF).min(MAX_BACKOFF);
+        self.down_until = Some(now + self.backoff);
+    }
+
+    fn succeeded(&mut self) {
+        self.backoff = Duration::from_millis(0);
+        self.down_until = None;
+    }
+}
+
+#[derive(Deserialize)]
+struct HttpResponse {
+    #[serde(default)]
+    leader: Option<String>,
+    #[serde(flatten)]
+    body: Response,
+}
+
+// What applications use to talk to the store, so they can be tested against MemoryClient instead of a cluster. The
+// HTTP front end has no compare-and-swap or watch, so neither does this.
+pub trait KvClient {
+    // the empty string if the key isn't set
+    fn get(&mut self, key: &str) -> io::Result<String>;
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()>;
+    // deleting a key that isn't set succeeds too
+    fn delete(&mut self, key: &str) -> io::Result<()>;
+}
+
+// A client for the HTTP front end (KV_HTTP_CLIENTS) that knows every member of the cluster. Requests go to the
+// leader once a redirect has named it, members that refuse connections are skipped with a growing backoff, and a
+// request fails over to the next member until every one has been tried. Replicas only answer reads on the leader, so
+// reads get the same leader affinity as writes.
+pub struct Client {
+    members: Vec<MemberState>,
+    leader: Option<usize>,
+    // where to start looking when there's no known leader, moved on past members that fail
+    next: usize,
+    timeout: Duration,
+}
+
+impl Client {
+    pub fn new(members: Vec<Member>, timeout: Duration) -> Client {
+        let members = members.into_iter()
+            .map(|member| MemberState { member, backoff: Duration::from_millis(0), down_until: None })
+            .collect();
+        Client { members, leader: None, next: 0, timeout }
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.map(|i| self.members[i].member.name.as_str())
+    }
+
+    // the empty string if the key isn't set
+    pub fn get(&mut self, key: &str) -> io::Result<String> {
+        self.request("GET", key, b"")
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.request("PUT", key, value.as_bytes()).map(|_| ())
+    }
+
+    // deleting a key that isn't set succeeds too
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.request("DELETE", key, b"").map(|_| ())
+    }
+
+    fn request(&mut self, method: &str, key: &str, body: &[u8]) -> io::Result<String> {
+        let path = format!("/kv/{}", percent_encode(key));
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "no members are up");
+
+        // a redirect and a failover each use up an attempt, so a cluster with no leader can't bounce a request forever
+        for _ in 0..self.members.len() * 2 {
+            let i = match self.pick() {
+                Some(i) => i,
+                None => break,
+            };
+            let now = Instant::now();
+            let response = match send(self.members[i].member.addr, method, &path, body, self.timeout) {
+                Ok(response) => response,
+                Err(e) => {
+                    self.members[i].failed(now);
+                    if self.leader == Some(i) {
+                        self.leader = None;
+                    }
+                    self.next = (i + 1) % self.members.len();
+                    last_error = e;
+                    continue;
+                }
+            };
+            self.members[i].succeeded();
+
+            let leader = response.leader.as_ref().and_then(|name| self.members.iter().position(|m| &m.member.name == name));
+            match response.body {
+                Response::Ok { value, .. } => {
+                    self.leader = leader.or(self.leader);
+                    return Ok(value.unwrap_or_default());
+                }
+                Response::Redirect { .. } => {
+                    self.leader = leader;
+                    if leader.is_none() {
+                        self.next = (i + 1) % self.members.len();
+                    }
+                    last_error = io::Error::new(io::ErrorKind::Other, format!("{} redirected without a known leader", self.members[i].member.name));
+                }
+                Response::Fail { .. } => return Err(io::Error::new(io::ErrorKind::Other, format!("{} failed the request", self.members[i].member.name))),
+                // HTTP clients are never notified
+                Response::Expired { .. } => last_error = io::Error::new(io::ErrorKind::InvalidData, "unexpected expired message"),
+            }
+        }
+        Err(last_error)
+    }
+
+    // the leader if it's up, otherwise the next member that is
+    fn pick(&self) -> Option<usize> {
+        let now = Instant::now();
+        if let Some(leader) = self.leader.filter(|i| self.members[*i].is_up(now)) {
+            return Some(leader);
+        }
+        (0..self.members.len())
+            .map(|offset| (self.next + offset) % self.members.len())
+            .find(|i| self.members[*i].is_up(now))
+    }
+}
+
+impl KvClient for Client {
+    fn get(&mut self, key: &str) -> io::Result<String> {
+        Client::get(self, key)
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        Client::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        Client::delete(self, key)
+    }
+}
+
+fn send(addr: SocketAddr, method: &str, path: &str, body: &[u8], timeout: Duration) -> io::Result<HttpResponse> {
+    let body = http(addr, method, path, body, timeout)?;
+    serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// one of a replica's status or admin endpoints on its HTTP port, whose JSON comes back whatever the status code
+pub fn admin(addr: SocketAddr, method: &str, path: &str, timeout: Duration) -> io::Result<serde_json::Value> {
+    let body = http(addr, method, path, b"", timeout)?;
+    serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn http(addr: SocketAddr, method: &str, path: &str, body: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let header = format!("{} {} HTTP/1.0\r\nContent-Length: {}\r\n\r\n", method, path, body.len());
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)?;
+
+    // the server closes the connection after its response
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    let body_start = response.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "response has no body"))? + 4;
+    response.drain(..body_start);
+    Ok(response)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::client::{Client, Member, percent_encode};
+
+    // answers each connection with the next of the bodies, then stops listening
+    fn serve(bodies: Vec<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for body in bodies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                let mut body_len = 0;
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(len) = line.strip_prefix("Content-Length: ") {
+                        body_len = len.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                // read the request body so closing doesn't reset the connection before the client reads the response
+                reader.read_exact(&mut vec![0; body_len]).unwrap();
+                let _ = write!(reader.get_mut(), "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn follows_redirects_and_fails_over() {
+        // nothing listens here, so the client has to move on
+        let down = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let follower = serve(vec![r#"{"type":"redirect","MID":"1","leader":"0002"}"#]);
+        let leader = serve(vec![
+            r#"{"type":"ok","MID":"2","leader":"0002"}"#,
+            r#"{"type":"ok","MID":"3","value":"v","leader":"0002"}"#,
+        ]);
+
+        let members = vec![
+            Member { name: "0000".to_string(), addr: down },
+            Member { name: "0001".to_string(), addr: follower },
+            Member { name: "0002".to_string(), addr: leader },
+        ];
+        let mut client = Client::new(members, Duration::from_secs(1));
+
+        client.put("k", "v").unwrap();
+        assert_eq!(client.leader(), Some("0002"));
+        assert_eq!(client.get("k").unwrap(), "v");
+
+        // everything is gone now
+        assert!(client.get("k").is_err());
+        assert_eq!(client.leader(), None);
+    }
+
+    #[test]
+    fn encodes_keys() {
+        assert_eq!(percent_encode("a b/c"), "a%20b%2Fc");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::Serialize;
+
+// clients not seen for the longest are forgotten past this many
+const MAX_CLIENTS: usize = 4096;
+
+// token bucket refilled at rate requests per second, holding up to burst
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct ClientLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+#[derive(Default, Serialize)]
+pub struct ClientStats {
+    pub requests: u64,
+    pub bytes: u64,
+    pub redirects: u64,
+    pub failures: u64,
+    pub rate_limited: u64,
+}
+
+struct Client {
+    stats: ClientStats,
+    tokens: f64,
+    last_seen: Instant,
+}
+
+// Request accounting and rate limits per client id. Limits can be changed at runtime, for every client or just one,
+// and are local to this node.
+pub struct ClientTable {
+    clients: HashMap<u32, Client>,
+    default_limit: Option<ClientLimit>,
+    limits: HashMap<u32, Option<ClientLimit>>,
+}
+
+impl ClientTable {
+    pub fn new(default_limit: Option<ClientLimit>) -> ClientTable {
+        ClientTable { clients: HashMap::new(), default_limit, limits: HashMap::new() }
+    }
+
+    pub fn limit(&self, id: u32) -> Option<ClientLimit> {
+        self.limits.get(&id).copied().unwrap_or(self.default_limit)
+    }
+
+    // the limit for one client, overriding the default, or the default when id is None
+    pub fn set_limit(&mut self, id: Option<u32>, limit: Option<ClientLimit>) {
+        match id {
+            Some(id) => {
+                self.limits.insert(id, limit);
+            }
+            None => self.default_limit = limit,
+        }
+    }
+
+    // counts a request, giving whether it's within the client's rate limit
+    pub fn request(&mut self, id: u32, bytes: usize, now: Instant) -> bool {
+        let limit = self.limit(id);
+
+        if !self.clients.contains_key(&id) && self.clients.len() >= MAX_CLIENTS {
+            if let Some(oldest) = self.clients.iter().min_by_key(|(_, c)| c.last_seen).map(|(id, _)| *id) {
+                self.clients.remove(&oldest);
+            }
+        }
+        let client = self.clients.entry(id).or_insert_with(|| Client {
+            stats: ClientStats::default(),
+            tokens: limit.map_or(0.0, |l| l.burst),
+            last_seen: now,
+        });
+
+        client.stats.requests += 1;
+        client.stats.bytes += bytes as u64;
+
+        let elapsed = now.saturating_duration_since(client.last_seen).as_secs_f64();
+        client.last_seen = now;
+
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        client.tokens = (client.tokens + elapsed * limit.rate).min(limit.burst);
+        if client.tokens >= 1.0 {
+            client.tokens -= 1.0;
+            true
+        } else {
+            client.stats.rate_limited += 1;
+            false
+        }
+    }
+
+    pub fn redirected(&mut self, id: u32) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.stats.redirects += 1;
+        }
+    }
+
+    pub fn failed(&mut self, id: u32) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.stats.failures += 1;
+        }
+    }
+
+    // whether the client has sent anything recently enough to still be remembered
+    pub fn is_known(&self, id: u32) -> bool {
+        self.clients.contains_key(&id)
+    }
+
+    pub fn stats(&self) -> impl Iterator<Item=(u32, &ClientStats)> {
+        self.clients.iter().map(|(id, client)| (*id, &client.stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::clients::{ClientLimit, ClientTable};
+
+    #[test]
+    fn rate_limits() {
+        let mut table = ClientTable::new(Some(ClientLimit { rate: 10.0, burst: 2.0 }));
+        let start = Instant::now();
+
+        assert!(table.request(1, 10, start));
+        assert!(table.request(1, 10, start));
+        assert!(!table.request(1, 10, start));
+        // a tenth of a second refills one token
+        assert!(table.request(1, 10, start + Duration::from_millis(100)));
+        assert!(!table.request(1, 10, start + Duration::from_millis(100)));
+
+        table.set_limit(Some(2), None);
+        for _ in 0..100 {
+            assert!(table.request(2, 10, start));
+        }
+
+        let stats: Vec<_> = table.stats().filter(|(id, _)| *id == 1).collect();
+        assert_eq!(stats[0].1.requests, 5);
+        assert_eq!(stats[0].1.bytes, 50);
+        assert_eq!(stats[0].1.rate_limited, 2);
+    }
+}
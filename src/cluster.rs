@@ -0,0 +1,160 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use my_raft::config::NodeAddress;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+const CLUSTER_ID_FILE: &str = "cluster_id";
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ClusterId(pub u128);
+
+impl ClusterId {
+    // used when no cluster id is configured, so nodes started with the same peer list agree on an id
+    pub fn from_nodes(nodes: &HashMap<u32, NodeAddress>) -> ClusterId {
+        let mut ids: Vec<u32> = nodes.keys().copied().collect();
+        ids.sort();
+
+        let mut high = DefaultHasher::new();
+        let mut low = DefaultHasher::new();
+        low.write_u8(0xff);
+        for id in ids {
+            high.write_u32(id);
+            low.write_u32(id);
+        }
+
+        ClusterId(((high.finish() as u128) << 64) | low.finish() as u128)
+    }
+
+    // random version 4 UUID
+    pub fn generate() -> io::Result<ClusterId> {
+        let mut bytes = [0u8; 16];
+        File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        let n = u128::from_be_bytes(bytes);
+        let n = (n & !(0xf_u128 << 76)) | (0x4_u128 << 76);
+        let n = (n & !(0x3_u128 << 62)) | (0x2_u128 << 62);
+        Ok(ClusterId(n))
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+
+    // Reads the cluster id stored in the data directory, writing one (the configured id, or a new random one) if the
+    // directory hasn't been bootstrapped yet. Fails if the stored id doesn't match the configured one.
+    pub fn load_or_bootstrap(data_dir: &Path, configured: Option<ClusterId>) -> io::Result<ClusterId> {
+        let path = data_dir.join(CLUSTER_ID_FILE);
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let stored: ClusterId = contents.trim().parse().map_err(|_|
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{} does not contain a valid cluster id", path.display())))?;
+
+                match configured {
+                    Some(configured) if configured != stored => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("data directory {} belongs to cluster {}, but cluster {} is configured", data_dir.display(), stored, configured))),
+                    _ => Ok(stored)
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let id = match configured {
+                    Some(id) => id,
+                    None => ClusterId::generate()?,
+                };
+                ClusterId::replace(data_dir, id)?;
+                Ok(id)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    // stores the id in the data directory whether or not it has one, for a node forced into a new cluster
+    pub fn replace(data_dir: &Path, id: ClusterId) -> io::Result<()> {
+        fs::create_dir_all(data_dir)?;
+        let tmp = data_dir.join(format!("{}.tmp", CLUSTER_ID_FILE));
+        fs::write(&tmp, format!("{}\n", id))?;
+        fs::rename(&tmp, data_dir.join(CLUSTER_ID_FILE))
+    }
+}
+
+impl fmt::Display for ClusterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = self.0;
+        write!(f, "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+               (n >> 96) as u32,
+               (n >> 80) as u16,
+               (n >> 64) as u16,
+               (n >> 48) as u16,
+               n & 0xffff_ffff_ffff)
+    }
+}
+
+impl FromStr for ClusterId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 {
+            return Err(());
+        }
+        u128::from_str_radix(&hex, 16).map(ClusterId).map_err(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use my_raft::config::NodeAddress;
+
+    use crate::cluster::ClusterId;
+
+    #[test]
+    fn display_and_parse() {
+        let id = ClusterId(0x0123_4567_89ab_cdef_0011_2233_4455_6677);
+        assert_eq!(id.to_string(), "01234567-89ab-cdef-0011-223344556677");
+        assert_eq!(id.to_string().parse::<ClusterId>(), Ok(id));
+        assert_eq!("not-a-uuid".parse::<ClusterId>(), Err(()));
+    }
+
+    #[test]
+    fn from_nodes_ignores_order() {
+        let mut a = HashMap::new();
+        a.insert(1, NodeAddress::String("0001".to_string()));
+        a.insert(2, NodeAddress::String("0002".to_string()));
+
+        let mut b = HashMap::new();
+        b.insert(2, NodeAddress::String("0002".to_string()));
+        b.insert(1, NodeAddress::String("0001".to_string()));
+
+        assert_eq!(ClusterId::from_nodes(&a), ClusterId::from_nodes(&b));
+    }
+
+    #[test]
+    fn data_dir_fencing() {
+        let dir = std::env::temp_dir().join(format!("cluster_id_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let id = ClusterId::load_or_bootstrap(&dir, None).unwrap();
+        assert_eq!(ClusterId::load_or_bootstrap(&dir, None).unwrap(), id);
+        assert_eq!(ClusterId::load_or_bootstrap(&dir, Some(id)).unwrap(), id);
+        assert!(ClusterId::load_or_bootstrap(&dir, Some(ClusterId(id.0 ^ 1))).is_err());
+
+        let rotated = ClusterId(id.0 ^ 1);
+        ClusterId::replace(&dir, rotated).unwrap();
+        assert_eq!(ClusterId::load_or_bootstrap(&dir, None).unwrap(), rotated);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
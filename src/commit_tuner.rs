@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+const MAX_SAMPLES: usize = 256;
+// the window only moves once there are this many commits to go on, and no more often than every ADJUST_INTERVAL
+const MIN_SAMPLES: usize = 20;
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+pub const MIN_WINDOW: Duration = Duration::from_millis(5);
+
+// Keeps the leader's commit latency (command received to applied) under a target by moving how long it lets work
+// pile up: halving the window whenever the p99 is over the target, and doubling it back towards the heartbeat timeout
+// once the p99 is comfortably under it, so an idle cluster goes back to sending as little as it did.
+pub struct CommitTuner {
+    target: Duration,
+    max_window: Duration,
+    window: Duration,
+    // commits since the last time the window was looked at
+    samples: VecDeque<Duration>,
+    last_adjusted: Option<Instant>,
+    last_p99: Option<Duration>,
+    shrinks: u64,
+    grows: u64,
+}
+
+impl CommitTuner {
+    pub fn new(target: Duration, max_window: Duration) -> CommitTuner {
+        let max_window = max_window.max(MIN_WINDOW);
+        CommitTuner {
+            target,
+            max_window,
+            window: max_window,
+            samples: VecDeque::new(),
+            last_adjusted: None,
+            last_p99: None,
+            shrinks: 0,
+            grows: 0,
+        }
+    }
+
+    pub fn set_max_window(&mut self, max_window: Duration) {
+        self.max_window = max_window.max(MIN_WINDOW);
+        self.window = self.window.min(self.max_window);
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    // as of when the window was last looked at
+    pub fn last_p99(&self) -> Option<Duration> {
+        self.last_p99
+    }
+
+    // gives back the new window when this commit moved it
+    pub fn record(&mut self, latency: Duration, now: Instant) -> Option<Duration> {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+
+        if self.samples.len() < MIN_SAMPLES || self.last_adjusted.map_or(false, |t| now.duration_since(t) < ADJUST_INTERVAL) {
+            return None;
+        }
+        let p99 = self.p99()?;
+        self.samples.clear();
+        self.last_adjusted = Some(now);
+        self.last_p99 = Some(p99);
+
+        let window = if p99 > self.target {
+            (self.window / 2).max(MIN_WINDOW)
+        } else if p99 < self.target / 2 {
+            (self.window * 2).min(self.max_window)
+        } else {
+            self.window
+        };
+        if window == self.window {
+            return None;
+        }
+
+        if window < self.window {
+            self.shrinks += 1;
+        } else {
+            self.grows += 1;
+        }
+        self.window = window;
+        Some(window)
+    }
+
+    fn p99(&self) -> Option<Duration> {
+        let mut samples: Vec<Duration> = self.samples.iter().copied().collect();
+        samples.sort_unstable();
+        samples.get((samples.len() * 99 / 100).min(samples.len().saturating_sub(1))).copied()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "target_ms": self.target.as_secs_f64() * 1000.0,
+            "p99_ms": self.last_p99.map(|p99| p99.as_secs_f64() * 1000.0),
+            "window_ms": self.window.as_secs_f64() * 1000.0,
+            "max_window_ms": self.max_window.as_secs_f64() * 1000.0,
+            "shrinks": self.shrinks,
+            "grows": self.grows,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::commit_tuner::{CommitTuner, MIN_WINDOW};
+
+    #[test]
+    fn adapts_window() {
+        let mut tuner = CommitTuner::new(Duration::from_millis(20), Duration::from_millis(100));
+        let mut now = Instant::now();
+        let mut record = |tuner: &mut CommitTuner, ms: u64, count: usize| {
+            now += Duration::from_secs(2);
+            (0..count).filter_map(|_| tuner.record(Duration::from_millis(ms), now)).last()
+        };
+
+        assert_eq!(record(&mut tuner, 15, 20), None);
+        assert_eq!(record(&mut tuner, 50, 20), Some(Duration::from_millis(50)));
+        assert_eq!(record(&mut tuner, 50, 20), Some(Duration::from_millis(25)));
+        for _ in 0..5 {
+            record(&mut tuner, 50, 20);
+        }
+        assert_eq!(tuner.window(), MIN_WINDOW);
+
+        assert_eq!(record(&mut tuner, 1, 20), Some(Duration::from_millis(10)));
+        tuner.set_max_window(Duration::from_millis(8));
+        assert_eq!(tuner.window(), Duration::from_millis(8));
+
+        let json = tuner.to_json();
+        assert_eq!(json["grows"], 1);
+        assert_eq!(json["shrinks"], 5);
+    }
+}
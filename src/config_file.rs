@@ -0,0 +1,206 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cluster::ClusterId;
+use crate::state_transfer::Transfer;
+use crate::storage::{LogRepair, SnapshotCodec};
+
+// every setting a file can give, and whether a value for it parses as what the node reads it as
+const SETTINGS: &[(&str, fn(&str) -> bool)] = &[
+    ("admin_socket", parses::<String>),
+    ("append_entries_bytes", parses::<usize>),
+    ("apply_breaker_after", parses::<u32>),
+    ("apply_budget_ms", parses::<u64>),
+    ("auth_secret", parses::<String>),
+    ("authz_rules", parses::<String>),
+    ("backup_compress_segments", parses::<bool>),
+    ("backup_interval_secs", parses::<u64>),
+    ("backup_s3_access_key", parses::<String>),
+    ("backup_s3_bucket", parses::<String>),
+    ("backup_s3_endpoint", parses::<String>),
+    ("backup_s3_prefix", parses::<String>),
+    ("backup_s3_region", parses::<String>),
+    ("backup_s3_secret_key", parses::<String>),
+    ("catch_up_read_ahead", parses::<usize>),
+    ("checkpoint_interval_secs", parses::<u64>),
+    ("checkpoint_path", parses::<String>),
+    ("client_rate_burst", parses::<f64>),
+    ("client_rate_limit", parses::<f64>),
+    ("cluster_id", parses::<ClusterId>),
+    ("commit_latency_target_ms", parses::<u64>),
+    ("data_dir", parses::<String>),
+    ("dead_node_timeout_secs", parses::<u64>),
+    ("discovery_dns_server", parses::<SocketAddr>),
+    ("discovery_expected_nodes", parses::<usize>),
+    ("discovery_srv", parses::<String>),
+    ("discovery_timeout_secs", parses::<u64>),
+    ("election_timeout_min_ms", parses::<u64>),
+    ("election_timeout_range_ms", parses::<u64>),
+    ("failover_budget_ms", parses::<u64>),
+    ("growth_report_secs", parses::<u64>),
+    ("heartbeat_timeout_ms", parses::<u64>),
+    ("hot_standby", parses::<bool>),
+    ("http_clients", parses::<bool>),
+    ("http_port", parses::<u16>),
+    ("kms_key_file", parses::<String>),
+    ("log_repair", parses::<LogRepair>),
+    ("max_bytes_in_install_snapshot", parses::<u64>),
+    ("max_entries_in_append_entries", parses::<u64>),
+    ("metrics_port", parses::<u16>),
+    ("min_free_disk_mb", parses::<u64>),
+    ("mmap_dir", parses::<String>),
+    ("mmap_index_slots", parses::<u32>),
+    ("next_index_decrease_rate", parses::<u64>),
+    ("pause_snapshots", parses::<bool>),
+    ("read_overload_threshold", parses::<usize>),
+    ("read_staleness_ms", parses::<u64>),
+    ("ready_max_leader_silence_ms", parses::<u64>),
+    ("ready_requires_leader", parses::<bool>),
+    ("redirect_suppress_ms", parses::<u64>),
+    ("response_order_timeout_ms", parses::<u64>),
+    ("restore_from_s3", parses::<bool>),
+    ("restore_to_index", parses::<u32>),
+    ("restore_to_tag", parses::<String>),
+    ("restore_to_timestamp", parses::<u64>),
+    ("rpc_response_timeout_ms", parses::<u64>),
+    ("sled_dir", parses::<String>),
+    ("snapshot_compression", parses::<SnapshotCodec>),
+    ("snapshot_dedup", parses::<bool>),
+    ("snapshot_format", parses::<String>),
+    ("snapshot_min_log_size", parses::<u32>),
+    ("snapshot_pull", parses::<bool>),
+    ("state_transfer_max_hold_secs", parses::<u64>),
+    ("state_transfer_prefer", parses::<Transfer>),
+    ("state_transfer_threshold", parses::<u32>),
+    ("suspicion_threshold", parses::<f64>),
+    ("tombstone_retention_secs", parses::<u64>),
+    ("transport", parses::<String>),
+    ("transport_listen", parses::<String>),
+    ("wal_dir", parses::<String>),
+    ("wal_segment_bytes", parses::<u64>),
+    ("watchdog_abort", parses::<bool>),
+    ("watchdog_secs", parses::<u64>),
+];
+
+fn parses<T: FromStr>(value: &str) -> bool {
+    value.parse::<T>().is_ok()
+}
+
+// Settings from a JSON or TOML file given with --config or KV_CONFIG. Every key is the name of the environment variable
+// it stands in for without the KV_ and in lower case, so `wal_dir = "/var/kv"` is KV_WAL_DIR, and environment variables
+// that are set override the file. Nodes are given as they would be on the command line, ours first. A key that isn't a
+// setting, or a value the node couldn't read, fails the whole file rather than the node finding out once it's running.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    pub settings: Vec<(String, String)>,
+    pub nodes: Vec<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read config file {}: {}", path.display(), e))?;
+        let value = if path.extension().map_or(false, |ext| ext == "toml") {
+            let value: toml::Value = toml::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+            serde_json::to_value(value).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+        } else {
+            serde_json::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+        };
+        ConfigFile::from_value(value).map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<ConfigFile, String> {
+        let object = match value {
+            serde_json::Value::Object(object) => object,
+            _ => return Err("expected a table of settings".to_string()),
+        };
+
+        let mut file = ConfigFile::default();
+        for (key, value) in object {
+            if key == "nodes" {
+                file.nodes = match value {
+                    serde_json::Value::Array(nodes) => nodes.into_iter()
+                        .map(|node| node.as_str().map(str::to_string).ok_or_else(|| "nodes should all be strings".to_string()))
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err("nodes should be a list".to_string()),
+                };
+                continue;
+            }
+
+            let valid = match SETTINGS.iter().find(|(name, _)| *name == key) {
+                Some((_, valid)) => valid,
+                None => return Err(format!("{} isn't a setting, they're lower case with underscores like wal_dir", key)),
+            };
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                _ => return Err(format!("{} should be a string, number or boolean", key)),
+            };
+            if !valid(&value) {
+                return Err(format!("invalid value for {}: {}", key, value));
+            }
+            file.settings.push((format!("KV_{}", key.to_uppercase()), value));
+        }
+        Ok(file)
+    }
+
+    // only fills in what the environment doesn't already have, before anything reads it
+    pub fn apply(&self) {
+        for (name, value) in self.unset(|name| std::env::var_os(name).is_some()) {
+            std::env::set_var(name, value);
+        }
+    }
+
+    fn unset(&self, is_set: impl Fn(&str) -> bool) -> Vec<&(String, String)> {
+        self.settings.iter().filter(|(name, _)| !is_set(name)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::config_file::ConfigFile;
+
+    #[test]
+    fn reads_settings() {
+        let file = ConfigFile::from_value(json!({
+            "nodes": ["0000", "0001"],
+            "wal_dir": "/var/kv",
+            "heartbeat_timeout_ms": 100,
+            "http_clients": true,
+        })).unwrap();
+        assert_eq!(file.nodes, vec!["0000", "0001"]);
+        assert_eq!(file.settings, vec![
+            ("KV_HEARTBEAT_TIMEOUT_MS".to_string(), "100".to_string()),
+            ("KV_HTTP_CLIENTS".to_string(), "true".to_string()),
+            ("KV_WAL_DIR".to_string(), "/var/kv".to_string()),
+        ]);
+
+        assert!(ConfigFile::from_value(json!(["wal_dir"])).is_err());
+        assert!(ConfigFile::from_value(json!({ "WAL_DIR": "/var/kv" })).is_err());
+        assert!(ConfigFile::from_value(json!({ "wal_dir": ["/var/kv"] })).is_err());
+        assert!(ConfigFile::from_value(json!({ "nodes": [1] })).is_err());
+    }
+
+    #[test]
+    fn checks_settings() {
+        assert!(ConfigFile::from_value(json!({ "log_repair": "truncate", "snapshot_compression": "lz4", "discovery_dns_server": "10.0.0.2:53" })).is_ok());
+
+        let unknown = ConfigFile::from_value(json!({ "wal_dri": "/var/kv" })).unwrap_err();
+        assert!(unknown.contains("wal_dri"));
+        let invalid = ConfigFile::from_value(json!({ "heartbeat_timeout_ms": "soon" })).unwrap_err();
+        assert!(invalid.contains("heartbeat_timeout_ms") && invalid.contains("soon"));
+        assert!(ConfigFile::from_value(json!({ "http_port": 70000 })).is_err());
+        assert!(ConfigFile::from_value(json!({ "hot_standby": 1 })).is_err());
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let file = ConfigFile::from_value(json!({ "wal_dir": "/var/kv", "http_clients": true })).unwrap();
+        let unset = file.unset(|name| name == "KV_WAL_DIR");
+        assert_eq!(unset, vec![&("KV_HTTP_CLIENTS".to_string(), "true".to_string())]);
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+// Finds the cluster's nodes from the SRV records of a service, e.g. a headless service's _raft._udp.kv.svc.cluster.local,
+// so deployments don't need to pass every peer on the command line. The first label of each record's target is the
+// node's name. Keeps asking until at least `expected` nodes are listed, since peers register as they come up.
+pub fn discover_srv(service: &str, server: SocketAddr, expected: usize, timeout: Duration) -> io::Result<Vec<String>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match query_srv(service, server) {
+            Ok(names) if names.len() >= expected => return Ok(names),
+            Ok(names) => eprintln!("discovery: {} lists {} of {} nodes", service, names.len(), expected),
+            Err(e) => eprintln!("discovery: querying {} failed: {}", service, e),
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} never listed {} nodes", service, expected)));
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// the first nameserver in /etc/resolv.conf
+pub fn system_nameserver() -> io::Result<SocketAddr> {
+    let resolv = fs::read_to_string("/etc/resolv.conf")?;
+    resolv.lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+}
+
+fn query_srv(service: &str, server: SocketAddr) -> io::Result<Vec<String>> {
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+    socket.connect(server)?;
+
+    let id = crate::hash(service) as u16;
+    socket.send(&srv_query(id, service))?;
+
+    let mut buffer = [0u8; 4096];
+    let amt = socket.recv(&mut buffer)?;
+    srv_targets(&buffer[..amt], id)
+}
+
+fn srv_query(id: u16, service: &str) -> Vec<u8> {
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in service.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+// the node names the SRV answers in a response point at
+fn srv_targets(response: &[u8], id: u16) -> io::Result<Vec<String>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad DNS response: {}", what));
+    let u16_at = |i: usize| response.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(|| invalid("truncated"));
+
+    if u16_at(0)? != id {
+        return Err(invalid("wrong id"));
+    }
+    let rcode = u16_at(2)? & 0xF;
+    if rcode != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("DNS server returned rcode {}", rcode)));
+    }
+
+    let mut i = 12;
+    for _ in 0..u16_at(4)? {
+        i = read_name(response, i)?.1 + 4;
+    }
+
+    let mut names = vec![];
+    for _ in 0..u16_at(6)? {
+        i = read_name(response, i)?.1;
+        let record_type = u16_at(i)?;
+        let data_len = u16_at(i + 8)? as usize;
+        let data = i + 10;
+        if record_type == SRV {
+            // priority, weight and port come before the target
+            let (target, _) = read_name(response, data + 6)?;
+            let node = target.split('.').next().unwrap_or_default().to_string();
+            if !node.is_empty() && !names.contains(&node) {
+                names.push(node);
+            }
+        }
+        i = data + data_len;
+    }
+    Ok(names)
+}
+
+// a possibly compressed name starting at i, and where the record continues after it
+fn read_name(message: &[u8], mut i: usize) -> io::Result<(String, usize)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad DNS response: bad name");
+    let mut labels: Vec<String> = vec![];
+    let mut end = None;
+    // bounds the pointers followed, so a pointer loop can't spin forever
+    for _ in 0..128 {
+        let len = *message.get(i).ok_or_else(invalid)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(i + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            let offset = ((len & 0x3F) << 8) | *message.get(i + 1).ok_or_else(invalid)? as usize;
+            end.get_or_insert(i + 2);
+            i = offset;
+            continue;
+        }
+        let label = message.get(i + 1..i + 1 + len).ok_or_else(invalid)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        i += 1 + len;
+    }
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::{srv_query, srv_targets};
+
+    #[test]
+    fn parses_srv_answers() {
+        let mut response = srv_query(7, "_raft._udp.kv");
+        // flip it into a response with two answers
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+
+        for target in &["0001", "0002"] {
+            // the record's name points back at the question
+            response.extend_from_slice(&[0xC0, 12, 0, 33, 0, 1, 0, 0, 0, 30]);
+            response.extend_from_slice(&[0, (6 + 1 + target.len() + 2) as u8, 0, 0, 0, 0, 0x1F, 0x90]);
+            response.push(target.len() as u8);
+            response.extend_from_slice(target.as_bytes());
+            // ".kv", pointing at the question's last label
+            response.extend_from_slice(&[0xC0, 23]);
+        }
+
+        assert_eq!(srv_targets(&response, 7).unwrap(), vec!["0001".to_string(), "0002".to_string()]);
+        assert!(srv_targets(&response, 8).is_err());
+        assert!(srv_targets(&response[..response.len() - 3], 7).is_err());
+    }
+}
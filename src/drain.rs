@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::client::{admin, Member};
+
+// the leader waits up to 10 seconds for commands in flight to be applied, then up to 15 for leadership to move, before
+// answering a step down
+const STEPDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default, Debug)]
+pub struct DrainReport {
+    // what was done, in order
+    pub steps: Vec<String>,
+    // why the node isn't safe to stop yet, empty if it is
+    pub problems: Vec<String>,
+}
+
+// Gets a node ready to be stopped for maintenance. A leader is stepped down, so it stops taking commands (clients get
+// redirected or failed over) and answers once the ones in flight have been applied. The node is then only safe to stop
+// if the rest of the members can still make a quorum, which is where every committed write already is, so nothing
+// the node holds is lost with it. The raft core has no leadership transfer or learners, so the next leader is elected
+// once the node stops.
+pub fn drain(node: &str, members: &[Member], timeout: Duration) -> DrainReport {
+    let mut report = DrainReport::default();
+    let target = match members.iter().find(|member| member.name == node) {
+        Some(target) => target,
+        None => {
+            report.problems.push(format!("{} isn't one of the members given", node));
+            return report;
+        }
+    };
+
+    let status = match admin(target.addr, "GET", "/status", timeout) {
+        Ok(status) => status,
+        Err(e) => {
+            report.problems.push(format!("can't get the status of {}: {}", node, e));
+            return report;
+        }
+    };
+    let num_members = status["members"].as_array().map_or(members.len(), Vec::len);
+
+    if status["role"] == "leader" {
+        report.steps.push(format!("stepping down {}, the leader", node));
+        match admin(target.addr, "POST", "/stepdown", STEPDOWN_TIMEOUT) {
+            Ok(response) => {
+                let result = &response["result"];
+                if result["drained"] == true {
+                    report.steps.push("commands in flight were applied".to_string());
+                } else {
+                    report.problems.push(format!("{} commands are still in flight on {}", result["in_flight"], node));
+                }
+                // the rest elect a leader once it's stopped if it couldn't hand over
+                match result["leader"].as_str() {
+                    Some(leader) if result["transferred"] == true => report.steps.push(format!("leadership moved to {}", leader)),
+                    _ => report.steps.push(format!("leadership wasn't handed over: {}", result["error"])),
+                }
+            }
+            Err(e) => report.problems.push(format!("can't step down {}: {}", node, e)),
+        }
+    }
+
+    let mut ready = 0;
+    for member in members.iter().filter(|member| member.name != node) {
+        match admin(member.addr, "GET", "/readyz", timeout) {
+            Ok(readyz) if readyz["ready"] == true => ready += 1,
+            Ok(readyz) => report.steps.push(format!("{} isn't ready: {}", member.name, readyz["reasons"])),
+            Err(e) => report.steps.push(format!("{} is unreachable: {}", member.name, e)),
+        }
+    }
+    report.steps.push(format!("{} of the other members are ready", ready));
+    report.problems.extend(quorum_problem(node, num_members, ready));
+    report
+}
+
+fn quorum_problem(node: &str, num_members: usize, ready_others: usize) -> Option<String> {
+    let quorum = num_members / 2 + 1;
+    if ready_others >= quorum {
+        None
+    } else {
+        Some(format!("only {} of the other members are ready, {} of {} are needed to keep a quorum without {}", ready_others, quorum, num_members, node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::drain::quorum_problem;
+
+    #[test]
+    fn needs_quorum_without_node() {
+        assert!(quorum_problem("0001", 5, 3).is_none());
+        assert!(quorum_problem("0001", 5, 2).is_some());
+        assert!(quorum_problem("0001", 3, 2).is_none());
+        // a single member can never be drained
+        assert!(quorum_problem("0001", 1, 0).is_some());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const MAX_SAMPLES: usize = 100;
+// keeps a perfectly regular heartbeat from making the smallest delay look like a failure
+const MIN_STD_DEV_MILLIS: f64 = 10.0;
+
+// Phi accrual failure detector (Hayashibara et al.), fed with the arrival times of messages from one peer. Phi is how
+// unlikely it is, on a -log10 scale, that the peer is still alive given how long it's been since we last heard from it.
+#[derive(Default)]
+pub struct PhiAccrualDetector {
+    intervals: VecDeque<f64>,
+    last_arrival: Option<Instant>,
+}
+
+impl PhiAccrualDetector {
+    pub fn heartbeat(&mut self, now: Instant) {
+        if let Some(last) = self.last_arrival {
+            if self.intervals.len() == MAX_SAMPLES {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(millis(now.saturating_duration_since(last)));
+        }
+        self.last_arrival = Some(now);
+    }
+
+    pub fn last_arrival(&self) -> Option<Instant> {
+        self.last_arrival
+    }
+
+    pub fn phi(&self, now: Instant) -> f64 {
+        let last = match self.last_arrival {
+            Some(last) if !self.intervals.is_empty() => last,
+            _ => return 0.0,
+        };
+
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self.intervals.iter().map(|i| (i - mean) * (i - mean)).sum::<f64>() / n;
+        let std_dev = variance.sqrt().max(MIN_STD_DEV_MILLIS);
+
+        let elapsed = millis(now.saturating_duration_since(last));
+
+        // logistic approximation of the normal CDF
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::failure_detector::PhiAccrualDetector;
+
+    #[test]
+    fn phi_grows_with_silence() {
+        let mut detector = PhiAccrualDetector::default();
+        let start = Instant::now();
+        for i in 0..20 {
+            detector.heartbeat(start + Duration::from_millis(100 * i));
+        }
+        let last = start + Duration::from_millis(1900);
+
+        let soon = detector.phi(last + Duration::from_millis(100));
+        let late = detector.phi(last + Duration::from_millis(300));
+        let very_late = detector.phi(last + Duration::from_millis(1000));
+
+        assert!(soon < 1.0);
+        assert!(soon < late);
+        assert!(late < very_late);
+        assert!(very_late > 8.0);
+    }
+
+    #[test]
+    fn no_samples_is_not_suspicious() {
+        let detector = PhiAccrualDetector::default();
+        assert_eq!(detector.phi(Instant::now()), 0.0);
+    }
+}
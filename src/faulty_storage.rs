@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use my_raft::bytes::{TryFromBytes, WriteBytes};
+use my_raft::state_machine::{RaftStateMachine, StateMachine};
+use my_raft::storage::log::LogEntry;
+use my_raft::storage::Storage;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fault {
+    // the sync's log write stops halfway through the new entries, leaving the last one cut short
+    TornWrite,
+    // nothing from the sync reaches the disk, and nothing synced after it can be trusted to either
+    FailedSync,
+    // the snapshot's index and term reach the disk but only the first half of its bytes do
+    PartialSnapshot,
+}
+
+// What a crash leaves behind, kept as bytes so a torn write can cut through the middle of something.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Durable {
+    pub current_term: u32,
+    pub voted_for: Option<u32>,
+    pub snapshot_last_index: u32,
+    pub snapshot_last_term: u32,
+    pub snapshot: Vec<u8>,
+    pub log: Vec<Vec<u8>>,
+}
+
+// Wraps storage for tests, keeping track of what would be on disk as of every sync point (save_log and set_snapshot)
+// and breaking the Nth sync with a fault. Every fault is the node crashing at that point, so from then on nothing is
+// acknowledged, which is what recovery gets checked against.
+pub struct FaultyStorage<S: StateMachine + Clone, St: Storage<S>> {
+    inner: St,
+    faults: HashMap<u32, Fault>,
+    syncs: u32,
+    durable: Durable,
+    // what was on disk after the last sync that fully succeeded
+    acknowledged: Durable,
+    crashed: bool,
+    state_machine: PhantomData<S>,
+}
+
+impl<S: StateMachine + Clone, St: Storage<S>> FaultyStorage<S, St> {
+    pub fn new(inner: St) -> FaultyStorage<S, St> {
+        FaultyStorage {
+            inner,
+            faults: HashMap::new(),
+            syncs: 0,
+            durable: Durable::default(),
+            acknowledged: Durable::default(),
+            crashed: false,
+            state_machine: PhantomData,
+        }
+    }
+
+    // syncs are counted from 1
+    pub fn fail_sync(&mut self, sync: u32, fault: Fault) {
+        self.faults.insert(sync, fault);
+    }
+
+    pub fn acknowledged(&self) -> &Durable {
+        &self.acknowledged
+    }
+
+    fn sync(&mut self) {
+        self.syncs += 1;
+        let mut written = Durable {
+            current_term: self.inner.current_term(),
+            voted_for: self.inner.voted_for(),
+            snapshot_last_index: self.inner.snapshot_last_index(),
+            snapshot_last_term: self.inner.snapshot_last_term(),
+            snapshot: to_bytes(&self.inner.snapshot()),
+            log: (0..self.inner.num_log_entries()).map(|i| to_bytes(self.inner.log_entry(i).unwrap())).collect(),
+        };
+
+        match self.faults.get(&self.syncs) {
+            None => {}
+            Some(Fault::FailedSync) => written = self.durable.clone(),
+            Some(Fault::TornWrite) => {
+                let kept = self.durable.log.iter().zip(&written.log).take_while(|(old, new)| old == new).count();
+                let torn = kept + (written.log.len() - kept + 1) / 2;
+                written.log.truncate(torn);
+                if let Some(last) = written.log.last_mut().filter(|_| torn > kept) {
+                    last.truncate(last.len() / 2);
+                }
+            }
+            Some(Fault::PartialSnapshot) if written.snapshot_last_index != self.durable.snapshot_last_index => {
+                let len = written.snapshot.len() / 2;
+                written.snapshot.truncate(len);
+            }
+            Some(Fault::PartialSnapshot) => {}
+        }
+
+        self.crashed |= self.faults.contains_key(&self.syncs);
+        if !self.crashed {
+            self.acknowledged = written.clone();
+        }
+        self.durable = written;
+    }
+
+    // Restarts from what's on disk into fresh storage. A log entry that was cut short is dropped, since it was never
+    // acknowledged, but a snapshot that was cut short is refused, since the entries it replaced are gone.
+    pub fn recover(&self, mut fresh: St) -> Result<St, String> {
+        let durable = &self.durable;
+        fresh.set_current_term(durable.current_term);
+        fresh.set_voted_for(durable.voted_for);
+        if durable.snapshot_last_index > 0 {
+            let snapshot = RaftStateMachine::<S>::try_from_slice(&durable.snapshot)
+                .ok_or_else(|| format!("snapshot through {} is corrupt", durable.snapshot_last_index))?;
+            fresh.set_snapshot(durable.snapshot_last_index, durable.snapshot_last_term, &snapshot);
+        }
+        for (i, bytes) in durable.log.iter().enumerate() {
+            match LogEntry::<S::Command>::try_from_slice(bytes) {
+                Some(entry) => fresh.add_log_entry(entry),
+                None if i + 1 == durable.log.len() => break,
+                None => return Err(format!("log entry {} is corrupt", i)),
+            }
+        }
+        fresh.save_log();
+        Ok(fresh)
+    }
+}
+
+// everything acknowledged before the crash that recovered storage has lost
+pub fn lost_writes<S: StateMachine + Clone>(acknowledged: &Durable, recovered: &impl Storage<S>) -> Vec<String> {
+    let mut lost = vec![];
+    if recovered.current_term() < acknowledged.current_term {
+        lost.push(format!("term went back from {} to {}", acknowledged.current_term, recovered.current_term()));
+    }
+    if recovered.current_term() == acknowledged.current_term && recovered.voted_for() != acknowledged.voted_for {
+        lost.push(format!("vote changed from {:?} to {:?}", acknowledged.voted_for, recovered.voted_for()));
+    }
+    if recovered.snapshot_last_index() < acknowledged.snapshot_last_index {
+        lost.push(format!("snapshot went back from {} to {}", acknowledged.snapshot_last_index, recovered.snapshot_last_index()));
+    } else if recovered.snapshot_last_index() == acknowledged.snapshot_last_index && to_bytes(&recovered.snapshot()) != acknowledged.snapshot {
+        lost.push(format!("snapshot through {} changed", acknowledged.snapshot_last_index));
+    }
+
+    let acknowledged_last = acknowledged.snapshot_last_index as usize + acknowledged.log.len();
+    let recovered_last = recovered.snapshot_last_index() as usize + recovered.num_log_entries();
+    if recovered_last < acknowledged_last {
+        lost.push(format!("log went back from {} to {}", acknowledged_last, recovered_last));
+    }
+    for (i, bytes) in acknowledged.log.iter().enumerate() {
+        let index = acknowledged.snapshot_last_index as usize + i + 1;
+        let entry = index.checked_sub(recovered.snapshot_last_index() as usize + 1).and_then(|i| recovered.log_entry(i));
+        if entry.map_or(false, |entry| to_bytes(entry) != *bytes) {
+            lost.push(format!("log entry {} changed", index));
+        }
+    }
+    lost
+}
+
+fn to_bytes(value: &impl WriteBytes) -> Vec<u8> {
+    let mut bytes = vec![];
+    value.write_bytes_with_writer(&mut bytes).unwrap();
+    bytes
+}
+
+impl<S: StateMachine + Clone, St: Storage<S>> Storage<S> for FaultyStorage<S, St> {
+    fn add_log_entry(&mut self, entry: LogEntry<S::Command>) {
+        self.inner.add_log_entry(entry)
+    }
+
+    fn remove_log_entries_before(&mut self, index: usize) {
+        self.inner.remove_log_entries_before(index)
+    }
+
+    fn remove_log_entries_starting_at(&mut self, index: usize) {
+        self.inner.remove_log_entries_starting_at(index)
+    }
+
+    fn save_log(&mut self) {
+        self.inner.save_log();
+        self.sync();
+    }
+
+    fn log_entry(&self, index: usize) -> Option<&LogEntry<S::Command>> {
+        self.inner.log_entry(index)
+    }
+
+    fn log_entries(&self, start_index: usize) -> &[LogEntry<S::Command>] {
+        self.inner.log_entries(start_index)
+    }
+
+    fn get_index_of_last_config_in_log(&self) -> Option<usize> {
+        self.inner.get_index_of_last_config_in_log()
+    }
+
+    fn num_log_entries(&self) -> usize {
+        self.inner.num_log_entries()
+    }
+
+    fn set_snapshot(&mut self, last_index: u32, last_term: u32, snapshot: &RaftStateMachine<S>) {
+        self.inner.set_snapshot(last_index, last_term, snapshot);
+        self.sync();
+    }
+
+    fn snapshot(&self) -> RaftStateMachine<S> {
+        self.inner.snapshot()
+    }
+
+    fn snapshot_last_index(&self) -> u32 {
+        self.inner.snapshot_last_index()
+    }
+
+    fn snapshot_last_term(&self) -> u32 {
+        self.inner.snapshot_last_term()
+    }
+
+    fn add_new_snapshot_chunk(&mut self, offset: u32, data: &[u8]) {
+        self.inner.add_new_snapshot_chunk(offset, data)
+    }
+
+    fn try_use_chunks_as_new_snapshot(&mut self, last_index: u32, last_term: u32) -> Option<RaftStateMachine<S>> {
+        let snapshot = self.inner.try_use_chunks_as_new_snapshot(last_index, last_term);
+        if snapshot.is_some() {
+            self.sync();
+        }
+        snapshot
+    }
+
+    fn snapshot_chunk(&self, offset: u32, amt: u32) -> &[u8] {
+        self.inner.snapshot_chunk(offset, amt)
+    }
+
+    fn total_snapshot_bytes(&self) -> u32 {
+        self.inner.total_snapshot_bytes()
+    }
+
+    fn set_voted_for(&mut self, voted_for: Option<u32>) {
+        self.inner.set_voted_for(voted_for)
+    }
+
+    fn voted_for(&self) -> Option<u32> {
+        self.inner.voted_for()
+    }
+
+    fn set_current_term(&mut self, current_term: u32) {
+        self.inner.set_current_term(current_term)
+    }
+
+    fn current_term(&self) -> u32 {
+        self.inner.current_term()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use my_raft::storage::Storage;
+
+    use crate::cluster::ClusterId;
+    use crate::faulty_storage::{Fault, FaultyStorage, lost_writes};
+    use crate::init_state_machine;
+    use crate::state_machine::KvStateMachine;
+    use crate::storage::RamStorage;
+
+    fn fresh() -> RamStorage<KvStateMachine> {
+        RamStorage::new(init_state_machine(0, HashMap::new()), ClusterId(0))
+    }
+
+    // votes for itself in term 1 and takes a snapshot, then tries to move on to term 2
+    fn run(fault: Option<(u32, Fault)>) -> FaultyStorage<KvStateMachine, RamStorage<KvStateMachine>> {
+        let mut storage = FaultyStorage::new(fresh());
+        if let Some((sync, fault)) = fault {
+            storage.fail_sync(sync, fault);
+        }
+
+        storage.set_current_term(1);
+        storage.set_voted_for(Some(0));
+        storage.save_log();
+
+        let mut sm = storage.snapshot();
+        sm.inner.data.insert("k".to_string(), "v".to_string());
+        storage.set_snapshot(4, 1, &sm);
+
+        storage.set_current_term(2);
+        storage.set_voted_for(None);
+        storage.save_log();
+        storage
+    }
+
+    #[test]
+    fn recovery_keeps_acknowledged_writes() {
+        for sync in 1..=3 {
+            for fault in &[Fault::TornWrite, Fault::FailedSync, Fault::PartialSnapshot] {
+                let storage = run(Some((sync, *fault)));
+                if let Ok(recovered) = storage.recover(fresh()) {
+                    let lost = lost_writes(storage.acknowledged(), &recovered);
+                    assert!(lost.is_empty(), "{:?} at sync {} lost {:?}", fault, sync, lost);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_acknowledged_after_a_failed_sync() {
+        let storage = run(Some((1, Fault::FailedSync)));
+        assert_eq!(storage.acknowledged().current_term, 0);
+
+        let recovered = storage.recover(fresh()).unwrap();
+        assert_eq!(recovered.current_term(), 0);
+        assert_eq!(recovered.snapshot_last_index(), 0);
+    }
+
+    #[test]
+    fn partial_snapshot_refused() {
+        let storage = run(Some((2, Fault::PartialSnapshot)));
+        assert_eq!(storage.acknowledged().current_term, 1);
+        assert!(storage.recover(fresh()).is_err());
+
+        let storage = run(None);
+        let recovered = storage.recover(fresh()).unwrap();
+        assert_eq!(recovered.current_term(), 2);
+        assert_eq!(recovered.snapshot_last_index(), 4);
+        assert_eq!(recovered.snapshot().inner.data.get("k").map(String::as_str), Some("v"));
+        assert!(lost_writes(storage.acknowledged(), &recovered).is_empty());
+    }
+}
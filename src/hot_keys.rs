@@ -0,0 +1,182 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+// how many of the heaviest keys are tracked by name
+const TRACKED_KEYS: usize = 64;
+// counts are halved this often, so they reflect recent traffic rather than all time
+const DECAY_INTERVAL: Duration = Duration::from_secs(60);
+const PREFIX_SEPARATORS: &[char] = &[':', '/', '.'];
+
+// Count-min sketch: never underestimates, and overestimates by a small fraction of the total count with high
+// probability, in fixed memory however many distinct keys there are.
+pub struct CountMinSketch {
+    rows: Vec<Vec<u64>>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        CountMinSketch { rows: vec![vec![0; width]; depth] }
+    }
+
+    fn column(&self, row: usize, item: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        item.hash(&mut hasher);
+        hasher.finish() as usize % self.rows[row].len()
+    }
+
+    // adds one occurrence of the item, giving its new estimated count
+    pub fn add(&mut self, item: &str) -> u64 {
+        let mut estimate = u64::MAX;
+        for row in 0..self.rows.len() {
+            let column = self.column(row, item);
+            let count = &mut self.rows[row][column];
+            *count += 1;
+            estimate = estimate.min(*count);
+        }
+        estimate
+    }
+
+    pub fn halve(&mut self) {
+        for count in self.rows.iter_mut().flatten() {
+            *count /= 2;
+        }
+    }
+}
+
+// the items with the highest estimated counts in a sketch
+pub struct HeavyHitters {
+    sketch: CountMinSketch,
+    top: HashMap<String, u64>,
+}
+
+impl Default for HeavyHitters {
+    fn default() -> Self {
+        HeavyHitters { sketch: CountMinSketch::new(SKETCH_WIDTH, SKETCH_DEPTH), top: HashMap::new() }
+    }
+}
+
+impl HeavyHitters {
+    pub fn record(&mut self, item: &str) {
+        let estimate = self.sketch.add(item);
+
+        if let Some(count) = self.top.get_mut(item) {
+            *count = estimate;
+            return;
+        }
+        if self.top.len() >= TRACKED_KEYS {
+            let (lightest, count) = self.top.iter().min_by_key(|(_, count)| **count).map(|(k, c)| (k.clone(), *c)).unwrap();
+            if count >= estimate {
+                return;
+            }
+            self.top.remove(&lightest);
+        }
+        self.top.insert(item.to_string(), estimate);
+    }
+
+    pub fn top(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut top: Vec<(&str, u64)> = self.top.iter().map(|(k, c)| (k.as_str(), *c)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        top.truncate(n);
+        top
+    }
+
+    fn halve(&mut self) {
+        self.sketch.halve();
+        for count in self.top.values_mut() {
+            *count /= 2;
+        }
+    }
+}
+
+// Read and write counts per key, and per key prefix (up to the first ':', '/' or '.', listed as e.g. "user:*").
+pub struct HotKeys {
+    pub reads: HeavyHitters,
+    pub writes: HeavyHitters,
+    last_decay: Instant,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys { reads: HeavyHitters::default(), writes: HeavyHitters::default(), last_decay: Instant::now() }
+    }
+}
+
+impl HotKeys {
+    pub fn read(&mut self, key: &str) {
+        record_with_prefix(&mut self.reads, key);
+    }
+
+    pub fn write(&mut self, key: &str) {
+        record_with_prefix(&mut self.writes, key);
+    }
+
+    pub fn decay_if_due(&mut self) {
+        if self.last_decay.elapsed() >= DECAY_INTERVAL {
+            self.last_decay = Instant::now();
+            self.reads.halve();
+            self.writes.halve();
+        }
+    }
+}
+
+fn record_with_prefix(hitters: &mut HeavyHitters, key: &str) {
+    hitters.record(key);
+    if let Some(i) = key.find(PREFIX_SEPARATORS) {
+        hitters.record(&format!("{}*", &key[..=i]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hot_keys::{CountMinSketch, HeavyHitters, HotKeys};
+
+    #[test]
+    fn sketch_never_underestimates() {
+        let mut sketch = CountMinSketch::new(64, 4);
+        for i in 0..1000 {
+            sketch.add(&format!("key{}", i % 100));
+        }
+        for i in 0..100 {
+            assert!(sketch.add(&format!("key{}", i)) >= 11);
+        }
+
+        sketch.halve();
+        assert!(sketch.add("key0") >= 6);
+    }
+
+    #[test]
+    fn finds_heaviest_keys() {
+        let mut hitters = HeavyHitters::default();
+        for i in 0..10_000 {
+            hitters.record(&format!("cold{}", i));
+            if i % 2 == 0 {
+                hitters.record("hot");
+            }
+            if i % 5 == 0 {
+                hitters.record("warm");
+            }
+        }
+
+        let top = hitters.top(2);
+        assert_eq!(top[0].0, "hot");
+        assert!(top[0].1 >= 5000);
+        assert_eq!(top[1].0, "warm");
+    }
+
+    #[test]
+    fn prefixes() {
+        let mut hot_keys = HotKeys::default();
+        hot_keys.write("user:1");
+        hot_keys.write("user:2");
+        hot_keys.write("plain");
+        hot_keys.read("user:1");
+
+        assert_eq!(hot_keys.writes.top(1), vec![("user:*", 2)]);
+        assert_eq!(hot_keys.reads.top(3), vec![("user:*", 1), ("user:1", 1)]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+// Minimal HTTP/1.0 server for status pages and the HTTP client API, polled from the network's receive loop so handlers can read node state
+// without any locking. Every request gets one response and the connection is closed.
+pub struct HttpServer {
+    listener: TcpListener,
+}
+
+pub struct HttpRequest {
+    stream: TcpStream,
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl HttpServer {
+    pub fn bind(port: u16) -> io::Result<HttpServer> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpServer { listener })
+    }
+
+    // None if there's no connection waiting, or it didn't send a valid request line in time
+    pub fn accept(&self) -> Option<HttpRequest> {
+        let (stream, _) = self.listener.accept().ok()?;
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(REQUEST_READ_TIMEOUT)).ok()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).ok()?;
+
+        // the only header that matters is the length of the body
+        let mut content_length = 0;
+        let mut header = String::new();
+        while reader.read_line(&mut header).ok()? > 2 {
+            if let Some(colon) = header.find(':') {
+                if header[..colon].trim().eq_ignore_ascii_case("content-length") {
+                    content_length = header[colon + 1..].trim().parse().ok()?;
+                }
+            }
+            header.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+
+        if content_length > MAX_BODY_BYTES {
+            return None;
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+
+        Some(HttpRequest { stream: reader.into_inner(), method, path, body })
+    }
+}
+
+impl HttpRequest {
+    pub fn respond(mut self, status: &str, content_type: &str, body: &[u8]) {
+        let header = format!("HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                             status, content_type, body.len());
+        // the client going away isn't our problem
+        let _ = self.stream.write_all(header.as_bytes()).and_then(|_| self.stream.write_all(body));
+    }
+
+    pub fn respond_json(self, body: &serde_json::Value) {
+        self.respond_json_with_status("200 OK", body)
+    }
+
+    pub fn respond_json_with_status(self, status: &str, body: &serde_json::Value) {
+        self.respond(status, "application/json", body.to_string().as_bytes())
+    }
+
+    pub fn not_found(self) {
+        self.respond("404 Not Found", "text/plain", b"not found\n")
+    }
+}
+
+// None if it has a bad escape or isn't UTF-8
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::percent_decode;
+
+    #[test]
+    fn decodes_paths() {
+        assert_eq!(percent_decode("plain"), Some("plain".to_string()));
+        assert_eq!(percent_decode("a%20b%2Fc"), Some("a b/c".to_string()));
+        assert_eq!(percent_decode("%e2%9c%93"), Some("\u{2713}".to_string()));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+}
@@ -0,0 +1,152 @@
+use serde_json::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Paths are keys separated by dots and array indexes in brackets, optionally starting with $, like $.a.b[2].c. An empty
+// path, or just $, is the whole document.
+pub fn parse(path: &str) -> Option<Vec<Segment>> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            segments.push(Segment::Index(after[..end].parse().ok()?));
+            rest = &after[end + 1..];
+            continue;
+        }
+
+        // only the first key can go without a dot
+        let after = match rest.strip_prefix('.') {
+            Some(after) => after,
+            None if segments.is_empty() => rest,
+            None => return None,
+        };
+        let end = after.find(|c: char| c == '.' || c == '[').unwrap_or(after.len());
+        if end == 0 {
+            return None;
+        }
+        segments.push(Segment::Key(after[..end].to_string()));
+        rest = &after[end..];
+    }
+    Some(segments)
+}
+
+pub fn get<'a>(doc: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    path.iter().try_fold(doc, |value, segment| match segment {
+        Segment::Key(key) => value.get(key),
+        Segment::Index(i) => value.get(i),
+    })
+}
+
+// Keys that aren't there are added, with null turning into an object on the way, and an index one past the end of an
+// array appends to it. Anything else that isn't there, or a key into something that isn't an object, fails and leaves
+// the document as it was.
+pub fn set(doc: &mut Value, path: &[Segment], value: Value) -> bool {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => {
+            *doc = value;
+            return true;
+        }
+    };
+    if !can_set(doc, path) {
+        return false;
+    }
+
+    let mut target = doc;
+    for segment in parents {
+        target = child(target, segment);
+    }
+    *child(target, last) = value;
+    true
+}
+
+fn can_set(doc: &Value, path: &[Segment]) -> bool {
+    let mut target = Some(doc);
+    for segment in path {
+        let value = match target {
+            Some(value) => value,
+            // a missing key, which becomes an object if there's more to the path
+            None => match segment {
+                Segment::Key(_) => continue,
+                Segment::Index(_) => return false,
+            },
+        };
+        target = match (value, segment) {
+            (Value::Null, Segment::Key(_)) => None,
+            (Value::Object(map), Segment::Key(key)) => map.get(key),
+            (Value::Array(array), Segment::Index(i)) if *i == array.len() => None,
+            (Value::Array(array), Segment::Index(i)) => match array.get(*i) {
+                Some(value) => Some(value),
+                None => return false,
+            },
+            _ => return false,
+        };
+    }
+    true
+}
+
+// only called on paths can_set has checked
+fn child<'a>(value: &'a mut Value, segment: &Segment) -> &'a mut Value {
+    if value.is_null() {
+        *value = Value::Object(serde_json::Map::new());
+    }
+    match (value, segment) {
+        (Value::Object(map), Segment::Key(key)) => map.entry(key.clone()).or_insert(Value::Null),
+        (Value::Array(array), Segment::Index(i)) => {
+            if *i == array.len() {
+                array.push(Value::Null);
+            }
+            &mut array[*i]
+        }
+        _ => unreachable!("path was checked"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::json_path::{get, parse, Segment, set};
+
+    #[test]
+    fn parses() {
+        let key = |k: &str| Segment::Key(k.to_string());
+        assert_eq!(parse(""), Some(vec![]));
+        assert_eq!(parse("$"), Some(vec![]));
+        assert_eq!(parse("$.a.b[2].c"), Some(vec![key("a"), key("b"), Segment::Index(2), key("c")]));
+        assert_eq!(parse("a[0][1]"), Some(vec![key("a"), Segment::Index(0), Segment::Index(1)]));
+        assert_eq!(parse("[3]"), Some(vec![Segment::Index(3)]));
+        assert_eq!(parse("a..b"), None);
+        assert_eq!(parse("a[x]"), None);
+        assert_eq!(parse("a[1"), None);
+        assert_eq!(parse("a[0]b"), None);
+    }
+
+    #[test]
+    fn gets_and_sets() {
+        let mut doc = json!({ "a": { "b": [1, 2] }, "s": "x" });
+        assert_eq!(get(&doc, &parse("a.b[1]").unwrap()), Some(&json!(2)));
+        assert_eq!(get(&doc, &parse("a.c").unwrap()), None);
+
+        assert!(set(&mut doc, &parse("a.b[0]").unwrap(), json!("one")));
+        assert!(set(&mut doc, &parse("a.b[2]").unwrap(), json!(3)));
+        assert!(set(&mut doc, &parse("new.deep").unwrap(), json!(true)));
+        assert_eq!(doc, json!({ "a": { "b": ["one", 2, 3] }, "s": "x", "new": { "deep": true } }));
+
+        let before = doc.clone();
+        assert!(!set(&mut doc, &parse("a.b[5]").unwrap(), json!(0)));
+        assert!(!set(&mut doc, &parse("s.t").unwrap(), json!(0)));
+        assert!(!set(&mut doc, &parse("missing[0]").unwrap(), json!(0)));
+        assert_eq!(doc, before);
+
+        let mut empty = serde_json::Value::Null;
+        assert!(!set(&mut empty, &parse("a[0]").unwrap(), json!(1)));
+        assert!(set(&mut empty, &[], json!([])));
+        assert_eq!(empty, json!([]));
+    }
+}
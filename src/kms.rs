@@ -0,0 +1,149 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use serde::Deserialize;
+
+// encrypted values are stored as enc:<key id>:<hex nonce and ciphertext>
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+#[derive(Deserialize)]
+struct KeyFile {
+    // id of the key new values are encrypted with, the others are only kept for decrypting older values
+    active: String,
+    // hex encoded 256 bit keys by id
+    keys: HashMap<String, String>,
+}
+
+// Keys for encrypting sensitive values, read from a local file that every node needs a copy of:
+// {"active": "2", "keys": {"1": "<64 hex digits>", "2": "<64 hex digits>"}}
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub fn load(path: &Path) -> io::Result<Keyring> {
+        Keyring::from_json(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    fn from_json(json: &str) -> Result<Keyring, String> {
+        let file: KeyFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        if file.active.contains(':') {
+            return Err("key ids can't contain ':'".to_string());
+        }
+
+        let mut keys = HashMap::new();
+        for (id, hex) in file.keys {
+            let key = from_hex(&hex).filter(|k| k.len() == 32).ok_or_else(|| format!("key {} isn't 64 hex digits", id))?;
+            keys.insert(id, ChaCha20Poly1305::new(Key::from_slice(&key)));
+        }
+        if !keys.contains_key(&file.active) {
+            return Err(format!("active key {} isn't in the file", file.active));
+        }
+
+        Ok(Keyring { active: file.active, keys })
+    }
+
+    pub fn encrypt(&self, value: &str) -> io::Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+
+        let ciphertext = self.keys[&self.active].encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}:{}", ENCRYPTED_PREFIX, self.active, to_hex(&sealed)))
+    }
+
+    // plaintext values are given back as they are, None if the value is encrypted but can't be decrypted
+    pub fn decrypt<'a>(&self, value: &'a str) -> Option<Cow<'a, str>> {
+        let rest = match value.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(rest) => rest,
+            None => return Some(Cow::Borrowed(value)),
+        };
+
+        let mut parts = rest.splitn(2, ':');
+        let key = self.keys.get(parts.next()?)?;
+        let sealed = from_hex(parts.next()?)?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = key.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok().map(Cow::Owned)
+    }
+}
+
+// how a value is read back when there's no keyring at all
+pub fn decrypt_without_keyring(value: &str) -> Option<Cow<str>> {
+    if value.starts_with(ENCRYPTED_PREFIX) {
+        None
+    } else {
+        Some(Cow::Borrowed(value))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kms::Keyring;
+
+    const KEYS: &str = r#"{"active": "2", "keys": {
+        "1": "0000000000000000000000000000000000000000000000000000000000000001",
+        "2": "0000000000000000000000000000000000000000000000000000000000000002"}}"#;
+
+    #[test]
+    fn round_trip() {
+        let keyring = Keyring::from_json(KEYS).unwrap();
+        let sealed = keyring.encrypt("hunter2").unwrap();
+
+        assert!(sealed.starts_with("enc:2:"));
+        assert!(!sealed.contains("hunter2"));
+        assert_ne!(sealed, keyring.encrypt("hunter2").unwrap());
+        assert_eq!(keyring.decrypt(&sealed).as_deref(), Some("hunter2"));
+        assert_eq!(keyring.decrypt("plain").as_deref(), Some("plain"));
+    }
+
+    #[test]
+    fn old_keys_still_decrypt() {
+        let old = Keyring::from_json(&KEYS.replace(r#""active": "2""#, r#""active": "1""#)).unwrap();
+        let sealed = old.encrypt("v").unwrap();
+        assert!(sealed.starts_with("enc:1:"));
+        assert_eq!(Keyring::from_json(KEYS).unwrap().decrypt(&sealed).as_deref(), Some("v"));
+    }
+
+    #[test]
+    fn tampering_and_unknown_keys() {
+        let keyring = Keyring::from_json(KEYS).unwrap();
+        let sealed = keyring.encrypt("v").unwrap();
+
+        let last = sealed.chars().last().unwrap();
+        let tampered = format!("{}{}", &sealed[..sealed.len() - 1], if last == '0' { '1' } else { '0' });
+        assert_eq!(keyring.decrypt(&tampered), None);
+        assert_eq!(keyring.decrypt(&sealed.replacen("enc:2:", "enc:3:", 1)), None);
+
+        assert!(Keyring::from_json(r#"{"active": "1", "keys": {"1": "00"}}"#).is_err());
+        assert!(Keyring::from_json(r#"{"active": "2", "keys": {}}"#).is_err());
+    }
+}
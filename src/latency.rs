@@ -0,0 +1,96 @@
+use std::collections::{HashMap, VecDeque};
+
+const MAX_SAMPLES: usize = 20;
+
+// Round trip times from this node to its peers, plus the median round trip times other nodes last reported to theirs,
+// so every node can tell which member is closest to all the others.
+#[derive(Default)]
+pub struct LatencyTable {
+    samples: HashMap<u32, VecDeque<f64>>,
+    reported: HashMap<u32, HashMap<u32, f64>>,
+}
+
+impl LatencyTable {
+    pub fn record(&mut self, peer: u32, rtt_ms: f64) {
+        let samples = self.samples.entry(peer).or_default();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt_ms);
+    }
+
+    pub fn report(&mut self, from: u32, medians: HashMap<u32, f64>) {
+        self.reported.insert(from, medians);
+    }
+
+    pub fn medians(&self) -> HashMap<u32, f64> {
+        self.samples.iter()
+            .filter_map(|(peer, samples)| median(samples.iter().copied()).map(|m| (*peer, m)))
+            .collect()
+    }
+
+    // the member with the lowest median round trip time to the rest of the members, along with that median
+    pub fn recommend_leader(&self, our_id: u32, members: &[u32]) -> Option<(u32, f64)> {
+        let ours = self.medians();
+
+        members.iter()
+            .filter_map(|candidate| {
+                let rtts = if *candidate == our_id { &ours } else { self.reported.get(candidate)? };
+                let to_others = members.iter()
+                    .filter(|m| *m != candidate)
+                    .map(|m| rtts.get(m).copied())
+                    .collect::<Option<Vec<f64>>>()?;
+                median(to_others.into_iter()).map(|m| (*candidate, m))
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)))
+    }
+}
+
+fn median(values: impl Iterator<Item=f64>) -> Option<f64> {
+    let mut values: Vec<f64> = values.collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::latency::LatencyTable;
+
+    #[test]
+    fn recommends_closest_member() {
+        let mut table = LatencyTable::default();
+        table.record(2, 80.0);
+        table.record(3, 80.0);
+
+        let mut from_2 = HashMap::new();
+        from_2.insert(1, 50.0);
+        from_2.insert(3, 5.0);
+        table.report(2, from_2);
+
+        let mut from_3 = HashMap::new();
+        from_3.insert(1, 50.0);
+        from_3.insert(2, 5.0);
+        table.report(3, from_3);
+
+        // 2 and 3 tie on median, the lower id wins
+        assert_eq!(table.recommend_leader(1, &[1, 2, 3]), Some((2, 50.0)));
+
+        table.record(2, 1.0);
+        table.record(2, 1.0);
+        table.record(3, 1.0);
+        table.record(3, 1.0);
+        assert_eq!(table.recommend_leader(1, &[1, 2, 3]), Some((1, 1.0)));
+    }
+
+    #[test]
+    fn needs_full_rtt_data() {
+        let mut table = LatencyTable::default();
+        table.record(2, 10.0);
+        assert_eq!(table.recommend_leader(1, &[1, 2, 3]), None);
+    }
+}
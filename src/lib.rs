@@ -0,0 +1,4 @@
+pub mod client;
+pub mod drain;
+pub mod memory_client;
+pub mod protocol;
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+// commands given to the core and not yet applied
+const YELLOW_IN_FLIGHT: usize = 256;
+const RED_IN_FLIGHT: usize = 1024;
+// messages waiting to go to the slowest member, bulk ones are dropped past 64
+const YELLOW_SEND_QUEUE: usize = 32;
+const RED_SEND_QUEUE: usize = 128;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Load {
+    Green,
+    // still answering everything, but a client with somewhere else to go should go there
+    Yellow,
+    // failing or about to fail requests
+    Red,
+}
+
+impl Load {
+    pub fn name(self) -> &'static str {
+        match self {
+            Load::Green => "green",
+            Load::Yellow => "yellow",
+            Load::Red => "red",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LoadSignals {
+    pub pending_reads: usize,
+    // reads start failing at this many pending
+    pub read_overload_threshold: usize,
+    pub in_flight: usize,
+    pub max_send_queue: usize,
+    pub commit_p99: Option<Duration>,
+    pub commit_target: Option<Duration>,
+    // stepping down, tripped the apply breaker or low on disk
+    pub refusing_writes: bool,
+}
+
+impl LoadSignals {
+    // the worst of every signal, with why it isn't green
+    pub fn assess(&self) -> (Load, Vec<String>) {
+        let mut reasons = vec![];
+        let mut check = |load: Load, reason: String| {
+            if load > Load::Green {
+                reasons.push(reason);
+            }
+            load
+        };
+
+        let levels = [
+            check(
+                level(self.pending_reads, self.read_overload_threshold / 2, self.read_overload_threshold),
+                format!("{} reads pending, they fail at {}", self.pending_reads, self.read_overload_threshold),
+            ),
+            check(level(self.in_flight, YELLOW_IN_FLIGHT, RED_IN_FLIGHT), format!("{} commands in flight", self.in_flight)),
+            check(level(self.max_send_queue, YELLOW_SEND_QUEUE, RED_SEND_QUEUE), format!("{} messages queued for a member", self.max_send_queue)),
+            match (self.commit_p99, self.commit_target) {
+                (Some(p99), Some(target)) => check(
+                    if p99 > target * 2 { Load::Red } else if p99 > target { Load::Yellow } else { Load::Green },
+                    format!("p99 commit latency is {}ms against a target of {}ms", p99.as_millis(), target.as_millis()),
+                ),
+                _ => Load::Green,
+            },
+            check(if self.refusing_writes { Load::Red } else { Load::Green }, "refusing writes".to_string()),
+        ];
+        (levels.iter().copied().max().unwrap_or(Load::Green), reasons)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let (load, reasons) = self.assess();
+        json!({
+            "load": load.name(),
+            "reasons": reasons,
+            "pending_reads": self.pending_reads,
+            "in_flight": self.in_flight,
+            "max_send_queue": self.max_send_queue,
+            "commit_p99_ms": self.commit_p99.map(|p99| p99.as_secs_f64() * 1000.0),
+            "refusing_writes": self.refusing_writes,
+        })
+    }
+}
+
+fn level(value: usize, yellow: usize, red: usize) -> Load {
+    if value >= red {
+        Load::Red
+    } else if value >= yellow {
+        Load::Yellow
+    } else {
+        Load::Green
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::load::{Load, LoadSignals};
+
+    #[test]
+    fn worst_signal_wins() {
+        let mut signals = LoadSignals { read_overload_threshold: 64, ..LoadSignals::default() };
+        assert_eq!(signals.assess(), (Load::Green, vec![]));
+
+        signals.pending_reads = 32;
+        assert_eq!(signals.assess().0, Load::Yellow);
+
+        signals.commit_target = Some(Duration::from_millis(20));
+        signals.commit_p99 = Some(Duration::from_millis(50));
+        let (load, reasons) = signals.assess();
+        assert_eq!(load, Load::Red);
+        assert_eq!(reasons.len(), 2);
+
+        signals = LoadSignals { read_overload_threshold: 64, refusing_writes: true, ..LoadSignals::default() };
+        assert_eq!(signals.assess(), (Load::Red, vec!["refusing writes".to_string()]));
+    }
+}
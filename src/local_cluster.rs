@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+
+use my_raft::config::NodeAddress;
+use my_raft::core::Raft;
+use my_raft::network::NetworkInterface;
+use my_project6::protocol::NO_LEADER;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockFlag, SockType};
+use serde_json::{json, Value};
+
+use crate::{init_state_machine, num_to_network_name};
+use crate::cluster::ClusterId;
+use crate::network::{Cs3700UnixNetwork, NetworkConfig};
+use crate::storage::RamStorage;
+
+pub const DEFAULT_CLIENT_PORT: u16 = 7000;
+
+const PACKET_SIZE: usize = 65527;
+// clients on the client port are named from here up, past any node
+const FIRST_CLIENT_ID: u32 = 0xC000;
+// a request is handed back to its client after being redirected this many times
+const MAX_REDIRECTS: u32 = 3;
+const POLL_TIMEOUT_MS: i32 = 100;
+
+struct Client {
+    stream: TcpStream,
+    // bytes received since the last full line
+    partial: Vec<u8>,
+}
+
+// Plays the part of the CS3700 simulator for nodes running as threads in this process, each talking to it over its
+// own socketpair, and takes client requests as lines of JSON on a single TCP port. Requests go to the last leader a
+// node named, and redirects are followed for the client, so one connection can use the whole cluster.
+struct Router {
+    nodes: Vec<(String, RawFd)>,
+    listener: TcpListener,
+    clients: HashMap<String, Client>,
+    next_client: u32,
+    leader: Option<String>,
+    // requests waiting on an answer, by client and MID, along with how many times they've been redirected
+    pending: HashMap<(String, String), (Value, u32)>,
+    buffer: Vec<u8>,
+}
+
+pub fn run(size: u32, client_port: u16) {
+    let nodes: HashMap<u32, NodeAddress> = (0..size).map(|id| (id, NodeAddress::String(num_to_network_name(id)))).collect();
+    let cluster_id = ClusterId::from_nodes(&nodes);
+
+    let mut router_fds = vec![];
+    for id in 0..size {
+        let (node_fd, router_fd) = socket::socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::empty())
+            .expect("could not create socket pair");
+        router_fds.push((num_to_network_name(id), router_fd));
+
+        let nodes = nodes.clone();
+        thread::Builder::new()
+            .name(format!("node-{}", num_to_network_name(id)))
+            .spawn(move || {
+                let init_state_machine = init_state_machine(id, nodes);
+                let mut network = Cs3700UnixNetwork::with_socket(node_fd, id, cluster_id, NetworkConfig::default());
+                network.on_config_update(&init_state_machine.config);
+                let mut raft = Raft::new(RamStorage::new(init_state_machine, cluster_id), network);
+                raft.start();
+            })
+            .expect("could not start node thread");
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", client_port)).expect("could not bind client port");
+    listener.set_nonblocking(true).unwrap();
+    println!("local cluster of {} nodes, send requests as lines of JSON to 127.0.0.1:{}", size, client_port);
+
+    let mut router = Router {
+        nodes: router_fds,
+        listener,
+        clients: HashMap::new(),
+        next_client: FIRST_CLIENT_ID,
+        leader: None,
+        pending: HashMap::new(),
+        buffer: vec![0u8; PACKET_SIZE],
+    };
+    loop {
+        router.route();
+    }
+}
+
+impl Router {
+    fn route(&mut self) {
+        let client_names: Vec<String> = self.clients.keys().cloned().collect();
+        let mut fds: Vec<PollFd> = self.nodes.iter().map(|(_, fd)| *fd)
+            .chain(std::iter::once(self.listener.as_raw_fd()))
+            .chain(client_names.iter().map(|name| self.clients[name].stream.as_raw_fd()))
+            .map(|fd| PollFd::new(fd, PollFlags::POLLIN))
+            .collect();
+        if poll(&mut fds, POLL_TIMEOUT_MS).unwrap_or(0) <= 0 {
+            return;
+        }
+        let ready: Vec<bool> = fds.iter().map(|fd| fd.revents().map_or(false, |r| !r.is_empty())).collect();
+
+        for i in 0..self.nodes.len() {
+            if ready[i] {
+                self.from_node(self.nodes[i].1);
+            }
+        }
+        if ready[self.nodes.len()] {
+            self.accept_clients();
+        }
+        for (name, _) in client_names.iter().zip(&ready[self.nodes.len() + 1..]).filter(|(_, ready)| **ready) {
+            self.from_client(name);
+        }
+    }
+
+    fn from_node(&mut self, fd: RawFd) {
+        let amt = match socket::recv(fd, &mut self.buffer, MsgFlags::empty()) {
+            Ok(amt) if amt > 0 => amt,
+            _ => return,
+        };
+        let message: Value = match serde_json::from_slice(&self.buffer[..amt]) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        if let Some(leader) = message["leader"].as_str().filter(|l| *l != NO_LEADER) {
+            self.leader = Some(leader.to_string());
+        }
+
+        let dst = message["dst"].as_str().unwrap_or_default().to_string();
+        if let Some((_, node_fd)) = self.nodes.iter().find(|(name, _)| *name == dst) {
+            let _ = socket::send(*node_fd, &self.buffer[..amt], MsgFlags::empty());
+            return;
+        }
+
+        let key = (dst, message["MID"].as_str().unwrap_or_default().to_string());
+        let redirected = match self.pending.get_mut(&key) {
+            Some((request, redirects)) if message["type"] == "redirect" && *redirects < MAX_REDIRECTS => {
+                *redirects += 1;
+                Some(request.clone())
+            }
+            _ => None,
+        };
+        match redirected {
+            Some(request) => self.to_node(&key.0, request),
+            None => {
+                self.pending.remove(&key);
+                self.to_client(&key.0, &message);
+            }
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let name = format!("{:X}", self.next_client);
+            self.next_client += 1;
+            self.clients.insert(name, Client { stream, partial: vec![] });
+        }
+    }
+
+    fn from_client(&mut self, name: &str) {
+        let client = match self.clients.get_mut(name) {
+            Some(client) => client,
+            None => return,
+        };
+        match client.stream.read(&mut self.buffer) {
+            Ok(amt) if amt > 0 => client.partial.extend_from_slice(&self.buffer[..amt]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            _ => {
+                self.clients.remove(name);
+                return;
+            }
+        }
+
+        // None for lines that aren't requests
+        let mut requests = vec![];
+        while let Some(end) = client.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = client.partial.drain(..=end).collect();
+            requests.push(serde_json::from_slice::<Value>(&line).ok().filter(Value::is_object));
+        }
+
+        for request in requests {
+            match request {
+                Some(request) => {
+                    let mid = request["MID"].as_str().unwrap_or_default().to_string();
+                    self.pending.insert((name.to_string(), mid), (request.clone(), 0));
+                    self.to_node(name, request);
+                }
+                None => self.to_client(name, &json!({ "type": "fail", "error": "requests are JSON objects, one per line" })),
+            }
+        }
+    }
+
+    // to the leader, or the first node until one has been named
+    fn to_node(&mut self, client: &str, mut request: Value) {
+        let dst = self.leader.clone().unwrap_or_else(|| self.nodes[0].0.clone());
+        request["src"] = json!(client);
+        request["dst"] = json!(dst);
+        request["leader"] = json!(NO_LEADER);
+        if let Some((_, fd)) = self.nodes.iter().find(|(name, _)| *name == dst) {
+            let _ = socket::send(*fd, request.to_string().as_bytes(), MsgFlags::empty());
+        }
+    }
+
+    // clients that can't keep up with their responses are disconnected
+    fn to_client(&mut self, name: &str, message: &Value) {
+        let sent = match self.clients.get_mut(name) {
+            Some(client) => client.stream.write_all(format!("{}\n", message).as_bytes()).is_ok(),
+            None => return,
+        };
+        if !sent {
+            self.clients.remove(name);
+        }
+    }
+}
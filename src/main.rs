@@ -1,61 +1,607 @@
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
+use clap::Parser;
 use my_raft::config::{Config, NodeAddress};
 use my_raft::core::Raft;
+use my_raft::network::NetworkInterface;
 use my_raft::state_machine::RaftStateMachine;
+use my_raft::storage::Storage;
 
-use crate::network::Cs3700UnixNetwork;
+use crate::admin::AdminServer;
+use crate::authz::Rules;
+use crate::backup::{RestorePoint, S3Target};
+use crate::cli::{Cli, Command, RunArgs};
+use crate::clients::ClientLimit;
+use crate::cluster::ClusterId;
+use crate::config_file::ConfigFile;
+use crate::kms::Keyring;
+use crate::membership::Tunables;
+use crate::mmap_storage::MmapStorage;
+use crate::network::{Cs3700UnixNetwork, NetworkConfig, TcpNetwork, UdpNetwork};
+use crate::shutdown::Shutdown;
+#[cfg(feature = "sled-storage")]
+use crate::sled_storage::SledStorage;
+use crate::snapshot_format::SnapshotEncoding;
+use crate::snapshot_pause::SnapshotPause;
+use crate::snapshot_pull::SnapshotExchange;
 use crate::state_machine::KvStateMachine;
-use crate::storage::RamStorage;
+use crate::state_transfer::StateTransferPolicy;
+use crate::storage::{LogRepair, MAX_APPEND_ENTRIES_BYTES, RamStorage, SnapshotCodec};
+use crate::storage_metrics::StorageMetrics;
+use crate::systemd::Notifier;
+use crate::watchdog::Watchdog;
 
+mod admin;
 mod storage;
 mod state_machine;
+mod state_transfer;
 mod network;
+mod cluster;
+mod config_file;
+mod commit_tuner;
+mod discovery;
+mod alloc_stats;
+mod failure_detector;
+mod http;
+mod json_path;
+mod latency;
+mod load;
+mod script;
+mod backup;
+mod auth;
+mod authz;
+mod bench;
+mod cli;
+mod kms;
+mod hot_keys;
+mod clients;
+mod local_cluster;
+mod membership;
+mod metrics;
+mod mmap_storage;
+mod peer_sender;
+mod prometheus;
+mod redirects;
+mod response_order;
+mod shutdown;
+#[cfg(feature = "sled-storage")]
+mod sled_storage;
+mod snapshot_chunks;
+mod snapshot_file;
+mod snapshot_format;
+mod snapshot_pause;
+mod snapshot_pull;
+mod storage_metrics;
+mod systemd;
+mod tcp_transport;
+mod udp_transport;
+mod wal;
+mod watchdog;
+#[cfg(test)]
+mod faulty_storage;
 
 fn main() {
-    let (our_id, nodes) = get_nodes_and_id();
+    let cli = Cli::parse();
+    match cli.command {
+        None => run(cli.run),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::InspectLog { wal_dir }) => print_or_exit(storage::inspect_wal::<KvStateMachine>(&wal_dir)),
+        Some(Command::Compact { wal_dir }) => {
+            let codec = env_var("KV_SNAPSHOT_COMPRESSION").unwrap_or(SnapshotCodec::Uncompressed);
+            print_or_exit(storage::compact_wal::<KvStateMachine>(&wal_dir, codec, snapshot_encoding()));
+        }
+        Some(Command::Bench { members, requests, value_bytes }) => print_or_exit(Ok(bench::run(members, requests, value_bytes))),
+    }
+}
+
+fn print_or_exit(result: std::io::Result<serde_json::Value>) {
+    match result {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(args: RunArgs) {
+    if let Some(size) = args.local_cluster {
+        local_cluster::run(size, args.client_port);
+        return;
+    }
+
+    // SIGINT and SIGTERM are waited for on a thread of their own, which only works if they're blocked before any other
+    // thread is started
+    let shutdown = Shutdown::on_signals().unwrap_or_else(|e| {
+        eprintln!("refusing to start: can't handle signals: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut node_args = args.nodes;
+    if let Some(path) = args.config.or_else(|| std::env::var_os("KV_CONFIG").map(PathBuf::from)) {
+        match ConfigFile::load(&path) {
+            Ok(file) => {
+                file.apply();
+                if node_args.is_empty() {
+                    node_args = file.nodes;
+                }
+            }
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // with KV_TRANSPORT=tcp or udp the node runs across machines, see TransportNetwork
+    let transport: Option<String> = env_var("KV_TRANSPORT");
+    let (our_id, mut nodes) = get_nodes_and_id(node_args, transport.is_some()).unwrap_or_else(|e| {
+        eprintln!("refusing to start: {}", e);
+        std::process::exit(1);
+    });
+    if args.force_new_cluster {
+        nodes.retain(|id, _| *id == our_id);
+        force_new_cluster(our_id, &nodes, args.yes);
+    }
+
+    let configured_cluster_id: Option<ClusterId> = env_var("KV_CLUSTER_ID");
+
+    let cluster_id = match std::env::var_os("KV_DATA_DIR") {
+        Some(data_dir) => match ClusterId::load_or_bootstrap(Path::new(&data_dir), configured_cluster_id) {
+            Ok(id) => id,
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => configured_cluster_id.unwrap_or_else(|| ClusterId::from_nodes(&nodes)),
+    };
+
+    let init_state_machine = init_state_machine(our_id, nodes);
+    let problems = membership::validate_tunables(&init_state_machine.config, &Tunables::default());
+    if !problems.is_empty() {
+        eprintln!("refusing to start: {}", problems.join(", "));
+        std::process::exit(1);
+    }
+
+    let snapshot_encoding = snapshot_encoding();
+    if let Some(ms) = env_var("KV_APPLY_BUDGET_MS") {
+        state_machine::set_apply_budget(Duration::from_millis(ms), env_var("KV_APPLY_BREAKER_AFTER").unwrap_or(0));
+    }
+
+    let mut network_config = NetworkConfig::default();
+    if let Some(ms) = env_var("KV_READ_STALENESS_MS") {
+        network_config.read_staleness = Duration::from_millis(ms);
+    }
+    if let Some(threshold) = env_var("KV_READ_OVERLOAD_THRESHOLD") {
+        network_config.read_overload_threshold = threshold;
+    }
+    if let Some(phi) = env_var("KV_SUSPICION_THRESHOLD") {
+        network_config.suspicion_threshold = phi;
+    }
+    network_config.http_port = env_var("KV_HTTP_PORT");
+    network_config.http_clients = env_var("KV_HTTP_CLIENTS").unwrap_or(false);
+    network_config.metrics_port = env_var("KV_METRICS_PORT");
+    network_config.catch_up_read_ahead = env_var("KV_CATCH_UP_READ_AHEAD").unwrap_or(0);
+    network_config.hot_standby = env_var("KV_HOT_STANDBY").unwrap_or(false);
+    network_config.growth_report_interval = env_var("KV_GROWTH_REPORT_SECS").map(Duration::from_secs);
+    network_config.tombstone_retention = env_var("KV_TOMBSTONE_RETENTION_SECS").map(Duration::from_secs);
+    network_config.state_transfer = env_var("KV_STATE_TRANSFER_PREFER").map(|prefer| StateTransferPolicy {
+        prefer,
+        threshold: env_var("KV_STATE_TRANSFER_THRESHOLD").unwrap_or(10_000),
+        max_hold: Duration::from_secs(env_var("KV_STATE_TRANSFER_MAX_HOLD_SECS").unwrap_or(60)),
+    });
+    if let Some(path) = std::env::var_os("KV_AUTHZ_RULES") {
+        match Rules::load(Path::new(&path)) {
+            Ok(rules) => network_config.authorizer = Box::new(rules),
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    network_config.failover_budget = env_var("KV_FAILOVER_BUDGET_MS").map(Duration::from_millis);
+    if let Some(requires_leader) = env_var("KV_READY_REQUIRES_LEADER") {
+        network_config.ready_requires_leader = requires_leader;
+    }
+    if let Some(ms) = env_var("KV_READY_MAX_LEADER_SILENCE_MS") {
+        network_config.ready_max_leader_silence = Duration::from_millis(ms);
+    }
+    network_config.commit_latency_target = env_var("KV_COMMIT_LATENCY_TARGET_MS").map(Duration::from_millis);
+    network_config.response_order_timeout = env_var("KV_RESPONSE_ORDER_TIMEOUT_MS").map(Duration::from_millis);
+    // zero sends every redirect
+    network_config.redirect_suppression = Some(Duration::from_millis(env_var("KV_REDIRECT_SUPPRESS_MS").unwrap_or(250)))
+        .filter(|ttl| *ttl > Duration::from_millis(0));
+    network_config.auth_secret = std::env::var("KV_AUTH_SECRET").ok().map(String::into_bytes);
+    network_config.dead_node_timeout = env_var("KV_DEAD_NODE_TIMEOUT_SECS").map(Duration::from_secs);
+    network_config.client_limit = env_var("KV_CLIENT_RATE_LIMIT").map(|rate| ClientLimit {
+        rate,
+        burst: env_var("KV_CLIENT_RATE_BURST").unwrap_or(rate),
+    });
+    let storage_metrics = StorageMetrics::default();
+    network_config.storage_metrics = storage_metrics.clone();
+    let snapshot_exchange = if env_var("KV_SNAPSHOT_PULL").unwrap_or(false) { Some(SnapshotExchange::default()) } else { None };
+    network_config.snapshot_pull = snapshot_exchange.clone();
+    // snapshots can also be paused and resumed at runtime with pause_snapshots and resume_snapshots
+    let snapshot_pause = SnapshotPause::default();
+    snapshot_pause.set_paused(env_var("KV_PAUSE_SNAPSHOTS").unwrap_or(false));
+    network_config.snapshot_pause = snapshot_pause.clone();
+    network_config.shutdown = shutdown.clone();
+    network_config.data_dir = std::env::var_os("KV_DATA_DIR")
+        .or_else(|| std::env::var_os("KV_WAL_DIR"))
+        .or_else(|| std::env::var_os("KV_CHECKPOINT_PATH"))
+        .map(PathBuf::from)
+        .map(|path| match path.parent() {
+            // a checkpoint file is watched through the directory it's in
+            Some(parent) if !path.is_dir() => if parent.as_os_str().is_empty() { PathBuf::from(".") } else { parent.to_path_buf() },
+            _ => path,
+        });
+    network_config.min_free_disk_bytes = env_var("KV_MIN_FREE_DISK_MB").map_or(0, |mb: u64| mb * 1024 * 1024);
+    // a path for a Unix socket, or host:port for TCP
+    if let Some(address) = env_var::<String>("KV_ADMIN_SOCKET") {
+        match AdminServer::bind(&address) {
+            Ok(admin) => network_config.admin = Some(admin),
+            Err(e) => {
+                eprintln!("refusing to start: can't listen for admin commands on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    network_config.systemd = Notifier::from_env();
+    network_config.watchdog = env_var("KV_WATCHDOG_SECS")
+        .map(|secs| Watchdog::spawn(Duration::from_secs(secs), env_var("KV_WATCHDOG_ABORT").unwrap_or(false)));
+    if let Some(path) = std::env::var_os("KV_KMS_KEY_FILE") {
+        match Keyring::load(Path::new(&path)) {
+            Ok(keyring) => network_config.keyring = Some(keyring),
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // sled makes everything durable itself, so the write-ahead log, checkpoints, backups and snapshot pulls aren't used
+    #[cfg(feature = "sled-storage")]
+    {
+        if let Some(dir) = std::env::var_os("KV_SLED_DIR") {
+            let raft_config = init_state_machine.config.clone();
+            let mut storage = match SledStorage::open(init_state_machine, cluster_id, Path::new(&dir)) {
+                Ok(storage) => storage,
+                Err(e) => {
+                    eprintln!("refusing to start: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Some(bytes) = append_entries_bytes() {
+                storage.set_append_entries_bytes(bytes);
+            }
+            if let Some(codec) = env_var("KV_SNAPSHOT_COMPRESSION") {
+                storage.set_snapshot_codec(codec);
+            }
+            storage.set_snapshot_encoding(snapshot_encoding);
+            network_config.snapshot_pull = None;
+            start_raft(storage, transport, network_config, &raft_config, our_id, cluster_id);
+            return;
+        }
+    }
+
+    // the same goes for a log that's mapped
+    if let Some(dir) = std::env::var_os("KV_MMAP_DIR") {
+        let raft_config = init_state_machine.config.clone();
+        let slots = env_var("KV_MMAP_INDEX_SLOTS").unwrap_or(mmap_storage::DEFAULT_INDEX_SLOTS);
+        let mut storage = match MmapStorage::open(init_state_machine, cluster_id, Path::new(&dir), slots, storage_metrics) {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(bytes) = append_entries_bytes() {
+            storage.set_append_entries_bytes(bytes);
+        }
+        if let Some(codec) = env_var("KV_SNAPSHOT_COMPRESSION") {
+            storage.set_snapshot_codec(codec);
+        }
+        storage.set_snapshot_encoding(snapshot_encoding);
+        network_config.snapshot_pull = None;
+        start_raft(storage, transport, network_config, &raft_config, our_id, cluster_id);
+        return;
+    }
+
+    let s3_target = || env_var("KV_BACKUP_S3_ENDPOINT").map(|endpoint| S3Target {
+        endpoint,
+        bucket: env_var("KV_BACKUP_S3_BUCKET").unwrap_or_else(|| {
+            eprintln!("refusing to start: KV_BACKUP_S3_BUCKET must be set with KV_BACKUP_S3_ENDPOINT");
+            std::process::exit(1);
+        }),
+        prefix: env_var("KV_BACKUP_S3_PREFIX").unwrap_or_else(|| format!("{}/{}/", cluster_id, num_to_network_name(our_id))),
+        region: env_var("KV_BACKUP_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+        access_key: env_var("KV_BACKUP_S3_ACCESS_KEY").unwrap_or_default(),
+        secret_key: env_var("KV_BACKUP_S3_SECRET_KEY").unwrap_or_default(),
+    });
+    let backups = s3_target().map(|target| backup::spawn_uploader(Box::new(target), env_var("KV_BACKUP_COMPRESS_SEGMENTS").unwrap_or(false)));
+    network_config.backups = backups.clone();
+
+    let raft_config = init_state_machine.config.clone();
+
+    let restore_point = match (env_var("KV_RESTORE_TO_TAG"), env_var("KV_RESTORE_TO_INDEX"), env_var("KV_RESTORE_TO_TIMESTAMP")) {
+        (Some(tag), _, _) => Some(RestorePoint::Tag(tag)),
+        (None, Some(index), _) => Some(RestorePoint::Index(index)),
+        (None, None, Some(time)) => Some(RestorePoint::Timestamp(time)),
+        (None, None, None) if env_var("KV_RESTORE_FROM_S3") == Some(true) => Some(RestorePoint::Latest),
+        (None, None, None) => None,
+    };
+
+    let mut storage = if let Some(point) = restore_point {
+        let mut target = s3_target().unwrap_or_else(|| {
+            eprintln!("refusing to start: restoring needs KV_BACKUP_S3_ENDPOINT");
+            std::process::exit(1);
+        });
+        match RamStorage::restore(init_state_machine, cluster_id, &mut target, point) {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!("refusing to start: restore failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(dir) = std::env::var_os("KV_WAL_DIR") {
+        let segment_bytes = env_var("KV_WAL_SEGMENT_BYTES").unwrap_or(wal::DEFAULT_SEGMENT_BYTES);
+        match RamStorage::open_wal(init_state_machine, cluster_id, Path::new(&dir), segment_bytes, storage_metrics.clone()) {
+            Ok(storage) => storage,
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(path) = std::env::var_os("KV_CHECKPOINT_PATH") {
+        match RamStorage::load_checkpoint(state_machine::clone_state_machine(&init_state_machine), cluster_id, Path::new(&path)) {
+            Ok(Some(storage)) => storage,
+            Ok(None) => RamStorage::new(init_state_machine, cluster_id),
+            Err(e) => {
+                eprintln!("refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        RamStorage::new(init_state_machine, cluster_id)
+    };
+
+    match storage.verify_log_prefix(env_var("KV_LOG_REPAIR").unwrap_or(LogRepair::Refuse)) {
+        Ok(None) => {}
+        Ok(Some(repaired)) => eprintln!("repaired storage: {}", repaired),
+        Err(e) => {
+            eprintln!("refusing to start: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(bytes) = append_entries_bytes() {
+        storage.set_append_entries_bytes(bytes);
+    }
+    if let Some(codec) = env_var("KV_SNAPSHOT_COMPRESSION") {
+        storage.set_snapshot_codec(codec);
+    }
+    storage.set_snapshot_encoding(snapshot_encoding);
+
+    if let Some(exchange) = snapshot_exchange {
+        storage.share_snapshots(exchange);
+    }
+    storage.share_snapshot_pause(snapshot_pause);
+    storage.share_metrics(storage_metrics.clone());
+
+    if let Some(path) = std::env::var_os("KV_CHECKPOINT_PATH") {
+        let interval = Duration::from_secs(env_var("KV_CHECKPOINT_INTERVAL_SECS").unwrap_or(10));
+        storage.start_checkpoints(PathBuf::from(path), interval, storage_metrics);
+    }
+    storage.share_shutdown(shutdown);
+
+    if let Some(backups) = backups {
+        let interval = Duration::from_secs(env_var("KV_BACKUP_INTERVAL_SECS").unwrap_or(60));
+        storage.start_backups(backups.uploads, interval);
+    }
+
+    start_raft(storage, transport, network_config, &raft_config, our_id, cluster_id);
+}
+
+fn start_raft<St: Storage<KvStateMachine>>(storage: St, transport: Option<String>, network_config: NetworkConfig, raft_config: &Config, our_id: u32, cluster_id: ClusterId) {
+    if let Some(transport) = transport {
+        // our own address unless told otherwise, like to listen on 0.0.0.0
+        let listen = match &raft_config.nodes[&our_id] {
+            NodeAddress::String(address) => env_var("KV_TRANSPORT_LISTEN").unwrap_or_else(|| address.clone()),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        match transport.as_str() {
+            "tcp" => {
+                let mut network = listening(TcpNetwork::new(our_id, cluster_id, network_config, &listen), &listen);
+                network.on_config_update(raft_config);
+                let mut raft = Raft::new(storage, network);
+                raft.start();
+            }
+            "udp" => {
+                let mut network = listening(UdpNetwork::new(our_id, cluster_id, network_config, &listen), &listen);
+                network.on_config_update(raft_config);
+                let mut raft = Raft::new(storage, network);
+                raft.start();
+            }
+            other => {
+                eprintln!("refusing to start: invalid value for KV_TRANSPORT: {}", other);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let mut network = Cs3700UnixNetwork::new(our_id, cluster_id, network_config);
+        network.on_config_update(raft_config);
+        let mut raft = Raft::new(storage, network);
+        raft.start();
+    }
+}
+
+// Rewrites the write-ahead log to make this node the only member of a new cluster, after asking on the terminal unless
+// told not to, since the old cluster's other members can't join the new one with what they have.
+fn force_new_cluster(our_id: u32, nodes: &HashMap<u32, NodeAddress>, confirmed: bool) {
+    let wal_dir = match std::env::var_os("KV_WAL_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("refusing to start: --force-new-cluster needs KV_WAL_DIR, the only storage it can rewrite");
+            std::process::exit(1);
+        }
+    };
+    if std::env::var_os("KV_CLUSTER_ID").is_some() {
+        eprintln!("refusing to start: --force-new-cluster gives the node a new cluster id, so KV_CLUSTER_ID can't be set");
+        std::process::exit(1);
+    }
+
+    let name = num_to_network_name(our_id);
+    if !confirmed {
+        eprintln!("--force-new-cluster makes {} the only member of a new cluster, keeping its data. Only do this once a quorum of the old \
+                   cluster is gone for good, its other members will have to be wiped and added back.", name);
+        eprint!("type {} to go ahead: ", name);
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() || answer.trim() != name {
+            eprintln!("refusing to start: --force-new-cluster wasn't confirmed");
+            std::process::exit(1);
+        }
+    }
+
+    // a data directory keeps a new random id, otherwise it's worked out from the members like for any other cluster
+    let data_dir = std::env::var_os("KV_DATA_DIR").map(PathBuf::from);
+    let cluster_id = match &data_dir {
+        Some(_) => ClusterId::generate(),
+        None => Ok(ClusterId::from_nodes(nodes)),
+    };
+    let (codec, encoding) = (env_var("KV_SNAPSHOT_COMPRESSION").unwrap_or(SnapshotCodec::Uncompressed), snapshot_encoding());
+    let forced = cluster_id.and_then(|cluster_id| {
+        let summary = storage::force_new_cluster::<KvStateMachine>(&wal_dir, our_id, cluster_id, codec, encoding)?;
+        if let Some(dir) = &data_dir {
+            ClusterId::replace(dir, cluster_id)?;
+        }
+        Ok(summary)
+    });
+    match forced {
+        Ok(summary) => eprintln!("forced a new cluster: {}", summary),
+        Err(e) => {
+            eprintln!("refusing to start: --force-new-cluster failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let init_state_machine = RaftStateMachine {
+// timeouts are in milliseconds
+pub fn init_state_machine(our_id: u32, nodes: HashMap<u32, NodeAddress>) -> RaftStateMachine<KvStateMachine> {
+    RaftStateMachine {
         inner: KvStateMachine::default(),
         config: Config {
-            election_timeout_min: 750,
-            election_timeout_range: 250,
-            heartbeat_timeout: 500,
-            rpc_response_timeout: 20,
-            max_entries_in_append_entries: 100,
-            max_bytes_in_install_snapshot: 100,
-            next_index_decrease_rate: 100,
-            snapshot_min_log_size: u32::max_value(),
+            election_timeout_min: env_var("KV_ELECTION_TIMEOUT_MIN_MS").unwrap_or(750),
+            election_timeout_range: env_var("KV_ELECTION_TIMEOUT_RANGE_MS").unwrap_or(250),
+            heartbeat_timeout: env_var("KV_HEARTBEAT_TIMEOUT_MS").unwrap_or(500),
+            rpc_response_timeout: env_var("KV_RPC_RESPONSE_TIMEOUT_MS").unwrap_or(20),
+            // AppendEntries are cut off by size in storage, this is only a backstop
+            max_entries_in_append_entries: env_var("KV_MAX_ENTRIES_IN_APPEND_ENTRIES").unwrap_or(10_000),
+            max_bytes_in_install_snapshot: env_var("KV_MAX_BYTES_IN_INSTALL_SNAPSHOT").unwrap_or(100),
+            // how far the leader moves a follower's next index back after each rejected AppendEntries
+            next_index_decrease_rate: env_var("KV_NEXT_INDEX_DECREASE_RATE").unwrap_or(100),
+            // how long the log gets before the core snapshots it, never by default
+            snapshot_min_log_size: env_var("KV_SNAPSHOT_MIN_LOG_SIZE").unwrap_or(u32::max_value()),
             id: our_id,
             nodes,
         },
         client_last_command_ids: Default::default(),
-    };
-
-    let network = Cs3700UnixNetwork::new(our_id);
+    }
+}
 
-    let mut raft = Raft::new(RamStorage::new(init_state_machine), network);
-    raft.start();
+// how snapshots are written, for a node or compact
+fn snapshot_encoding() -> SnapshotEncoding {
+    let format = match env_var::<String>("KV_SNAPSHOT_FORMAT") {
+        None => None,
+        Some(name) if name == "native" => None,
+        Some(name) => match snapshot_format::by_name(&name) {
+            Some(format) => Some(format),
+            None => {
+                eprintln!("refusing to start: unknown snapshot format {}", name);
+                std::process::exit(1);
+            }
+        },
+    };
+    SnapshotEncoding { format, dedup: env_var("KV_SNAPSHOT_DEDUP").unwrap_or(false) }
 }
 
-fn get_nodes_and_id() -> (u32, HashMap<u32, NodeAddress>) {
-    let mut args = std::env::args();
-    args.next();
+fn get_nodes_and_id(args: Vec<String>, over_transport: bool) -> Result<(u32, HashMap<u32, NodeAddress>), String> {
+    let mut args = args.into_iter();
+
+    // over a transport every member is given as <id>=<host:port>, ours first
+    if over_transport {
+        let members: Vec<(u32, NodeAddress)> = args.map(|arg| network::parse_member(&arg).ok_or_else(|| format!("invalid member {}, expected <id>=<host:port>", arg)))
+            .collect::<Result<_, _>>()?;
+        let this_id = members.first().ok_or("no members given, expected <id>=<host:port> for each, ours first")?.0;
+        return Ok((this_id, members.into_iter().collect()));
+    }
 
-    let this_name = args.next().unwrap();
+    let this_name = args.next().ok_or("no node id given, expected <id> [<peer id>...] or nodes in the config file")?;
 
     let mut nodes = HashMap::new();
 
-    let this_id = network_name_to_num(&this_name);
+    let this_id = parse_node_id(&this_name)?;
+
+    // with discovery on, only our own name is passed and the peers come from DNS
+    let peers: Vec<String> = match env_var::<String>("KV_DISCOVERY_SRV") {
+        Some(service) => {
+            let server = env_var("KV_DISCOVERY_DNS_SERVER").map_or_else(discovery::system_nameserver, Ok);
+            let expected = env_var("KV_DISCOVERY_EXPECTED_NODES").unwrap_or(1);
+            let timeout = Duration::from_secs(env_var("KV_DISCOVERY_TIMEOUT_SECS").unwrap_or(60));
+            match server.and_then(|server| discovery::discover_srv(&service, server, expected, timeout)) {
+                Ok(names) => names,
+                Err(e) => {
+                    eprintln!("refusing to start: discovery failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => args.collect(),
+    };
+
     nodes.insert(this_id, NodeAddress::String(this_name));
+    for name in peers {
+        nodes.entry(parse_node_id(&name)?).or_insert(NodeAddress::String(name));
+    }
+
+    Ok((this_id, nodes))
+}
+
+fn parse_node_id(name: &str) -> Result<u32, String> {
+    u32::from_str_radix(name, 16).map_err(|_| format!("invalid node id {}, expected hex like 0001", name))
+}
 
-    for name in args {
-        nodes.insert(network_name_to_num(&name), NodeAddress::String(name));
+fn listening<N>(network: std::io::Result<N>, listen: &str) -> N {
+    match network {
+        Ok(network) => network,
+        Err(e) => {
+            eprintln!("refusing to start: can't listen on {}: {}", listen, e);
+            std::process::exit(1);
+        }
     }
+}
+
+// an AppendEntries budget bigger than the network can send is refused rather than cut down quietly
+fn append_entries_bytes() -> Option<usize> {
+    let bytes = env_var("KV_APPEND_ENTRIES_BYTES")?;
+    if bytes > MAX_APPEND_ENTRIES_BYTES {
+        eprintln!("refusing to start: KV_APPEND_ENTRIES_BYTES is {}, but no more than {} fits in a message", bytes, MAX_APPEND_ENTRIES_BYTES);
+        std::process::exit(1);
+    }
+    Some(bytes)
+}
 
-    (this_id, nodes)
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().map(|s| s.parse().unwrap_or_else(|_| {
+        eprintln!("refusing to start: invalid value for {}: {}", name, s);
+        std::process::exit(1);
+    }))
 }
 
 pub fn network_name_to_num(name: &str) -> u32 {
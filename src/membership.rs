@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+
+use my_raft::config::Config;
+use serde::Deserialize;
+
+use crate::num_to_network_name;
+
+// Raft timing and batching settings a change would set, the rest stay as they are
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Tunables {
+    pub election_timeout_min: Option<u64>,
+    pub election_timeout_range: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub rpc_response_timeout: Option<u64>,
+    pub max_entries_in_append_entries: Option<u64>,
+    pub max_bytes_in_install_snapshot: Option<u64>,
+    pub next_index_decrease_rate: Option<u64>,
+}
+
+fn majority(n: usize) -> usize {
+    n / 2 + 1
+}
+
+// The ids of nodes named by a client, with a problem for each name that isn't a hex id like 0001.
+pub fn parse_node_ids(names: &[String], problems: &mut Vec<String>) -> Vec<u32> {
+    names
+        .iter()
+        .filter_map(|name| match u32::from_str_radix(name, 16) {
+            Ok(id) => Some(id),
+            Err(_) => {
+                problems.push(format!("invalid node id {}, expected hex like 0001", name));
+                None
+            }
+        })
+        .collect()
+}
+
+// Everything that would go wrong committing a change to the members, given which of them are reachable right now. The
+// change goes through a joint configuration, so both the old and new sets of members need a reachable quorum. Empty if
+// the change is safe to make.
+pub fn validate_membership(members: &[u32], reachable: &HashSet<u32>, add: &[u32], remove: &[u32]) -> Vec<String> {
+    let mut problems = vec![];
+
+    for id in add {
+        if members.contains(id) {
+            problems.push(format!("{} is already a member", num_to_network_name(*id)));
+        }
+        if remove.contains(id) {
+            problems.push(format!("{} is both added and removed", num_to_network_name(*id)));
+        }
+    }
+    for id in remove {
+        if !members.contains(id) {
+            problems.push(format!("{} is not a member", num_to_network_name(*id)));
+        }
+    }
+
+    let new_members: Vec<u32> = members.iter()
+        .filter(|id| !remove.contains(id))
+        .chain(add.iter().filter(|id| !members.contains(id)))
+        .copied()
+        .collect();
+    if new_members.is_empty() {
+        problems.push("the change would remove every member".to_string());
+        return problems;
+    }
+
+    let reachable_old = members.iter().filter(|id| reachable.contains(id)).count();
+    if reachable_old < majority(members.len()) {
+        problems.push(format!("only {} of {} current members are reachable, a quorum of {} is needed to commit the change",
+                              reachable_old, members.len(), majority(members.len())));
+    }
+
+    let reachable_new = new_members.iter().filter(|id| reachable.contains(id)).count();
+    if reachable_new < majority(new_members.len()) {
+        problems.push(format!("only {} of {} members after the change would be reachable, a quorum of {} is needed",
+                              reachable_new, new_members.len(), majority(new_members.len())));
+    }
+
+    problems
+}
+
+// Everything wrong with the timing and batching settings the config would have after the tunables change.
+pub fn validate_tunables(config: &Config, changes: &Tunables) -> Vec<String> {
+    let election_timeout_min = changes.election_timeout_min.unwrap_or(config.election_timeout_min as u64);
+    let election_timeout_range = changes.election_timeout_range.unwrap_or(config.election_timeout_range as u64);
+    let heartbeat_timeout = changes.heartbeat_timeout.unwrap_or(config.heartbeat_timeout as u64);
+    let rpc_response_timeout = changes.rpc_response_timeout.unwrap_or(config.rpc_response_timeout as u64);
+    let max_entries = changes.max_entries_in_append_entries.unwrap_or(config.max_entries_in_append_entries as u64);
+    let max_bytes = changes.max_bytes_in_install_snapshot.unwrap_or(config.max_bytes_in_install_snapshot as u64);
+    let decrease_rate = changes.next_index_decrease_rate.unwrap_or(config.next_index_decrease_rate as u64);
+
+    let mut problems = vec![];
+    if heartbeat_timeout >= election_timeout_min {
+        problems.push(format!("heartbeat timeout {} must be shorter than the minimum election timeout {}, or followers will start elections against a healthy leader",
+                              heartbeat_timeout, election_timeout_min));
+    }
+    if election_timeout_range == 0 {
+        problems.push("election timeout range must be above 0, or nodes time out together and keep splitting votes".to_string());
+    }
+    if rpc_response_timeout >= election_timeout_min {
+        problems.push(format!("RPC response timeout {} must be shorter than the minimum election timeout {}", rpc_response_timeout, election_timeout_min));
+    }
+    if max_entries == 0 {
+        problems.push("max entries in append entries must be at least 1, or the log can never be replicated".to_string());
+    }
+    if max_bytes == 0 {
+        problems.push("max bytes in install snapshot must be at least 1, or snapshots can never be sent".to_string());
+    }
+    if decrease_rate == 0 {
+        problems.push("next index decrease rate must be at least 1, or lagging followers never catch up".to_string());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use my_raft::config::Config;
+
+    use crate::membership::{Tunables, parse_node_ids, validate_membership, validate_tunables};
+
+    #[test]
+    fn membership_quorums() {
+        let members = [1, 2, 3, 4, 5];
+        let all: HashSet<u32> = members.iter().copied().collect();
+        let three: HashSet<u32> = [1, 2, 3].iter().copied().collect();
+
+        assert!(validate_membership(&members, &all, &[6], &[1]).is_empty());
+        // fine with 3 of 5 up, but removing a reachable node leaves 2 of 4
+        assert!(validate_membership(&members, &three, &[], &[4]).is_empty());
+        assert_eq!(validate_membership(&members, &three, &[], &[3]).len(), 1);
+        // new nodes that haven't been heard from don't count
+        assert!(validate_membership(&[1], &[1, 2].iter().copied().collect(), &[2], &[]).is_empty());
+        assert_eq!(validate_membership(&[1], &[1].iter().copied().collect(), &[2, 3], &[]).len(), 1);
+
+        assert_eq!(validate_membership(&members, &all, &[1], &[9]).len(), 2);
+        assert!(!validate_membership(&members, &all, &[], &members).is_empty());
+    }
+
+    #[test]
+    fn node_ids() {
+        let mut problems = vec![];
+        assert_eq!(parse_node_ids(&["0001".to_string(), "zz".to_string(), "000A".to_string()], &mut problems), vec![1, 10]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("zz"));
+    }
+
+    #[test]
+    fn tunables() {
+        let config = Config {
+            election_timeout_min: 750,
+            election_timeout_range: 250,
+            heartbeat_timeout: 500,
+            rpc_response_timeout: 20,
+            max_entries_in_append_entries: 100,
+            max_bytes_in_install_snapshot: 100,
+            next_index_decrease_rate: 100,
+            snapshot_min_log_size: 0,
+            id: 0,
+            nodes: Default::default(),
+        };
+
+        assert!(validate_tunables(&config, &Tunables::default()).is_empty());
+        assert_eq!(validate_tunables(&config, &Tunables { heartbeat_timeout: Some(800), ..Tunables::default() }).len(), 1);
+        assert_eq!(validate_tunables(&config, &Tunables { election_timeout_min: Some(10), max_entries_in_append_entries: Some(0), ..Tunables::default() }).len(), 3);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::client::KvClient;
+
+// An in-memory store behind the KvClient trait, for unit testing applications without running a cluster. Clones share
+// the same keys, like clients of one cluster, and requests can be made to fail the way they do when a replica fails
+// them or no member can be reached.
+#[derive(Clone, Default)]
+pub struct MemoryClient {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    data: BTreeMap<String, String>,
+    // requests from any clone that fail before they start succeeding again
+    failures: Vec<io::ErrorKind>,
+    requests: u64,
+}
+
+impl MemoryClient {
+    pub fn new() -> MemoryClient {
+        MemoryClient::default()
+    }
+
+    // the next request fails with an error of the kind, after any already waiting, NotConnected standing in for
+    // every member being down and Other for a replica failing the request
+    pub fn fail_next(&self, kind: io::ErrorKind) {
+        self.inner.lock().unwrap().failures.push(kind);
+    }
+
+    pub fn contents(&self) -> BTreeMap<String, String> {
+        self.inner.lock().unwrap().data.clone()
+    }
+
+    // how many requests have been made, failed ones included
+    pub fn requests(&self) -> u64 {
+        self.inner.lock().unwrap().requests
+    }
+
+    fn request<T>(&self, op: impl FnOnce(&mut BTreeMap<String, String>) -> T) -> io::Result<T> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requests += 1;
+        if !inner.failures.is_empty() {
+            let kind = inner.failures.remove(0);
+            return Err(io::Error::new(kind, "failed by MemoryClient::fail_next"));
+        }
+        Ok(op(&mut inner.data))
+    }
+}
+
+impl KvClient for MemoryClient {
+    fn get(&mut self, key: &str) -> io::Result<String> {
+        self.request(|data| data.get(key).cloned().unwrap_or_default())
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.request(|data| {
+            data.insert(key.to_string(), value.to_string());
+        })
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.request(|data| {
+            data.remove(key);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::client::KvClient;
+    use crate::memory_client::MemoryClient;
+
+    // what an application's code would look like, written against the trait
+    fn rename(client: &mut impl KvClient, from: &str, to: &str) -> io::Result<()> {
+        let value = client.get(from)?;
+        client.put(to, &value)?;
+        client.delete(from)
+    }
+
+    #[test]
+    fn behaves_like_a_cluster() {
+        let mut client = MemoryClient::new();
+        let observer = client.clone();
+
+        assert_eq!(client.get("a").unwrap(), "");
+        client.put("a", "1").unwrap();
+        rename(&mut client, "a", "b").unwrap();
+        assert_eq!(observer.contents().into_iter().collect::<Vec<_>>(), vec![("b".to_string(), "1".to_string())]);
+        client.delete("missing").unwrap();
+
+        observer.fail_next(io::ErrorKind::NotConnected);
+        assert_eq!(rename(&mut client, "b", "c").unwrap_err().kind(), io::ErrorKind::NotConnected);
+        assert_eq!(client.get("b").unwrap(), "1");
+        assert_eq!(observer.requests(), 8);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+// values of 2^39 and up all go in the last bucket, over six days in microseconds
+const BUCKETS: usize = 40;
+
+// Counts values in power of two buckets: the first is for 0 and bucket i for 2^(i-1) up to but not including 2^i.
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram { buckets: vec![0; BUCKETS], count: 0, sum: 0, max: 0 }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        let bucket = ((64 - value.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    // the most the value at the percentile could be, going by its bucket
+    fn percentile(&self, p: u64) -> Option<u64> {
+        let rank = ((self.count * p + 99) / 100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some((bucket_end(bucket) - 1).min(self.max));
+            }
+        }
+        None
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let buckets: Vec<serde_json::Value> = self.buckets.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| json!({ "lt": bucket_end(bucket), "count": count }))
+            .collect();
+        json!({
+            "count": self.count,
+            "sum": self.sum,
+            "max": self.max,
+            "p50": self.percentile(50),
+            "p99": self.percentile(99),
+            "buckets": buckets,
+        })
+    }
+
+    // in the Prometheus text format, taking the values to be microseconds and giving them in seconds
+    pub fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        // the last bucket has no upper bound, so it's only counted in +Inf
+        let used = self.buckets.iter().rposition(|count| *count > 0).map_or(0, |bucket| bucket + 1).min(BUCKETS - 1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate().take(used) {
+            seen += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bucket_end(bucket) as f64 / 1e6, seen).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum as f64 / 1e6).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+fn bucket_end(bucket: usize) -> u64 {
+    1 << bucket
+}
+
+// What clients have seen since the node started or the metrics were last reset, for bracketing benchmark runs.
+pub struct Metrics {
+    since: Instant,
+    // received to applied, for commands given to the core here
+    commands_us: Histogram,
+    // received to answered
+    reads_us: Histogram,
+    // responses to clients by type
+    responses: BTreeMap<&'static str, u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics { since: Instant::now(), commands_us: Histogram::default(), reads_us: Histogram::default(), responses: BTreeMap::new() }
+    }
+}
+
+impl Metrics {
+    pub fn record_command(&mut self, latency: Duration) {
+        self.commands_us.record(latency.as_micros() as u64);
+    }
+
+    pub fn record_read(&mut self, latency: Duration) {
+        self.reads_us.record(latency.as_micros() as u64);
+    }
+
+    pub fn record_response(&mut self, kind: &'static str) {
+        *self.responses.entry(kind).or_default() += 1;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Metrics::default();
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "since_reset_ms": self.since.elapsed().as_millis() as u64,
+            "commands_us": self.commands_us.to_json(),
+            "reads_us": self.reads_us.to_json(),
+            "responses": self.responses,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::{Histogram, Metrics};
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.to_json()["p50"], serde_json::Value::Null);
+
+        for value in &[0, 1, 3, 3, 900, 1000, 1023, 1024] {
+            histogram.record(*value);
+        }
+        let json = histogram.to_json();
+        assert_eq!(json["count"], 8);
+        assert_eq!(json["max"], 1024);
+        assert_eq!(json["buckets"], serde_json::json!([
+            { "lt": 1, "count": 1 },
+            { "lt": 2, "count": 1 },
+            { "lt": 4, "count": 2 },
+            { "lt": 1024, "count": 3 },
+            { "lt": 2048, "count": 1 },
+        ]));
+        assert_eq!(json["p50"], 3);
+        assert_eq!(json["p99"], 1024);
+    }
+
+    #[test]
+    fn reset_clears() {
+        let mut metrics = Metrics::default();
+        metrics.record_command(Duration::from_millis(3));
+        metrics.record_response("ok");
+        assert_eq!(metrics.to_json()["responses"]["ok"], 1);
+
+        metrics.reset();
+        let json = metrics.to_json();
+        assert_eq!(json["commands_us"]["count"], 0);
+        assert!(json["responses"].as_object().unwrap().is_empty());
+    }
+}
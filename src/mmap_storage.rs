@@ -0,0 +1,512 @@
+use std::cell::OnceCell;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::MmapMut;
+use my_raft::bytes::{TryFromBytes, WriteBytes};
+use my_raft::state_machine::{RaftStateMachine, StateMachine};
+use my_raft::storage::log::{LogEntry, LogEntryType};
+use my_raft::storage::Storage;
+
+use crate::backup::{checksum, take_u32};
+use crate::cluster::ClusterId;
+use crate::snapshot_chunks::{Rejected, SnapshotChunks};
+use crate::snapshot_file::SnapshotBytes;
+use crate::snapshot_format::SnapshotEncoding;
+use crate::state_machine::clone_state_machine;
+use crate::storage::{DEFAULT_APPEND_ENTRIES_BYTES, parse_snapshot, SNAPSHOT_HEADER_LEN, SnapshotCodec, write_snapshot_body};
+use crate::storage_metrics::{LogGauges, StorageMetrics, StoredBytes};
+use crate::wal::Wal;
+
+const LOG_FILE: &str = "log.mmap";
+const MAGIC: [u8; 8] = *b"KVMMLOG1";
+// the magic, the index of the first entry, how many entries there are and how many slots
+const HEADER_LEN: usize = 20;
+// where an entry's bytes end in the data, their checksum and its flags
+const SLOT_LEN: usize = 16;
+const CONFIG_FLAG: u32 = 1;
+pub const DEFAULT_INDEX_SLOTS: u32 = 1 << 16;
+// the data is grown by at least this much at a time
+const MIN_GROWTH: u64 = 1 << 20;
+// entries are deserialized this many at a time, the first time any of them is asked for
+const CHUNK_ENTRIES: usize = 256;
+
+// A log file that's mapped rather than read in. It starts with a fixed size header and a slot for every entry it has
+// room for, saying where the entry ends in the data after the slots, so opening it reads the header and nothing else.
+// Appends go straight into the map and the header's count only moves once they've been flushed, so a crash loses what
+// wasn't synced rather than leaving a torn entry. It's rewritten with twice the slots when they run out, and without the
+// entries before the snapshot when it's compacted.
+struct MappedLog {
+    path: PathBuf,
+    file: File,
+    map: MmapMut,
+    first_index: u32,
+    // entries in the map, some of which may not have been synced yet
+    count: u32,
+    slots: u32,
+}
+
+impl MappedLog {
+    fn open(path: &Path, slots: u32) -> io::Result<MappedLog> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("mapped log {} {}", path.display(), what));
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return MappedLog::create(path, 1, slots, &[]),
+            Err(e) => return Err(e),
+        };
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        if map.len() < HEADER_LEN || map[..MAGIC.len()] != MAGIC {
+            return Err(invalid("has no header"));
+        }
+        let mut header = &map[MAGIC.len()..HEADER_LEN];
+        let first_index = take_u32(&mut header).ok_or_else(|| invalid("has no header"))?;
+        let count = take_u32(&mut header).ok_or_else(|| invalid("has no header"))?;
+        let slots = take_u32(&mut header).ok_or_else(|| invalid("has no header"))?;
+        let log = MappedLog { path: path.to_path_buf(), file, map, first_index, count, slots };
+        if count > slots || log.data_start() > log.map.len() || log.data_start() + log.data_len() > log.map.len() {
+            return Err(invalid("is corrupt"));
+        }
+        Ok(log)
+    }
+
+    // writes a new file with the entries, and their flags, and renames it over the old one
+    fn create(path: &Path, first_index: u32, slots: u32, entries: &[(&[u8], u32)]) -> io::Result<MappedLog> {
+        let tmp = path.with_extension("tmp");
+        let data_len: usize = entries.iter().map(|(bytes, _)| bytes.len()).sum();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp)?;
+        file.set_len((HEADER_LEN + slots as usize * SLOT_LEN + data_len) as u64 + MIN_GROWTH)?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let mut log = MappedLog { path: path.to_path_buf(), file, map, first_index, count: 0, slots };
+        log.map[..MAGIC.len()].copy_from_slice(&MAGIC);
+        for (bytes, flags) in entries {
+            log.write_entry(bytes, *flags);
+        }
+        log.write_header();
+        log.map.flush()?;
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(log)
+    }
+
+    fn data_start(&self) -> usize {
+        HEADER_LEN + self.slots as usize * SLOT_LEN
+    }
+
+    fn slot(&self, i: u32) -> (u64, u32, u32) {
+        let at = HEADER_LEN + i as usize * SLOT_LEN;
+        let slot = &self.map[at..at + SLOT_LEN];
+        let mut end = [0; 8];
+        end.copy_from_slice(&slot[..8]);
+        let mut rest = &slot[8..];
+        (u64::from_be_bytes(end), take_u32(&mut rest).unwrap(), take_u32(&mut rest).unwrap())
+    }
+
+    fn data_len(&self) -> usize {
+        match self.count {
+            0 => 0,
+            count => self.slot(count - 1).0 as usize,
+        }
+    }
+
+    fn entry_start(&self, i: u32) -> usize {
+        if i == 0 { 0 } else { self.slot(i - 1).0 as usize }
+    }
+
+    fn entry_len(&self, i: u32) -> usize {
+        self.slot(i).0 as usize - self.entry_start(i)
+    }
+
+    fn flags(&self, i: u32) -> u32 {
+        self.slot(i).2
+    }
+
+    // None if it's not what was written
+    fn entry(&self, i: u32) -> Option<&[u8]> {
+        let (end, sum, _) = self.slot(i);
+        let bytes = self.map.get(self.data_start() + self.entry_start(i)..self.data_start() + end as usize)?;
+        if checksum(bytes) == sum { Some(bytes) } else { None }
+    }
+
+    fn append(&mut self, bytes: &[u8], flags: u32) -> io::Result<()> {
+        if self.count == self.slots {
+            self.rewrite(self.first_index, 0, self.slots * 2)?;
+        }
+        let needed = self.data_start() + self.data_len() + bytes.len();
+        if needed > self.map.len() {
+            let grown = (needed as u64).max(2 * self.map.len() as u64).max(self.map.len() as u64 + MIN_GROWTH);
+            self.map.flush()?;
+            self.file.set_len(grown)?;
+            self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        }
+        self.write_entry(bytes, flags);
+        Ok(())
+    }
+
+    fn write_entry(&mut self, bytes: &[u8], flags: u32) {
+        let start = self.data_len();
+        let data_at = self.data_start() + start;
+        self.map[data_at..data_at + bytes.len()].copy_from_slice(bytes);
+        let at = HEADER_LEN + self.count as usize * SLOT_LEN;
+        self.map[at..at + 8].copy_from_slice(&((start + bytes.len()) as u64).to_be_bytes());
+        self.map[at + 8..at + 12].copy_from_slice(&checksum(bytes).to_be_bytes());
+        self.map[at + 12..at + 16].copy_from_slice(&flags.to_be_bytes());
+        self.count += 1;
+    }
+
+    fn write_header(&mut self) {
+        let fields = [self.first_index, self.count, self.slots];
+        for (i, n) in fields.iter().enumerate() {
+            let at = MAGIC.len() + 4 * i;
+            self.map[at..at + 4].copy_from_slice(&n.to_be_bytes());
+        }
+    }
+
+    // the entries and slots first, then the count that makes them part of the log
+    fn sync(&mut self) -> io::Result<()> {
+        self.map.flush()?;
+        self.write_header();
+        self.map.flush_range(0, HEADER_LEN)
+    }
+
+    fn truncate(&mut self, count: u32) -> io::Result<()> {
+        self.count = count;
+        self.write_header();
+        self.map.flush_range(0, HEADER_LEN)
+    }
+
+    // keeps the entries from skip on, starting at first_index
+    fn rewrite(&mut self, first_index: u32, skip: u32, slots: u32) -> io::Result<()> {
+        let entries: Vec<(&[u8], u32)> = (skip..self.count)
+            .map(|i| {
+                let start = self.data_start() + self.entry_start(i);
+                (&self.map[start..start + self.entry_len(i)], self.flags(i))
+            })
+            .collect();
+        let rewritten = MappedLog::create(&self.path, first_index, slots, &entries)?;
+        *self = rewritten;
+        Ok(())
+    }
+}
+
+// Storage whose log is a MappedLog, for nodes with logs long enough that reading them all in makes restarts slow. The
+// term, vote and snapshot are kept like the write-ahead log keeps them, in files of their own in the same directory.
+// Entries are deserialized a chunk at a time the first time the core asks for one of them, which is usually only the
+// last few after a restart.
+pub struct MmapStorage<S: StateMachine> {
+    cluster_id: ClusterId,
+    wal: Wal,
+    log: MappedLog,
+    chunks: Vec<OnceCell<Vec<LogEntry<S::Command>>>>,
+    append_entries_bytes: usize,
+    current_term: u32,
+    voted_for: Option<u32>,
+    snapshot_bytes: SnapshotBytes,
+    snapshot_codec: SnapshotCodec,
+    snapshot_encoding: SnapshotEncoding,
+    snapshot_last_index: u32,
+    snapshot_last_term: u32,
+    snapshot_chunks: SnapshotChunks,
+    init_state_machine: RaftStateMachine<S>,
+    metrics: StorageMetrics,
+}
+
+impl<S: StateMachine> MmapStorage<S> {
+    // storage as it was left in the directory, which it carries on writing to
+    pub fn open(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId, dir: &Path, slots: u32, metrics: StorageMetrics) -> io::Result<MmapStorage<S>> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("mapped storage in {} {}", dir.display(), what));
+
+        let (wal, recovered) = Wal::open(dir, u64::MAX, metrics.clone())?;
+        if !recovered.snapshot.is_empty() {
+            if recovered.snapshot.get(..SNAPSHOT_HEADER_LEN) != Some(&cluster_id.to_bytes()[..]) {
+                return Err(invalid("is from a different cluster".to_string()));
+            }
+            if let Err(e) = parse_snapshot::<S>(&recovered.snapshot) {
+                return Err(invalid(format!("has a snapshot that {}", e)));
+            }
+        }
+
+        // a crash between saving a snapshot and compacting the log leaves entries the snapshot has replaced
+        let mut log = MappedLog::open(&dir.join(LOG_FILE), slots)?;
+        let next_index = recovered.snapshot_last_index + 1;
+        if log.first_index > next_index {
+            return Err(invalid(format!("is missing entries from {}", next_index)));
+        }
+        if log.first_index < next_index {
+            let skip = (next_index - log.first_index).min(log.count);
+            log.rewrite(next_index, skip, log.slots)?;
+        }
+
+        let mut storage = MmapStorage {
+            cluster_id,
+            wal,
+            log,
+            chunks: vec![],
+            append_entries_bytes: DEFAULT_APPEND_ENTRIES_BYTES,
+            current_term: recovered.current_term,
+            voted_for: recovered.voted_for,
+            snapshot_bytes: recovered.snapshot,
+            snapshot_codec: SnapshotCodec::Uncompressed,
+            snapshot_encoding: SnapshotEncoding::default(),
+            snapshot_last_index: recovered.snapshot_last_index,
+            snapshot_last_term: recovered.snapshot_last_term,
+            snapshot_chunks: SnapshotChunks::default(),
+            init_state_machine,
+            metrics,
+        };
+        storage.reset_chunks();
+        Ok(storage)
+    }
+
+    pub fn set_append_entries_bytes(&mut self, bytes: usize) {
+        self.append_entries_bytes = bytes;
+    }
+
+    pub fn set_snapshot_codec(&mut self, codec: SnapshotCodec) {
+        self.snapshot_codec = codec;
+    }
+
+    pub fn set_snapshot_encoding(&mut self, encoding: SnapshotEncoding) {
+        self.snapshot_encoding = encoding;
+    }
+
+    fn reset_chunks(&mut self) {
+        let num_chunks = (self.log.count as usize + CHUNK_ENTRIES - 1) / CHUNK_ENTRIES;
+        self.chunks = (0..num_chunks).map(|_| OnceCell::new()).collect();
+    }
+
+    // the node can't carry on with a log it can't read any more than one it can't write
+    fn chunk(&self, chunk: usize) -> &[LogEntry<S::Command>] {
+        self.chunks[chunk].get_or_init(|| {
+            let end = (chunk + 1) * CHUNK_ENTRIES;
+            (chunk * CHUNK_ENTRIES..end.min(self.log.count as usize))
+                .map(|i| {
+                    let entry = self.log.entry(i as u32).and_then(LogEntry::try_from_slice);
+                    entry.unwrap_or_else(|| panic!("the mapped log's entry at {} is corrupt", self.log.first_index + i as u32))
+                })
+                .collect()
+        })
+    }
+
+    fn report_log(&self) {
+        self.metrics.record_log(LogGauges {
+            current_term: self.current_term,
+            last_index: self.log.first_index + self.log.count - 1,
+            entries: self.log.count as usize,
+        }, StoredBytes {
+            log: self.log.data_len() as u64,
+            snapshot: self.snapshot_bytes.len() as u64,
+        });
+    }
+
+    fn save_snapshot(&mut self, last_index: u32, last_term: u32, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) {
+        self.snapshot_bytes = write_mapped(self.wal.write_snapshot(last_index, last_term, write));
+        self.snapshot_last_index = last_index;
+        self.snapshot_last_term = last_term;
+    }
+}
+
+impl<S: StateMachine + Clone> Storage<S> for MmapStorage<S> {
+    fn add_log_entry(&mut self, entry: LogEntry<<S as StateMachine>::Command>) {
+        let mut bytes = vec![];
+        entry.write_bytes_with_writer(&mut bytes).unwrap();
+        let flags = if matches!(entry.entry_type, LogEntryType::Config(_)) { CONFIG_FLAG } else { 0 };
+        write_mapped(self.log.append(&bytes, flags));
+        self.metrics.record_append(bytes.len());
+        if (self.log.count as usize - 1) % CHUNK_ENTRIES == 0 {
+            self.chunks.push(OnceCell::new());
+        }
+        // a chunk that hasn't been read yet gets the entry from the map when it is
+        if let Some(chunk) = self.chunks.last_mut().and_then(OnceCell::get_mut) {
+            chunk.push(entry);
+        }
+    }
+
+    fn remove_log_entries_before(&mut self, index: usize) {
+        let compacted = (0..index as u32).map(|i| self.log.entry_len(i)).sum();
+        self.metrics.record_compaction(index, compacted);
+        let first_index = self.log.first_index + index as u32;
+        write_mapped(self.log.rewrite(first_index, index as u32, self.log.slots));
+        self.reset_chunks();
+    }
+
+    fn remove_log_entries_starting_at(&mut self, index: usize) {
+        write_mapped(self.log.truncate(index as u32));
+        self.chunks.truncate((index + CHUNK_ENTRIES - 1) / CHUNK_ENTRIES);
+        if let Some(chunk) = self.chunks.last_mut().and_then(OnceCell::get_mut) {
+            chunk.truncate(index - (index - 1) / CHUNK_ENTRIES * CHUNK_ENTRIES);
+        }
+    }
+
+    fn save_log(&mut self) {
+        write_mapped(self.log.sync());
+        self.report_log();
+    }
+
+    fn log_entry(&self, index: usize) -> Option<&LogEntry<<S as StateMachine>::Command>> {
+        if index >= self.log.count as usize {
+            return None;
+        }
+        self.chunk(index / CHUNK_ENTRIES).get(index % CHUNK_ENTRIES)
+    }
+
+    // cut off at the byte budget like RamStorage's, and at the end of the chunk the first entry is in
+    fn log_entries(&self, start_index: usize) -> &[LogEntry<<S as StateMachine>::Command>] {
+        if start_index >= self.log.count as usize {
+            return &[];
+        }
+        let entries = &self.chunk(start_index / CHUNK_ENTRIES)[start_index % CHUNK_ENTRIES..];
+        let mut bytes = 0;
+        let fits = (start_index..start_index + entries.len())
+            .take_while(|i| {
+                bytes += self.log.entry_len(*i as u32);
+                bytes <= self.append_entries_bytes
+            })
+            .count();
+        &entries[..fits.max(1)]
+    }
+
+    // the flags in the slots say which are configs without deserializing anything
+    fn get_index_of_last_config_in_log(&self) -> Option<usize> {
+        (0..self.log.count).rev().find(|i| self.log.flags(*i) & CONFIG_FLAG != 0).map(|i| i as usize)
+    }
+
+    fn num_log_entries(&self) -> usize {
+        self.log.count as usize
+    }
+
+    fn set_snapshot(&mut self, last_index: u32, last_term: u32, snapshot: &RaftStateMachine<S>) {
+        let (header, codec, encoding) = (self.cluster_id.to_bytes(), self.snapshot_codec, self.snapshot_encoding);
+        self.save_snapshot(last_index, last_term, |out| {
+            out.write_all(&header)?;
+            write_snapshot_body(out, snapshot, codec, encoding)
+        });
+        self.metrics.record_snapshot(self.snapshot_bytes.len());
+    }
+
+    fn snapshot(&self) -> RaftStateMachine<S> {
+        parse_snapshot(&self.snapshot_bytes).unwrap_or_else(|_| clone_state_machine(&self.init_state_machine))
+    }
+
+    fn snapshot_last_index(&self) -> u32 {
+        self.snapshot_last_index
+    }
+
+    fn snapshot_last_term(&self) -> u32 {
+        self.snapshot_last_term
+    }
+
+    fn add_new_snapshot_chunk(&mut self, offset: u32, data: &[u8]) {
+        match self.snapshot_chunks.add(offset, data) {
+            Ok(()) | Err(Rejected::Duplicate) => {}
+            Err(rejected) => eprintln!("ignoring snapshot chunk of {} bytes at {}: {:?}", data.len(), offset, rejected),
+        }
+    }
+
+    fn try_use_chunks_as_new_snapshot(&mut self, last_index: u32, last_term: u32) -> Option<RaftStateMachine<S>> {
+        let bytes = self.snapshot_chunks.complete(None)?;
+        if bytes.get(..SNAPSHOT_HEADER_LEN)? != self.cluster_id.to_bytes() {
+            eprintln!("refusing to install snapshot from a different cluster");
+            return None;
+        }
+        let snapshot = match parse_snapshot::<S>(bytes) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("refusing to install snapshot through {}: it {}", last_index, e);
+                self.snapshot_chunks.take();
+                return None;
+            }
+        };
+        let chunks = self.snapshot_chunks.take();
+        self.save_snapshot(last_index, last_term, |out| out.write_all(&chunks));
+        Some(snapshot)
+    }
+
+    fn snapshot_chunk(&self, offset: u32, amt: u32) -> &[u8] {
+        &self.snapshot_bytes[offset as usize..(offset + amt) as usize]
+    }
+
+    fn total_snapshot_bytes(&self) -> u32 {
+        self.snapshot_bytes.len() as u32
+    }
+
+    fn set_voted_for(&mut self, voted_for: Option<u32>) {
+        if voted_for != self.voted_for {
+            write_mapped(self.wal.save_hard_state(self.current_term, voted_for));
+        }
+        self.voted_for = voted_for;
+    }
+
+    fn voted_for(&self) -> Option<u32> {
+        self.voted_for
+    }
+
+    fn set_current_term(&mut self, current_term: u32) {
+        if current_term != self.current_term {
+            write_mapped(self.wal.save_hard_state(current_term, self.voted_for));
+        }
+        self.current_term = current_term;
+        self.report_log();
+    }
+
+    fn current_term(&self) -> u32 {
+        self.current_term
+    }
+}
+
+// like the write-ahead log, the node stops rather than carry on without what it promised being durable
+fn write_mapped<T>(result: io::Result<T>) -> T {
+    match result {
+        Ok(written) => written,
+        Err(e) => panic!("failed to write mapped storage: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mmap_storage::{CONFIG_FLAG, MappedLog};
+
+    #[test]
+    fn mapped_log() {
+        let dir = std::env::temp_dir().join(format!("mmap_storage_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.mmap");
+        let entries: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; i as usize + 1]).collect();
+
+        // more entries than slots, so it's rewritten with more of them along the way
+        let mut log = MappedLog::open(&path, 4).unwrap();
+        for (i, entry) in entries.iter().enumerate() {
+            log.append(entry, if i == 6 { CONFIG_FLAG } else { 0 }).unwrap();
+        }
+        log.sync().unwrap();
+        // not synced, so not there after a restart
+        log.append(b"lost", 0).unwrap();
+        drop(log);
+
+        let mut log = MappedLog::open(&path, 4).unwrap();
+        assert_eq!((log.first_index, log.count, log.slots), (1, 10, 16));
+        assert_eq!(log.entry(9), Some(&entries[9][..]));
+        assert_eq!(log.flags(6), CONFIG_FLAG);
+
+        log.truncate(8).unwrap();
+        log.rewrite(4, 3, log.slots).unwrap();
+        drop(log);
+
+        let mut log = MappedLog::open(&path, 4).unwrap();
+        assert_eq!((log.first_index, log.count), (4, 5));
+        assert_eq!((0..5).map(|i| log.entry(i).unwrap().to_vec()).collect::<Vec<_>>(), entries[3..8].to_vec());
+        assert_eq!(log.flags(3), CONFIG_FLAG);
+
+        // a flipped bit in an entry is caught when it's read
+        let start = log.data_start() + log.entry_start(2);
+        log.map[start] ^= 1;
+        assert_eq!(log.entry(2), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
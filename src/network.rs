@@ -1,27 +1,132 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
 use std::io::Write;
-use std::time::Duration;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use my_raft::bytes::WriteBytes;
-use my_raft::config::Config;
+use my_raft::config::{Config, NodeAddress};
 use my_raft::network::{ClientCommandRequest, MessageEvent, NetworkInterface};
 use my_raft::state_machine::StateMachine;
+use my_project6::protocol::{NO_LEADER, Request, Response, ScheduledRequest};
 use nix::errno::Errno;
 use nix::sys::socket;
 use nix::sys::socket::{AddressFamily, MsgFlags, SockAddr, SockFlag, SockType};
 use nix::sys::socket::sockopt::ReceiveTimeout;
 use nix::sys::time::{TimeVal, TimeValLike};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{hash, network_name_to_num, num_to_network_name};
-use crate::state_machine::{KvStateMachine, SetValueCommand};
+use crate::{alloc_stats, hash, network_name_to_num, num_to_network_name};
+use crate::admin::{AdminCommand, AdminRequest, AdminServer};
+use crate::alloc_stats::Subsystem;
+use crate::auth;
+use crate::authz::{Access, AllowAll, Authorizer, Operation};
+use crate::backup::{Backups, Upload};
+use crate::clients::{ClientLimit, ClientTable};
+use crate::cluster::{ClusterId, PROTOCOL_VERSION};
+use crate::commit_tuner::CommitTuner;
+use crate::failure_detector::PhiAccrualDetector;
+use crate::hot_keys::HotKeys;
+use crate::http;
+use crate::http::{HttpRequest, HttpServer};
+use crate::json_path;
+use crate::kms;
+use crate::kms::Keyring;
+use crate::latency::LatencyTable;
+use crate::load::LoadSignals;
+use crate::membership;
+use crate::membership::Tunables;
+use crate::metrics::Metrics;
+use crate::peer_sender::{PeerQueue, PeerSender, Priority};
+use crate::prometheus::{Counters, Gauge};
+use crate::redirects::RecentRedirects;
+use crate::response_order::ResponseOrder;
+use crate::shutdown::Shutdown;
+use crate::snapshot_pause::SnapshotPause;
+use crate::snapshot_pull::SnapshotExchange;
+use crate::state_machine::{AppendCommand, ApplyStats, BatchSetCommand, CallCommand, CommandResult, DeleteIfCommand, DeleteValueCommand, Expected, ExpireKeysCommand, IncrCommand, JsonSetCommand, KvCommand, KvOp, KvStateMachine, MoveCommand, PurgeTombstonesCommand, RegisterCommand, RunScheduleCommand, ScheduleCommand, ScriptCommand, SetValueCommand, SetWithTtlCommand, UnscheduleCommand};
+use crate::state_transfer::{CatchUps, StateTransferPolicy, Transfer};
+use crate::storage::{entry_size, MAX_APPEND_ENTRIES_BYTES};
+use crate::storage_metrics;
+use crate::storage_metrics::{DiskUsage, StorageMetrics};
+use crate::systemd::Notifier;
+use crate::tcp_transport::TcpTransport;
+use crate::udp_transport::UdpTransport;
+use crate::watchdog::{Stage, Watchdog};
 
 const PACKET_SIZE: usize = 65527;
+// raft messages bigger than this are AppendEntries with entries or snapshot chunks, anything smaller is a heartbeat,
+// vote, or response and skips ahead of them when the socket backs up
+const BULK_MESSAGE_SIZE: usize = 256;
+// a command has to fit in an AppendEntries on its own, with room for its term and the AppendEntries header
+const MAX_COMMAND_BYTES: usize = MAX_APPEND_ENTRIES_BYTES - 64;
+// version 0.0.4 of the Prometheus text format
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// bulk loads are split into batch entries of about this many bytes, so they still fit in an AppendEntries message, and
+// multi puts have to fit in one
+const BULK_LOAD_BATCH_BYTES: usize = 2048;
+// separates the client's MID from the part number in the MIDs of all but the last batch of a bulk load
+const BULK_LOAD_PART_SEPARATOR: char = '\u{1f}';
+const MAX_RECENT_EVENTS: usize = 100;
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// how long the receive loop may block before checking for HTTP requests
+const HTTP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// HTTP clients get ids from here up, well clear of the four hex digit names clients have on the socket
+const HTTP_CLIENT_IDS_START: u32 = 0x8000_0000;
+const HTTP_KV_PATH: &str = "/kv/";
+// reads waiting for the state machine to reach their min_index fail after this long
+const MIN_INDEX_WAIT: Duration = Duration::from_secs(1);
+// how long a wait for a write to be applied lasts when the client doesn't say, and at most
+const DEFAULT_APPLY_WAIT: Duration = Duration::from_secs(5);
+const MAX_APPLY_WAIT: Duration = Duration::from_secs(30);
+// a command not applied after this long might be a retry the core dropped as a duplicate, so its result is looked up
+const REPLAY_AFTER: Duration = Duration::from_millis(100);
+// and the lookup gives up after this long
+const REPLAY_WAIT: Duration = Duration::from_secs(5);
+// commands answered recently are remembered so a lookup doesn't answer one a second time
+const MAX_ANSWERED: usize = 1024;
+// how long a batch of reads waits on the core to confirm the round ahead of it before they're failed
+const READ_ROUND_TIMEOUT: Duration = Duration::from_secs(2);
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// a step down is acknowledged after this long even if commands are still in flight
+const STEPDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a node shutting down waits for storage to write what it has left after telling its peers it's leaving
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+// a leadership transfer is given up on, and the leader takes commands again, if the target hasn't won by then
+const LEADERSHIP_TRANSFER_TIMEOUT: Duration = Duration::from_secs(15);
+// a follower pulls the rest of a snapshot once the leader hasn't pushed any of it for this long
+const SNAPSHOT_PULL_STALL: Duration = Duration::from_millis(500);
+// how often a follower asks for missing parts of a snapshot, and how many it asks for at a time
+const SNAPSHOT_PULL_INTERVAL: Duration = Duration::from_millis(200);
+const MAX_SNAPSHOT_PULLS: usize = 4;
+// prefix and range reads return at most this many pairs, and this many when the client doesn't say
+const MAX_SCAN_LIMIT: usize = 1000;
+const DEFAULT_SCAN_LIMIT: usize = 100;
+// the leader proposes deleting expired keys at most this often
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+// and runs schedules that are due at most this often, and only the soonest few at a time
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_SCHEDULES_PER_PROPOSAL: usize = 16;
+// MIDs of the commands and reads the leader makes itself start with this, they have no client to answer
+const INTERNAL_MID_PREFIX: &str = "\u{1e}";
+const EXPIRE_MID_PREFIX: &str = "\u{1e}expire-";
+
+#[cfg(feature = "dashboard")]
+const DASHBOARD: &[u8] = include_bytes!("dashboard.html");
+#[cfg(not(feature = "dashboard"))]
+const DASHBOARD: &[u8] = b"";
 
 #[derive(Serialize, Deserialize, Debug)]
 struct JsonMessage<'a> {
     src: &'a str,
     dst: &'a str,
     leader: &'a str,
+    // on messages to clients, so they can steer away from a node before it starts failing requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    load: Option<&'a str>,
     #[serde(flatten)]
     data: JsonMessageType<'a>,
 }
@@ -29,122 +134,2729 @@ struct JsonMessage<'a> {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "lowercase")]
 enum JsonMessageType<'a> {
-    Redirect { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str },
-    Fail { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str },
-    Get { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, key: &'a str },
-    #[serde(rename(deserialize = "ok", serialize = "ok"))]
-    Ok { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(skip_serializing_if = "Option::is_none")] value: Option<&'a str> },
-    Put { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, key: &'a str, value: &'a str },
     #[serde(rename(serialize = "raft"))]
     RaftRef { data: &'a [u8] },
     #[serde(rename(deserialize = "raft"))]
     RaftOwned { data: Vec<u8> },
+    // last_index is the sender's last log index, for how far behind the leader it is when it reconnects
+    Hello { version: u32, cluster: &'a str, #[serde(default)] last_index: u32 },
+    Stats { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] stats: Option<serde_json::Value> },
+    // the load level and what went into it
+    Health { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] health: Option<serde_json::Value> },
+    // latency histograms and response counts since the last reset, which a reset answers with before clearing them
+    #[serde(rename(deserialize = "metrics_dump", serialize = "metrics_dump"))]
+    MetricsDump { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] metrics: Option<serde_json::Value> },
+    #[serde(rename(deserialize = "metrics_reset", serialize = "metrics_reset"))]
+    MetricsReset { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] metrics: Option<serde_json::Value> },
+    // what the log and snapshots have cost on disk since the last reset, see StorageMetrics::growth
+    #[serde(rename(deserialize = "log_growth", serialize = "log_growth"))]
+    LogGrowth { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] growth: Option<serde_json::Value> },
+    #[serde(rename(deserialize = "cluster_status", serialize = "cluster_status"))]
+    ClusterStatus { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] status: Option<serde_json::Value> },
+    Members { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] members: Option<serde_json::Value> },
+    Hotkeys { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing)] limit: Option<usize>, #[serde(default, skip_serializing_if = "Option::is_none")] hotkeys: Option<serde_json::Value> },
+    // checks a membership or tunables change without making it
+    #[serde(rename(deserialize = "validate_config", serialize = "validate_config"))]
+    ValidateConfig {
+        #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str,
+        #[serde(default, skip_serializing)] add: Vec<String>,
+        #[serde(default, skip_serializing)] remove: Vec<String>,
+        #[serde(default, skip_serializing)] tunables: Tunables,
+        #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value>,
+    },
+    // names the newest backed up snapshot so it can be restored with KV_RESTORE_TO_TAG
+    #[serde(rename(deserialize = "tag_snapshot", serialize = "tag_snapshot"))]
+    TagSnapshot { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(skip_serializing)] tag: String, #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value> },
+    #[serde(rename(deserialize = "snapshot_tags", serialize = "snapshot_tags"))]
+    SnapshotTags { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] tags: Option<serde_json::Value> },
+    Clients { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] clients: Option<serde_json::Value> },
+    // sets the rate limit for one client, or the default for all of them without client, no rate means no limit
+    #[serde(rename(deserialize = "client_limit", serialize = "client_limit"))]
+    SetClientLimit { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default)] client: Option<&'a str>, #[serde(default)] rate: Option<f64>, #[serde(default)] burst: Option<f64> },
+    // stops the leader taking new commands and, once the ones already in flight are applied, hands leadership to the peer
+    // furthest along, answering once it's moved or the transfer gave up
+    Stepdown { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value> },
+    // lets the leader take writes again after the apply breaker tripped
+    #[serde(rename(deserialize = "resume_writes", serialize = "resume_writes"))]
+    ResumeWrites { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value> },
+    // stops and starts this node taking snapshots and compacting its log, for looking into a bug with the log frozen
+    #[serde(rename(deserialize = "pause_snapshots", serialize = "pause_snapshots"))]
+    PauseSnapshots { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value> },
+    #[serde(rename(deserialize = "resume_snapshots", serialize = "resume_snapshots"))]
+    ResumeSnapshots { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing_if = "Option::is_none")] result: Option<serde_json::Value> },
+    #[serde(rename(deserialize = "recommend_leader", serialize = "recommend_leader"))]
+    RecommendLeader { #[serde(rename(deserialize = "MID", serialize = "MID"))] mid: &'a str, #[serde(default, skip_serializing)] execute: bool, #[serde(default, skip_serializing_if = "Option::is_none")] recommendation: Option<serde_json::Value> },
+    // round trip time probes between nodes, pings carry the sender's median round trip times to its peers
+    Ping { sent_us: u64, rtts: HashMap<String, f64>, #[serde(default)] last_index: u32 },
+    Pong { sent_us: u64 },
+    // sent to every peer by a node shutting down, a leader leaving has its followers start an election right away
+    Leaving {},
+    // sent by the leader to the node it's handing leadership to, once it's caught up, to start an election right away
+    #[serde(rename(deserialize = "timeout_now", serialize = "timeout_now"))]
+    TimeoutNow {},
+    // a follower asking for part of a snapshot it's installing, a last_index of 0 is whichever the node has
+    #[serde(rename(deserialize = "snapshot_pull", serialize = "snapshot_pull"))]
+    SnapshotPull { last_index: u32, offset: u32, len: u32 },
+    #[serde(rename(deserialize = "snapshot_part", serialize = "snapshot_part"))]
+    SnapshotPart { last_index: u32, total: u32, offset: u32, data: Vec<u8> },
+    // what clients send and are answered with, tried once the type isn't one of the above
+    #[serde(untagged)]
+    Request(Request),
+    #[serde(untagged)]
+    Response(Response),
+}
+
+impl JsonMessageType<'_> {
+    fn mid(&self) -> Option<&str> {
+        match self {
+            JsonMessageType::Request(request) => Some(request.mid()),
+            JsonMessageType::Stats { mid, .. } | JsonMessageType::ClusterStatus { mid, .. } | JsonMessageType::Members { mid, .. }
+            | JsonMessageType::Health { mid, .. } | JsonMessageType::MetricsDump { mid, .. } | JsonMessageType::MetricsReset { mid, .. }
+            | JsonMessageType::LogGrowth { mid, .. }
+            | JsonMessageType::Hotkeys { mid, .. } | JsonMessageType::Clients { mid, .. } | JsonMessageType::SetClientLimit { mid, .. }
+            | JsonMessageType::RecommendLeader { mid, .. } | JsonMessageType::ValidateConfig { mid, .. }
+            | JsonMessageType::TagSnapshot { mid, .. } | JsonMessageType::SnapshotTags { mid, .. }
+            | JsonMessageType::Stepdown { mid, .. } | JsonMessageType::ResumeWrites { mid, .. }
+            | JsonMessageType::PauseSnapshots { mid, .. } | JsonMessageType::ResumeSnapshots { mid, .. } => Some(*mid),
+            _ => None,
+        }
+    }
+
+    // what a client operation reads and writes, for the authorizer, empty for anything else
+    fn accessed(&self) -> Vec<(Access, Option<&str>)> {
+        let request = match self {
+            JsonMessageType::Request(request) => request,
+            _ => return vec![],
+        };
+        match request {
+            Request::Get { key, .. } | Request::Exists { key, .. } | Request::Type { key, .. } | Request::Strlen { key, .. }
+            | Request::JsonGet { key, .. } | Request::Prefix { prefix: key, .. } | Request::Range { start: key, .. }
+            | Request::Tombstones { prefix: key, .. } =>
+                vec![(Access::Read, Some(key.as_str()))],
+            Request::Put { key, .. } | Request::GetSet { key, .. } | Request::GetDel { key, .. } | Request::Eval { key, .. } | Request::Call { key, .. }
+            | Request::Delete { key, .. } | Request::DeleteIf { key, .. } | Request::PutTtl { key, .. }
+            | Request::Append { key, .. } | Request::Incr { key, .. } | Request::JsonSet { key, .. } =>
+                vec![(Access::Write, Some(key.as_str()))],
+            Request::BulkLoad { pairs, .. } | Request::MultiPut { pairs, .. } =>
+                pairs.iter().map(|(key, _)| (Access::Write, Some(key.as_str()))).collect(),
+            Request::Rename { from, to, .. } => vec![(Access::Write, Some(from.as_str())), (Access::Write, Some(to.as_str()))],
+            Request::Copy { from, to, .. } => vec![(Access::Read, Some(from.as_str())), (Access::Write, Some(to.as_str()))],
+            Request::Schedule { command, .. } => vec![(Access::Write, Some(command.key()))],
+            Request::Defrag { .. } | Request::Register { .. } | Request::Unschedule { .. } => vec![(Access::Write, None)],
+            Request::Wait { .. } => vec![],
+        }
+    }
+
+    // the type as it is on the wire, for counting messages by
+    fn kind(&self) -> &'static str {
+        match self {
+            JsonMessageType::RaftRef { .. } | JsonMessageType::RaftOwned { .. } => "raft",
+            JsonMessageType::Hello { .. } => "hello",
+            JsonMessageType::Stats { .. } => "stats",
+            JsonMessageType::Health { .. } => "health",
+            JsonMessageType::MetricsDump { .. } => "metrics_dump",
+            JsonMessageType::MetricsReset { .. } => "metrics_reset",
+            JsonMessageType::LogGrowth { .. } => "log_growth",
+            JsonMessageType::ClusterStatus { .. } => "cluster_status",
+            JsonMessageType::Members { .. } => "members",
+            JsonMessageType::Hotkeys { .. } => "hotkeys",
+            JsonMessageType::ValidateConfig { .. } => "validate_config",
+            JsonMessageType::TagSnapshot { .. } => "tag_snapshot",
+            JsonMessageType::SnapshotTags { .. } => "snapshot_tags",
+            JsonMessageType::Clients { .. } => "clients",
+            JsonMessageType::SetClientLimit { .. } => "client_limit",
+            JsonMessageType::Stepdown { .. } => "stepdown",
+            JsonMessageType::ResumeWrites { .. } => "resume_writes",
+            JsonMessageType::PauseSnapshots { .. } => "pause_snapshots",
+            JsonMessageType::ResumeSnapshots { .. } => "resume_snapshots",
+            JsonMessageType::RecommendLeader { .. } => "recommend_leader",
+            JsonMessageType::Ping { .. } => "ping",
+            JsonMessageType::Pong { .. } => "pong",
+            JsonMessageType::Leaving { .. } => "leaving",
+            JsonMessageType::TimeoutNow { .. } => "timeout_now",
+            JsonMessageType::SnapshotPull { .. } => "snapshot_pull",
+            JsonMessageType::SnapshotPart { .. } => "snapshot_part",
+            JsonMessageType::Request(request) => request.kind(),
+            JsonMessageType::Response(response) => response.kind(),
+        }
+    }
+}
+
+// what a scheduled write is proposed as when it's due
+fn scheduled_op(command: ScheduledRequest) -> KvOp {
+    match command {
+        ScheduledRequest::Put { key, value } => KvOp::Set(SetValueCommand { key, value }),
+        // scheduled deletes aren't stamped, so they leave no tombstone
+        ScheduledRequest::Delete { key } => KvOp::Delete(DeleteValueCommand { key, deleted_at_ms: None }),
+        ScheduledRequest::Eval { key, script } => KvOp::Script(ScriptCommand { key, script }),
+        ScheduledRequest::Call { name, key, arg } => KvOp::Call(CallCommand { name, key, arg }),
+        ScheduledRequest::Append { key, value } => KvOp::Append(AppendCommand { key, value }),
+        ScheduledRequest::Incr { key, by } => KvOp::Incr(IncrCommand { key, by: by.unwrap_or(1) }),
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum PeerStatus {
+    Verified,
+    Rejected,
+}
+
+#[derive(Clone, Eq, PartialEq)]
+enum ReadKind {
+    Get,
+    Exists,
+    Type,
+    Strlen,
+    // the key is the prefix
+    Prefix { limit: usize },
+    // the key is the start of the range, and end isn't part of it
+    Range { end: Option<String>, limit: usize },
+    // the key is the prefix
+    Tombstones { since_ms: u64, limit: usize },
+    // the key is the MID of a write, and the read waits up to this long for it to be applied
+    Applied(Duration),
+    // like Applied, but for a command that may have been dropped as a duplicate, answered with its original response
+    Replay,
+    // the leader's own, which isn't answered
+    Sync,
+    // the part of the key's JSON document at the path, as JSON
+    JsonGet { path: String },
 }
 
 pub struct ReadValueRequest {
+    kind: ReadKind,
     key: String,
     mid: String,
     client_id: u32,
+    min_index: u32,
+    received: Instant,
+    // the confirmation round the core is running for this read and any batched behind it
+    round: u64,
+}
+
+impl ReadValueRequest {
+    // None if the value is encrypted and can't be decrypted
+    fn response_value<'a>(&self, state_machine: &'a KvStateMachine, keyring: Option<&Keyring>) -> Option<Cow<'a, str>> {
+        let now_ms = now_ms();
+        let value = state_machine.get(&self.key, now_ms);
+        Some(match &self.kind {
+            ReadKind::Get => match value {
+                Some(value) => decrypt(keyring, value)?,
+                None => Cow::Borrowed(""),
+            },
+            ReadKind::Exists => Cow::Borrowed(if value.is_some() { "1" } else { "0" }),
+            ReadKind::Type => Cow::Borrowed(if value.is_some() { "string" } else { "none" }),
+            ReadKind::Strlen => match value {
+                Some(value) => Cow::Owned(decrypt(keyring, value)?.len().to_string()),
+                None => Cow::Borrowed("0"),
+            },
+            ReadKind::Prefix { limit } => {
+                let pairs = state_machine.range(&self.key, None, now_ms).take_while(|(key, _)| key.starts_with(&self.key)).take(*limit);
+                Cow::Owned(pairs_json(pairs, keyring)?)
+            }
+            ReadKind::Range { end, limit } =>
+                Cow::Owned(pairs_json(state_machine.range(&self.key, end.as_deref(), now_ms).take(*limit), keyring)?),
+            ReadKind::Tombstones { since_ms, limit } => {
+                let tombstones: Vec<(&String, u64, u32)> = state_machine.tombstones(&self.key, *since_ms).take(*limit)
+                    .map(|(key, tombstone)| (key, tombstone.deleted_at_ms, tombstone.index))
+                    .collect();
+                Cow::Owned(serde_json::to_string(&tombstones).ok()?)
+            }
+            ReadKind::Sync => Cow::Borrowed(""),
+            ReadKind::JsonGet { path } => match value {
+                Some(value) => {
+                    let doc: serde_json::Value = serde_json::from_str(&decrypt(keyring, value)?).ok()?;
+                    match json_path::get(&doc, &json_path::parse(path)?) {
+                        Some(part) => Cow::Owned(part.to_string()),
+                        None => Cow::Borrowed(""),
+                    }
+                }
+                None => Cow::Borrowed(""),
+            },
+            ReadKind::Applied(_) | ReadKind::Replay => Cow::Borrowed(match state_machine.result(&self.key)? {
+                CommandResult::Failed => "failed",
+                _ => "ok",
+            }),
+        })
+    }
+
+    fn is_ready(&self, state_machine: &KvStateMachine) -> bool {
+        state_machine.applied_index() >= self.min_index
+            && (!matches!(self.kind, ReadKind::Applied(_) | ReadKind::Replay) || state_machine.applied_at(&self.key).is_some())
+    }
+
+    fn max_wait(&self) -> Duration {
+        match self.kind {
+            ReadKind::Applied(timeout) => timeout,
+            ReadKind::Replay => REPLAY_WAIT,
+            _ => MIN_INDEX_WAIT,
+        }
+    }
+}
+
+fn pairs_json<'a>(pairs: impl Iterator<Item = (&'a String, &'a String)>, keyring: Option<&Keyring>) -> Option<String> {
+    let pairs = pairs.map(|(key, value)| Some((key, decrypt(keyring, value)?))).collect::<Option<Vec<(&String, Cow<str>)>>>()?;
+    serde_json::to_string(&pairs).ok()
 }
 
+fn decrypt<'a>(keyring: Option<&Keyring>, value: &'a str) -> Option<Cow<'a, str>> {
+    match keyring {
+        Some(keyring) => keyring.decrypt(value),
+        None => kms::decrypt_without_keyring(value),
+    }
+}
+
+struct StepDown {
+    // the client and MID of the stepdown message, until leadership is being handed over
+    waiting: Option<(u32, String)>,
+    started: Instant,
+}
+
+// who's answered once a leadership transfer finishes
+enum TransferWaiting {
+    Admin(AdminRequest),
+    // the client and MID of a stepdown message
+    Stepdown(u32, String),
+    // the client and MID of a recommend_leader message with execute, and what was recommended
+    RecommendLeader(u32, String, serde_json::Value),
+}
+
+struct LeadershipTransfer {
+    target: u32,
+    started: Instant,
+    // whether the target's been told to start an election
+    sent: bool,
+    waiting: TransferWaiting,
+}
+
+struct ShuttingDown {
+    started: Instant,
+    // when the peers were told
+    left: Option<Instant>,
+}
+
+pub struct NetworkConfig {
+    // how long after the last read quorum an overloaded leader may answer reads from its applied state, zero disables
+    pub read_staleness: Duration,
+    // number of reads waiting on a quorum before the leader counts as overloaded
+    pub read_overload_threshold: usize,
+    // phi above which a peer is suspected to be down and only sent heartbeat-sized messages
+    pub suspicion_threshold: f64,
+    // port for the HTTP status endpoint, which is off when None
+    pub http_port: Option<u16>,
+    // whether the HTTP port also takes GET and PUT of /kv/<key>, alongside clients on the socket
+    pub http_clients: bool,
+    // how many waiting messages a follower reads past a raft message for the client and status messages behind it, none when 0
+    pub catch_up_read_ahead: usize,
+    // a follower stays ready to take over: it gives the core raft messages as soon as they arrive, whatever
+    // catch_up_read_ahead says, and handshakes with every member before it needs to send to them
+    pub hot_standby: bool,
+    // failovers taking longer than this are alerted on and counted, never when None
+    pub failover_budget: Option<Duration>,
+    // how often the log growth report is logged, never when None
+    pub growth_report_interval: Option<Duration>,
+    // how the leader has nodes that reconnect behind it catch up, always however the core does when None
+    pub state_transfer: Option<StateTransferPolicy>,
+    // how long the keys of deletes are kept as tombstones for tombstones reads, none are kept when None
+    pub tombstone_retention: Option<Duration>,
+    // port for just /metrics, for a Prometheus that shouldn't reach anything else, none when None
+    pub metrics_port: Option<u16>,
+    // when set, every message sent is signed with it and every message received has to be
+    pub auth_secret: Option<Vec<u8>>,
+    // how long a member can go unheard from before it's reported as dead, never when None
+    pub dead_node_timeout: Option<Duration>,
+    // encrypts values put with the sensitive flag before they enter the log, which fail without one
+    pub keyring: Option<Keyring>,
+    // rate limit for every client until changed with a client_limit message
+    pub client_limit: Option<ClientLimit>,
+    // for tagging backed up snapshots, which can't be done without backups
+    pub backups: Option<Backups>,
+    pub watchdog: Option<Watchdog>,
+    pub systemd: Option<Notifier>,
+    pub storage_metrics: StorageMetrics,
+    // the directory storage writes to, whose free space is reported
+    pub data_dir: Option<PathBuf>,
+    // commands are refused while the data directory has less free space than this, zero never refuses them
+    pub min_free_disk_bytes: u64,
+    // /readyz fails while there's no known leader
+    pub ready_requires_leader: bool,
+    // /readyz fails on a follower that hasn't heard from the leader for longer than this
+    pub ready_max_leader_silence: Duration,
+    // the leader shortens how long it waits between rounds of AppendEntries and HTTP polls while its p99 commit latency
+    // is over this, never when None
+    pub commit_latency_target: Option<Duration>,
+    // followers pull the parts of a snapshot that the leader's pushes missed, shared with storage, never when None
+    pub snapshot_pull: Option<SnapshotExchange>,
+    // responses to each socket client go out in the order its requests came in, held for at most this long behind one
+    // that hasn't been answered, never when None
+    pub response_order_timeout: Option<Duration>,
+    // a redirect for the same client, MID and leader isn't sent again for this long, never suppressed when None
+    pub redirect_suppression: Option<Duration>,
+    // shared with storage
+    pub snapshot_pause: SnapshotPause,
+    // shared with storage and the thread waiting for signals
+    pub shutdown: Shutdown,
+    // the socket for admin commands, see AdminServer, off when None
+    pub admin: Option<AdminServer>,
+    pub authorizer: Box<dyn Authorizer>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            read_staleness: Duration::from_millis(0),
+            read_overload_threshold: 64,
+            suspicion_threshold: 8.0,
+            http_port: None,
+            http_clients: false,
+            metrics_port: None,
+            catch_up_read_ahead: 0,
+            hot_standby: false,
+            failover_budget: None,
+            growth_report_interval: None,
+            state_transfer: None,
+            tombstone_retention: None,
+            auth_secret: None,
+            dead_node_timeout: None,
+            keyring: None,
+            client_limit: None,
+            backups: None,
+            watchdog: None,
+            systemd: None,
+            storage_metrics: StorageMetrics::default(),
+            data_dir: None,
+            min_free_disk_bytes: 0,
+            ready_requires_leader: true,
+            ready_max_leader_silence: Duration::from_secs(2),
+            commit_latency_target: None,
+            snapshot_pull: None,
+            response_order_timeout: None,
+            redirect_suppression: None,
+            snapshot_pause: SnapshotPause::default(),
+            shutdown: Shutdown::default(),
+            admin: None,
+            authorizer: Box::new(AllowAll),
+        }
+    }
+}
 
 pub struct Cs3700UnixNetwork {
     our_id: u32,
     our_name: String,
+    cluster_name: String,
+    config: NetworkConfig,
+    nodes: HashMap<u32, NodeAddress>,
+    raft_config: Option<Config>,
+    peers: HashMap<u32, PeerStatus>,
+    failure_detectors: HashMap<u32, PhiAccrualDetector>,
+    latencies: LatencyTable,
+    started: Instant,
+    last_ping: Option<Instant>,
+    last_snapshot_pull: Option<Instant>,
+    leader_id: Option<u32>,
+    pending_reads: usize,
+    // the round whose read is with the core, and when it was handed over
+    confirming_round: Option<(u64, Instant)>,
+    read_rounds_started: u64,
+    // reads waiting on a round other than their own, by round
+    read_rounds: BTreeMap<u64, Vec<ReadValueRequest>>,
+    // reads answered by another read's round
+    batched_reads: u64,
+    last_read_quorum: Option<Instant>,
+    stale_reads: Vec<ReadValueRequest>,
+    // reads with a min_index the state machine hadn't reached yet when they were ready, and how they were ready
+    waiting_reads: Vec<(ReadValueRequest, &'static str)>,
+    // messages to clients that couldn't be sent without blocking
+    outgoing: HashMap<u32, PeerQueue>,
+    // messages to members are sent from a thread per member
+    senders: HashMap<u32, PeerSender>,
+    // client commands given to the core that haven't been applied or redirected yet
+    in_flight: usize,
+    // commands from socket clients that haven't been answered, by client and MID, with when they were received
+    replays: Vec<(u32, String, Instant)>,
+    // when commands were last answered, by client and MID, oldest first
+    answered: HashMap<(u32, String), Instant>,
+    answered_order: VecDeque<(u32, String)>,
+    commit_tuner: Option<CommitTuner>,
+    // when each command in flight was received, by client and MID
+    command_started: HashMap<(u32, String), Instant>,
+    // set while the leader is stepping down and refusing new commands, until leadership moves
+    stepdown: Option<StepDown>,
+    // set while the leader hands leadership to another node, refusing new commands like a step down
+    leadership_transfer: Option<LeadershipTransfer>,
+    // the last index each peer reported in its pings
+    peer_last_index: HashMap<u32, u32>,
+    // set once a signal asks the node to stop, refusing new commands until it exits
+    shutting_down: Option<ShuttingDown>,
+    // as of the last command applied here
+    apply_stats: ApplyStats,
+    // set when the apply breaker trips, refusing new commands until resume_writes
+    read_only: bool,
+    disk_usage: Option<DiskUsage>,
+    last_disk_check: Option<Instant>,
+    last_growth_report: Option<Instant>,
+    // the nodes the leader is holding snapshots back for while they catch up from its log
+    catch_ups: CatchUps,
+    // set while the data directory is below min_free_disk_bytes
+    low_disk: bool,
+    // when the next key expires, as of the last time the core handed over the state machine
+    next_expiry_ms: Option<u64>,
+    last_expire_proposal: Option<Instant>,
+    // the time in the last ExpireKeys proposed, keys due by then are already on their way out
+    expired_through_ms: u64,
+    // when the oldest tombstone's key was deleted, as of the same time
+    oldest_tombstone_ms: Option<u64>,
+    last_purge_proposal: Option<Instant>,
+    // the schedules due soonest, as of the same time
+    upcoming_schedules: Vec<(u64, String)>,
+    last_schedule_proposal: Option<Instant>,
+    // events for the core produced by a single message that maps to several of them
+    pending_events: VecDeque<MessageEvent<KvCommand, ReadValueRequest>>,
+    // raft messages read ahead of where the core is, by the peer they're from
+    held_raft: VecDeque<(u32, Vec<u8>)>,
+    recent_events: VecDeque<(u64, String)>,
+    // messages dropped for a missing or wrong MAC
+    auth_failures: u64,
+    // peer messages dropped for coming from an id that isn't in the config
+    unknown_peer_messages: u64,
+    // client operations the authorizer refused
+    denied_operations: u64,
+    // peer messages dropped for not being signed for a term, or carrying state from a term older than ours
+    stale_term_messages: u64,
+    // members reported as dead and not heard from since
+    dead_nodes: HashSet<u32>,
+    hot_keys: HotKeys,
+    clients: ClientTable,
+    metrics: Metrics,
+    counters: Counters,
+    // commands the state machine had applied when the core last handed it over
+    applied_index: u32,
+    // the leader before the current one or before there was none, which could be us
+    last_leader: Option<u32>,
+    // when the old leader was last heard from, while we're a new leader that hasn't committed a client write yet
+    failover_from: Option<Instant>,
+    last_failover: Option<Duration>,
+    response_order: Option<ResponseOrder>,
+    recent_redirects: Option<RecentRedirects>,
+    http: Option<HttpServer>,
+    metrics_http: Option<HttpServer>,
+    // HTTP requests waiting on the core, by the client id they were given
+    http_waiting: HashMap<u32, HttpRequest>,
+    http_requests: u32,
+    // MIDs of HTTP requests start with this, so they don't repeat across restarts
+    http_mid_prefix: String,
     socket_fd: i32,
     buffer: [u8; PACKET_SIZE],
 }
 
 impl Cs3700UnixNetwork {
-    pub fn new(our_id: u32) -> Cs3700UnixNetwork {
-        let our_name = num_to_network_name(our_id);
+    pub fn new(our_id: u32, cluster_id: ClusterId, config: NetworkConfig) -> Cs3700UnixNetwork {
         let socket_fd = socket::socket(AddressFamily::Unix, SockType::SeqPacket, SockFlag::empty(), None).unwrap();
-        socket::connect(socket_fd, &SockAddr::new_unix::<str>(&our_name).unwrap()).unwrap();
+        socket::connect(socket_fd, &SockAddr::new_unix::<str>(&num_to_network_name(our_id)).unwrap()).unwrap();
+        Cs3700UnixNetwork::with_socket(socket_fd, our_id, cluster_id, config)
+    }
+
+    // for a SeqPacket socket that's already connected to whatever routes messages by dst
+    pub fn with_socket(socket_fd: RawFd, our_id: u32, cluster_id: ClusterId, config: NetworkConfig) -> Cs3700UnixNetwork {
+        let our_name = num_to_network_name(our_id);
+        let http = config.http_port.map(|port| HttpServer::bind(port).expect("could not bind HTTP port"));
+        let metrics_http = config.metrics_port.map(|port| HttpServer::bind(port).expect("could not bind metrics port"));
+        let clients = ClientTable::new(config.client_limit);
+        let response_order = config.response_order_timeout.map(ResponseOrder::new);
+        let recent_redirects = config.redirect_suppression.map(RecentRedirects::new);
+        let started_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        let http_mid_prefix = format!("http-{}-{}-", our_name, started_ms);
         Cs3700UnixNetwork {
             socket_fd,
             our_name,
             our_id,
+            cluster_name: cluster_id.to_string(),
+            config,
+            nodes: HashMap::new(),
+            raft_config: None,
+            peers: HashMap::new(),
+            failure_detectors: HashMap::new(),
+            latencies: LatencyTable::default(),
+            started: Instant::now(),
+            last_ping: None,
+            last_snapshot_pull: None,
+            leader_id: None,
+            pending_reads: 0,
+            confirming_round: None,
+            read_rounds_started: 0,
+            read_rounds: BTreeMap::new(),
+            batched_reads: 0,
+            last_read_quorum: None,
+            stale_reads: vec![],
+            waiting_reads: vec![],
+            outgoing: HashMap::new(),
+            senders: HashMap::new(),
+            in_flight: 0,
+            replays: vec![],
+            answered: HashMap::new(),
+            answered_order: VecDeque::new(),
+            commit_tuner: None,
+            command_started: HashMap::new(),
+            stepdown: None,
+            leadership_transfer: None,
+            peer_last_index: HashMap::new(),
+            shutting_down: None,
+            apply_stats: ApplyStats::default(),
+            read_only: false,
+            disk_usage: None,
+            last_disk_check: None,
+            last_growth_report: None,
+            catch_ups: CatchUps::default(),
+            low_disk: false,
+            next_expiry_ms: None,
+            last_expire_proposal: None,
+            expired_through_ms: 0,
+            oldest_tombstone_ms: None,
+            last_purge_proposal: None,
+            upcoming_schedules: vec![],
+            last_schedule_proposal: None,
+            pending_events: VecDeque::new(),
+            held_raft: VecDeque::new(),
+            recent_events: VecDeque::new(),
+            auth_failures: 0,
+            unknown_peer_messages: 0,
+            denied_operations: 0,
+            stale_term_messages: 0,
+            dead_nodes: HashSet::new(),
+            hot_keys: HotKeys::default(),
+            clients,
+            metrics: Metrics::default(),
+            counters: Counters::default(),
+            applied_index: 0,
+            last_leader: None,
+            failover_from: None,
+            last_failover: None,
+            response_order,
+            recent_redirects,
+            http,
+            metrics_http,
+            http_waiting: HashMap::new(),
+            http_requests: 0,
+            http_mid_prefix,
             buffer: [0u8; PACKET_SIZE],
         }
     }
 
-    fn send_message_to(&mut self, to: u32, leader_id: Option<u32>, data: JsonMessageType) {
-        let leader_name = leader_id.map(|id| num_to_network_name(id));
+    fn send_hello(&mut self, to: u32) {
+        let cluster_name = self.cluster_name.clone();
+        let last_index = self.config.storage_metrics.log().last_index;
+        self.send_message_to(to, None, JsonMessageType::Hello { version: PROTOCOL_VERSION, cluster: &cluster_name, last_index });
+    }
 
-        let mut writer = self.buffer.as_mut();
-        serde_json::to_writer(&mut writer, &JsonMessage {
-            src: self.our_name.as_str(),
-            dst: &num_to_network_name(to),
-            leader: leader_name.as_ref().map(|s| s.as_str()).unwrap_or("FFFF"),
-            data,
-        }).unwrap();
+    fn handle_hello(&mut self, from: u32, accepted: bool) {
+        if !accepted {
+            if self.peers.insert(from, PeerStatus::Rejected) != Some(PeerStatus::Rejected) {
+                eprintln!("{} rejected handshake from {}: different cluster or protocol version", self.our_name, num_to_network_name(from));
+                self.record_event(format!("rejected handshake from {}", num_to_network_name(from)));
+            }
+            return;
+        }
+
+        if self.peers.insert(from, PeerStatus::Verified) != Some(PeerStatus::Verified) {
+            self.record_event(format!("handshake with {} verified", num_to_network_name(from)));
+            self.send_hello(from);
+        }
+    }
 
-        let amt = PACKET_SIZE - writer.len();
+    fn recv(&mut self, timeout: Duration) -> Result<usize, MessageEvent<<KvStateMachine as StateMachine>::Command, ReadValueRequest>> {
+        let micros = (timeout.as_micros() as i64).max(1);
+        socket::setsockopt(self.socket_fd, ReceiveTimeout, &TimeVal::microseconds(micros)).unwrap();
 
-        socket::send(self.socket_fd, &self.buffer[..amt], MsgFlags::empty()).unwrap();
+        match socket::recv(self.socket_fd, &mut self.buffer, MsgFlags::empty()) {
+            Ok(amt) if amt == 0 => Err(MessageEvent::Fail),
+            Ok(amt) => Ok(amt),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => Err(MessageEvent::Timeout),
+            Err(_) => Err(MessageEvent::Fail)
+        }
     }
-}
 
-impl NetworkInterface<KvStateMachine> for Cs3700UnixNetwork {
-    type ReadRequest = ReadValueRequest;
+    fn should_serve_stale_read(&self) -> bool {
+        self.config.read_staleness > Duration::from_millis(0)
+            && self.leader_id == Some(self.our_id)
+            && self.pending_reads >= self.config.read_overload_threshold
+            && self.last_read_quorum.map_or(false, |t| t.elapsed() <= self.config.read_staleness)
+    }
 
-    fn on_config_update(&mut self, _config: &Config) {}
+    fn send_read_response(&mut self, req: ReadValueRequest, state_machine: &KvStateMachine, mode: &'static str) {
+        if !req.is_ready(state_machine) {
+            self.waiting_reads.push((req, mode));
+            return;
+        }
+        if req.kind == ReadKind::Sync {
+            return;
+        }
+        if req.kind == ReadKind::Replay {
+            if !self.answered_since(req.client_id, &req.mid, req.received) {
+                self.send_command_result(req.client_id, &req.mid, state_machine);
+            }
+            return;
+        }
+        // a wait is answered with the index the write was applied at
+        let index = match req.kind {
+            ReadKind::Applied(_) => state_machine.applied_at(&req.key),
+            _ => None,
+        }.unwrap_or_else(|| state_machine.applied_index());
 
-    fn wait_for_message(&mut self, timeout: Duration, raft_message: &mut Vec<u8>) -> MessageEvent<<KvStateMachine as StateMachine>::Command, Self::ReadRequest> {
-        socket::setsockopt(self.socket_fd, ReceiveTimeout, &TimeVal::microseconds(timeout.as_micros() as i64)).unwrap();
+        self.metrics.record_read(req.received.elapsed());
+        match req.response_value(state_machine, self.config.keyring.as_ref()) {
+            Some(value) => self.send_message_to(req.client_id, Some(self.our_id), JsonMessageType::Response(Response::Ok { mid: req.mid, value: Some(value.into_owned()), read: Some(mode.to_string()), index: Some(index) })),
+            None => self.send_message_to(req.client_id, Some(self.our_id), JsonMessageType::Response(Response::Fail { mid: req.mid })),
+        }
+    }
 
-        let amt = match socket::recv(self.socket_fd, &mut self.buffer, MsgFlags::empty()) {
-            Ok(amt) if amt == 0 => return MessageEvent::Fail,
-            Ok(amt) => amt,
-            Err(nix::Error::Sys(Errno::EAGAIN)) => return MessageEvent::Timeout,
-            Err(_) => return MessageEvent::Fail
+    // answers stale reads, and waiting reads whose min_index has been reached or whose write has been applied
+    fn answer_deferred_reads(&mut self, state_machine: &KvStateMachine) {
+        for req in std::mem::take(&mut self.stale_reads) {
+            self.send_read_response(req, state_machine, "stale");
+        }
+
+        let (ready, waiting) = std::mem::take(&mut self.waiting_reads).into_iter().partition(|(req, _)| req.is_ready(state_machine));
+        self.waiting_reads = waiting;
+        for (req, mode) in ready {
+            self.send_read_response(req, state_machine, mode);
+        }
+
+        let (applied, replays) = std::mem::take(&mut self.replays).into_iter().partition(|(_, mid, _)| state_machine.applied_at(mid).is_some());
+        self.replays = replays;
+        for (client_id, mid, received) in applied {
+            if !self.answered_since(client_id, &mid, received) {
+                self.send_command_result(client_id, &mid, state_machine);
+            }
+        }
+    }
+
+    // Commands still waiting after REPLAY_AFTER go through the core as reads, so their results are looked up even if
+    // nothing else is applied in the meantime. Commands the core is still working on are answered when applied, and
+    // the lookup skips them.
+    fn replay_waiting_commands(&mut self) {
+        let now = Instant::now();
+        let (due, replays) = std::mem::take(&mut self.replays).into_iter().partition(|(_, _, received)| now.duration_since(*received) >= REPLAY_AFTER);
+        self.replays = replays;
+        for (client_id, mid, _) in due {
+            if let Some(event) = self.client_read(client_id, mid.clone(), mid, ReadKind::Replay, 0) {
+                self.pending_events.push_back(event);
+            }
+        }
+    }
+
+    fn send_command_result(&mut self, client_id: u32, mid: &str, state_machine: &KvStateMachine) {
+        let index = Some(state_machine.applied_at(mid).unwrap_or_else(|| state_machine.applied_index()));
+        match state_machine.result(mid) {
+            Some(CommandResult::Failed) => self.send_message_to(client_id, Some(self.our_id), JsonMessageType::Response(Response::Fail { mid: mid.to_string() })),
+            Some(CommandResult::Value(value)) => {
+                match value.as_ref().map_or(Some(Cow::Borrowed("")), |v| decrypt(self.config.keyring.as_ref(), v)) {
+                    Some(value) => self.send_message_to(client_id, Some(self.our_id), JsonMessageType::Response(Response::Ok { mid: mid.to_string(), value: Some(value.into_owned()), read: None, index })),
+                    None => self.send_message_to(client_id, Some(self.our_id), JsonMessageType::Response(Response::Fail { mid: mid.to_string() })),
+                }
+            }
+            _ => self.send_message_to(client_id, Some(self.our_id), JsonMessageType::Response(Response::Ok { mid: mid.to_string(), value: None, read: None, index })),
+        }
+
+        let key = (client_id, mid.to_string());
+        if self.answered.insert(key.clone(), Instant::now()).is_none() {
+            self.answered_order.push_back(key);
+        }
+        while self.answered_order.len() > MAX_ANSWERED {
+            if let Some(old) = self.answered_order.pop_front() {
+                self.answered.remove(&old);
+            }
+        }
+    }
+
+    fn answered_since(&self, client_id: u32, mid: &str, since: Instant) -> bool {
+        self.answered.get(&(client_id, mid.to_string())).map_or(false, |answered| *answered >= since)
+    }
+
+    fn forget_replay(&mut self, client_id: u32, mid: &str) {
+        self.replays.retain(|(c, m, _)| *c != client_id || m != mid);
+    }
+
+    fn fail_expired_waiting_reads(&mut self) {
+        let (expired, waiting) = std::mem::take(&mut self.waiting_reads).into_iter().partition(|(req, _)| req.received.elapsed() > req.max_wait());
+        self.waiting_reads = waiting;
+        // a lookup that found nothing has nothing to say, the command itself is answered or redirected by the core
+        for (req, _) in expired.into_iter().filter(|(req, _)| !matches!(req.kind, ReadKind::Replay | ReadKind::Sync)) {
+            self.send_message_to(req.client_id, Some(self.our_id), JsonMessageType::Response(Response::Fail { mid: req.mid }));
+        }
+    }
+
+    fn suspicion(&self, peer: u32) -> f64 {
+        self.failure_detectors.get(&peer).map_or(0.0, |d| d.phi(Instant::now()))
+    }
+
+    fn client_read(&mut self, client_id: u32, mid: String, key: String, kind: ReadKind, min_index: u32) -> Option<MessageEvent<KvCommand, ReadValueRequest>> {
+        let mut req = ReadValueRequest { kind, key, mid, client_id, min_index, received: Instant::now(), round: 0 };
+        if self.should_serve_stale_read() {
+            // answered from the applied state the next time the core hands us the state machine
+            self.stale_reads.push(req);
+            return None;
+        }
+
+        self.pending_reads += 1;
+        match self.confirming_round {
+            // a round that started before this read arrived can't confirm it, so it waits for the next one
+            Some((round, _)) => {
+                self.read_rounds.entry(round + 1).or_default().push(req);
+                None
+            }
+            None => {
+                req.round = self.read_rounds_started + 1;
+                self.read_rounds_started = req.round;
+                self.confirming_round = Some((req.round, Instant::now()));
+                Some(MessageEvent::ClientRead(req))
+            }
+        }
+    }
+
+    // Called once the core is done with the round's read. The reads batched behind it go to the core as one read, and
+    // are all answered when it is.
+    fn start_next_read_round(&mut self) {
+        self.confirming_round = None;
+        let next = self.read_rounds_started + 1;
+        let mut batch = match self.read_rounds.remove(&next) {
+            Some(batch) => batch,
+            None => return,
         };
 
-        let message: JsonMessage = serde_json::from_slice(&self.buffer[..amt]).expect("Invalid JSON message");
+        let mut first = batch.remove(0);
+        first.round = next;
+        if !batch.is_empty() {
+            self.read_rounds.insert(next, batch);
+        }
+        self.read_rounds_started = next;
+        self.confirming_round = Some((next, Instant::now()));
+        self.pending_events.push_back(MessageEvent::ClientRead(first));
+    }
 
-        let src_id = network_name_to_num(message.src);
+    // if the core never gets back about a round, its batch is failed so the reads behind it aren't stuck too
+    fn fail_stuck_read_round(&mut self) {
+        let round = match self.confirming_round {
+            Some((round, started)) if started.elapsed() >= READ_ROUND_TIMEOUT => round,
+            _ => return,
+        };
+        for req in self.read_rounds.remove(&round).unwrap_or_default() {
+            self.pending_reads = self.pending_reads.saturating_sub(1);
+            self.send_message_to(req.client_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid: req.mid }));
+        }
+        self.start_next_read_round();
+    }
 
-        match message.data {
-            JsonMessageType::Get { mid, key } =>
-                MessageEvent::ClientRead(ReadValueRequest { key: key.to_string(), mid: mid.to_string(), client_id: src_id }),
-            JsonMessageType::Put { mid, key, value } => {
-                let request_id = hash(mid);
-                MessageEvent::ClientCommand(ClientCommandRequest {
-                    request_id,
-                    client_id: src_id,
-                    command: SetValueCommand { key: key.to_string(), value: value.to_string(), mid: mid.to_string() },
-                })
+    fn send_pings_if_due(&mut self) {
+        if self.last_ping.map_or(false, |t| t.elapsed() < PING_INTERVAL) {
+            return;
+        }
+        self.last_ping = Some(Instant::now());
+
+        let rtts: HashMap<String, f64> = self.latencies.medians().into_iter()
+            .map(|(id, rtt)| (num_to_network_name(id), rtt))
+            .collect();
+        let sent_us = self.started.elapsed().as_micros() as u64;
+        let last_index = self.config.storage_metrics.log().last_index;
+
+        if self.config.hot_standby && self.leader_id != Some(self.our_id) {
+            let unknown: Vec<u32> = self.nodes.keys().copied().filter(|id| *id != self.our_id && !self.peers.contains_key(id)).collect();
+            for id in unknown {
+                self.send_hello(id);
             }
-            JsonMessageType::RaftOwned { data } => {
-                raft_message.write_all(&data).unwrap();
-                MessageEvent::Node {
-                    src_node_id: src_id,
+        }
+
+        let peers: Vec<u32> = self.peers.iter()
+            .filter(|(_, status)| **status == PeerStatus::Verified)
+            .map(|(id, _)| *id)
+            .collect();
+        for peer in peers {
+            self.send_message_to(peer, self.leader_id, JsonMessageType::Ping { sent_us, rtts: rtts.clone(), last_index });
+        }
+    }
+
+    fn pull_snapshot_if_stalled(&mut self) {
+        let exchange = match &self.config.snapshot_pull {
+            Some(exchange) if self.last_snapshot_pull.map_or(true, |t| t.elapsed() >= SNAPSHOT_PULL_INTERVAL) => exchange,
+            _ => return,
+        };
+        let leader = match self.leader_id {
+            Some(leader) if leader != self.our_id && self.peers.get(&leader) == Some(&PeerStatus::Verified) => leader,
+            _ => return,
+        };
+        let (last_index, wanted) = match exchange.wanted(SNAPSHOT_PULL_STALL, MAX_SNAPSHOT_PULLS) {
+            Some(wanted) => wanted,
+            None => return,
+        };
+        self.last_snapshot_pull = Some(Instant::now());
+        for (offset, end) in wanted {
+            self.send_message_to(leader, self.leader_id, JsonMessageType::SnapshotPull { last_index, offset, len: end - offset });
+        }
+    }
+
+    // Decides how a node that's just said hello catches up, from how far behind the leader's log it says it is. The core
+    // sends it a snapshot only once the entries it's missing have been compacted away, so catching up from the log means
+    // holding back snapshots until the node has them all.
+    fn plan_catch_up(&mut self, node: u32, last_index: u32) {
+        let policy = match self.config.state_transfer {
+            Some(policy) if self.leader_id == Some(self.our_id) => policy,
+            _ => return,
+        };
+        let our_last_index = self.config.storage_metrics.log().last_index;
+        let gap = our_last_index.saturating_sub(last_index);
+        if gap == 0 {
+            return;
+        }
+        let from = match policy.decide(gap) {
+            Transfer::Log => {
+                self.catch_ups.hold(node, our_last_index);
+                "log"
+            }
+            Transfer::Snapshot => "next snapshot",
+        };
+        self.record_event(format!("{} reconnected {} entries behind, catching up from the {}", num_to_network_name(node), gap, from));
+    }
+
+    fn hold_snapshots_for_catch_ups(&mut self) {
+        let max_hold = match self.config.state_transfer {
+            Some(policy) => policy.max_hold,
+            None => return,
+        };
+        if self.leader_id != Some(self.our_id) {
+            self.catch_ups.clear();
+        }
+        for node in self.catch_ups.expire(max_hold) {
+            self.record_event(format!("stopped holding snapshots for {} after {:?}", num_to_network_name(node), max_hold));
+        }
+        self.config.snapshot_pause.set_held(self.catch_ups.is_holding());
+    }
+
+    // the core doesn't share the term, so the status is the role and leader
+    fn notify_systemd(&mut self) {
+        if self.config.systemd.is_none() {
+            return;
+        }
+        let ready = self.not_ready_reasons().is_empty();
+        let status = format!("{}, leader {}", self.role_of(self.our_id), self.leader_id.map_or("unknown".to_string(), num_to_network_name));
+        if let Some(systemd) = &mut self.config.systemd {
+            if ready {
+                systemd.ready();
+            }
+            systemd.status(status);
+            systemd.keepalive_if_due();
+        }
+    }
+
+    fn check_disk_if_due(&mut self) {
+        let data_dir = match &self.config.data_dir {
+            Some(data_dir) if self.last_disk_check.map_or(true, |t| t.elapsed() >= DISK_CHECK_INTERVAL) => data_dir,
+            _ => return,
+        };
+        self.last_disk_check = Some(Instant::now());
+
+        let usage = match storage_metrics::disk_usage(data_dir) {
+            Ok(usage) => usage,
+            Err(e) => {
+                eprintln!("can't check free space in {}: {}", data_dir.display(), e);
+                return;
+            }
+        };
+        self.disk_usage = Some(usage);
+
+        let low_disk = usage.free_bytes < self.config.min_free_disk_bytes;
+        if low_disk != self.low_disk {
+            self.low_disk = low_disk;
+            self.record_event(if low_disk {
+                format!("only {} bytes free on disk, refusing writes", usage.free_bytes)
+            } else {
+                format!("{} bytes free on disk, taking writes again", usage.free_bytes)
+            });
+        }
+    }
+
+    fn report_growth_if_due(&mut self) {
+        let interval = match self.config.growth_report_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        // the first report comes an interval after starting, when there's something to say
+        let last = *self.last_growth_report.get_or_insert(self.started);
+        if last.elapsed() >= interval {
+            self.last_growth_report = Some(Instant::now());
+            eprintln!("log growth: {}", self.config.storage_metrics.growth());
+        }
+    }
+
+    // Alerts about members that haven't been heard from in dead_node_timeout, as long as the rest still make a quorum,
+    // since removing one is only an option then.
+    fn check_dead_nodes(&mut self) {
+        let timeout = match self.config.dead_node_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let dead: HashSet<u32> = self.nodes.keys()
+            .copied()
+            .filter(|id| *id != self.our_id)
+            .filter(|id| {
+                let last_heard = self.failure_detectors.get(id).and_then(|d| d.last_arrival()).unwrap_or(self.started);
+                now.saturating_duration_since(last_heard) > timeout
+            })
+            .collect();
+
+        for id in self.dead_nodes.difference(&dead).copied().collect::<Vec<u32>>() {
+            self.dead_nodes.remove(&id);
+            self.record_event(format!("{} is reachable again", num_to_network_name(id)));
+        }
+
+        if self.nodes.len() - dead.len() <= self.nodes.len() / 2 {
+            return;
+        }
+
+        for id in dead {
+            if !self.dead_nodes.insert(id) {
+                continue;
+            }
+            let alert = format!("{} has been unreachable for over {}s, consider removing it", num_to_network_name(id), timeout.as_secs());
+            eprintln!("{}: {}", self.our_name, alert);
+            self.record_event(alert);
+        }
+    }
+
+    // us, and every peer we've heard from that isn't suspected or dead
+    fn reachable(&self) -> HashSet<u32> {
+        let mut reachable: HashSet<u32> = self.failure_detectors.iter()
+            .filter(|(id, d)| d.last_arrival().is_some() && !self.dead_nodes.contains(*id) && self.suspicion(**id) <= self.config.suspicion_threshold)
+            .map(|(id, _)| *id)
+            .collect();
+        reachable.insert(self.our_id);
+        reachable
+    }
+
+    fn validate_config(&self, add: &[String], remove: &[String], tunables: &Tunables) -> serde_json::Value {
+        let mut members: Vec<u32> = self.nodes.keys().copied().collect();
+        members.sort();
+        let mut problems = vec![];
+        let add = membership::parse_node_ids(add, &mut problems);
+        let remove = membership::parse_node_ids(remove, &mut problems);
+
+        problems.extend(membership::validate_membership(&members, &self.reachable(), &add, &remove));
+        if let Some(config) = &self.raft_config {
+            problems.extend(membership::validate_tunables(config, tunables));
+        }
+
+        json!({ "valid": problems.is_empty(), "problems": problems })
+    }
+
+    fn tag_snapshot(&self, tag: String) -> serde_json::Value {
+        let backups = match &self.config.backups {
+            Some(backups) => backups,
+            None => return json!({ "error": "backups are not enabled" }),
+        };
+        let snapshot = backups.manifest.lock().unwrap().snapshots.last().cloned();
+        if snapshot.is_none() {
+            return json!({ "error": "no snapshot has been backed up yet" });
+        }
+
+        // the tag is written to the manifest once the uploads queued before it are done
+        match backups.uploads.send(Upload::Tag { tag: tag.clone() }) {
+            Ok(()) => json!({ "tag": tag, "snapshot": snapshot }),
+            Err(_) => json!({ "error": "backups have stopped" }),
+        }
+    }
+
+    fn snapshot_tags(&self) -> serde_json::Value {
+        match &self.config.backups {
+            Some(backups) => serde_json::to_value(&backups.manifest.lock().unwrap().tags).unwrap(),
+            None => json!([]),
+        }
+    }
+
+    fn watchdog(&self, stage: Stage) {
+        if let Some(watchdog) = &self.config.watchdog {
+            watchdog.progress(stage);
+        }
+    }
+
+    fn wait_for_event(&mut self, timeout: Duration, raft_message: &mut Vec<u8>) -> MessageEvent<KvCommand, ReadValueRequest> {
+        // handshakes are handled here, so keep receiving until a message for the core arrives or the timeout is used up
+        let window = self.commit_window();
+        let deadline = Instant::now() + window.map_or(timeout, |window| timeout.min(window));
+        loop {
+            self.watchdog(Stage::NetworkUpkeep);
+            self.flush_queued_messages();
+            self.serve_http();
+            self.serve_admin();
+            while let Some(event) = self.pending_events.pop_front() {
+                if let Some(event) = self.admit(event) {
+                    return event;
+                }
+            }
+            self.send_pings_if_due();
+            self.pull_snapshot_if_stalled();
+            self.check_dead_nodes();
+            self.check_disk_if_due();
+            self.report_growth_if_due();
+            self.hold_snapshots_for_catch_ups();
+            self.notify_systemd();
+            self.hot_keys.decay_if_due();
+            self.fail_expired_waiting_reads();
+            self.replay_waiting_commands();
+            self.fail_stuck_read_round();
+            self.finish_stepdown_if_drained();
+            self.transfer_leadership_if_ready();
+            self.shut_down_if_requested();
+            self.release_expired_responses();
+            self.propose_expiry_if_due();
+            self.propose_tombstone_purge_if_due();
+            self.propose_schedules_if_due();
+
+            if let Some((src_node_id, data)) = self.held_raft.pop_front() {
+                raft_message.extend_from_slice(&data);
+                self.read_ahead();
+                return MessageEvent::Node { src_node_id };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return MessageEvent::Timeout;
+            }
+
+            let mut recv_timeout = deadline - now;
+            if self.has_queued_messages() {
+                recv_timeout = recv_timeout.min(Duration::from_millis(1));
+            }
+            if self.http.is_some() {
+                recv_timeout = recv_timeout.min(window.map_or(HTTP_POLL_INTERVAL, |window| window.min(HTTP_POLL_INTERVAL)));
+            }
+
+            self.watchdog(Stage::Receiving);
+            let amt = match self.recv(recv_timeout) {
+                Ok(amt) => amt,
+                Err(MessageEvent::Timeout) => continue,
+                Err(event) => return event,
+            };
+
+            let (amt, signed_term) = match self.verify(amt) {
+                Some(verified) => verified,
+                None => continue,
+            };
+
+            self.watchdog(Stage::HandlingMessage);
+            if let Some(event) = self.handle_message(amt, signed_term, raft_message) {
+                if let Some(event) = self.admit(event) {
+                    if let MessageEvent::Node { .. } = event {
+                        self.read_ahead();
+                    }
+                    return event;
                 }
             }
-            _ => unimplemented!()
         }
     }
 
-    fn send_raft_message(&mut self, node: u32, leader_id: Option<u32>, msg: impl WriteBytes) {
-        let mut data = [0u8; 4096];
-        let amt = msg.write_bytes_with_writer(data.as_mut()).unwrap();
-        self.send_message_to(node, leader_id, JsonMessageType::RaftRef { data: &data[..amt] })
+    // the length of the message without its signature and the term it was signed for, or None if it isn't signed right
+    fn verify(&mut self, amt: usize) -> Option<(usize, Option<u32>)> {
+        let secret = match &self.config.auth_secret {
+            Some(secret) => secret,
+            None => return Some((amt, None)),
+        };
+        let verified = auth::verify_with_term(secret, &mut self.buffer, amt);
+        if verified.is_none() {
+            self.auth_failures += 1;
+        }
+        verified
+    }
+
+    // A follower catching up gets raft messages back to back, and the core applies a slice of entries for each one. Reading
+    // past them to what's already waiting behind lets client and status messages be handled between slices instead of
+    // after the whole backlog, with the raft messages held and given to the core in order, one per loop.
+    fn read_ahead(&mut self) {
+        let limit = self.config.catch_up_read_ahead;
+        if self.leader_id == Some(self.our_id) || self.config.hot_standby {
+            return;
+        }
+        for _ in 0..limit {
+            if self.held_raft.len() >= limit {
+                return;
+            }
+            let amt = match socket::recv(self.socket_fd, &mut self.buffer, MsgFlags::MSG_DONTWAIT) {
+                Ok(amt) if amt > 0 => amt,
+                // a closed socket is found out by the next wait
+                _ => return,
+            };
+            let (amt, signed_term) = match self.verify(amt) {
+                Some(verified) => verified,
+                None => continue,
+            };
+
+            let mut data = vec![];
+            match self.handle_message(amt, signed_term, &mut data) {
+                Some(MessageEvent::Node { src_node_id }) => self.held_raft.push_back((src_node_id, data)),
+                Some(event) => self.pending_events.push_back(event),
+                None => {}
+            }
+        }
     }
 
-    fn handle_command_applied(&mut self, req: ClientCommandRequest<&<KvStateMachine as StateMachine>::Command>, _state_machine: &KvStateMachine) {
-        self.send_message_to(req.client_id, Some(self.our_id), JsonMessageType::Ok { mid: &req.command.mid, value: None });
+    // Expired keys are deleted by a command rather than by each node on its own clock, so every replica deletes the same
+    // keys at the same point in the log. Until it's applied, reads just don't see them.
+    fn propose_expiry_if_due(&mut self) {
+        let now_ms = now_ms();
+        if self.leader_id != Some(self.our_id)
+            || self.next_expiry_ms.map_or(true, |next| next > now_ms)
+            || self.last_expire_proposal.map_or(false, |last| last.elapsed() < EXPIRE_INTERVAL) {
+            return;
+        }
+        let expire = self.expire_keys_command(now_ms);
+        self.pending_events.push_back(expire);
     }
 
-    fn handle_ready_to_read(&mut self, req: Self::ReadRequest, state_machine: &KvStateMachine) {
-        let value = Some(state_machine.0.get(&req.key).map(|s| s.as_str()).unwrap_or(""));
-        self.send_message_to(req.client_id, Some(self.our_id), JsonMessageType::Ok { mid: &req.mid, value });
+    fn expire_keys_command(&mut self, now_ms: u64) -> MessageEvent<KvCommand, ReadValueRequest> {
+        self.last_expire_proposal = Some(Instant::now());
+        self.expired_through_ms = now_ms;
+        let mid = format!("{}{}-{}", EXPIRE_MID_PREFIX, self.our_name, now_ms);
+        client_command(self.our_id, &mid, KvOp::ExpireKeys(ExpireKeysCommand { now_ms }))
     }
 
-    fn redirect_command_request(&mut self, leader_id: u32, req: ClientCommandRequest<<KvStateMachine as StateMachine>::Command>) {
-        self.send_message_to(req.client_id, Some(leader_id), JsonMessageType::Redirect { mid: &req.command.mid });
+    // Commands see expired keys until they're deleted, so one that depends on a key's current value goes after an
+    // ExpireKeys once a key is due, rather than reviving or moving a value reads already treat as gone.
+    fn expire_before(&self, op: &KvOp, now_ms: u64) -> bool {
+        self.leader_id == Some(self.our_id)
+            && op.reads_current()
+            && self.next_expiry_ms.map_or(false, |next| next <= now_ms && next > self.expired_through_ms)
     }
 
-    fn redirect_read_request(&mut self, leader_id: u32, req: Self::ReadRequest) {
-        self.send_message_to(req.client_id, Some(leader_id), JsonMessageType::Redirect { mid: &req.mid });
+    // Tombstones are forgotten the same way, once the oldest is older than the retention window. Without one, any left
+    // from when there was one are all forgotten.
+    fn propose_tombstone_purge_if_due(&mut self) {
+        let before_ms = match self.config.tombstone_retention {
+            Some(retention) => now_ms().saturating_sub(retention.as_millis() as u64),
+            None => u64::MAX,
+        };
+        if self.leader_id != Some(self.our_id)
+            || self.oldest_tombstone_ms.map_or(true, |oldest| oldest >= before_ms)
+            || self.last_purge_proposal.map_or(false, |last| last.elapsed() < EXPIRE_INTERVAL) {
+            return;
+        }
+        self.last_purge_proposal = Some(Instant::now());
+        let mid = format!("{}purge-{}-{}", INTERNAL_MID_PREFIX, self.our_name, before_ms);
+        self.pending_events.push_back(client_command(self.our_id, &mid, KvOp::PurgeTombstones(PurgeTombstonesCommand { before_ms })));
+    }
+
+    // when a delete is stamped with, which is when it leaves a tombstone
+    fn deleted_at_ms(&self) -> Option<u64> {
+        self.config.tombstone_retention.map(|_| now_ms())
+    }
+
+    // Like expiry, the leader proposes running schedules that are due. They're kept in the state machine, so a new leader
+    // picks up any that came due while there wasn't one.
+    fn propose_schedules_if_due(&mut self) {
+        let now_ms = now_ms();
+        if self.leader_id != Some(self.our_id)
+            || self.upcoming_schedules.first().map_or(true, |(due_at_ms, _)| *due_at_ms > now_ms)
+            || self.last_schedule_proposal.map_or(false, |last| last.elapsed() < SCHEDULE_INTERVAL) {
+            return;
+        }
+        self.last_schedule_proposal = Some(Instant::now());
+        let due: Vec<(u64, String)> = self.upcoming_schedules.iter().take_while(|(due_at_ms, _)| *due_at_ms <= now_ms).cloned().collect();
+        for (due_at_ms, name) in due {
+            let mid = format!("{}schedule-{}-{}", INTERNAL_MID_PREFIX, name, due_at_ms);
+            self.pending_events.push_back(client_command(self.our_id, &mid, KvOp::RunSchedule(RunScheduleCommand { name, due_at_ms, now_ms })));
+        }
+    }
+
+    // only to clients this node knows, the others are probably talking to some other node or gone
+    fn notify_expired(&mut self, mid: &str, state_machine: &KvStateMachine) {
+        let index = match state_machine.applied_at(mid) {
+            Some(index) => index,
+            None => return,
+        };
+        for (client_id, key) in state_machine.expired_notices(index) {
+            if self.clients.is_known(*client_id) {
+                self.send_message_to(*client_id, Some(self.our_id), JsonMessageType::Response(Response::Expired { key: key.clone() }));
+            }
+        }
+    }
+
+    // what the upkeep loop and /metrics need from the state machine, which they don't get to see
+    fn note_state_machine(&mut self, state_machine: &KvStateMachine) {
+        self.applied_index = state_machine.applied_index();
+        self.next_expiry_ms = state_machine.next_expiry();
+        self.oldest_tombstone_ms = state_machine.oldest_tombstone();
+        self.upcoming_schedules = state_machine.upcoming_schedules(MAX_SCHEDULES_PER_PROPOSAL);
+    }
+
+    fn start_stepdown(&mut self, client_id: u32, mid: String) {
+        if self.leader_id != Some(self.our_id) {
+            self.send_message_to(client_id, self.leader_id, JsonMessageType::Stepdown { mid: &mid, result: Some(json!({ "error": "not the leader" })) });
+            return;
+        }
+        if self.stepdown.is_none() {
+            self.record_event(format!("stepping down with {} commands in flight", self.in_flight));
+        }
+        self.stepdown = Some(StepDown { waiting: Some((client_id, mid)), started: Instant::now() });
+        self.finish_stepdown_if_drained();
+    }
+
+    // Once nothing is in flight, hands leadership to the peer furthest along, and acknowledges the step down once it's
+    // moved or the transfer gave up.
+    fn finish_stepdown_if_drained(&mut self) {
+        let (client_id, mid) = match &mut self.stepdown {
+            Some(stepdown) if self.in_flight == 0 || stepdown.started.elapsed() >= STEPDOWN_DRAIN_TIMEOUT => match stepdown.waiting.take() {
+                Some(waiting) => waiting,
+                None => return,
+            },
+            _ => return,
+        };
+        self.record_event(format!("stepped down, {} commands still in flight", self.in_flight));
+
+        let target = self.peers.iter()
+            .filter(|(_, status)| **status == PeerStatus::Verified)
+            .map(|(id, _)| *id)
+            .max_by_key(|id| (self.peer_last_index.get(id).copied().unwrap_or(0), std::cmp::Reverse(*id)));
+        match target {
+            Some(target) => self.start_leadership_transfer(target, TransferWaiting::Stepdown(client_id, mid)),
+            None => {
+                // there's no reason to keep refusing commands with nobody to take over
+                self.stepdown = None;
+                let result = json!({ "transferred": false, "error": "no connected peer to hand leadership to" });
+                self.answer_leadership_transfer(TransferWaiting::Stepdown(client_id, mid), result);
+            }
+        }
+    }
+
+    // Once a signal asks for it, stops taking commands and waits for the ones in flight like a step down, then tells the
+    // peers it's leaving so they don't wait out an election timeout, gives storage a moment to write its last checkpoint,
+    // closes the socket and exits. The core has no way to be stopped, so the process exits from under it.
+    fn shut_down_if_requested(&mut self) {
+        if !self.config.shutdown.is_requested() {
+            return;
+        }
+        let (started, left) = match &self.shutting_down {
+            Some(shutting_down) => (shutting_down.started, shutting_down.left),
+            None => {
+                self.record_event(format!("shutting down with {} commands in flight", self.in_flight));
+                if let Some(systemd) = &mut self.config.systemd {
+                    systemd.stopping();
+                }
+                self.shutting_down = Some(ShuttingDown { started: Instant::now(), left: None });
+                return;
+            }
+        };
+
+        match left {
+            None if self.in_flight == 0 || started.elapsed() >= STEPDOWN_DRAIN_TIMEOUT => {
+                self.record_event(format!("leaving, {} commands still in flight", self.in_flight));
+                let peers: Vec<u32> = self.peers.iter()
+                    .filter(|(_, status)| **status == PeerStatus::Verified)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for peer in peers {
+                    self.send_message_to(peer, self.leader_id, JsonMessageType::Leaving {});
+                }
+                self.shutting_down = Some(ShuttingDown { started, left: Some(Instant::now()) });
+            }
+            Some(left) if left.elapsed() >= SHUTDOWN_FLUSH_TIMEOUT || (!self.config.shutdown.is_flush_pending() && !self.has_queued_messages()) => {
+                if self.config.shutdown.is_flush_pending() {
+                    eprintln!("exiting before storage wrote its last checkpoint");
+                }
+                self.flush_queued_messages();
+                let _ = nix::unistd::close(self.socket_fd);
+                std::process::exit(0);
+            }
+            _ => {}
+        }
+    }
+
+    // counts client commands going to the core, and refuses them while stepping down or shutting down, or if they're too
+    // big to replicate
+    fn admit(&mut self, event: MessageEvent<KvCommand, ReadValueRequest>) -> Option<MessageEvent<KvCommand, ReadValueRequest>> {
+        if let MessageEvent::ClientCommand(req) = &event {
+            let now_ms = now_ms();
+            if self.expire_before(&req.command.op, now_ms) {
+                let expire = self.expire_keys_command(now_ms);
+                self.pending_events.push_front(event);
+                self.pending_events.push_front(expire);
+                return None;
+            }
+            let too_big = entry_size(&req.command) > MAX_COMMAND_BYTES;
+            if too_big || self.stepdown.is_some() || self.shutting_down.is_some() || self.read_only || self.low_disk {
+                if !has_no_client(&req.command.mid) {
+                    let (client_id, mid) = (req.client_id, req.command.mid.clone());
+                    self.send_message_to(client_id, None, JsonMessageType::Response(Response::Fail { mid }));
+                }
+                return None;
+            }
+            self.in_flight += 1;
+            // HTTP requests get a new MID every time, so they're never retries
+            if req.client_id < HTTP_CLIENT_IDS_START && !has_no_client(&req.command.mid) {
+                self.replays.push((req.client_id, req.command.mid.clone(), Instant::now()));
+            }
+            self.command_started.insert((req.client_id, req.command.mid.clone()), Instant::now());
+        }
+        Some(event)
+    }
+
+    // Only the leader's waits are cut short, returning to the core sooner so it sends the next round of AppendEntries
+    // sooner. A follower's wait is its election timeout.
+    fn commit_window(&self) -> Option<Duration> {
+        match &self.commit_tuner {
+            Some(tuner) if self.leader_id == Some(self.our_id) => Some(tuner.window()),
+            _ => None,
+        }
+    }
+
+    fn record_commit_latency(&mut self, client_id: u32, mid: &str) {
+        let started = match self.command_started.remove(&(client_id, mid.to_string())) {
+            Some(started) => started,
+            None => return,
+        };
+        let now = Instant::now();
+        self.metrics.record_command(now.duration_since(started));
+        let window = match &mut self.commit_tuner {
+            Some(tuner) => tuner.record(now.duration_since(started), now),
+            None => return,
+        };
+        if let Some(window) = window {
+            self.record_event(format!("commit latency window is now {}ms", window.as_millis()));
+        }
+    }
+
+    fn check_apply_breaker(&mut self, stats: &ApplyStats) {
+        if stats.applies > self.apply_stats.applies {
+            self.counters.record_apply(stats.last_us);
+        }
+        if stats.trips > self.apply_stats.trips && !self.read_only {
+            self.read_only = true;
+            self.record_event(format!("apply breaker tripped after {} slow applies, refusing writes", stats.over_budget));
+        }
+        self.apply_stats = stats.clone();
+    }
+
+    // the node to recommend, if there's enough data to pick one, and the recommendation for the client
+    fn recommend_leader(&self) -> (Option<u32>, serde_json::Value) {
+        let mut members: Vec<u32> = self.nodes.keys().copied().collect();
+        members.sort();
+
+        match self.latencies.recommend_leader(self.our_id, &members) {
+            Some((id, median_rtt_ms)) => (Some(id), json!({
+                "recommended": num_to_network_name(id),
+                "median_rtt_ms": median_rtt_ms,
+                "current_leader": self.leader_id.map(num_to_network_name),
+                "executed": false,
+            })),
+            None => (None, json!({ "error": "not enough round trip time data yet" })),
+        }
+    }
+
+    fn record_failover(&mut self, took: Duration) {
+        let over_budget = self.config.failover_budget.map_or(false, |budget| took > budget);
+        self.counters.record_failover(took.as_micros() as u64, over_budget);
+        self.last_failover = Some(took);
+        if over_budget {
+            eprintln!("ALERT: failover to {} took {} ms, over the budget", self.our_name, took.as_millis());
+        }
+        self.record_event(format!("took over as leader, first write committed after {} ms", took.as_millis()));
+    }
+
+    fn record_event(&mut self, event: String) {
+        let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        self.recent_events.push_back((time_ms, event));
+        if self.recent_events.len() > MAX_RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+    }
+
+    fn recent_events(&self) -> serde_json::Value {
+        self.recent_events.iter()
+            .map(|(time_ms, event)| json!({ "time_ms": time_ms, "event": event }))
+            .collect()
+    }
+
+    fn serve_http(&mut self) {
+        let scrapes: Vec<_> = self.metrics_http.iter().flat_map(|http| std::iter::from_fn(move || http.accept())).collect();
+        for req in scrapes {
+            if req.method == "GET" && req.path == "/metrics" {
+                req.respond("200 OK", PROMETHEUS_CONTENT_TYPE, self.prometheus_metrics().as_bytes());
+            } else {
+                req.not_found();
+            }
+        }
+
+        let requests: Vec<_> = match &self.http {
+            Some(http) => std::iter::from_fn(|| http.accept()).collect(),
+            None => return,
+        };
+
+        for req in requests {
+            let path = req.path.clone();
+            match path.as_str() {
+                _ if self.config.http_clients && path.starts_with(HTTP_KV_PATH) => self.http_client_request(req),
+                "/stepdown" if req.method == "POST" => self.http_stepdown(req),
+                "/metrics/reset" if req.method == "POST" => req.respond_json(&self.reset_metrics()),
+                "/snapshots/pause" if req.method == "POST" => req.respond_json(&self.pause_snapshots(true)),
+                "/snapshots/resume" if req.method == "POST" => req.respond_json(&self.pause_snapshots(false)),
+                _ if req.method != "GET" => req.not_found(),
+                "/healthz" => req.respond("200 OK", "text/plain", b"ok\n"),
+                "/readyz" => {
+                    let reasons = self.not_ready_reasons();
+                    let status = if reasons.is_empty() { "200 OK" } else { "503 Service Unavailable" };
+                    req.respond_json_with_status(status, &json!({ "ready": reasons.is_empty(), "reasons": reasons }));
+                }
+                "/status" => req.respond_json(&self.cluster_status()),
+                // for load balancers, a red node should get no new traffic
+                "/health" => {
+                    let health = self.load_signals().to_json();
+                    let status = if health["load"] == "red" { "503 Service Unavailable" } else { "200 OK" };
+                    req.respond_json_with_status(status, &health);
+                }
+                "/events" => req.respond_json(&self.recent_events()),
+                "/metrics/dump" => req.respond_json(&self.dump_metrics()),
+                "/storage/growth" => req.respond_json(&self.config.storage_metrics.growth()),
+                "/metrics" => req.respond("200 OK", PROMETHEUS_CONTENT_TYPE, self.prometheus_metrics().as_bytes()),
+                "/" if cfg!(feature = "dashboard") => req.respond("200 OK", "text/html", DASHBOARD),
+                _ => req.not_found(),
+            }
+        }
+    }
+
+    fn authorized(&self, client_id: u32, data: &JsonMessageType) -> bool {
+        let kind = data.kind();
+        data.accessed().into_iter()
+            .all(|(access, key)| self.config.authorizer.authorize(&Operation { client_id: Some(client_id), kind, access, key }))
+    }
+
+    // Turns GET, PUT and DELETE of /kv/<key> into the same reads and writes clients on the socket send, answered once the core
+    // gets back to send_message_to with the request's client id.
+    fn http_client_request(&mut self, req: HttpRequest) {
+        let key = match http::percent_decode(&req.path[HTTP_KV_PATH.len()..]) {
+            Some(key) if !key.is_empty() => key,
+            _ => return req.respond("400 Bad Request", "text/plain", b"bad key\n"),
+        };
+
+        self.http_requests = self.http_requests.wrapping_add(1) % HTTP_CLIENT_IDS_START;
+        let client_id = HTTP_CLIENT_IDS_START + self.http_requests;
+        let mid = format!("{}{}", self.http_mid_prefix, self.http_requests);
+
+        let (kind, access) = match req.method.as_str() {
+            "GET" => ("get", Access::Read),
+            "PUT" => ("put", Access::Write),
+            "DELETE" => ("delete", Access::Write),
+            _ => return req.respond("405 Method Not Allowed", "text/plain", b"method not allowed\n"),
+        };
+        if !self.config.authorizer.authorize(&Operation { client_id: None, kind, access, key: Some(&key) }) {
+            self.denied_operations += 1;
+            return req.respond("403 Forbidden", "text/plain", b"not authorized\n");
+        }
+
+        let event = match kind {
+            "get" => self.client_read(client_id, mid, key, ReadKind::Get, 0),
+            "put" => match String::from_utf8(req.body.clone()) {
+                Ok(value) => Some(client_command(client_id, &mid, KvOp::Set(SetValueCommand { key, value }))),
+                Err(_) => return req.respond("400 Bad Request", "text/plain", b"value isn't UTF-8\n"),
+            },
+            _ => Some(client_command(client_id, &mid, KvOp::Delete(DeleteValueCommand { key, deleted_at_ms: self.deleted_at_ms() }))),
+        };
+        self.http_waiting.insert(client_id, req);
+        self.pending_events.extend(event);
+    }
+
+    // a stepdown message over HTTP, answered once leadership has been handed over
+    fn http_stepdown(&mut self, req: HttpRequest) {
+        self.http_requests = self.http_requests.wrapping_add(1) % HTTP_CLIENT_IDS_START;
+        let client_id = HTTP_CLIENT_IDS_START + self.http_requests;
+        let mid = format!("{}{}", self.http_mid_prefix, self.http_requests);
+        self.http_waiting.insert(client_id, req);
+        self.start_stepdown(client_id, mid);
+    }
+
+    // Why this node shouldn't get client traffic right now, empty if it's ready. The commit index isn't visible from
+    // here, so a follower counts as caught up as long as it's been hearing from the leader.
+    fn not_ready_reasons(&self) -> Vec<String> {
+        let mut reasons = vec![];
+        match self.leader_id {
+            None if self.config.ready_requires_leader => reasons.push("no known leader".to_string()),
+            Some(leader) if leader != self.our_id => {
+                let silence = self.failure_detectors.get(&leader)
+                    .and_then(|d| d.last_arrival())
+                    .map(|t| t.elapsed());
+                match silence {
+                    Some(silence) if silence <= self.config.ready_max_leader_silence => {}
+                    Some(silence) => reasons.push(format!("last heard from the leader {}ms ago", silence.as_millis())),
+                    None => reasons.push("never heard from the leader".to_string()),
+                }
+            }
+            _ => {}
+        }
+        if self.stepdown.is_some() {
+            reasons.push("stepping down".to_string());
+        }
+        if self.read_only {
+            reasons.push("refusing writes after slow applies".to_string());
+        }
+        if self.low_disk {
+            reasons.push("low on disk space".to_string());
+        }
+        reasons
+    }
+
+    fn prometheus_metrics(&self) -> String {
+        let log = self.config.storage_metrics.log();
+        let stored = self.config.storage_metrics.stored();
+        self.counters.render(&[
+            Gauge { name: "raft_current_term", help: "The current term.", value: log.current_term as f64 },
+            Gauge { name: "raft_last_log_index", help: "Index of the last entry in the log, counting ones compacted into the snapshot.", value: log.last_index as f64 },
+            Gauge { name: "raft_log_entries", help: "Entries in the log since the last snapshot.", value: log.entries as f64 },
+            Gauge { name: "kv_stored_log_bytes", help: "Serialized size of the entries in the log since the last snapshot.", value: stored.log as f64 },
+            Gauge { name: "kv_stored_snapshot_bytes", help: "Size of the snapshot as stored.", value: stored.snapshot as f64 },
+            // the core applies entries as soon as they commit, so this follows the commit index
+            Gauge { name: "kv_applied_index", help: "Commands applied to the state machine.", value: self.applied_index as f64 },
+            Gauge { name: "raft_is_leader", help: "Whether this node is the leader.", value: if self.leader_id == Some(self.our_id) { 1.0 } else { 0.0 } },
+        ])
+    }
+
+    fn dump_metrics(&self) -> serde_json::Value {
+        let mut metrics = self.metrics.to_json();
+        metrics["id"] = json!(self.our_name);
+        metrics["fsync_us"] = self.config.storage_metrics.fsync_histogram();
+        metrics["log_growth"] = self.config.storage_metrics.growth();
+        metrics
+    }
+
+    // what was recorded up until the reset
+    fn reset_metrics(&mut self) -> serde_json::Value {
+        let metrics = self.dump_metrics();
+        self.metrics.reset();
+        self.config.storage_metrics.reset();
+        self.record_event("metrics reset".to_string());
+        metrics
+    }
+
+    fn pause_snapshots(&mut self, paused: bool) -> serde_json::Value {
+        if paused != self.config.snapshot_pause.is_paused() {
+            self.config.snapshot_pause.set_paused(paused);
+            self.record_event(if paused { "paused snapshots" } else { "resumed snapshots" }.to_string());
+        }
+        self.config.snapshot_pause.to_json()
+    }
+
+    fn load_signals(&self) -> LoadSignals {
+        LoadSignals {
+            pending_reads: self.pending_reads,
+            read_overload_threshold: self.config.read_overload_threshold,
+            in_flight: self.in_flight,
+            max_send_queue: self.senders.values().map(PeerSender::queued).max().unwrap_or(0),
+            commit_p99: self.commit_tuner.as_ref().and_then(CommitTuner::last_p99),
+            commit_target: self.commit_tuner.as_ref().map(CommitTuner::target),
+            refusing_writes: self.stepdown.is_some() || self.read_only || self.low_disk,
+        }
+    }
+
+    fn role_of(&self, id: u32) -> &'static str {
+        match self.leader_id {
+            Some(leader) if leader == id => "leader",
+            Some(_) => "follower",
+            None => "unknown",
+        }
+    }
+
+    fn members(&self) -> serde_json::Value {
+        let mut ids: Vec<u32> = self.nodes.keys().copied().collect();
+        ids.sort();
+
+        let now = Instant::now();
+        let members: Vec<serde_json::Value> = ids.into_iter().map(|id| {
+            let address = match &self.nodes[&id] {
+                NodeAddress::String(s) => Some(s.clone()),
+                #[allow(unreachable_patterns)]
+                _ => None,
+            };
+            let last_contact_ms = self.failure_detectors.get(&id)
+                .and_then(|d| d.last_arrival())
+                .map(|t| now.saturating_duration_since(t).as_millis() as u64);
+
+            json!({
+                "id": num_to_network_name(id),
+                "address": address,
+                "role": self.role_of(id),
+                "handshake": match self.peers.get(&id) {
+                    _ if id == self.our_id => "self",
+                    Some(PeerStatus::Verified) => "verified",
+                    Some(PeerStatus::Rejected) => "rejected",
+                    None => "pending",
+                },
+                "last_contact_ms": last_contact_ms,
+                "suspicion": self.suspicion(id),
+                "dead": self.dead_nodes.contains(&id),
+            })
+        }).collect();
+
+        serde_json::Value::Array(members)
+    }
+
+    fn cluster_status(&self) -> serde_json::Value {
+        json!({
+            "id": self.our_name,
+            "cluster": self.cluster_name,
+            "role": self.role_of(self.our_id),
+            "leader": self.leader_id.map(num_to_network_name),
+            "members": self.members(),
+        })
+    }
+
+    fn hot_keys(&self, limit: usize) -> serde_json::Value {
+        let list = |top: Vec<(&str, u64)>| top.into_iter()
+            .map(|(key, count)| json!({ "key": key, "count": count }))
+            .collect::<Vec<serde_json::Value>>();
+        json!({
+            "reads": list(self.hot_keys.reads.top(limit)),
+            "writes": list(self.hot_keys.writes.top(limit)),
+        })
+    }
+
+    fn clients(&self) -> serde_json::Value {
+        let clients: serde_json::Map<String, serde_json::Value> = self.clients.stats()
+            .map(|(id, stats)| {
+                let mut value = serde_json::to_value(stats).unwrap();
+                value["limit"] = serde_json::to_value(self.clients.limit(id)).unwrap();
+                (num_to_network_name(id), value)
+            })
+            .collect();
+        serde_json::Value::Object(clients)
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        let suspicion: HashMap<String, f64> = self.failure_detectors.keys()
+            .map(|id| (num_to_network_name(*id), self.suspicion(*id)))
+            .collect();
+        let send_queues: HashMap<String, usize> = self.senders.iter()
+            .map(|(id, sender)| (num_to_network_name(*id), sender.queued()))
+            .collect();
+
+        json!({
+            "id": self.our_name,
+            "alloc": alloc_stats::snapshot(),
+            "suspicion": suspicion,
+            "send_queues": send_queues,
+            "auth_failures": self.auth_failures,
+            "unknown_peer_messages": self.unknown_peer_messages,
+            "denied_operations": self.denied_operations,
+            "stale_term_messages": self.stale_term_messages,
+            "batched_reads": self.batched_reads,
+            "held_raft_messages": self.held_raft.len(),
+            "suppressed_redirects": self.recent_redirects.as_ref().map_or(0, RecentRedirects::suppressed),
+            "failover": {
+                "hot_standby": self.config.hot_standby,
+                "last_ms": self.last_failover.map(|took| took.as_millis() as u64),
+                "budget_ms": self.config.failover_budget.map(|budget| budget.as_millis() as u64),
+            },
+            "storage": self.config.storage_metrics.to_json(),
+            "disk": self.disk_usage.map(|usage| json!({
+                "total_bytes": usage.total_bytes,
+                "free_bytes": usage.free_bytes,
+                "low": self.low_disk,
+            })),
+            "apply": {
+                "applies": self.apply_stats.applies,
+                "over_budget": self.apply_stats.over_budget,
+                "slowest_us": self.apply_stats.slowest_us,
+                "breaker_trips": self.apply_stats.trips,
+                "read_only": self.read_only,
+            },
+            "commit_latency": self.commit_tuner.as_ref().map(CommitTuner::to_json),
+            "snapshots": self.config.snapshot_pause.to_json(),
+        })
+    }
+
+    fn handle_request(&mut self, src_id: u32, request: Request) -> Option<MessageEvent<<KvStateMachine as StateMachine>::Command, ReadValueRequest>> {
+        match &request {
+            Request::Get { key, .. } | Request::Exists { key, .. } | Request::Type { key, .. } | Request::Strlen { key, .. }
+            | Request::JsonGet { key, .. } =>
+                self.hot_keys.read(key),
+            Request::Put { key, .. } | Request::GetSet { key, .. } | Request::GetDel { key, .. } | Request::Eval { key, .. } | Request::Call { key, .. }
+            | Request::Delete { key, .. } | Request::DeleteIf { key, .. } | Request::PutTtl { key, .. }
+            | Request::Append { key, .. } | Request::Incr { key, .. } | Request::JsonSet { key, .. } =>
+                self.hot_keys.write(key),
+            Request::BulkLoad { pairs, .. } | Request::MultiPut { pairs, .. } => pairs.iter().for_each(|(key, _)| self.hot_keys.write(key)),
+            Request::Rename { from, to, .. } | Request::Copy { from, to, .. } => {
+                self.hot_keys.write(from);
+                self.hot_keys.write(to);
+            }
+            _ => {}
+        }
+
+        match request {
+            Request::Get { mid, key, min_index } => self.client_read(src_id, mid, key, ReadKind::Get, min_index),
+            Request::Exists { mid, key, min_index } => self.client_read(src_id, mid, key, ReadKind::Exists, min_index),
+            Request::Type { mid, key, min_index } => self.client_read(src_id, mid, key, ReadKind::Type, min_index),
+            Request::Strlen { mid, key, min_index } => self.client_read(src_id, mid, key, ReadKind::Strlen, min_index),
+            Request::Prefix { mid, prefix, limit, min_index } =>
+                self.client_read(src_id, mid, prefix, ReadKind::Prefix { limit: scan_limit(limit) }, min_index),
+            Request::Tombstones { mid, prefix, since_ms, limit, min_index } =>
+                self.client_read(src_id, mid, prefix, ReadKind::Tombstones { since_ms, limit: scan_limit(limit) }, min_index),
+            Request::Range { mid, start, end, limit, min_index } =>
+                self.client_read(src_id, mid, start, ReadKind::Range { end, limit: scan_limit(limit) }, min_index),
+            Request::Wait { mid, target, timeout_ms } => {
+                let timeout = if timeout_ms == 0 { DEFAULT_APPLY_WAIT } else { Duration::from_millis(timeout_ms).min(MAX_APPLY_WAIT) };
+                self.client_read(src_id, mid, target, ReadKind::Applied(timeout), 0)
+            }
+            Request::Put { mid, key, value, sensitive } => {
+                // encrypted here so the plaintext never reaches the log
+                let value = match &self.config.keyring {
+                    _ if !sensitive => Ok(value),
+                    Some(keyring) => keyring.encrypt(&value),
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, "no keyring configured")),
+                };
+                match value {
+                    Ok(value) => Some(client_command(src_id, &mid, KvOp::Set(SetValueCommand { key, value }))),
+                    Err(e) => {
+                        eprintln!("{} can't encrypt sensitive value: {}", self.our_name, e);
+                        self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid }));
+                        None
+                    }
+                }
+            }
+            Request::PutTtl { mid, key, value, ttl_ms, notify } => {
+                let expires_at_ms = now_ms().saturating_add(ttl_ms);
+                let notify = if notify { Some(src_id) } else { None };
+                Some(client_command(src_id, &mid, KvOp::SetWithTtl(SetWithTtlCommand { key, value, expires_at_ms, notify })))
+            }
+            Request::Schedule { mid, name, delay_ms, interval_ms, command } => {
+                let op = Box::new(scheduled_op(command));
+                let at_ms = now_ms().saturating_add(delay_ms);
+                Some(client_command(src_id, &mid, KvOp::Schedule(ScheduleCommand { name, at_ms, interval_ms, op })))
+            }
+            Request::JsonSet { mid, key, path, value } =>
+                Some(client_command(src_id, &mid, KvOp::JsonSet(JsonSetCommand { key, path, value: value.to_string() }))),
+            Request::JsonGet { mid, key, path, min_index } =>
+                self.client_read(src_id, mid, key, ReadKind::JsonGet { path }, min_index),
+            Request::Append { mid, key, value } =>
+                Some(client_command(src_id, &mid, KvOp::Append(AppendCommand { key, value }))),
+            Request::Incr { mid, key, by } =>
+                Some(client_command(src_id, &mid, KvOp::Incr(IncrCommand { key, by: by.unwrap_or(1) }))),
+            Request::Unschedule { mid, name } =>
+                Some(client_command(src_id, &mid, KvOp::Unschedule(UnscheduleCommand { name }))),
+            Request::GetSet { mid, key, value } =>
+                Some(client_command(src_id, &mid, KvOp::GetSet(SetValueCommand { key, value }))),
+            Request::GetDel { mid, key } =>
+                Some(client_command(src_id, &mid, KvOp::GetDelete(DeleteValueCommand { key, deleted_at_ms: self.deleted_at_ms() }))),
+            Request::Delete { mid, key } =>
+                Some(client_command(src_id, &mid, KvOp::Delete(DeleteValueCommand { key, deleted_at_ms: self.deleted_at_ms() }))),
+            Request::Defrag { mid } => Some(client_command(src_id, &mid, KvOp::Defrag)),
+            Request::Eval { mid, key, script } =>
+                Some(client_command(src_id, &mid, KvOp::Script(ScriptCommand { key, script }))),
+            Request::Register { mid, name, script } =>
+                Some(client_command(src_id, &mid, KvOp::Register(RegisterCommand { name, script }))),
+            Request::Call { mid, name, key, arg } =>
+                Some(client_command(src_id, &mid, KvOp::Call(CallCommand { name, key, arg }))),
+            Request::Rename { mid, from, to } =>
+                Some(client_command(src_id, &mid, KvOp::Rename(MoveCommand { from, to, deleted_at_ms: self.deleted_at_ms() }))),
+            Request::Copy { mid, from, to } =>
+                Some(client_command(src_id, &mid, KvOp::Copy(MoveCommand { from, to, deleted_at_ms: None }))),
+            Request::DeleteIf { mid, key, value, version } => {
+                let expected = match (value, version) {
+                    (Some(value), None) => Some(Expected::Value(value)),
+                    (None, Some(version)) => Some(Expected::Version(version)),
+                    _ => None,
+                };
+                match expected {
+                    Some(expected) => Some(client_command(src_id, &mid, KvOp::DeleteIf(DeleteIfCommand { key, expected, deleted_at_ms: self.deleted_at_ms() }))),
+                    None => {
+                        self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid }));
+                        None
+                    }
+                }
+            }
+            Request::BulkLoad { mid, pairs } => {
+                self.pending_events.extend(bulk_load_commands(src_id, &mid, pairs));
+                self.pending_events.pop_front()
+            }
+            Request::MultiPut { mid, pairs } => {
+                if pairs.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>() > BULK_LOAD_BATCH_BYTES {
+                    self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid }));
+                    return None;
+                }
+                Some(client_command(src_id, &mid, KvOp::Batch(BatchSetCommand(pairs))))
+            }
+        }
+    }
+
+    fn handle_message(&mut self, amt: usize, signed_term: Option<u32>, raft_message: &mut Vec<u8>) -> Option<MessageEvent<<KvStateMachine as StateMachine>::Command, ReadValueRequest>> {
+        let _subsystem = alloc_stats::enter(Subsystem::Buffers);
+        let message: JsonMessage = serde_json::from_slice(&self.buffer[..amt]).expect("Invalid JSON message");
+
+        let src_id = network_name_to_num(message.src);
+        self.counters.record_received(message.data.kind());
+
+        // only members of the cluster get to talk to the core or take part in handshakes and pings
+        let from_peer = matches!(message.data,
+            JsonMessageType::RaftOwned { .. } | JsonMessageType::Hello { .. } | JsonMessageType::Ping { .. } | JsonMessageType::Pong { .. }
+            | JsonMessageType::Leaving { .. } | JsonMessageType::TimeoutNow { .. } | JsonMessageType::SnapshotPull { .. } | JsonMessageType::SnapshotPart { .. });
+        if from_peer && (src_id == self.our_id || !self.nodes.contains_key(&src_id)) {
+            self.unknown_peer_messages += 1;
+            return None;
+        }
+        // With a secret, peers sign for the term they're in. Raft messages and snapshot parts signed for an older term than
+        // ours are delayed or replayed ones that shouldn't get to the core, the rest only keep the connection going.
+        if from_peer && self.config.auth_secret.is_some() {
+            let carries_state = matches!(message.data, JsonMessageType::RaftOwned { .. } | JsonMessageType::SnapshotPart { .. });
+            if signed_term.map_or(true, |term| carries_state && term < self.config.storage_metrics.log().current_term) {
+                self.stale_term_messages += 1;
+                return None;
+            }
+        }
+
+        if !from_peer {
+            if let (Some(order), Some(mid)) = (&mut self.response_order, message.data.mid()) {
+                order.received(src_id, mid, Instant::now());
+            }
+        }
+
+        if !from_peer && !self.clients.request(src_id, amt, Instant::now()) {
+            if let Some(mid) = message.data.mid() {
+                let mid = mid.to_string();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid }));
+            }
+            return None;
+        }
+
+        if !from_peer && !self.authorized(src_id, &message.data) {
+            self.denied_operations += 1;
+            if let Some(mid) = message.data.mid() {
+                let mid = mid.to_string();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid }));
+            }
+            return None;
+        }
+
+        match message.data {
+            JsonMessageType::Request(request) => self.handle_request(src_id, request),
+            JsonMessageType::RaftOwned { data } => {
+                match self.peers.get(&src_id) {
+                    Some(PeerStatus::Verified) => {
+                        self.failure_detectors.entry(src_id).or_default().heartbeat(Instant::now());
+                        raft_message.write_all(&data).unwrap();
+                        Some(MessageEvent::Node {
+                            src_node_id: src_id,
+                        })
+                    }
+                    Some(PeerStatus::Rejected) => None,
+                    None => {
+                        self.send_hello(src_id);
+                        None
+                    }
+                }
+            }
+            JsonMessageType::Hello { version, cluster, last_index } => {
+                let accepted = version == PROTOCOL_VERSION && cluster == self.cluster_name;
+                self.handle_hello(src_id, accepted);
+                if accepted {
+                    self.plan_catch_up(src_id, last_index);
+                }
+                None
+            }
+            JsonMessageType::Stats { mid, .. } => {
+                let mid = mid.to_string();
+                let stats = self.stats();
+                self.send_message_to(src_id, None, JsonMessageType::Stats { mid: &mid, stats: Some(stats) });
+                None
+            }
+            JsonMessageType::MetricsDump { mid, .. } => {
+                let mid = mid.to_string();
+                let metrics = self.dump_metrics();
+                self.send_message_to(src_id, None, JsonMessageType::MetricsDump { mid: &mid, metrics: Some(metrics) });
+                None
+            }
+            JsonMessageType::MetricsReset { mid, .. } => {
+                let mid = mid.to_string();
+                let metrics = self.reset_metrics();
+                self.send_message_to(src_id, None, JsonMessageType::MetricsReset { mid: &mid, metrics: Some(metrics) });
+                None
+            }
+            JsonMessageType::LogGrowth { mid, .. } => {
+                let mid = mid.to_string();
+                let growth = self.config.storage_metrics.growth();
+                self.send_message_to(src_id, None, JsonMessageType::LogGrowth { mid: &mid, growth: Some(growth) });
+                None
+            }
+            JsonMessageType::Health { mid, .. } => {
+                let mid = mid.to_string();
+                let health = self.load_signals().to_json();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Health { mid: &mid, health: Some(health) });
+                None
+            }
+            JsonMessageType::ClusterStatus { mid, .. } => {
+                let mid = mid.to_string();
+                let status = self.cluster_status();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::ClusterStatus { mid: &mid, status: Some(status) });
+                None
+            }
+            JsonMessageType::Members { mid, .. } => {
+                let mid = mid.to_string();
+                let members = self.members();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Members { mid: &mid, members: Some(members) });
+                None
+            }
+            JsonMessageType::ValidateConfig { mid, add, remove, tunables, .. } => {
+                let mid = mid.to_string();
+                let result = self.validate_config(&add, &remove, &tunables);
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::ValidateConfig {
+                    mid: &mid,
+                    add: vec![],
+                    remove: vec![],
+                    tunables: Tunables::default(),
+                    result: Some(result),
+                });
+                None
+            }
+            JsonMessageType::TagSnapshot { mid, tag, .. } => {
+                let mid = mid.to_string();
+                let result = self.tag_snapshot(tag);
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::TagSnapshot { mid: &mid, tag: String::new(), result: Some(result) });
+                None
+            }
+            JsonMessageType::SnapshotTags { mid, .. } => {
+                let mid = mid.to_string();
+                let tags = self.snapshot_tags();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::SnapshotTags { mid: &mid, tags: Some(tags) });
+                None
+            }
+            JsonMessageType::Clients { mid, .. } => {
+                let mid = mid.to_string();
+                let clients = self.clients();
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Clients { mid: &mid, clients: Some(clients) });
+                None
+            }
+            JsonMessageType::SetClientLimit { mid, client, rate, burst } => {
+                let mid = mid.to_string();
+                let limit = rate.map(|rate| ClientLimit { rate, burst: burst.unwrap_or(rate).max(1.0) });
+                // fails for a client that isn't a hex id like 0001
+                match client.map(|name| u32::from_str_radix(name, 16)).transpose() {
+                    Ok(client) => {
+                        self.clients.set_limit(client, limit);
+                        self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Ok { mid, value: None, read: None, index: None }));
+                    }
+                    Err(_) => self.send_message_to(src_id, self.leader_id, JsonMessageType::Response(Response::Fail { mid })),
+                }
+                None
+            }
+            JsonMessageType::Hotkeys { mid, limit, .. } => {
+                let mid = mid.to_string();
+                let hotkeys = self.hot_keys(limit.unwrap_or(10));
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::Hotkeys { mid: &mid, limit, hotkeys: Some(hotkeys) });
+                None
+            }
+            JsonMessageType::Stepdown { mid, .. } => {
+                self.start_stepdown(src_id, mid.to_string());
+                None
+            }
+            JsonMessageType::ResumeWrites { mid, .. } => {
+                let mid = mid.to_string();
+                let was_read_only = std::mem::replace(&mut self.read_only, false);
+                if was_read_only {
+                    self.record_event("resumed writes".to_string());
+                }
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::ResumeWrites { mid: &mid, result: Some(json!({ "was_read_only": was_read_only })) });
+                None
+            }
+            JsonMessageType::PauseSnapshots { mid, .. } => {
+                let mid = mid.to_string();
+                let result = self.pause_snapshots(true);
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::PauseSnapshots { mid: &mid, result: Some(result) });
+                None
+            }
+            JsonMessageType::ResumeSnapshots { mid, .. } => {
+                let mid = mid.to_string();
+                let result = self.pause_snapshots(false);
+                self.send_message_to(src_id, self.leader_id, JsonMessageType::ResumeSnapshots { mid: &mid, result: Some(result) });
+                None
+            }
+            JsonMessageType::RecommendLeader { mid, execute, .. } => {
+                let mid = mid.to_string();
+                match self.recommend_leader() {
+                    (Some(target), recommendation) if execute => {
+                        self.start_leadership_transfer(target, TransferWaiting::RecommendLeader(src_id, mid, recommendation));
+                    }
+                    (_, recommendation) => {
+                        self.send_message_to(src_id, self.leader_id, JsonMessageType::RecommendLeader { mid: &mid, execute, recommendation: Some(recommendation) });
+                    }
+                }
+                None
+            }
+            JsonMessageType::Ping { sent_us, rtts, last_index } => {
+                if self.peers.get(&src_id) == Some(&PeerStatus::Verified) {
+                    self.peer_last_index.insert(src_id, last_index);
+                    self.catch_ups.progress(src_id, last_index);
+                    // a peer's ids that don't parse are left out rather than taking the node down
+                    let rtts = rtts.into_iter().filter_map(|(name, rtt)| Some((u32::from_str_radix(&name, 16).ok()?, rtt))).collect();
+                    self.latencies.report(src_id, rtts);
+                    self.send_message_to(src_id, self.leader_id, JsonMessageType::Pong { sent_us });
+                }
+                None
+            }
+            JsonMessageType::Pong { sent_us } => {
+                let now_us = self.started.elapsed().as_micros() as u64;
+                self.latencies.record(src_id, now_us.saturating_sub(sent_us) as f64 / 1000.0);
+                None
+            }
+            JsonMessageType::Leaving {} => {
+                self.record_event(format!("{} is leaving", num_to_network_name(src_id)));
+                // the core takes a wait that timed out as its election timeout running out
+                if self.leader_id == Some(src_id) {
+                    Some(MessageEvent::Timeout)
+                } else {
+                    None
+                }
+            }
+            JsonMessageType::TimeoutNow {} => {
+                if self.leader_id == Some(src_id) {
+                    self.record_event(format!("{} is handing leadership over", num_to_network_name(src_id)));
+                    Some(MessageEvent::Timeout)
+                } else {
+                    None
+                }
+            }
+            JsonMessageType::SnapshotPull { last_index, offset, len } => {
+                let part = self.config.snapshot_pull.as_ref().and_then(|exchange| exchange.serve(last_index, offset, len));
+                if let Some((last_index, total, data)) = part {
+                    let part = JsonMessageType::SnapshotPart { last_index, total, offset, data };
+                    self.send_message_with_priority(src_id, self.leader_id, part, Priority::Bulk);
+                }
+                None
+            }
+            JsonMessageType::SnapshotPart { last_index, total, offset, data } => {
+                if let Some(exchange) = self.config.snapshot_pull.as_ref().filter(|_| self.leader_id == Some(src_id)) {
+                    exchange.record_pulled(last_index, total, offset, data);
+                }
+                None
+            }
+            _ => unimplemented!()
+        }
+    }
+
+    fn send_message_to(&mut self, to: u32, leader_id: Option<u32>, data: JsonMessageType) {
+        self.send_message_with_priority(to, leader_id, data, Priority::Control)
+    }
+
+    fn send_message_with_priority(&mut self, to: u32, leader_id: Option<u32>, data: JsonMessageType, priority: Priority) {
+        match &data {
+            JsonMessageType::Response(response @ (Response::Ok { .. } | Response::Fail { .. } | Response::Redirect { .. })) =>
+                self.metrics.record_response(response.kind()),
+            _ => {}
+        }
+        if let Some(req) = self.http_waiting.remove(&to) {
+            let status = match &data {
+                JsonMessageType::Response(Response::Ok { .. }) => "200 OK",
+                JsonMessageType::Response(Response::Redirect { .. }) => "421 Misdirected Request",
+                JsonMessageType::Stepdown { result: Some(result), .. } if result["drained"] == true => "200 OK",
+                _ => "503 Service Unavailable",
+            };
+            let mut body = serde_json::to_value(&data).unwrap();
+            body["leader"] = json!(leader_id.map(num_to_network_name));
+            body["load"] = json!(self.load_signals().assess().0.name());
+            return req.respond_json_with_status(status, &body);
+        }
+
+        self.counters.record_sent(data.kind());
+        match data {
+            JsonMessageType::Response(Response::Fail { .. }) => self.clients.failed(to),
+            JsonMessageType::Response(Response::Redirect { .. }) => self.clients.redirected(to),
+            _ => {}
+        }
+
+        let response_mid = match &data {
+            _ if self.response_order.is_none() => None,
+            JsonMessageType::Response(Response::Expired { .. }) => None,
+            JsonMessageType::Response(response) => Some(response.mid().to_string()),
+            data => data.mid().map(str::to_string),
+        };
+        let leader_name = leader_id.map(|id| num_to_network_name(id));
+        let load = if self.nodes.contains_key(&to) { None } else { Some(self.load_signals().assess().0.name()) };
+
+        let mut writer = self.buffer.as_mut();
+        serde_json::to_writer(&mut writer, &JsonMessage {
+            src: self.our_name.as_str(),
+            dst: &num_to_network_name(to),
+            leader: leader_name.as_ref().map(|s| s.as_str()).unwrap_or(NO_LEADER),
+            load,
+            data,
+        }).unwrap();
+
+        let mut amt = PACKET_SIZE - writer.len();
+        if let Some(secret) = &self.config.auth_secret {
+            amt = if self.nodes.contains_key(&to) {
+                auth::sign_for_term(secret, self.config.storage_metrics.log().current_term, &mut self.buffer, amt)
+            } else {
+                auth::sign(secret, &mut self.buffer, amt)
+            };
+        }
+
+        let message = self.buffer[..amt].to_vec();
+        let released = match (&mut self.response_order, response_mid) {
+            (Some(order), Some(mid)) => order.respond(to, &mid, message),
+            _ => vec![message],
+        };
+        for message in released {
+            self.send_bytes(to, priority, message);
+        }
+    }
+
+    fn send_bytes(&mut self, to: u32, priority: Priority, message: Vec<u8>) {
+        if let Some(sender) = self.senders.get(&to) {
+            sender.push(priority, message);
+            return;
+        }
+
+        let queue = self.outgoing.entry(to).or_default();
+        if queue.clear_through(priority) {
+            match socket::send(self.socket_fd, &message, MsgFlags::MSG_DONTWAIT) {
+                Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+                result => {
+                    result.unwrap();
+                    return;
+                }
+            }
+        }
+        queue.push(priority, message);
+    }
+
+    fn serve_admin(&mut self) {
+        let requests: Vec<AdminRequest> = match &self.config.admin {
+            Some(admin) => std::iter::from_fn(|| admin.accept()).collect(),
+            None => return,
+        };
+        for mut req in requests {
+            let command = match std::mem::replace(&mut req.command, Err(String::new())) {
+                Ok(command) => command,
+                Err(e) => {
+                    req.respond(&json!({ "error": e }));
+                    continue;
+                }
+            };
+            self.record_event(format!("admin command {}", command.name()));
+            match command {
+                AdminCommand::Status => req.respond(&self.admin_status()),
+                AdminCommand::TransferLeadership(target) => self.start_leadership_transfer(target, TransferWaiting::Admin(req)),
+                AdminCommand::SnapshotNow => req.respond(&json!({
+                    // the core only snapshots once the log is as long as it's configured to get
+                    "error": "snapshots are taken by the raft core once the log reaches KV_SNAPSHOT_MIN_LOG_SIZE entries",
+                    "log_entries": self.config.storage_metrics.log().entries,
+                    "snapshots": self.config.snapshot_pause.to_json(),
+                })),
+            }
+        }
+    }
+
+    fn admin_status(&self) -> serde_json::Value {
+        let log = self.config.storage_metrics.log();
+        json!({
+            "id": self.our_name,
+            "role": self.role_of(self.our_id),
+            "leader": self.leader_id.map(num_to_network_name),
+            "term": log.current_term,
+            // entries are applied as soon as they're known to be committed, so this is as far as this node knows
+            "commit_index": self.applied_index,
+            "last_index": log.last_index,
+            "peers": self.members(),
+            "leadership_transfer": self.leadership_transfer.as_ref().map(|transfer| json!({
+                "target": num_to_network_name(transfer.target),
+                "elapsed_ms": transfer.started.elapsed().as_millis() as u64,
+                "sent": transfer.sent,
+            })),
+        })
+    }
+
+    // The leader stops taking commands like it does for a step down, waits for the ones in flight and for the target to
+    // report it has every entry, then tells the target to start an election, which it wins with the newest log and a
+    // higher term. Answered once leadership has moved or the transfer times out.
+    fn start_leadership_transfer(&mut self, target: u32, waiting: TransferWaiting) {
+        if self.leader_id == Some(target) {
+            self.answer_leadership_transfer(waiting, json!({ "transferred": true, "leader": num_to_network_name(target) }));
+            return;
+        }
+        let error = if self.leader_id != Some(self.our_id) {
+            "not the leader"
+        } else if self.leadership_transfer.is_some() {
+            "a leadership transfer is already in progress"
+        } else if self.peers.get(&target) != Some(&PeerStatus::Verified) {
+            "the target isn't a connected peer"
+        } else {
+            self.record_event(format!("transferring leadership to {}", num_to_network_name(target)));
+            if self.stepdown.is_none() {
+                self.stepdown = Some(StepDown { waiting: None, started: Instant::now() });
+            }
+            self.leadership_transfer = Some(LeadershipTransfer { target, started: Instant::now(), sent: false, waiting });
+            self.transfer_leadership_if_ready();
+            return;
+        };
+        self.answer_leadership_transfer(waiting, json!({ "transferred": false, "error": error }));
+    }
+
+    fn transfer_leadership_if_ready(&mut self) {
+        let (target, started, sent) = match &self.leadership_transfer {
+            Some(transfer) => (transfer.target, transfer.started, transfer.sent),
+            None => return,
+        };
+
+        let result = match self.leader_id {
+            Some(leader) if leader != self.our_id => Some(json!({
+                "transferred": leader == target,
+                "leader": num_to_network_name(leader),
+            })),
+            _ if started.elapsed() >= LEADERSHIP_TRANSFER_TIMEOUT => {
+                // there's no reason to keep refusing commands if we're still the leader
+                self.stepdown = None;
+                Some(json!({ "transferred": false, "error": "timed out" }))
+            }
+            _ => None,
+        };
+        if let Some(result) = result {
+            self.record_event(format!("leadership transfer to {} finished: {}", num_to_network_name(target), result));
+            if let Some(transfer) = self.leadership_transfer.take() {
+                self.answer_leadership_transfer(transfer.waiting, result);
+            }
+            return;
+        }
+
+        let caught_up = self.peer_last_index.get(&target).map_or(false, |index| *index >= self.config.storage_metrics.log().last_index);
+        if !sent && self.leader_id == Some(self.our_id) && self.in_flight == 0 && caught_up {
+            self.send_message_to(target, self.leader_id, JsonMessageType::TimeoutNow {});
+            if let Some(transfer) = &mut self.leadership_transfer {
+                transfer.sent = true;
+            }
+        }
+    }
+
+    fn answer_leadership_transfer(&mut self, waiting: TransferWaiting, mut result: serde_json::Value) {
+        match waiting {
+            TransferWaiting::Admin(req) => req.respond(&result),
+            TransferWaiting::Stepdown(client_id, mid) => {
+                result["drained"] = json!(self.in_flight == 0);
+                result["in_flight"] = json!(self.in_flight);
+                self.send_message_to(client_id, self.leader_id, JsonMessageType::Stepdown { mid: &mid, result: Some(result) });
+            }
+            TransferWaiting::RecommendLeader(client_id, mid, mut recommendation) => {
+                recommendation["executed"] = result["transferred"].clone();
+                recommendation["transfer"] = result;
+                self.send_message_to(client_id, self.leader_id, JsonMessageType::RecommendLeader { mid: &mid, execute: true, recommendation: Some(recommendation) });
+            }
+        }
+    }
+
+    // a client retrying against a follower has already been told where the leader is, unless it's changed since
+    fn send_redirect(&mut self, client_id: u32, leader_id: u32, mid: &str) {
+        if let Some(redirects) = &mut self.recent_redirects {
+            if !redirects.should_send(client_id, mid, leader_id, Instant::now()) {
+                return;
+            }
+        }
+        self.send_message_to(client_id, Some(leader_id), JsonMessageType::Response(Response::Redirect { mid: mid.to_string() }));
+    }
+
+    fn release_expired_responses(&mut self) {
+        let released = match &mut self.response_order {
+            Some(order) => order.expire(Instant::now()),
+            None => return,
+        };
+        for (client, message) in released {
+            self.send_bytes(client, Priority::Control, message);
+        }
+    }
+
+    fn has_queued_messages(&self) -> bool {
+        self.outgoing.values().any(|q| !q.is_empty())
+    }
+
+    // sends queued control messages for every peer before any bulk ones, stopping once the socket backs up again
+    fn flush_queued_messages(&mut self) {
+        for priority in &[Priority::Control, Priority::Bulk] {
+            for queue in self.outgoing.values_mut() {
+                let messages = queue.get_mut(*priority);
+                while let Some(message) = messages.front() {
+                    match socket::send(self.socket_fd, message, MsgFlags::MSG_DONTWAIT) {
+                        Err(nix::Error::Sys(Errno::EAGAIN)) => return,
+                        result => {
+                            result.unwrap();
+                            messages.pop_front();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn client_command(client_id: u32, mid: &str, op: KvOp) -> MessageEvent<KvCommand, ReadValueRequest> {
+    MessageEvent::ClientCommand(ClientCommandRequest {
+        request_id: hash(mid),
+        client_id,
+        command: KvCommand { mid: mid.to_string(), op },
+    })
+}
+
+// Splits a bulk load into batch commands. Raft applies them in order, so the client is only answered once the last
+// one, which carries the client's MID, is applied.
+fn bulk_load_commands(client_id: u32, mid: &str, pairs: Vec<(String, String)>) -> Vec<MessageEvent<KvCommand, ReadValueRequest>> {
+    let mut batches = vec![vec![]];
+    let mut batch_bytes = 0;
+    for (key, value) in pairs {
+        if batch_bytes > 0 && batch_bytes + key.len() + value.len() > BULK_LOAD_BATCH_BYTES {
+            batches.push(vec![]);
+            batch_bytes = 0;
+        }
+        batch_bytes += key.len() + value.len();
+        batches.last_mut().unwrap().push((key, value));
+    }
+
+    let last = batches.len() - 1;
+    batches.into_iter().enumerate().map(|(i, batch)| {
+        let op = KvOp::Batch(BatchSetCommand(batch));
+        if i == last {
+            client_command(client_id, mid, op)
+        } else {
+            client_command(client_id, &format!("{}{}{}", mid, BULK_LOAD_PART_SEPARATOR, i), op)
+        }
+    }).collect()
+}
+
+fn is_bulk_load_part(mid: &str) -> bool {
+    mid.contains(BULK_LOAD_PART_SEPARATOR)
+}
+
+// bulk load parts and the commands the leader proposes itself
+fn has_no_client(mid: &str) -> bool {
+    is_bulk_load_part(mid) || mid.starts_with(INTERNAL_MID_PREFIX)
+}
+
+fn scan_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_SCAN_LIMIT).min(MAX_SCAN_LIMIT)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+impl NetworkInterface<KvStateMachine> for Cs3700UnixNetwork {
+    type ReadRequest = ReadValueRequest;
+
+    fn on_config_update(&mut self, config: &Config) {
+        self.nodes = config.nodes.clone();
+        self.raft_config = Some(config.clone());
+
+        let heartbeat = Duration::from_millis(config.heartbeat_timeout as u64);
+        match (&mut self.commit_tuner, self.config.commit_latency_target) {
+            (Some(tuner), _) => tuner.set_max_window(heartbeat),
+            (None, Some(target)) => self.commit_tuner = Some(CommitTuner::new(target, heartbeat)),
+            (None, None) => {}
+        }
+
+        self.senders.retain(|id, _| config.nodes.contains_key(id));
+        let (our_id, socket_fd) = (self.our_id, self.socket_fd);
+        for id in config.nodes.keys().filter(|id| **id != our_id) {
+            self.senders.entry(*id).or_insert_with(|| PeerSender::spawn(socket_fd, &num_to_network_name(*id)));
+        }
+    }
+
+    fn wait_for_message(&mut self, timeout: Duration, raft_message: &mut Vec<u8>) -> MessageEvent<<KvStateMachine as StateMachine>::Command, Self::ReadRequest> {
+        self.watchdog(Stage::NetworkUpkeep);
+        let event = self.wait_for_event(timeout, raft_message);
+        self.watchdog(Stage::Core);
+        event
+    }
+
+    fn send_raft_message(&mut self, node: u32, leader_id: Option<u32>, msg: impl WriteBytes) {
+        let _subsystem = alloc_stats::enter(Subsystem::Buffers);
+        if leader_id != self.leader_id {
+            self.record_event(match leader_id {
+                Some(id) => format!("leader is now {}", num_to_network_name(id)),
+                None => "no leader".to_string(),
+            });
+            // whatever was in flight is redirected or dropped by the core, and timing it across the change means nothing
+            self.command_started.clear();
+            // a new leader pushes its own snapshot, which can't be pieced together with parts of the old one's
+            if let Some(exchange) = &self.config.snapshot_pull {
+                exchange.finish();
+            }
+            if leader_id.is_some() {
+                self.counters.record_election();
+            }
+            if let Some(old) = self.leader_id {
+                self.last_leader = Some(old);
+            }
+            // the old leader probably went down around when it was last heard from, and the failover lasts until a
+            // client's write gets through again
+            if let Some(old) = self.last_leader.filter(|old| leader_id == Some(self.our_id) && *old != self.our_id) {
+                self.failover_from = Some(self.failure_detectors.get(&old).and_then(|d| d.last_arrival()).unwrap_or_else(Instant::now));
+            }
+            // A node that wasn't the leader only sees the state machine when it answers a read, so a new leader reads it
+            // once to find out which keys and schedules are due.
+            if leader_id == Some(self.our_id) {
+                let mid = format!("{}sync-{}", INTERNAL_MID_PREFIX, now_ms());
+                if let Some(event) = self.client_read(self.our_id, mid, String::new(), ReadKind::Sync, 0) {
+                    self.pending_events.push_back(event);
+                }
+            }
+        }
+        self.leader_id = leader_id;
+        if leader_id != Some(self.our_id) {
+            self.stepdown = None;
+            self.failover_from = None;
+        }
+
+        match self.peers.get(&node) {
+            Some(PeerStatus::Verified) => {}
+            Some(PeerStatus::Rejected) => return,
+            None => self.send_hello(node),
+        }
+
+        let mut data = vec![];
+        let amt = match msg.write_bytes_with_writer(&mut data) {
+            Ok(amt) => amt,
+            Err(e) => {
+                self.record_event(format!("couldn't serialize a raft message for {}: {}", num_to_network_name(node), e));
+                return;
+            }
+        };
+        let priority = if amt > BULK_MESSAGE_SIZE { Priority::Bulk } else { Priority::Control };
+
+        // a suspected peer probably won't receive it anyway, heartbeats keep probing until it's heard from again
+        if priority == Priority::Bulk && self.suspicion(node) > self.config.suspicion_threshold {
+            return;
+        }
+        self.send_message_with_priority(node, leader_id, JsonMessageType::RaftRef { data: &data[..amt] }, priority)
+    }
+
+    fn handle_command_applied(&mut self, req: ClientCommandRequest<&<KvStateMachine as StateMachine>::Command>, state_machine: &KvStateMachine) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.finish_stepdown_if_drained();
+        self.check_apply_breaker(state_machine.apply_stats());
+
+        let mid = &req.command.mid;
+        self.record_commit_latency(req.client_id, mid);
+        self.note_state_machine(state_machine);
+        if mid.starts_with(EXPIRE_MID_PREFIX) {
+            self.notify_expired(mid, state_machine);
+        }
+        if has_no_client(mid) {
+            self.answer_deferred_reads(state_machine);
+            return;
+        }
+
+        if let Some(since) = self.failover_from.take() {
+            self.record_failover(since.elapsed());
+        }
+        self.forget_replay(req.client_id, mid);
+        self.send_command_result(req.client_id, mid, state_machine);
+        self.answer_deferred_reads(state_machine);
+    }
+
+    fn handle_ready_to_read(&mut self, req: Self::ReadRequest, state_machine: &KvStateMachine) {
+        self.pending_reads = self.pending_reads.saturating_sub(1);
+        self.last_read_quorum = Some(Instant::now());
+        self.note_state_machine(state_machine);
+
+        let round = req.round;
+        self.send_read_response(req, state_machine, "linearizable");
+        for req in self.read_rounds.remove(&round).unwrap_or_default() {
+            self.pending_reads = self.pending_reads.saturating_sub(1);
+            self.batched_reads += 1;
+            self.send_read_response(req, state_machine, "linearizable");
+        }
+        if self.confirming_round.map(|(r, _)| r) == Some(round) {
+            self.start_next_read_round();
+        }
+        self.answer_deferred_reads(state_machine);
+    }
+
+    fn redirect_command_request(&mut self, leader_id: u32, req: ClientCommandRequest<<KvStateMachine as StateMachine>::Command>) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.command_started.remove(&(req.client_id, req.command.mid.clone()));
+        self.forget_replay(req.client_id, &req.command.mid);
+        if has_no_client(&req.command.mid) {
+            return;
+        }
+        self.send_redirect(req.client_id, leader_id, &req.command.mid);
+    }
+
+    fn redirect_read_request(&mut self, leader_id: u32, req: Self::ReadRequest) {
+        self.pending_reads = self.pending_reads.saturating_sub(1);
+        if req.kind != ReadKind::Sync {
+            self.send_redirect(req.client_id, leader_id, &req.mid);
+        }
+
+        // every batched read would end up at the same leader
+        let batched: Vec<ReadValueRequest> = std::mem::take(&mut self.read_rounds).into_iter().flat_map(|(_, batch)| batch).collect();
+        self.pending_reads = self.pending_reads.saturating_sub(batched.len());
+        self.confirming_round = None;
+
+        for req in batched.into_iter().chain(std::mem::take(&mut self.stale_reads)).filter(|req| req.kind != ReadKind::Sync) {
+            self.send_redirect(req.client_id, leader_id, &req.mid);
+        }
+    }
+}
+
+// Carries a node's messages to the other members by something other than the simulator, so it can run across
+// machines with the members' addresses given as host:port.
+pub trait Transport: Sized {
+    // the socket for the node to use, routed by dst like the simulator's
+    fn start(listen: &str) -> io::Result<(RawFd, Self)>;
+
+    fn set_members(&self, nodes: &HashMap<u32, NodeAddress>);
+}
+
+pub type TcpNetwork = TransportNetwork<TcpTransport>;
+pub type UdpNetwork = TransportNetwork<UdpTransport>;
+
+// The same node over a Transport instead of the simulator. Clients use the HTTP port since there's nothing to route
+// their messages back to them.
+pub struct TransportNetwork<T: Transport> {
+    inner: Cs3700UnixNetwork,
+    transport: T,
+}
+
+impl<T: Transport> TransportNetwork<T> {
+    pub fn new(our_id: u32, cluster_id: ClusterId, config: NetworkConfig, listen: &str) -> io::Result<TransportNetwork<T>> {
+        let (socket_fd, transport) = T::start(listen)?;
+        Ok(TransportNetwork { inner: Cs3700UnixNetwork::with_socket(socket_fd, our_id, cluster_id, config), transport })
+    }
+}
+
+impl<T: Transport> NetworkInterface<KvStateMachine> for TransportNetwork<T> {
+    type ReadRequest = ReadValueRequest;
+
+    fn on_config_update(&mut self, config: &Config) {
+        self.transport.set_members(&config.nodes);
+        self.inner.on_config_update(config);
+    }
+
+    fn wait_for_message(&mut self, timeout: Duration, raft_message: &mut Vec<u8>) -> MessageEvent<<KvStateMachine as StateMachine>::Command, Self::ReadRequest> {
+        self.inner.wait_for_message(timeout, raft_message)
+    }
+
+    fn send_raft_message(&mut self, node: u32, leader_id: Option<u32>, msg: impl WriteBytes) {
+        self.inner.send_raft_message(node, leader_id, msg)
+    }
+
+    fn handle_command_applied(&mut self, req: ClientCommandRequest<&<KvStateMachine as StateMachine>::Command>, state_machine: &KvStateMachine) {
+        self.inner.handle_command_applied(req, state_machine)
+    }
+
+    fn handle_ready_to_read(&mut self, req: Self::ReadRequest, state_machine: &KvStateMachine) {
+        self.inner.handle_ready_to_read(req, state_machine)
+    }
+
+    fn redirect_command_request(&mut self, leader_id: u32, req: ClientCommandRequest<<KvStateMachine as StateMachine>::Command>) {
+        self.inner.redirect_command_request(leader_id, req)
+    }
+
+    fn redirect_read_request(&mut self, leader_id: u32, req: Self::ReadRequest) {
+        self.inner.redirect_read_request(leader_id, req)
+    }
+}
+
+#[derive(Deserialize)]
+struct Routing<'a> {
+    dst: &'a str,
+}
+
+// the member a message the node sent is for
+pub fn routed_to(message: &[u8]) -> Option<u32> {
+    let routing: Routing = serde_json::from_slice(message).ok()?;
+    u32::from_str_radix(routing.dst, 16).ok()
+}
+
+// the host:port of every member
+pub fn member_addresses(nodes: &HashMap<u32, NodeAddress>) -> HashMap<u32, String> {
+    nodes.iter()
+        .filter_map(|(id, address)| match address {
+            NodeAddress::String(address) => Some((*id, address.clone())),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        })
+        .collect()
+}
+
+// members from the command line when running over a Transport, given as <id>=<host:port>
+pub fn parse_member(arg: &str) -> Option<(u32, NodeAddress)> {
+    let separator = arg.find('=')?;
+    let id = u32::from_str_radix(&arg[..separator], 16).ok()?;
+    Some((id, NodeAddress::String(arg[separator + 1..].to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use my_raft::config::NodeAddress;
+    use my_project6::protocol::{Message, Request, Response, ScheduledRequest};
+
+    use crate::network::{JsonMessage, JsonMessageType, parse_member, routed_to};
+
+    // every client request in the shared protocol has to parse here, and responses sent from here have to parse there
+    #[test]
+    fn matches_client_protocol() {
+        let requests = vec![
+            Request::Get { mid: "1".to_string(), key: "k".to_string(), min_index: 5 },
+            Request::Exists { mid: "2".to_string(), key: "k".to_string(), min_index: 0 },
+            Request::Type { mid: "3".to_string(), key: "k".to_string(), min_index: 0 },
+            Request::Strlen { mid: "4".to_string(), key: "k".to_string(), min_index: 0 },
+            Request::Put { mid: "5".to_string(), key: "k".to_string(), value: "v".to_string(), sensitive: true },
+            Request::GetSet { mid: "6".to_string(), key: "k".to_string(), value: "v".to_string() },
+            Request::GetDel { mid: "7".to_string(), key: "k".to_string() },
+            Request::Defrag { mid: "8".to_string() },
+            Request::Eval { mid: "9".to_string(), key: "k".to_string(), script: "concat(value, \"!\")".to_string() },
+            Request::Register { mid: "10".to_string(), name: "incr".to_string(), script: "add(value, arg)".to_string() },
+            Request::Call { mid: "11".to_string(), name: "incr".to_string(), key: "k".to_string(), arg: "1".to_string() },
+            Request::BulkLoad { mid: "12".to_string(), pairs: vec![("k".to_string(), "v".to_string())] },
+            Request::Rename { mid: "13".to_string(), from: "k".to_string(), to: "k2".to_string() },
+            Request::Copy { mid: "14".to_string(), from: "k".to_string(), to: "k2".to_string() },
+            Request::DeleteIf { mid: "15".to_string(), key: "k".to_string(), value: None, version: Some(3) },
+            Request::Wait { mid: "16".to_string(), target: "5".to_string(), timeout_ms: 1000 },
+            Request::Delete { mid: "17".to_string(), key: "k".to_string() },
+            Request::MultiPut { mid: "18".to_string(), pairs: vec![("k".to_string(), "v".to_string()), ("k2".to_string(), "v2".to_string())] },
+            Request::PutTtl { mid: "19".to_string(), key: "k".to_string(), value: "v".to_string(), ttl_ms: 60_000, notify: true },
+            Request::Prefix { mid: "20".to_string(), prefix: "user:".to_string(), limit: Some(10), min_index: 0 },
+            Request::Range { mid: "21".to_string(), start: "a".to_string(), end: Some("b".to_string()), limit: None, min_index: 0 },
+            Request::Schedule { mid: "22".to_string(), name: "tick".to_string(), delay_ms: 0, interval_ms: 1000, command: ScheduledRequest::Call { name: "incr".to_string(), key: "k".to_string(), arg: "1".to_string() } },
+            Request::Unschedule { mid: "23".to_string(), name: "tick".to_string() },
+            Request::Append { mid: "24".to_string(), key: "k".to_string(), value: "v".to_string() },
+            Request::Incr { mid: "25".to_string(), key: "k".to_string(), by: Some(-2) },
+            Request::JsonSet { mid: "26".to_string(), key: "k".to_string(), path: "a.b[0]".to_string(), value: serde_json::json!({ "c": [1, "two"] }) },
+            Request::JsonGet { mid: "27".to_string(), key: "k".to_string(), path: "a".to_string(), min_index: 0 },
+            Request::Tombstones { mid: "28".to_string(), prefix: "user:".to_string(), since_ms: 1000, limit: None, min_index: 0 },
+        ];
+        for request in requests {
+            let bytes = serde_json::to_vec(&Message::new("C000", "0001", None, request.clone())).unwrap();
+            let message: JsonMessage = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(message.data.kind(), serde_json::to_value(&request).unwrap()["type"].as_str().unwrap());
+            assert!(matches!(message.data, JsonMessageType::Request(parsed) if parsed == request));
+        }
+
+        // node messages aren't mistaken for client requests
+        let hello: JsonMessage = serde_json::from_slice(br#"{"src":"0002","dst":"0001","leader":"FFFF","type":"hello","version":1,"cluster":"kv"}"#).unwrap();
+        assert!(matches!(hello.data, JsonMessageType::Hello { version: 1, cluster: "kv", last_index: 0 }));
+
+        let responses = vec![
+            Response::Ok { mid: "1".to_string(), value: Some("v".to_string()), read: Some("stale".to_string()), index: Some(3) },
+            Response::Fail { mid: "2".to_string() },
+            Response::Redirect { mid: "3".to_string() },
+            Response::Expired { key: "lease".to_string() },
+        ];
+        for response in responses {
+            let data = JsonMessageType::Response(response.clone());
+            let bytes = serde_json::to_vec(&JsonMessage { src: "0001", dst: "C000", leader: "0001", load: Some("green"), data }).unwrap();
+            let sent: Message<Response> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(sent.leader(), Some("0001"));
+            assert_eq!(sent.load.as_deref(), Some("green"));
+            assert_eq!(sent.body, response);
+        }
+    }
+
+    #[test]
+    fn parses_members() {
+        assert!(matches!(parse_member("000A=db-1:7100"), Some((10, NodeAddress::String(address))) if address == "db-1:7100"));
+        assert!(parse_member("db-1:7100").is_none());
+        assert!(parse_member("zz=db-1:7100").is_none());
+
+        assert_eq!(routed_to(br#"{"src":"0000","dst":"001F","leader":"FFFF","type":"pong","sent_us":1}"#), Some(0x1f));
+        assert_eq!(routed_to(b"not json"), None);
     }
 }
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use nix::sys::socket;
+use nix::sys::socket::MsgFlags;
+
+// raft retries lost AppendEntries and snapshot chunks, so old bulk messages can be dropped past this many
+const MAX_QUEUED_BULK_MESSAGES: usize = 64;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Priority {
+    Control,
+    Bulk,
+}
+
+#[derive(Default)]
+pub struct PeerQueue {
+    control: VecDeque<Vec<u8>>,
+    bulk: VecDeque<Vec<u8>>,
+}
+
+impl PeerQueue {
+    pub fn get_mut(&mut self, priority: Priority) -> &mut VecDeque<Vec<u8>> {
+        match priority {
+            Priority::Control => &mut self.control,
+            Priority::Bulk => &mut self.bulk,
+        }
+    }
+
+    // whether a message of this priority can go out now without overtaking a queued one
+    pub fn clear_through(&self, priority: Priority) -> bool {
+        match priority {
+            Priority::Control => self.control.is_empty(),
+            Priority::Bulk => self.control.is_empty() && self.bulk.is_empty(),
+        }
+    }
+
+    pub fn push(&mut self, priority: Priority, message: Vec<u8>) {
+        let queue = self.get_mut(priority);
+        queue.push_back(message);
+        if priority == Priority::Bulk && queue.len() > MAX_QUEUED_BULK_MESSAGES {
+            queue.pop_front();
+        }
+    }
+
+    // the next message to send, control messages first
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.control.pop_front().or_else(|| self.bulk.pop_front())
+    }
+
+    pub fn len(&self) -> usize {
+        self.control.len() + self.bulk.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.bulk.is_empty()
+    }
+}
+
+struct SenderState {
+    queue: PeerQueue,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<SenderState>,
+    ready: Condvar,
+}
+
+// A thread sending one peer's messages, so a send that blocks while the socket is backed up only holds up that peer's
+// messages and never the receive loop or heartbeats to the others. The thread exits once this is dropped and its
+// queue is drained.
+pub struct PeerSender {
+    shared: Arc<Shared>,
+}
+
+impl PeerSender {
+    pub fn spawn(socket_fd: RawFd, peer_name: &str) -> PeerSender {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(SenderState { queue: PeerQueue::default(), closed: false }),
+            ready: Condvar::new(),
+        });
+
+        let worker = shared.clone();
+        let name = peer_name.to_string();
+        thread::Builder::new()
+            .name(format!("send-{}", peer_name))
+            .spawn(move || run(socket_fd, &name, &worker))
+            .expect("could not start send thread");
+
+        PeerSender { shared }
+    }
+
+    pub fn push(&self, priority: Priority, message: Vec<u8>) {
+        self.shared.state.lock().unwrap().queue.push(priority, message);
+        self.shared.ready.notify_one();
+    }
+
+    pub fn queued(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+}
+
+impl Drop for PeerSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+    }
+}
+
+fn run(socket_fd: RawFd, peer_name: &str, shared: &Shared) {
+    loop {
+        let message = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(message) = state.queue.pop() {
+                    break message;
+                }
+                if state.closed {
+                    return;
+                }
+                state = shared.ready.wait(state).unwrap();
+            }
+        };
+
+        if let Err(e) = socket::send(socket_fd, &message, MsgFlags::empty()) {
+            eprintln!("failed to send to {}: {}", peer_name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::socket;
+    use nix::sys::socket::{AddressFamily, MsgFlags, SockFlag, SockType};
+    use nix::unistd;
+
+    use crate::peer_sender::{PeerQueue, PeerSender, Priority};
+
+    #[test]
+    fn control_before_bulk() {
+        let mut queue = PeerQueue::default();
+        queue.push(Priority::Bulk, b"append".to_vec());
+        queue.push(Priority::Control, b"vote".to_vec());
+        assert!(!queue.clear_through(Priority::Bulk));
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.pop(), Some(b"vote".to_vec()));
+        assert_eq!(queue.pop(), Some(b"append".to_vec()));
+        assert_eq!(queue.pop(), None);
+
+        for i in 0..100u8 {
+            queue.push(Priority::Bulk, vec![i]);
+        }
+        assert_eq!(queue.len(), 64);
+        assert_eq!(queue.pop(), Some(vec![36]));
+    }
+
+    #[test]
+    fn sends_from_worker() {
+        let (ours, theirs) = socket::socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::empty()).unwrap();
+
+        let sender = PeerSender::spawn(ours, "0001");
+        sender.push(Priority::Control, b"one".to_vec());
+        sender.push(Priority::Bulk, b"two".to_vec());
+        drop(sender);
+
+        let mut received = vec![];
+        let mut buffer = [0u8; 16];
+        for _ in 0..2 {
+            let amt = socket::recv(theirs, &mut buffer, MsgFlags::empty()).unwrap();
+            received.push(buffer[..amt].to_vec());
+        }
+        received.sort();
+        assert_eq!(received, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        unistd::close(ours).unwrap();
+        unistd::close(theirs).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::metrics::Histogram;
+
+// What the /metrics exporter counts. Unlike the metrics behind /metrics/dump these are never reset, Prometheus works
+// out rates itself and takes a counter going down to mean the node restarted.
+#[derive(Default)]
+pub struct Counters {
+    // times a new leader was heard of, each of which won an election
+    leader_elections: u64,
+    sent: BTreeMap<&'static str, u64>,
+    received: BTreeMap<&'static str, u64>,
+    apply_us: Histogram,
+    // from last hearing from the old leader to the first client write committed as the new one
+    failover_us: Histogram,
+    failovers_over_budget: u64,
+}
+
+// a value as of when it's scraped, read from wherever it's kept
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: f64,
+}
+
+impl Counters {
+    pub fn record_election(&mut self) {
+        self.leader_elections += 1;
+    }
+
+    pub fn record_sent(&mut self, kind: &'static str) {
+        *self.sent.entry(kind).or_default() += 1;
+    }
+
+    pub fn record_received(&mut self, kind: &'static str) {
+        *self.received.entry(kind).or_default() += 1;
+    }
+
+    pub fn record_apply(&mut self, took_us: u64) {
+        self.apply_us.record(took_us);
+    }
+
+    pub fn record_failover(&mut self, took_us: u64, over_budget: bool) {
+        self.failover_us.record(took_us);
+        if over_budget {
+            self.failovers_over_budget += 1;
+        }
+    }
+
+    // everything in the Prometheus text format
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut out = String::new();
+        for gauge in gauges {
+            write_header(&mut out, gauge.name, gauge.help, "gauge");
+            writeln!(out, "{} {}", gauge.name, gauge.value).unwrap();
+        }
+
+        write_header(&mut out, "raft_leader_elections_total", "New leaders this node has heard of.", "counter");
+        writeln!(out, "raft_leader_elections_total {}", self.leader_elections).unwrap();
+        write_by_type(&mut out, "kv_messages_sent_total", "Messages sent, by type.", &self.sent);
+        write_by_type(&mut out, "kv_messages_received_total", "Messages received, by type.", &self.received);
+        self.apply_us.write_prometheus(&mut out, "kv_apply_duration_seconds", "Time taken applying each command to the state machine.");
+        self.failover_us.write_prometheus(&mut out, "kv_failover_duration_seconds", "Time from the old leader going quiet to this node committing a client write as the new one.");
+        write_header(&mut out, "kv_failovers_over_budget_total", "Failovers that took longer than KV_FAILOVER_BUDGET_MS.", "counter");
+        writeln!(out, "kv_failovers_over_budget_total {}", self.failovers_over_budget).unwrap();
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_by_type(out: &mut String, name: &str, help: &str, counts: &BTreeMap<&'static str, u64>) {
+    write_header(out, name, help, "counter");
+    for (kind, count) in counts {
+        writeln!(out, "{}{{type=\"{}\"}} {}", name, kind, count).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prometheus::{Counters, Gauge};
+
+    #[test]
+    fn renders_text_format() {
+        let mut counters = Counters::default();
+        counters.record_election();
+        counters.record_sent("raft");
+        counters.record_sent("raft");
+        counters.record_received("get");
+        counters.record_apply(3);
+        counters.record_apply(1500);
+        counters.record_failover(300_000, true);
+
+        let text = counters.render(&[Gauge { name: "raft_current_term", help: "The current term.", value: 4.0 }]);
+        let lines: Vec<&str> = text.lines().filter(|line| !line.starts_with('#') && !line.contains("failover")).collect();
+        assert_eq!(lines, vec![
+            "raft_current_term 4",
+            "raft_leader_elections_total 1",
+            "kv_messages_sent_total{type=\"raft\"} 2",
+            "kv_messages_received_total{type=\"get\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000001\"} 0",
+            "kv_apply_duration_seconds_bucket{le=\"0.000002\"} 0",
+            "kv_apply_duration_seconds_bucket{le=\"0.000004\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000008\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000016\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000032\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000064\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000128\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000256\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.000512\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.001024\"} 1",
+            "kv_apply_duration_seconds_bucket{le=\"0.002048\"} 2",
+            "kv_apply_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "kv_apply_duration_seconds_sum 0.001503",
+            "kv_apply_duration_seconds_count 2",
+        ]);
+        assert!(text.contains("kv_failover_duration_seconds_bucket{le=\"0.524288\"} 1\n"));
+        assert!(text.contains("kv_failover_duration_seconds_count 1\n"));
+        assert!(text.contains("kv_failovers_over_budget_total 1\n"));
+        assert!(text.contains("# TYPE kv_messages_sent_total counter\n"));
+    }
+}
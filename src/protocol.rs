@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+
+// The JSON protocol clients use to talk to replicas, exported by the crate so the soak test and clients share one
+// definition of every message. Replicas parse client requests into these types and answer with them too, alongside
+// the messages they only send each other in network.rs.
+
+// the leader field when there's no known leader
+pub const NO_LEADER: &str = "FFFF";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Message<T> {
+    pub src: String,
+    pub dst: String,
+    pub leader: String,
+    // how loaded the replica that sent a response is, green, yellow or red
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load: Option<String>,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T> Message<T> {
+    pub fn new(src: &str, dst: &str, leader: Option<&str>, body: T) -> Message<T> {
+        Message { src: src.to_string(), dst: dst.to_string(), leader: leader.unwrap_or(NO_LEADER).to_string(), load: None, body }
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        if self.leader == NO_LEADER {
+            None
+        } else {
+            Some(&self.leader)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Request {
+    // reads can pass the index from an earlier ok response as min_index to see at least that state
+    Get { #[serde(rename = "MID")] mid: String, key: String, #[serde(default, skip_serializing_if = "is_zero")] min_index: u32 },
+    Exists { #[serde(rename = "MID")] mid: String, key: String, #[serde(default, skip_serializing_if = "is_zero")] min_index: u32 },
+    Type { #[serde(rename = "MID")] mid: String, key: String, #[serde(default, skip_serializing_if = "is_zero")] min_index: u32 },
+    Strlen { #[serde(rename = "MID")] mid: String, key: String, #[serde(default, skip_serializing_if = "is_zero")] min_index: u32 },
+    // Answered with a value that's a JSON array of [key, value] pairs in key order, of the keys starting with the prefix or
+    // from start up to but not including end. At most limit pairs are returned, 100 if it isn't given and never more than
+    // 1000, so reading everything means asking again from just past the last key.
+    Prefix {
+        #[serde(rename = "MID")] mid: String,
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] limit: Option<usize>,
+        #[serde(default, skip_serializing_if = "is_zero")] min_index: u32,
+    },
+    Range {
+        #[serde(rename = "MID")] mid: String,
+        start: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] end: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] limit: Option<usize>,
+        #[serde(default, skip_serializing_if = "is_zero")] min_index: u32,
+    },
+    // Answered with a value that's a JSON array of [key, deleted_at_ms, index] for the keys starting with the prefix that
+    // were deleted at or after since_ms, in key order and limited like prefix. index is where the delete was applied.
+    // Deletes are only kept while the replicas are started with a tombstone retention, and for that long.
+    Tombstones {
+        #[serde(rename = "MID")] mid: String,
+        #[serde(default)] prefix: String,
+        #[serde(default, skip_serializing_if = "is_zero_u64")] since_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")] limit: Option<usize>,
+        #[serde(default, skip_serializing_if = "is_zero")] min_index: u32,
+    },
+    // sensitive values are encrypted before they enter the log
+    Put { #[serde(rename = "MID")] mid: String, key: String, value: String, #[serde(default, skip_serializing_if = "is_false")] sensitive: bool },
+    GetSet { #[serde(rename = "MID")] mid: String, key: String, value: String },
+    GetDel { #[serde(rename = "MID")] mid: String, key: String },
+    Delete { #[serde(rename = "MID")] mid: String, key: String },
+    Defrag { #[serde(rename = "MID")] mid: String },
+    Eval { #[serde(rename = "MID")] mid: String, key: String, script: String },
+    Register { #[serde(rename = "MID")] mid: String, name: String, script: String },
+    Call { #[serde(rename = "MID")] mid: String, name: String, key: String, #[serde(default)] arg: String },
+    // both replace whatever the to key held, and fail when the from key isn't set
+    Rename { #[serde(rename = "MID")] mid: String, from: String, to: String },
+    Copy { #[serde(rename = "MID")] mid: String, from: String, to: String },
+    // Answered with ok once the write with the MID in for has been applied, with the index it was applied at and a value
+    // of "ok" or "failed" for how it went, or with fail after the timeout. Only the last 1024 writes are remembered.
+    Wait { #[serde(rename = "MID")] mid: String, #[serde(rename = "for")] target: String, #[serde(default, skip_serializing_if = "is_zero_u64")] timeout_ms: u64 },
+    // Deletes the key only if it has the value, or the version, which is the index in the ok response to the write that
+    // last set it. Exactly one of them has to be given, and values put as sensitive can only be matched by version.
+    #[serde(rename = "delete_if")]
+    DeleteIf {
+        #[serde(rename = "MID")] mid: String,
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] value: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] version: Option<u32>,
+    },
+    #[serde(rename = "bulk_load")]
+    BulkLoad { #[serde(rename = "MID")] mid: String, pairs: Vec<(String, String)> },
+    // Sets every pair in a single log entry, so either all of them are applied or none are. Fails without setting any
+    // if the pairs add up to more than 2048 bytes, which is what bulk_load is for.
+    #[serde(rename = "multi_put")]
+    MultiPut { #[serde(rename = "MID")] mid: String, pairs: Vec<(String, String)> },
+    // Sets the part of the key's value at the path, like $.a.b[2], to any JSON value, taking an unset key to be null. Keys
+    // missing along the way are added, and an index one past the end of an array appends to it. Fails if the key's value
+    // isn't JSON or the path doesn't fit it. json.get answers with the part at the path as JSON, or empty if there's
+    // nothing there. An empty path is the whole document.
+    #[serde(rename = "json.set")]
+    JsonSet { #[serde(rename = "MID")] mid: String, key: String, #[serde(default)] path: String, value: serde_json::Value },
+    #[serde(rename = "json.get")]
+    JsonGet {
+        #[serde(rename = "MID")] mid: String,
+        key: String,
+        #[serde(default)] path: String,
+        #[serde(default, skip_serializing_if = "is_zero")] min_index: u32,
+    },
+    // Answered with the value's new length. Values put as sensitive can't be appended to.
+    Append { #[serde(rename = "MID")] mid: String, key: String, value: String },
+    // Adds by, or 1 if it isn't given, to the key's value, which is taken to be 0 if the key isn't set. Answered with the
+    // new value, or fails if the value isn't a 64 bit integer or the sum doesn't fit in one.
+    Incr { #[serde(rename = "MID")] mid: String, key: String, #[serde(default, skip_serializing_if = "Option::is_none")] by: Option<i64> },
+    // fails if nothing's scheduled with the name
+    Unschedule { #[serde(rename = "MID")] mid: String, name: String },
+    // Sets the key for ttl_ms from when the node that takes it gets it, after which reads see it as unset. Putting the
+    // key again without a TTL keeps it for good. With notify, the client is sent an expired message once the key has
+    // expired and been deleted, if the node deleting it has heard from the client.
+    #[serde(rename = "put_ttl")]
+    PutTtl { #[serde(rename = "MID")] mid: String, key: String, value: String, ttl_ms: u64, #[serde(default, skip_serializing_if = "is_false")] notify: bool },
+    // Has the leader apply the command delay_ms from when this is received, and then every interval_ms if that isn't
+    // zero. Scheduling a name again replaces what it had. Runs missed while there's no leader aren't made up, the
+    // schedule just carries on from the next one.
+    Schedule {
+        #[serde(rename = "MID")] mid: String,
+        name: String,
+        #[serde(default, skip_serializing_if = "is_zero_u64")] delay_ms: u64,
+        #[serde(default, skip_serializing_if = "is_zero_u64")] interval_ms: u64,
+        command: ScheduledRequest,
+    },
+}
+
+impl Request {
+    pub fn mid(&self) -> &str {
+        match self {
+            Request::Get { mid, .. } | Request::Exists { mid, .. } | Request::Type { mid, .. } | Request::Strlen { mid, .. }
+            | Request::Prefix { mid, .. } | Request::Range { mid, .. } | Request::Tombstones { mid, .. } | Request::Put { mid, .. }
+            | Request::GetSet { mid, .. } | Request::GetDel { mid, .. } | Request::Delete { mid, .. } | Request::Defrag { mid }
+            | Request::Eval { mid, .. } | Request::Register { mid, .. } | Request::Call { mid, .. } | Request::Rename { mid, .. }
+            | Request::Copy { mid, .. } | Request::Wait { mid, .. } | Request::DeleteIf { mid, .. } | Request::BulkLoad { mid, .. }
+            | Request::MultiPut { mid, .. } | Request::Schedule { mid, .. } | Request::JsonSet { mid, .. } | Request::JsonGet { mid, .. }
+            | Request::Append { mid, .. } | Request::Incr { mid, .. } | Request::Unschedule { mid, .. } | Request::PutTtl { mid, .. } => mid,
+        }
+    }
+
+    // the type as it is on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Exists { .. } => "exists",
+            Request::Type { .. } => "type",
+            Request::Strlen { .. } => "strlen",
+            Request::Prefix { .. } => "prefix",
+            Request::Range { .. } => "range",
+            Request::Tombstones { .. } => "tombstones",
+            Request::Put { .. } => "put",
+            Request::GetSet { .. } => "getset",
+            Request::GetDel { .. } => "getdel",
+            Request::Delete { .. } => "delete",
+            Request::Defrag { .. } => "defrag",
+            Request::Eval { .. } => "eval",
+            Request::Register { .. } => "register",
+            Request::Call { .. } => "call",
+            Request::Rename { .. } => "rename",
+            Request::Copy { .. } => "copy",
+            Request::Wait { .. } => "wait",
+            Request::DeleteIf { .. } => "delete_if",
+            Request::BulkLoad { .. } => "bulk_load",
+            Request::MultiPut { .. } => "multi_put",
+            Request::Schedule { .. } => "schedule",
+            Request::JsonSet { .. } => "json.set",
+            Request::JsonGet { .. } => "json.get",
+            Request::Append { .. } => "append",
+            Request::Incr { .. } => "incr",
+            Request::Unschedule { .. } => "unschedule",
+            Request::PutTtl { .. } => "put_ttl",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ScheduledRequest {
+    Put { key: String, value: String },
+    Delete { key: String },
+    Eval { key: String, script: String },
+    Call { name: String, key: String, #[serde(default)] arg: String },
+    Append { key: String, value: String },
+    Incr { key: String, #[serde(default, skip_serializing_if = "Option::is_none")] by: Option<i64> },
+}
+
+impl ScheduledRequest {
+    pub fn key(&self) -> &str {
+        match self {
+            ScheduledRequest::Put { key, .. } | ScheduledRequest::Delete { key } | ScheduledRequest::Eval { key, .. }
+            | ScheduledRequest::Call { key, .. } | ScheduledRequest::Append { key, .. } | ScheduledRequest::Incr { key, .. } => key,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Response {
+    // index is the number of commands the replica had applied, for passing as min_index
+    Ok {
+        #[serde(rename = "MID")] mid: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] value: Option<String>,
+        // how a read was answered, "linearizable" or "stale"
+        #[serde(default, skip_serializing_if = "Option::is_none")] read: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")] index: Option<u32>,
+    },
+    Fail { #[serde(rename = "MID")] mid: String },
+    // retry with the replica in the leader field
+    Redirect { #[serde(rename = "MID")] mid: String },
+    // not an answer to a request, sent when a key put with notify expires
+    Expired { key: String },
+}
+
+impl Response {
+    pub fn mid(&self) -> &str {
+        match self {
+            Response::Ok { mid, .. } | Response::Fail { mid } | Response::Redirect { mid } => mid,
+            Response::Expired { .. } => "",
+        }
+    }
+
+    // the type as it is on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Response::Ok { .. } => "ok",
+            Response::Fail { .. } => "fail",
+            Response::Redirect { .. } => "redirect",
+            Response::Expired { .. } => "expired",
+        }
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_zero_u64(n: &u64) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
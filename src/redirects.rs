@@ -0,0 +1,69 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Remembers the client, MID and leader of each redirect for a little while, so a client retrying the same request
+// against a follower, like every client at once during an election, isn't sent the same redirect every time. A redirect
+// to a different leader than last time still goes out.
+pub struct RecentRedirects {
+    ttl: Duration,
+    sent: HashMap<(u32, String), u32>,
+    // in the order they were sent, which is the order they expire in
+    order: VecDeque<(u32, String, Instant)>,
+    suppressed: u64,
+}
+
+impl RecentRedirects {
+    pub fn new(ttl: Duration) -> RecentRedirects {
+        RecentRedirects { ttl, sent: HashMap::new(), order: VecDeque::new(), suppressed: 0 }
+    }
+
+    // whether to send the redirect, which is remembered if so
+    pub fn should_send(&mut self, client: u32, mid: &str, leader: u32, now: Instant) -> bool {
+        self.expire(now);
+        let key = (client, mid.to_string());
+        if self.sent.get(&key) == Some(&leader) {
+            self.suppressed += 1;
+            return false;
+        }
+        if self.sent.insert(key, leader).is_none() {
+            self.order.push_back((client, mid.to_string(), now));
+        }
+        true
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((client, mid, sent)) = self.order.front() {
+            if now.saturating_duration_since(*sent) < self.ttl {
+                return;
+            }
+            self.sent.remove(&(*client, mid.clone()));
+            self.order.pop_front();
+        }
+    }
+
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::redirects::RecentRedirects;
+
+    #[test]
+    fn suppresses_repeats_until_they_expire() {
+        let start = Instant::now();
+        let mut redirects = RecentRedirects::new(Duration::from_millis(100));
+        assert!(redirects.should_send(1, "a", 2, start));
+        assert!(!redirects.should_send(1, "a", 2, start + Duration::from_millis(50)));
+        assert!(redirects.should_send(1, "b", 2, start));
+        assert!(redirects.should_send(3, "a", 2, start));
+        // the leader changed, which the client needs to hear about
+        assert!(redirects.should_send(1, "a", 4, start + Duration::from_millis(60)));
+        assert!(!redirects.should_send(1, "a", 4, start + Duration::from_millis(70)));
+        assert!(redirects.should_send(1, "a", 4, start + Duration::from_millis(100)));
+        assert_eq!(redirects.suppressed(), 2);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+struct Slot {
+    mid: String,
+    response: Option<Vec<u8>>,
+    received: Instant,
+}
+
+// Holds back a client's responses until everything it sent before has been answered, so they arrive in the order its
+// requests did, which is the order the leader commits its commands in. Reads answered straight away, batched reads and
+// commands waiting on the apply budget can otherwise overtake one another. A request that's never answered, like one
+// dropped by a crashed leader, stops holding up the ones after it once it's been waiting for the timeout.
+pub struct ResponseOrder {
+    timeout: Duration,
+    clients: HashMap<u32, VecDeque<Slot>>,
+}
+
+impl ResponseOrder {
+    pub fn new(timeout: Duration) -> ResponseOrder {
+        ResponseOrder { timeout, clients: HashMap::new() }
+    }
+
+    pub fn received(&mut self, client: u32, mid: &str, now: Instant) {
+        self.clients.entry(client).or_default().push_back(Slot { mid: mid.to_string(), response: None, received: now });
+    }
+
+    // the responses that can be sent to the client now, in order, which is just this one if it wasn't waited for
+    pub fn respond(&mut self, client: u32, mid: &str, response: Vec<u8>) -> Vec<Vec<u8>> {
+        let slots = match self.clients.get_mut(&client) {
+            Some(slots) => slots,
+            None => return vec![response],
+        };
+        match slots.iter_mut().find(|slot| slot.response.is_none() && slot.mid == mid) {
+            Some(slot) => slot.response = Some(response),
+            None => return vec![response],
+        }
+        self.release(client, None)
+    }
+
+    // responses released by requests before them timing out, by client
+    pub fn expire(&mut self, now: Instant) -> Vec<(u32, Vec<u8>)> {
+        let expired: Vec<u32> = self.clients.iter()
+            .filter(|(_, slots)| slots.front().map_or(false, |slot| now.duration_since(slot.received) >= self.timeout))
+            .map(|(client, _)| *client)
+            .collect();
+        expired.into_iter()
+            .flat_map(|client| self.release(client, Some(now)).into_iter().map(move |response| (client, response)))
+            .collect()
+    }
+
+    fn release(&mut self, client: u32, now: Option<Instant>) -> Vec<Vec<u8>> {
+        let timeout = self.timeout;
+        let slots = match self.clients.get_mut(&client) {
+            Some(slots) => slots,
+            None => return vec![],
+        };
+        let mut released = vec![];
+        while let Some(slot) = slots.front_mut() {
+            match slot.response.take() {
+                Some(response) => released.push(response),
+                None if now.map_or(false, |now| now.duration_since(slot.received) >= timeout) => {}
+                None => break,
+            }
+            slots.pop_front();
+        }
+        if slots.is_empty() {
+            self.clients.remove(&client);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::response_order::ResponseOrder;
+
+    #[test]
+    fn in_request_order() {
+        let start = Instant::now();
+        let mut order = ResponseOrder::new(Duration::from_secs(1));
+        order.received(1, "a", start);
+        order.received(1, "b", start);
+        order.received(2, "c", start);
+        order.received(1, "d", start);
+
+        assert!(order.respond(1, "b", b"b".to_vec()).is_empty());
+        assert_eq!(order.respond(2, "c", b"c".to_vec()), vec![b"c".to_vec()]);
+        assert_eq!(order.respond(1, "a", b"a".to_vec()), vec![b"a".to_vec(), b"b".to_vec()]);
+        // not waited for
+        assert_eq!(order.respond(1, "x", b"x".to_vec()), vec![b"x".to_vec()]);
+
+        // d never gets an answer, so e is let go once d has waited long enough
+        order.received(1, "e", start + Duration::from_millis(500));
+        assert!(order.respond(1, "e", b"e".to_vec()).is_empty());
+        assert!(order.expire(start + Duration::from_millis(999)).is_empty());
+        assert_eq!(order.expire(start + Duration::from_secs(1)), vec![(1, b"e".to_vec())]);
+        assert_eq!(order.respond(1, "d", b"d".to_vec()), vec![b"d".to_vec()]);
+    }
+}
@@ -0,0 +1,278 @@
+// A tiny expression language for read-modify-write commands. A script is one expression computing a key's new value
+// from its current one, e.g. `if(eq(value, ""), 1, add(value, 1))`. Scripts run at apply time on every replica, so
+// everything here has to be deterministic: integers are checked i64s, and there are no loops, clocks or randomness.
+//
+// expr := "string" | integer | value | arg | name(expr, ...)
+
+const MAX_SCRIPT_LEN: usize = 1024;
+const MAX_VALUE_LEN: usize = 1 << 20;
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Str(String),
+    Int(i64),
+    Value,
+    Arg,
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Val {
+    Str(String),
+    Int(i64),
+}
+
+impl Val {
+    fn into_string(self) -> String {
+        match self {
+            Val::Str(s) => s,
+            Val::Int(n) => n.to_string(),
+        }
+    }
+
+    fn as_int(&self) -> Result<i64, String> {
+        match self {
+            Val::Int(n) => Ok(*n),
+            Val::Str(s) => s.parse().map_err(|_| format!("{:?} is not an integer", s)),
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        match self {
+            Val::Int(n) => *n != 0,
+            Val::Str(s) => !s.is_empty() && s != "0",
+        }
+    }
+}
+
+// values a script can refer to by name
+struct Inputs<'a> {
+    value: &'a str,
+    arg: &'a str,
+}
+
+// runs the script against the key's current value (empty if it's unset) and the caller's argument, giving the new value
+pub fn run(script: &str, value: &str, arg: &str) -> Result<String, String> {
+    let expr = parse(script)?;
+    let result = eval(&expr, &Inputs { value, arg })?.into_string();
+    if result.len() > MAX_VALUE_LEN {
+        return Err("result is too large".to_string());
+    }
+    Ok(result)
+}
+
+pub fn validate(script: &str) -> Result<(), String> {
+    parse(script).map(|_| ())
+}
+
+fn parse(script: &str) -> Result<Expr, String> {
+    if script.len() > MAX_SCRIPT_LEN {
+        return Err(format!("script is longer than {} bytes", MAX_SCRIPT_LEN));
+    }
+
+    let mut parser = Parser { chars: script.chars().collect(), pos: 0 };
+    let expr = parser.expr()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.chars.len() {
+        return Err(format!("unexpected input at {}", parser.pos));
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).map_or(false, |c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at {}", c, self.pos))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('"') => self.string(),
+            Some(c) if c.is_ascii_digit() || *c == '-' => self.int(),
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                let start = self.pos;
+                while self.chars.get(self.pos).map_or(false, |c| c.is_ascii_alphanumeric() || *c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+
+                self.skip_whitespace();
+                if self.chars.get(self.pos) != Some(&'(') {
+                    return match name.as_str() {
+                        "value" => Ok(Expr::Value),
+                        "arg" => Ok(Expr::Arg),
+                        _ => Err(format!("unknown name {}", name)),
+                    };
+                }
+
+                self.pos += 1;
+                let mut args = vec![];
+                self.skip_whitespace();
+                if self.chars.get(self.pos) == Some(&')') {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        args.push(self.expr()?);
+                        self.skip_whitespace();
+                        match self.chars.get(self.pos) {
+                            Some(',') => self.pos += 1,
+                            _ => break,
+                        }
+                    }
+                    self.expect(')')?;
+                }
+                Ok(Expr::Call(name, args))
+            }
+            _ => Err(format!("expected an expression at {}", self.pos)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Expr, String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(Expr::Str(s));
+                }
+                Some('\\') => {
+                    match self.chars.get(self.pos + 1) {
+                        Some('n') => s.push('\n'),
+                        Some(c) => s.push(*c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    s.push(*c);
+                    self.pos += 1;
+                }
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn int(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        self.pos += 1;
+        while self.chars.get(self.pos).map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        s.parse().map(Expr::Int).map_err(|_| format!("invalid integer {}", s))
+    }
+}
+
+fn eval(expr: &Expr, inputs: &Inputs) -> Result<Val, String> {
+    let (name, args) = match expr {
+        Expr::Str(s) => return Ok(Val::Str(s.clone())),
+        Expr::Int(n) => return Ok(Val::Int(*n)),
+        Expr::Value => return Ok(Val::Str(inputs.value.to_string())),
+        Expr::Arg => return Ok(Val::Str(inputs.arg.to_string())),
+        Expr::Call(name, args) => (name.as_str(), args),
+    };
+
+    // if only evaluates the branch it takes
+    if name == "if" {
+        if args.len() != 3 {
+            return Err("if takes 3 arguments".to_string());
+        }
+        return if eval(&args[0], inputs)?.is_true() { eval(&args[1], inputs) } else { eval(&args[2], inputs) };
+    }
+
+    let args = args.iter().map(|a| eval(a, inputs)).collect::<Result<Vec<Val>, String>>()?;
+    let arity = |n: usize| if args.len() == n { Ok(()) } else { Err(format!("{} takes {} arguments", name, n)) };
+    let overflow = || format!("{} overflowed", name);
+
+    match name {
+        "concat" => Ok(Val::Str(args.into_iter().map(|a| a.into_string()).collect())),
+        "add" | "sub" | "mul" | "div" | "mod" => {
+            arity(2)?;
+            let (a, b) = (args[0].as_int()?, args[1].as_int()?);
+            let result = match name {
+                "add" => a.checked_add(b),
+                "sub" => a.checked_sub(b),
+                "mul" => a.checked_mul(b),
+                "div" => a.checked_div(b),
+                _ => a.checked_rem(b),
+            };
+            result.map(Val::Int).ok_or_else(overflow)
+        }
+        "int" => {
+            arity(1)?;
+            Ok(Val::Int(args[0].as_int()?))
+        }
+        "len" => {
+            arity(1)?;
+            Ok(Val::Int(args[0].clone().into_string().chars().count() as i64))
+        }
+        "upper" | "lower" => {
+            arity(1)?;
+            let s = args[0].clone().into_string();
+            Ok(Val::Str(if name == "upper" { s.to_ascii_uppercase() } else { s.to_ascii_lowercase() }))
+        }
+        "substr" => {
+            arity(3)?;
+            let s = args[0].clone().into_string();
+            let (start, len) = (args[1].as_int()?.max(0) as usize, args[2].as_int()?.max(0) as usize);
+            Ok(Val::Str(s.chars().skip(start).take(len).collect()))
+        }
+        "eq" => {
+            arity(2)?;
+            Ok(Val::Int((args[0].clone().into_string() == args[1].clone().into_string()) as i64))
+        }
+        "lt" => {
+            arity(2)?;
+            Ok(Val::Int((args[0].as_int()? < args[1].as_int()?) as i64))
+        }
+        "default" => {
+            arity(2)?;
+            Ok(if args[0].is_true() { args[0].clone() } else { args[1].clone() })
+        }
+        _ => Err(format!("unknown function {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::script::run;
+
+    #[test]
+    fn counters_and_strings() {
+        assert_eq!(run(r#"if(eq(value, ""), 1, add(value, 1))"#, "", ""), Ok("1".to_string()));
+        assert_eq!(run(r#"if(eq(value, ""), 1, add(value, 1))"#, "41", ""), Ok("42".to_string()));
+        assert_eq!(run(r#"concat(upper(value), "-", len(value))"#, "abc", ""), Ok("ABC-3".to_string()));
+        assert_eq!(run(r#"substr(value, 1, 2)"#, "hello", ""), Ok("el".to_string()));
+        assert_eq!(run(r#"default(value, "fallback")"#, "", ""), Ok("fallback".to_string()));
+        assert_eq!(run(r#""quote \" inside""#, "", ""), Ok("quote \" inside".to_string()));
+        assert_eq!(run("add(value, arg)", "2", "3"), Ok("5".to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(run("add(value, 1)", "not a number", "").is_err());
+        assert!(run("div(1, 0)", "", "").is_err());
+        assert!(run("add(9223372036854775807, 1)", "", "").is_err());
+        assert!(run("nope(1)", "", "").is_err());
+        assert!(run("add(1, 2", "", "").is_err());
+        assert!(run("value value", "", "").is_err());
+    }
+}
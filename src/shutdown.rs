@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use nix::sys::signal::{SigSet, Signal};
+
+// Shared between the thread waiting for SIGINT and SIGTERM, the network, which stops taking commands, tells its peers
+// it's leaving and exits once it's done, and storage, which writes its last checkpoint and backup when it's asked to.
+// Storage that writes everything through as it changes has nothing left to flush and never asks to be waited for.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    flush_pending: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    // Blocks the signals on this thread and every thread started from it after, and waits for them on one of its own,
+    // so they're never handled in the middle of whatever the node was doing. Has to be called before any other thread
+    // is started.
+    pub fn on_signals() -> nix::Result<Shutdown> {
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGINT);
+        signals.add(Signal::SIGTERM);
+        signals.thread_block()?;
+
+        let shutdown = Shutdown::default();
+        let requested = shutdown.clone();
+        thread::spawn(move || {
+            if let Ok(signal) = signals.wait() {
+                eprintln!("shutting down on {:?}", signal);
+                requested.request();
+            }
+        });
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // storage that has something to write before the node exits
+    pub fn expect_flush(&self) {
+        self.flush_pending.store(true, Ordering::SeqCst);
+    }
+
+    // whether storage should write it now, at most once
+    pub fn take_flush(&self) -> bool {
+        self.is_requested() && self.flush_pending.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn is_flush_pending(&self) -> bool {
+        self.flush_pending.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn flushes_once_requested() {
+        let shutdown = Shutdown::default();
+        shutdown.expect_flush();
+        assert!(!shutdown.take_flush());
+        assert!(shutdown.is_flush_pending());
+
+        shutdown.clone().request();
+        assert!(shutdown.is_requested());
+        assert!(shutdown.take_flush());
+        assert!(!shutdown.take_flush());
+        assert!(!shutdown.is_flush_pending());
+    }
+}
@@ -0,0 +1,364 @@
+use std::io;
+use std::path::Path;
+
+use my_raft::bytes::{TryFromBytes, WriteBytes};
+use my_raft::state_machine::{RaftStateMachine, StateMachine};
+use my_raft::storage::log::{LogEntry, LogEntryType};
+use my_raft::storage::Storage;
+use sled::{Batch, Db, IVec, Tree};
+
+use crate::cluster::ClusterId;
+use crate::snapshot_chunks::{Rejected, SnapshotChunks};
+use crate::snapshot_format::SnapshotEncoding;
+use crate::state_machine::clone_state_machine;
+use crate::storage::{DEFAULT_APPEND_ENTRIES_BYTES, entry_size, parse_snapshot, SNAPSHOT_HEADER_LEN, SnapshotCodec, write_snapshot_body};
+
+const CURRENT_TERM: &[u8] = b"current_term";
+const VOTED_FOR: &[u8] = b"voted_for";
+const LAST_INDEX: &[u8] = b"last_index";
+const LAST_TERM: &[u8] = b"last_term";
+const SNAPSHOT: &[u8] = b"snapshot";
+
+// Storage kept in a sled database instead of a file format of our own. Log entries are in the log tree by index, the
+// term and vote in hard_state, and the latest snapshot with its index and term in snapshot, which are replaced together.
+// The core borrows entries and snapshot chunks, so both are also kept in memory, and sled is only read on open.
+pub struct SledStorage<S: StateMachine> {
+    cluster_id: ClusterId,
+    db: Db,
+    log_tree: Tree,
+    hard_state_tree: Tree,
+    snapshot_tree: Tree,
+    log: Vec<LogEntry<S::Command>>,
+    entry_sizes: Vec<usize>,
+    append_entries_bytes: usize,
+    current_term: u32,
+    voted_for: Option<u32>,
+    snapshot_bytes: Vec<u8>,
+    snapshot_codec: SnapshotCodec,
+    snapshot_encoding: SnapshotEncoding,
+    snapshot_last_index: u32,
+    snapshot_last_term: u32,
+    snapshot_chunks: SnapshotChunks,
+    init_state_machine: RaftStateMachine<S>,
+}
+
+impl<S: StateMachine> SledStorage<S> {
+    // storage as it was left in the database in the directory, which is created if there isn't one
+    pub fn open(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId, dir: &Path) -> io::Result<SledStorage<S>> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("sled database in {} {}", dir.display(), what));
+
+        let db = sled::open(dir)?;
+        let log_tree = db.open_tree("log")?;
+        let hard_state_tree = db.open_tree("hard_state")?;
+        let snapshot_tree = db.open_tree("snapshot")?;
+
+        let read_u32 = |tree: &Tree, key: &[u8]| -> io::Result<Option<u32>> {
+            match tree.get(key)? {
+                Some(value) => be_u32(&value).map(Some).ok_or_else(|| invalid(format!("has a corrupt {}", String::from_utf8_lossy(key)))),
+                None => Ok(None),
+            }
+        };
+        let current_term = read_u32(&hard_state_tree, CURRENT_TERM)?.unwrap_or(0);
+        let voted_for = read_u32(&hard_state_tree, VOTED_FOR)?;
+        let snapshot_last_index = read_u32(&snapshot_tree, LAST_INDEX)?.unwrap_or(0);
+        let snapshot_last_term = read_u32(&snapshot_tree, LAST_TERM)?.unwrap_or(0);
+        let snapshot_bytes = snapshot_tree.get(SNAPSHOT)?.map_or(vec![], |bytes| bytes.to_vec());
+        if !snapshot_bytes.is_empty() {
+            if snapshot_bytes.get(..SNAPSHOT_HEADER_LEN) != Some(&cluster_id.to_bytes()[..]) {
+                return Err(invalid("is from a different cluster".to_string()));
+            }
+            if let Err(e) = parse_snapshot::<S>(&snapshot_bytes) {
+                return Err(invalid(format!("has a snapshot that {}", e)));
+            }
+        }
+
+        // entries the last snapshot covers may not have been removed yet, and the log has to carry on from it
+        let mut log = vec![];
+        for item in log_tree.range(index_key(snapshot_last_index + 1)..) {
+            let (key, value) = item?;
+            let index = snapshot_last_index + 1 + log.len() as u32;
+            if be_u32(&key) != Some(index) {
+                return Err(invalid(format!("is missing the entry at {}", index)));
+            }
+            log.push(LogEntry::try_from_slice(&value).ok_or_else(|| invalid(format!("has a corrupt entry at {}", index)))?);
+        }
+
+        Ok(SledStorage {
+            cluster_id,
+            entry_sizes: log.iter().map(entry_size).collect(),
+            db,
+            log_tree,
+            hard_state_tree,
+            snapshot_tree,
+            log,
+            append_entries_bytes: DEFAULT_APPEND_ENTRIES_BYTES,
+            current_term,
+            voted_for,
+            snapshot_bytes,
+            snapshot_codec: SnapshotCodec::Uncompressed,
+            snapshot_encoding: SnapshotEncoding::default(),
+            snapshot_last_index,
+            snapshot_last_term,
+            snapshot_chunks: SnapshotChunks::default(),
+            init_state_machine,
+        })
+    }
+
+    pub fn set_append_entries_bytes(&mut self, bytes: usize) {
+        self.append_entries_bytes = bytes;
+    }
+
+    pub fn set_snapshot_codec(&mut self, codec: SnapshotCodec) {
+        self.snapshot_codec = codec;
+    }
+
+    pub fn set_snapshot_encoding(&mut self, encoding: SnapshotEncoding) {
+        self.snapshot_encoding = encoding;
+    }
+
+    fn index_of(&self, i: usize) -> u32 {
+        self.snapshot_last_index + 1 + i as u32
+    }
+
+    fn save_hard_state(&self) {
+        let mut batch = Batch::default();
+        batch.insert(CURRENT_TERM, &self.current_term.to_be_bytes()[..]);
+        match self.voted_for {
+            Some(id) => batch.insert(VOTED_FOR, &id.to_be_bytes()[..]),
+            None => batch.remove(VOTED_FOR),
+        }
+        write(self.hard_state_tree.apply_batch(batch));
+        // a vote or term that's forgotten after a crash can mean voting twice
+        write(self.db.flush());
+    }
+
+    // replaces the snapshot and drops the entries it covers, the snapshot first so a crash in between leaves entries
+    // that open skips
+    fn save_snapshot(&mut self, bytes: Vec<u8>, last_index: u32, last_term: u32) {
+        let mut batch = Batch::default();
+        batch.insert(LAST_INDEX, &last_index.to_be_bytes()[..]);
+        batch.insert(LAST_TERM, &last_term.to_be_bytes()[..]);
+        batch.insert(SNAPSHOT, IVec::from(&bytes[..]));
+        write(self.snapshot_tree.apply_batch(batch));
+        write(self.db.flush());
+        self.remove_entries_through(last_index);
+        self.snapshot_bytes = bytes;
+        self.snapshot_last_index = last_index;
+        self.snapshot_last_term = last_term;
+    }
+
+    fn remove_entries_through(&self, last_index: u32) {
+        let mut batch = Batch::default();
+        for key in self.log_tree.range(..=index_key(last_index)).keys() {
+            batch.remove(write(key));
+        }
+        write(self.log_tree.apply_batch(batch));
+    }
+}
+
+impl<S: StateMachine + Clone> Storage<S> for SledStorage<S> {
+    fn add_log_entry(&mut self, entry: LogEntry<<S as StateMachine>::Command>) {
+        let mut bytes = vec![];
+        entry.write_bytes_with_writer(&mut bytes).unwrap();
+        let index = self.index_of(self.log.len());
+        self.entry_sizes.push(bytes.len());
+        write(self.log_tree.insert(index_key(index), bytes));
+        self.log.push(entry);
+    }
+
+    fn remove_log_entries_before(&mut self, index: usize) {
+        self.log.drain(..index);
+        self.entry_sizes.drain(..index);
+        self.remove_entries_through(self.snapshot_last_index);
+    }
+
+    fn remove_log_entries_starting_at(&mut self, index: usize) {
+        let mut batch = Batch::default();
+        for key in self.log_tree.range(index_key(self.index_of(index))..).keys() {
+            batch.remove(write(key));
+        }
+        write(self.log_tree.apply_batch(batch));
+        self.log.drain(index..);
+        self.entry_sizes.drain(index..);
+    }
+
+    fn save_log(&mut self) {
+        write(self.db.flush());
+    }
+
+    fn log_entry(&self, index: usize) -> Option<&LogEntry<<S as StateMachine>::Command>> {
+        self.log.get(index)
+    }
+
+    // cut off at the byte budget, like RamStorage's
+    fn log_entries(&self, start_index: usize) -> &[LogEntry<<S as StateMachine>::Command>] {
+        let entries = &self.log[start_index..];
+        let mut bytes = 0;
+        let fits = self.entry_sizes[start_index..].iter()
+            .take_while(|size| {
+                bytes += **size;
+                bytes <= self.append_entries_bytes
+            })
+            .count();
+        &entries[..fits.max(1).min(entries.len())]
+    }
+
+    fn get_index_of_last_config_in_log(&self) -> Option<usize> {
+        self.log.iter().rposition(|e| matches!(e.entry_type, LogEntryType::Config(_)))
+    }
+
+    fn num_log_entries(&self) -> usize {
+        self.log.len()
+    }
+
+    fn set_snapshot(&mut self, last_index: u32, last_term: u32, snapshot: &RaftStateMachine<S>) {
+        let mut bytes = self.cluster_id.to_bytes().to_vec();
+        write_snapshot_body(&mut bytes, snapshot, self.snapshot_codec, self.snapshot_encoding).unwrap();
+        self.save_snapshot(bytes, last_index, last_term);
+    }
+
+    fn snapshot(&self) -> RaftStateMachine<S> {
+        parse_snapshot(&self.snapshot_bytes).unwrap_or_else(|_| clone_state_machine(&self.init_state_machine))
+    }
+
+    fn snapshot_last_index(&self) -> u32 {
+        self.snapshot_last_index
+    }
+
+    fn snapshot_last_term(&self) -> u32 {
+        self.snapshot_last_term
+    }
+
+    fn add_new_snapshot_chunk(&mut self, offset: u32, data: &[u8]) {
+        match self.snapshot_chunks.add(offset, data) {
+            Ok(()) | Err(Rejected::Duplicate) => {}
+            Err(rejected) => eprintln!("ignoring snapshot chunk of {} bytes at {}: {:?}", data.len(), offset, rejected),
+        }
+    }
+
+    fn try_use_chunks_as_new_snapshot(&mut self, last_index: u32, last_term: u32) -> Option<RaftStateMachine<S>> {
+        let bytes = self.snapshot_chunks.complete(None)?;
+        if bytes.get(..SNAPSHOT_HEADER_LEN)? != self.cluster_id.to_bytes() {
+            eprintln!("refusing to install snapshot from a different cluster");
+            return None;
+        }
+        let snapshot = match parse_snapshot::<S>(bytes) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("refusing to install snapshot through {}: it {}", last_index, e);
+                self.snapshot_chunks.take();
+                return None;
+            }
+        };
+        let bytes = self.snapshot_chunks.take();
+        self.save_snapshot(bytes, last_index, last_term);
+        Some(snapshot)
+    }
+
+    fn snapshot_chunk(&self, offset: u32, amt: u32) -> &[u8] {
+        &self.snapshot_bytes[offset as usize..(offset + amt) as usize]
+    }
+
+    fn total_snapshot_bytes(&self) -> u32 {
+        self.snapshot_bytes.len() as u32
+    }
+
+    fn set_voted_for(&mut self, voted_for: Option<u32>) {
+        if voted_for != self.voted_for {
+            self.voted_for = voted_for;
+            self.save_hard_state();
+        }
+    }
+
+    fn voted_for(&self) -> Option<u32> {
+        self.voted_for
+    }
+
+    fn set_current_term(&mut self, current_term: u32) {
+        if current_term != self.current_term {
+            self.current_term = current_term;
+            self.save_hard_state();
+        }
+    }
+
+    fn current_term(&self) -> u32 {
+        self.current_term
+    }
+}
+
+// big endian so the log tree iterates in index order
+fn index_key(index: u32) -> [u8; 4] {
+    index.to_be_bytes()
+}
+
+fn be_u32(bytes: &[u8]) -> Option<u32> {
+    let mut be = [0; 4];
+    if bytes.len() != be.len() {
+        return None;
+    }
+    be.copy_from_slice(bytes);
+    Some(u32::from_be_bytes(be))
+}
+
+// like the write-ahead log, the node stops rather than carry on without what it promised being durable
+fn write<T>(result: sled::Result<T>) -> T {
+    match result {
+        Ok(written) => written,
+        Err(e) => panic!("failed to write the sled database: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use my_raft::storage::Storage;
+
+    use crate::cluster::ClusterId;
+    use crate::init_state_machine;
+    use crate::sled_storage::SledStorage;
+    use crate::state_machine::KvStateMachine;
+
+    fn open(dir: &Path, cluster_id: ClusterId) -> std::io::Result<SledStorage<KvStateMachine>> {
+        SledStorage::open(init_state_machine(0, HashMap::new()), cluster_id, dir)
+    }
+
+    #[test]
+    fn recovers_what_was_saved() {
+        let dir = std::env::temp_dir().join(format!("sled_storage_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut storage = open(&dir.join("a"), ClusterId(0)).unwrap();
+        let mut sm = storage.snapshot();
+        sm.inner.data.insert("k".to_string(), "v".to_string());
+        storage.set_current_term(3);
+        storage.set_voted_for(Some(1));
+        storage.set_snapshot(7, 2, &sm);
+        storage.save_log();
+        let bytes = storage.snapshot_chunk(0, storage.total_snapshot_bytes()).to_vec();
+        drop(storage);
+
+        let recovered = open(&dir.join("a"), ClusterId(0)).unwrap();
+        assert_eq!(recovered.current_term(), 3);
+        assert_eq!(recovered.voted_for(), Some(1));
+        assert_eq!(recovered.snapshot_last_index(), 7);
+        assert_eq!(recovered.snapshot_last_term(), 2);
+        assert_eq!(recovered.num_log_entries(), 0);
+        assert_eq!(recovered.snapshot().inner.data, sm.inner.data);
+        drop(recovered);
+        assert!(open(&dir.join("a"), ClusterId(1)).is_err());
+
+        // the tail arriving first isn't the whole snapshot, and once it's all there it's saved like one taken here
+        let mut other = open(&dir.join("b"), ClusterId(0)).unwrap();
+        other.add_new_snapshot_chunk(10, &bytes[10..]);
+        assert!(other.try_use_chunks_as_new_snapshot(7, 2).is_none());
+        other.add_new_snapshot_chunk(0, &bytes[..10]);
+        assert_eq!(other.try_use_chunks_as_new_snapshot(7, 2).unwrap().inner.data, sm.inner.data);
+        drop(other);
+        let other = open(&dir.join("b"), ClusterId(0)).unwrap();
+        assert_eq!(other.snapshot_chunk(0, other.total_snapshot_bytes()), &bytes[..]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use crate::snapshot_pull::add_range;
+
+// The parts of a snapshot being installed that have arrived so far, from pushes and pulls, and which byte ranges of it
+// they cover. Nothing is given out for installing until the ranges cover it from start to end without a hole, so a
+// snapshot that only parses because its tail hasn't arrived yet can't be installed by mistake.
+#[derive(Default)]
+pub struct SnapshotChunks {
+    bytes: Vec<u8>,
+    // sorted and merged
+    received: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    // the same bytes again, like a chunk the leader sent again after its response was lost
+    Duplicate,
+    // anything else touching a range that's already arrived
+    Overlapping,
+    TooLarge,
+}
+
+impl SnapshotChunks {
+    // A chunk at offset 0 starts over, since that's the leader beginning a snapshot again, maybe a newer one. Past that,
+    // chunks can arrive in any order but never overlap what's already arrived.
+    pub fn add(&mut self, offset: u32, data: &[u8]) -> Result<(), Rejected> {
+        let end = offset.checked_add(data.len() as u32).ok_or(Rejected::TooLarge)?;
+        if offset == 0 {
+            self.bytes.clear();
+            self.received.clear();
+        }
+        if self.received.iter().any(|(start, stop)| *start < end && offset < *stop) {
+            let copy = self.received.iter().any(|(start, stop)| *start <= offset && end <= *stop)
+                && &self.bytes[offset as usize..end as usize] == data;
+            return Err(if copy { Rejected::Duplicate } else { Rejected::Overlapping });
+        }
+        if self.bytes.len() < end as usize {
+            self.bytes.resize(end as usize, 0);
+        }
+        self.bytes[offset as usize..end as usize].copy_from_slice(data);
+        add_range(&mut self.received, (offset, end));
+        Ok(())
+    }
+
+    // Everything that's arrived, if it's the whole snapshot: one range from the start, as long as the size it was said
+    // to be if that's known.
+    pub fn complete(&self, total: Option<u32>) -> Option<&[u8]> {
+        match self.received.as_slice() {
+            [(0, end)] if total.map_or(true, |total| total == *end) => Some(&self.bytes),
+            _ => None,
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<u8> {
+        self.received.clear();
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshot_chunks::{Rejected, SnapshotChunks};
+
+    #[test]
+    fn tracks_ranges() {
+        let snapshot: Vec<u8> = (0..100).collect();
+        let mut chunks = SnapshotChunks::default();
+        assert_eq!(chunks.complete(None), None);
+
+        chunks.add(0, &snapshot[..30]).unwrap();
+        chunks.add(60, &snapshot[60..]).unwrap();
+        // parts of it don't make a snapshot, even ones long enough to parse
+        assert_eq!(chunks.complete(None), None);
+
+        assert_eq!(chunks.add(60, &snapshot[60..80]), Err(Rejected::Duplicate));
+        assert_eq!(chunks.add(20, &snapshot[20..40]), Err(Rejected::Overlapping));
+        assert_eq!(chunks.add(70, &[0; 10]), Err(Rejected::Overlapping));
+        assert_eq!(chunks.add(u32::MAX, &[0; 2]), Err(Rejected::TooLarge));
+
+        chunks.add(40, &snapshot[40..60]).unwrap();
+        chunks.add(30, &snapshot[30..40]).unwrap();
+        assert_eq!(chunks.complete(Some(120)), None);
+        assert_eq!(chunks.complete(Some(100)), Some(&snapshot[..]));
+        assert_eq!(chunks.complete(None), Some(&snapshot[..]));
+
+        // starting over forgets the old snapshot, even when the new one is shorter
+        chunks.add(0, &snapshot[..10]).unwrap();
+        assert_eq!(chunks.complete(None), Some(&snapshot[..10]));
+        assert_eq!(chunks.take(), snapshot[..10].to_vec());
+        assert_eq!(chunks.complete(None), None);
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::ops::Deref;
+
+use memmap2::Mmap;
+
+// The bytes of a snapshot: in memory, or in a file in the write-ahead log's directory that's mapped rather than read in,
+// so that serving it to followers a chunk at a time doesn't take a second copy of the state machine in memory.
+pub enum SnapshotBytes {
+    Memory(Vec<u8>),
+    // the file's first `start` bytes aren't part of the snapshot, None when the file has nothing after them
+    Mapped { map: Option<Mmap>, start: usize },
+}
+
+impl Default for SnapshotBytes {
+    fn default() -> Self {
+        SnapshotBytes::Memory(vec![])
+    }
+}
+
+impl SnapshotBytes {
+    // Snapshot files are only ever replaced by renaming a new file over them, never written in place, so what's mapped
+    // doesn't change under us, and the old file's pages stay readable until its map is dropped.
+    pub fn map(file: &File, start: usize) -> std::io::Result<SnapshotBytes> {
+        if file.metadata()?.len() as usize <= start {
+            return Ok(SnapshotBytes::Mapped { map: None, start });
+        }
+        let map = unsafe { Mmap::map(file)? };
+        Ok(SnapshotBytes::Mapped { map: Some(map), start })
+    }
+}
+
+impl Deref for SnapshotBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            SnapshotBytes::Memory(bytes) => bytes,
+            SnapshotBytes::Mapped { map: Some(map), start } => &map[*start..],
+            SnapshotBytes::Mapped { map: None, .. } => &[],
+        }
+    }
+}
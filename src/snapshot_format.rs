@@ -0,0 +1,137 @@
+use std::cell::Cell;
+
+use crate::state_machine::{PortableState, PortableStateWithoutTombstones};
+
+// A way of writing the state machine in snapshots other than the native one, which is the most compact and the only one
+// older versions can read. The snapshot starts with a marker and the format's tag, so any of them can always be read
+// whichever one snapshots are being written in.
+pub trait SnapshotFormat: Sync {
+    fn name(&self) -> &'static str;
+    // written in snapshots, so never reused
+    fn tag(&self) -> u32;
+    fn encode(&self, state: &PortableState) -> Result<Vec<u8>, String>;
+    fn decode(&self, bytes: &[u8]) -> Option<PortableState>;
+}
+
+// for reading snapshots with jq
+pub struct Json;
+
+pub struct Cbor;
+
+pub struct Bincode;
+
+// bincode from before there were tombstones, only read
+pub struct BincodeWithoutTombstones;
+
+impl SnapshotFormat for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn tag(&self) -> u32 {
+        1
+    }
+
+    fn encode(&self, state: &PortableState) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(state).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<PortableState> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+impl SnapshotFormat for Cbor {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn tag(&self) -> u32 {
+        2
+    }
+
+    fn encode(&self, state: &PortableState) -> Result<Vec<u8>, String> {
+        serde_cbor::to_vec(state).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<PortableState> {
+        serde_cbor::from_slice(bytes).ok()
+    }
+}
+
+impl SnapshotFormat for Bincode {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn tag(&self) -> u32 {
+        4
+    }
+
+    fn encode(&self, state: &PortableState) -> Result<Vec<u8>, String> {
+        bincode::serialize(state).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<PortableState> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+impl SnapshotFormat for BincodeWithoutTombstones {
+    fn name(&self) -> &'static str {
+        "bincode-without-tombstones"
+    }
+
+    fn tag(&self) -> u32 {
+        3
+    }
+
+    fn encode(&self, _state: &PortableState) -> Result<Vec<u8>, String> {
+        Err("bincode snapshots without tombstones are only read".to_string())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<PortableState> {
+        bincode::deserialize::<PortableStateWithoutTombstones>(bytes).ok().map(PortableState::from)
+    }
+}
+
+pub static FORMATS: &[&dyn SnapshotFormat] = &[&Json, &Cbor, &Bincode];
+
+// still read, but never written
+static RETIRED_FORMATS: &[&dyn SnapshotFormat] = &[&BincodeWithoutTombstones];
+
+pub fn by_name(name: &str) -> Option<&'static dyn SnapshotFormat> {
+    FORMATS.iter().find(|format| format.name() == name).copied()
+}
+
+pub fn by_tag(tag: u32) -> Option<&'static dyn SnapshotFormat> {
+    FORMATS.iter().chain(RETIRED_FORMATS).find(|format| format.tag() == tag).copied()
+}
+
+// How a storage writes the state machine in its snapshots, set on each one next to its codec. Snapshots can always be
+// read whichever way they were written.
+#[derive(Clone, Copy, Default)]
+pub struct SnapshotEncoding {
+    // None for native
+    pub format: Option<&'static dyn SnapshotFormat>,
+    // values held by more than one key are written once and referred to after that, in the native format
+    pub dedup: bool,
+}
+
+thread_local! {
+    static WRITING_WITH: Cell<SnapshotEncoding> = Cell::new(SnapshotEncoding::default());
+}
+
+// The state machine is written through WriteBytes, which takes no options, so it picks the encoding up from here while
+// a storage is writing a snapshot with it. Anything else writing one, like the core sending it to a follower, gets the
+// default.
+pub fn write_with<T>(encoding: SnapshotEncoding, write: impl FnOnce() -> T) -> T {
+    let previous = WRITING_WITH.with(|writing| writing.replace(encoding));
+    let written = write();
+    WRITING_WITH.with(|writing| writing.set(previous));
+    written
+}
+
+pub fn writing_with() -> SnapshotEncoding {
+    WRITING_WITH.with(Cell::get)
+}
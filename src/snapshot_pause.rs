@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde_json::json;
+
+// Shared between the network, which pauses and resumes it with admin messages, and storage, which skips the snapshots
+// the core takes and the compaction after them while it's paused, so the log stays exactly as it is while a bug is
+// looked into. Snapshots installed from the leader still go through, a follower that's behind couldn't catch up
+// otherwise. The leader also holds snapshots back on its own while a node catches up from its log, which resuming
+// doesn't undo.
+#[derive(Clone, Default)]
+pub struct SnapshotPause {
+    paused: Arc<AtomicBool>,
+    held: Arc<AtomicBool>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl SnapshotPause {
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn set_held(&self, held: bool) {
+        self.held.store(held, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    // whether storage skips the snapshots the core takes, for either reason
+    pub fn skips_snapshots(&self) -> bool {
+        self.is_paused() || self.held.load(Ordering::SeqCst)
+    }
+
+    pub fn record_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "paused": self.is_paused(),
+            "held_for_catch_up": self.held.load(Ordering::SeqCst),
+            "skipped_snapshots": self.skipped.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// pulled parts are this big at most, which keeps them well inside a packet once they're a JSON array
+pub const PULL_PART_BYTES: u32 = 8192;
+
+#[derive(Default)]
+struct Exchange {
+    // this node's snapshot, for answering pulls, and its last index
+    serving: Option<(u32, Arc<Vec<u8>>)>,
+    // byte ranges of the snapshot being installed that the leader has pushed, sorted and merged
+    pushed: Vec<(u32, u32)>,
+    last_push: Option<Instant>,
+    // the last index and total size of the snapshot being pulled, and the parts pulled so far
+    pulling: Option<(u32, u32)>,
+    pulled: Vec<(u32, Vec<u8>)>,
+}
+
+// Shared between storage and the network so a follower can pull the parts of a snapshot it's missing from the leader,
+// alongside the chunks the leader pushes, like after it restarted partway through an install and the leader carried on
+// from where it was. Pulled parts only ever fill the gaps in what was pushed, and only when the core installs the
+// snapshot with the last index they were pulled for, so the core still decides when an install is done.
+#[derive(Clone, Default)]
+pub struct SnapshotExchange {
+    exchange: Arc<Mutex<Exchange>>,
+}
+
+impl SnapshotExchange {
+    pub fn publish(&self, last_index: u32, bytes: &[u8]) {
+        self.exchange.lock().unwrap().serving = Some((last_index, Arc::new(bytes.to_vec())));
+    }
+
+    pub fn record_pushed(&self, offset: u32, len: u32) {
+        let mut exchange = self.exchange.lock().unwrap();
+        // the leader starting over from the beginning overwrites everything anyway
+        if offset == 0 {
+            exchange.pushed.clear();
+        }
+        add_range(&mut exchange.pushed, (offset, offset + len));
+        exchange.last_push = Some(Instant::now());
+    }
+
+    // the pieces of what was pulled for the snapshot through last_index that fall in gaps in what was pushed
+    pub fn gaps(&self, last_index: u32) -> Vec<(u32, Vec<u8>)> {
+        let exchange = self.exchange.lock().unwrap();
+        if exchange.pulling.map(|(index, _)| index) != Some(last_index) {
+            return vec![];
+        }
+        let mut pieces = vec![];
+        for (offset, data) in &exchange.pulled {
+            let end = *offset + data.len() as u32;
+            for (gap_start, gap_end) in missing(&exchange.pushed, end) {
+                let (start, stop) = (gap_start.max(*offset), gap_end.min(end));
+                if start < stop {
+                    pieces.push((start, data[(start - offset) as usize..(stop - offset) as usize].to_vec()));
+                }
+            }
+        }
+        pieces
+    }
+
+    // the size the leader said the snapshot through last_index is, if anything's been pulled for it
+    pub fn total(&self, last_index: u32) -> Option<u32> {
+        match self.exchange.lock().unwrap().pulling {
+            Some((index, total)) if index == last_index => Some(total),
+            _ => None,
+        }
+    }
+
+    // forgets about the snapshot being installed, once it has been or the leader it was coming from is gone
+    pub fn finish(&self) {
+        let mut exchange = self.exchange.lock().unwrap();
+        exchange.pushed.clear();
+        exchange.last_push = None;
+        exchange.pulling = None;
+        exchange.pulled.clear();
+    }
+
+    // a part of this node's snapshot, along with its last index and total size, if it's the one asked for
+    pub fn serve(&self, last_index: u32, offset: u32, len: u32) -> Option<(u32, u32, Vec<u8>)> {
+        let exchange = self.exchange.lock().unwrap();
+        let (index, bytes) = exchange.serving.as_ref()?;
+        if last_index != 0 && last_index != *index {
+            return None;
+        }
+        let start = (offset as usize).min(bytes.len());
+        let end = (offset as usize + len.min(PULL_PART_BYTES) as usize).min(bytes.len());
+        Some((*index, bytes.len() as u32, bytes[start..end].to_vec()))
+    }
+
+    pub fn record_pulled(&self, last_index: u32, total: u32, offset: u32, data: Vec<u8>) {
+        let mut exchange = self.exchange.lock().unwrap();
+        if exchange.last_push.is_none() {
+            return;
+        }
+        if exchange.pulling != Some((last_index, total)) {
+            // the leader moved on to a newer snapshot, so what was pulled before is no use
+            exchange.pulling = Some((last_index, total));
+            exchange.pulled.clear();
+        }
+        exchange.pulled.push((offset, data));
+    }
+
+    // What to ask the leader for during an install: the last index of the snapshot being pulled (0 before the leader
+    // has said) and at most max missing ranges. Holes behind what's been pushed are always asked for, since pushes only
+    // move forwards, and everything up to the end once nothing has been pushed for stalled_for.
+    pub fn wanted(&self, stalled_for: Duration, max: usize) -> Option<(u32, Vec<(u32, u32)>)> {
+        let exchange = self.exchange.lock().unwrap();
+        let stalled = exchange.last_push?.elapsed() >= stalled_for;
+        let (last_index, total) = exchange.pulling.unwrap_or((0, 0));
+        let mut have = exchange.pushed.clone();
+        for (offset, data) in &exchange.pulled {
+            add_range(&mut have, (*offset, *offset + data.len() as u32));
+        }
+        let pushed_up_to = exchange.pushed.last().map_or(0, |(_, end)| *end);
+        // before the size is known, asking for a part past what's been pushed gets an answer saying what it is
+        let up_to = match (stalled, total) {
+            (true, 0) => pushed_up_to + PULL_PART_BYTES,
+            (true, total) => total,
+            (false, _) => pushed_up_to,
+        };
+        let ranges: Vec<(u32, u32)> = missing(&have, up_to).into_iter()
+            .flat_map(|(start, end)| (start..end).step_by(PULL_PART_BYTES as usize).map(move |s| (s, (s + PULL_PART_BYTES).min(end))))
+            .take(max)
+            .collect();
+        if ranges.is_empty() {
+            None
+        } else {
+            Some((last_index, ranges))
+        }
+    }
+}
+
+pub fn add_range(ranges: &mut Vec<(u32, u32)>, range: (u32, u32)) {
+    if range.0 >= range.1 {
+        return;
+    }
+    ranges.push(range);
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+// the ranges below total that aren't covered
+fn missing(ranges: &[(u32, u32)], total: u32) -> Vec<(u32, u32)> {
+    let mut gaps = vec![];
+    let mut covered = 0;
+    for (start, end) in ranges {
+        if *start > covered {
+            gaps.push((covered, (*start).min(total)));
+        }
+        covered = covered.max(*end);
+    }
+    if covered < total {
+        gaps.push((covered, total));
+    }
+    gaps.retain(|(start, end)| start < end);
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::snapshot_pull::{PULL_PART_BYTES, SnapshotExchange, add_range, missing};
+
+    #[test]
+    fn ranges() {
+        let mut ranges = vec![];
+        add_range(&mut ranges, (10, 20));
+        add_range(&mut ranges, (30, 40));
+        add_range(&mut ranges, (15, 30));
+        add_range(&mut ranges, (50, 50));
+        assert_eq!(ranges, vec![(10, 40)]);
+        assert_eq!(missing(&ranges, 60), vec![(0, 10), (40, 60)]);
+        assert_eq!(missing(&ranges, 30), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn pulls_gaps() {
+        let leader = SnapshotExchange::default();
+        let snapshot: Vec<u8> = (0..3 * PULL_PART_BYTES).map(|i| i as u8).collect();
+        leader.publish(7, &snapshot);
+
+        let follower = SnapshotExchange::default();
+        let pull = |index: u32, wanted: Vec<(u32, u32)>| {
+            for (start, end) in wanted {
+                let (index, total, data) = leader.serve(index, start, end - start).unwrap();
+                follower.record_pulled(index, total, start, data);
+            }
+        };
+
+        // a hole behind what's been pushed is asked for straight away, the rest only once pushes stall
+        follower.record_pushed(0, 100);
+        follower.record_pushed(200, 100);
+        assert_eq!(follower.wanted(Duration::from_secs(60), 8), Some((0, vec![(100, 200)])));
+        pull(0, vec![(100, 200)]);
+        assert_eq!(follower.wanted(Duration::from_secs(60), 8), None);
+
+        let (index, wanted) = follower.wanted(Duration::from_millis(0), 8).unwrap();
+        assert_eq!(index, 7);
+        assert_eq!(wanted, vec![(300, 300 + PULL_PART_BYTES), (300 + PULL_PART_BYTES, 300 + 2 * PULL_PART_BYTES), (300 + 2 * PULL_PART_BYTES, 3 * PULL_PART_BYTES)]);
+        pull(index, wanted);
+        assert_eq!(follower.wanted(Duration::from_millis(0), 8), None);
+        assert!(leader.serve(6, 0, 10).is_none());
+
+        assert!(follower.gaps(6).is_empty());
+        let gaps = follower.gaps(7);
+        assert_eq!(gaps.iter().map(|(_, data)| data.len()).sum::<usize>(), snapshot.len() - 200);
+        for (offset, data) in gaps {
+            assert_eq!(&snapshot[offset as usize..offset as usize + data.len()], &data[..]);
+        }
+
+        follower.finish();
+        assert!(follower.gaps(7).is_empty());
+        assert_eq!(follower.wanted(Duration::from_millis(0), 8), None);
+    }
+}
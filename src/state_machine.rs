@@ -1,83 +1,2088 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
 use std::io;
 use std::io::Write;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use my_raft::bytes::{BytesWriter, ReadBytes, TryFromBytes, WriteBytes};
 use my_raft::state_machine::{RaftStateMachine, StateMachine};
+use serde::{Deserialize, Serialize};
 
+use crate::alloc_stats;
+use crate::alloc_stats::Subsystem;
+use crate::json_path;
+use crate::script;
+use crate::snapshot_format;
+
+const SET_TAG: u32 = 1;
+const DELETE_TAG: u32 = 2;
+const CAS_TAG: u32 = 3;
+const BATCH_TAG: u32 = 4;
+const GET_SET_TAG: u32 = 5;
+const GET_DELETE_TAG: u32 = 6;
+const DEFRAG_TAG: u32 = 7;
+const SCRIPT_TAG: u32 = 8;
+const REGISTER_TAG: u32 = 9;
+const CALL_TAG: u32 = 10;
+const RENAME_TAG: u32 = 11;
+const COPY_TAG: u32 = 12;
+const DELETE_IF_TAG: u32 = 13;
+const SET_WITH_TTL_TAG: u32 = 14;
+const EXPIRE_KEYS_TAG: u32 = 15;
+const SCHEDULE_TAG: u32 = 16;
+const UNSCHEDULE_TAG: u32 = 17;
+const RUN_SCHEDULE_TAG: u32 = 18;
+const APPEND_TAG: u32 = 19;
+const INCR_TAG: u32 = 20;
+const JSON_SET_TAG: u32 = 21;
+const PURGE_TOMBSTONES_TAG: u32 = 22;
+
+// how many results of applied commands are kept around for responding to clients
+const MAX_RESULTS: usize = 1024;
+
+// Snapshots starting with this instead of the number of keys have their repeated values written once, in a dictionary
+// before the keys, with each key's value being either an index into it (high bit set) or a length prefixed string.
+const DEDUP_MARKER: u32 = u32::MAX;
+const VALUE_REF_BIT: u32 = 1 << 31;
+// Snapshots with key versions start with this, then the versions, before the data in either format. Older snapshots
+// don't have it, and their keys have no versions.
+const VERSIONS_MARKER: u32 = u32::MAX - 1;
+// Snapshots with the results of recent commands start with this, then the results, so a node that restarts from one can
+// still answer clients retrying the commands. Results come before the versions.
+const RESULTS_MARKER: u32 = u32::MAX - 2;
+// Snapshots with keys that expire start with this, then when each of them does, before the results.
+const EXPIRIES_MARKER: u32 = u32::MAX - 3;
+// Snapshots with scheduled commands start with this, then the schedules, before the expiries.
+const SCHEDULES_MARKER: u32 = u32::MAX - 4;
+// Snapshots with keys whose clients want to know when they expire start with this, then the keys and clients, before
+// the schedules.
+const EXPIRY_OWNERS_MARKER: u32 = u32::MAX - 5;
+// Snapshots in a format other than this native one start with this, then the format's tag and the length prefixed state
+// in that format, and nothing else.
+const FORMAT_MARKER: u32 = u32::MAX - 6;
+// Snapshots with tombstones start with this, then each deleted key with when and at what applied index, before the
+// expiry owners.
+const TOMBSTONES_MARKER: u32 = u32::MAX - 7;
+// how many ExpireKeys commands' expired keys with owners are kept around for the network to notify their clients
+const MAX_EXPIRED_NOTICES: usize = 16;
+
+// zero when applies have no budget
+static APPLY_BUDGET_US: AtomicU64 = AtomicU64::new(0);
+// zero when the breaker never trips
+static APPLY_BREAKER_AFTER: AtomicU32 = AtomicU32::new(0);
+
+// applies taking longer than the budget are logged and counted, and trip_after of them in a row trip the breaker
+pub fn set_apply_budget(budget: Duration, trip_after: u32) {
+    APPLY_BUDGET_US.store(budget.as_micros() as u64, Ordering::Relaxed);
+    APPLY_BREAKER_AFTER.store(trip_after, Ordering::Relaxed);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KvCommand {
+    pub mid: String,
+    pub op: KvOp,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KvOp {
+    Set(SetValueCommand),
+    Delete(DeleteValueCommand),
+    Cas(CasCommand),
+    Batch(BatchSetCommand),
+    // set or delete the key, with the previous value as the result
+    GetSet(SetValueCommand),
+    GetDelete(DeleteValueCommand),
+    // gives back what the hash maps hold onto after mass deletions, doesn't change any values. Keys and values are in a
+    // BTreeMap, which frees its nodes as keys are deleted, so there's nothing of theirs for it to give back.
+    Defrag,
+    // sets the key to the result of running the script on its current value, with the new value as the result
+    Script(ScriptCommand),
+    // adds a named command type to the state machine, defined by a script
+    Register(RegisterCommand),
+    // runs a registered command on a key, like Script
+    Call(CallCommand),
+    // moves or copies a key's value to another key, replacing whatever was there, and fails if the key isn't set
+    Rename(MoveCommand),
+    Copy(MoveCommand),
+    // deletes the key only if it's set and its value or version is the expected one
+    DeleteIf(DeleteIfCommand),
+    // sets the key until the time in the command, after which reads don't see it
+    SetWithTtl(SetWithTtlCommand),
+    // deletes every key that expired by the time in the command, which the leader proposes so every replica deletes the
+    // same keys at the same point in the log
+    ExpireKeys(ExpireKeysCommand),
+    // adds or replaces a named command to be applied at a time, and then every interval if it has one
+    Schedule(ScheduleCommand),
+    Unschedule(UnscheduleCommand),
+    // applies a scheduled command that's due, which the leader proposes, with the scheduled command's result
+    RunSchedule(RunScheduleCommand),
+    // appends to the key's value, an unset key being empty, with the new length as the result
+    Append(AppendCommand),
+    // adds to the key's value, an unset key being 0, with the new value as the result, and fails if the value isn't an
+    // integer or the sum overflows
+    Incr(IncrCommand),
+    // sets the part of the key's JSON document at the path, an unset key being null, and fails if the value there isn't
+    // JSON or the path can't be set
+    JsonSet(JsonSetCommand),
+    // forgets the tombstones of keys deleted before the time in the command, which the leader proposes once they're
+    // older than KV_TOMBSTONE_RETENTION_SECS
+    PurgeTombstones(PurgeTombstonesCommand),
+    // written by a newer version of this program, kept as-is and applied as a failed no-op
+    Unknown { tag: u32, payload: Vec<u8> },
+}
+
+impl KvOp {
+    // whether what it does depends on a key's current value, which it mustn't get from a key that's expired
+    pub fn reads_current(&self) -> bool {
+        matches!(self, KvOp::Cas(_) | KvOp::GetSet(_) | KvOp::GetDelete(_) | KvOp::Script(_) | KvOp::Call(_) | KvOp::Rename(_)
+            | KvOp::Copy(_) | KvOp::DeleteIf(_) | KvOp::RunSchedule(_) | KvOp::Append(_) | KvOp::Incr(_) | KvOp::JsonSet(_))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct SetValueCommand {
     pub key: String,
     pub value: String,
-    pub mid: String,
+}
+
+// times are milliseconds since the unix epoch, as seen by the node that took the command
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetWithTtlCommand {
+    pub key: String,
+    pub value: String,
+    pub expires_at_ms: u64,
+    // the client to tell when the key expires, if it wants to be told
+    pub notify: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpireKeysCommand {
+    pub now_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PurgeTombstonesCommand {
+    pub before_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppendCommand {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncrCommand {
+    pub key: String,
+    pub by: i64,
+}
+
+// the value is JSON text
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonSetCommand {
+    pub key: String,
+    pub path: String,
+    pub value: String,
+}
+
+// can't schedule the commands that manage schedules
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleCommand {
+    pub name: String,
+    pub at_ms: u64,
+    // zero runs it once
+    pub interval_ms: u64,
+    pub op: Box<KvOp>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnscheduleCommand {
+    pub name: String,
+}
+
+// Only runs the schedule if it's still due at due_at_ms, so running it twice, like when a new leader proposes it again
+// before the old proposal is applied, does nothing the second time. Runs missed while there was no leader aren't made
+// up, the next run is the first one after now_ms.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunScheduleCommand {
+    pub name: String,
+    pub due_at_ms: u64,
+    pub now_ms: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub deleted_at_ms: u64,
+    // the applied index of the delete, like a version
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Schedule {
+    op: KvOp,
+    next_at_ms: u64,
+    interval_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeleteValueCommand {
+    pub key: String,
+    // when the leader took it, only set when it keeps tombstones of deleted keys
+    pub deleted_at_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CasCommand {
+    pub key: String,
+    pub expected: Option<String>,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptCommand {
+    pub key: String,
+    pub script: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterCommand {
+    pub name: String,
+    pub script: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallCommand {
+    pub name: String,
+    pub key: String,
+    pub arg: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoveCommand {
+    pub from: String,
+    pub to: String,
+    // when a rename deletes from, for its tombstone like a delete's, copies never have one
+    pub deleted_at_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeleteIfCommand {
+    pub key: String,
+    pub expected: Expected,
+    // like DeleteValueCommand's
+    pub deleted_at_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expected {
+    Value(String),
+    Version(u32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchSetCommand(pub Vec<(String, String)>);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CommandResult {
+    Ok,
+    Failed,
+    Value(Option<String>),
+}
+
+#[derive(Clone, Default)]
+struct CommandResults {
+    // along with the applied index of the command
+    by_mid: HashMap<String, (CommandResult, u32)>,
+    order: VecDeque<String>,
+}
+
+impl CommandResults {
+    fn record(&mut self, mid: &str, result: CommandResult, index: u32) {
+        if self.by_mid.insert(mid.to_string(), (result, index)).is_none() {
+            self.order.push_back(mid.to_string());
+        }
+        while self.order.len() > MAX_RESULTS {
+            if let Some(old) = self.order.pop_front() {
+                self.by_mid.remove(&old);
+            }
+        }
+    }
+}
+
+// How long applies are taking. A slow apply holds up everything on the node including heartbeats, so it's worth
+// knowing about, and a run of them trips a breaker the network answers by refusing writes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApplyStats {
+    pub applies: u64,
+    pub over_budget: u64,
+    pub slowest_us: u64,
+    pub last_us: u64,
+    consecutive_over_budget: u32,
+    // times the breaker has tripped
+    pub trips: u32,
+}
+
+impl ApplyStats {
+    fn record(&mut self, mid: &str, took: Duration, budget_us: u64, trip_after: u32) {
+        let took_us = took.as_micros() as u64;
+        self.applies += 1;
+        self.slowest_us = self.slowest_us.max(took_us);
+        self.last_us = took_us;
+
+        if budget_us == 0 || took_us <= budget_us {
+            self.consecutive_over_budget = 0;
+            return;
+        }
+
+        self.over_budget += 1;
+        self.consecutive_over_budget += 1;
+        eprintln!("applying {} took {}us, over the {}us budget", mid, took_us, budget_us);
+        if self.consecutive_over_budget == trip_after {
+            self.trips += 1;
+            eprintln!("ALERT: {} applies in a row went over budget, tripping the apply breaker", trip_after);
+        }
+    }
 }
 
 #[derive(Clone, Default)]
-pub struct KvStateMachine(pub HashMap<String, String>);
+pub struct KvStateMachine {
+    // ordered so ranges of keys can be read without going through all of them
+    pub data: BTreeMap<String, String>,
+    // registered command types, by name
+    pub commands: HashMap<String, String>,
+    // A key's version is the applied index of the command that last wrote it, which is the index in that write's ok
+    // response. Keys set outside of commands have none.
+    versions: HashMap<String, u32>,
+    // When keys set with a TTL expire, by key and in order. Expired keys stay in data until an ExpireKeys command is
+    // applied, commands still see them, reads don't. The leader proposes one ahead of any command that depends on a
+    // key's current value once a key is due, see KvOp::reads_current.
+    expiries: HashMap<String, u64>,
+    expiry_order: BTreeSet<(u64, String)>,
+    // the clients of keys that were set with notify, by key
+    expiry_owners: HashMap<String, u32>,
+    // the keys with owners expired by recent ExpireKeys commands, by their applied index, not part of snapshots
+    expired_notices: VecDeque<(u32, Vec<(u32, String)>)>,
+    // scheduled commands by name, and by when they're next due
+    schedules: BTreeMap<String, Schedule>,
+    schedule_order: BTreeSet<(u64, String)>,
+    // Keys deleted by commands the leader stamped with a time, kept until a PurgeTombstones command forgets them, so
+    // consumers of changes and mirrors that catch up from a snapshot still see the deletes. Setting a key again drops
+    // its tombstone.
+    tombstones: BTreeMap<String, Tombstone>,
+    tombstone_order: BTreeSet<(u64, String)>,
+    // number of commands applied, which is the same on every node at the same point in the log, so clients can use it
+    // to order their writes and reads across nodes
+    applied: u32,
+    // used to answer the clients of recently applied commands, and their retries
+    results: CommandResults,
+    // not part of snapshots
+    apply_stats: ApplyStats,
+}
+
+impl KvStateMachine {
+    pub fn result(&self, mid: &str) -> Option<&CommandResult> {
+        self.results.by_mid.get(mid).map(|(result, _)| result)
+    }
+
+    // the applied index of a recently applied command
+    pub fn applied_at(&self, mid: &str) -> Option<u32> {
+        self.results.by_mid.get(mid).map(|(_, index)| *index)
+    }
+
+    pub fn applied_index(&self) -> u32 {
+        self.applied
+    }
+
+    pub fn version(&self, key: &str) -> Option<u32> {
+        self.versions.get(key).copied()
+    }
+
+    pub fn apply_stats(&self) -> &ApplyStats {
+        &self.apply_stats
+    }
+
+    // the key's value as a read sees it, which is none once it's expired
+    pub fn get(&self, key: &str, now_ms: u64) -> Option<&String> {
+        if self.is_expired(key, now_ms) {
+            return None;
+        }
+        self.data.get(key)
+    }
+
+    // the keys from start up to but not including end, in order, as a read sees them
+    pub fn range<'a>(&'a self, start: &str, end: Option<&str>, now_ms: u64) -> impl Iterator<Item = (&'a String, &'a String)> + 'a {
+        let end = match end {
+            // an empty range, rather than one that makes BTreeMap panic
+            Some(end) if end <= start => Bound::Excluded(start),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.data.range::<str, _>((Bound::Included(start), end))
+            .filter(move |(key, _)| !self.is_expired(key, now_ms))
+    }
+
+    fn is_expired(&self, key: &str, now_ms: u64) -> bool {
+        self.expiries.get(key).map_or(false, |expires_at_ms| *expires_at_ms <= now_ms)
+    }
+
+    pub fn expires_at(&self, key: &str) -> Option<u64> {
+        self.expiries.get(key).copied()
+    }
+
+    // when the next key expires
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.expiry_order.iter().next().map(|(expires_at_ms, _)| *expires_at_ms)
+    }
+
+    // the clients to notify and their keys expired by the ExpireKeys command applied at the index
+    pub fn expired_notices(&self, index: u32) -> &[(u32, String)] {
+        self.expired_notices.iter().find(|(i, _)| *i == index).map_or(&[], |(_, notices)| notices.as_slice())
+    }
+
+    // the tombstones of keys with the prefix deleted at or after since_ms, in key order
+    pub fn tombstones<'a>(&'a self, prefix: &'a str, since_ms: u64) -> impl Iterator<Item = (&'a String, &'a Tombstone)> + 'a {
+        self.tombstones.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .filter(move |(_, tombstone)| tombstone.deleted_at_ms >= since_ms)
+    }
+
+    // when the oldest tombstone's key was deleted
+    pub fn oldest_tombstone(&self) -> Option<u64> {
+        self.tombstone_order.iter().next().map(|(deleted_at_ms, _)| *deleted_at_ms)
+    }
+
+    // the schedules due soonest, with when they're due
+    pub fn upcoming_schedules(&self, limit: usize) -> Vec<(u64, String)> {
+        self.schedule_order.iter().take(limit).cloned().collect()
+    }
+}
+
+impl KvStateMachine {
+    // every write goes through these two so the key's version is kept up to date
+    // and any TTL it had is cleared, like in redis
+    fn set(&mut self, key: &str, value: String) -> Option<String> {
+        self.clear_expiry(key);
+        self.update(key, value)
+    }
+
+    // changes the value, keeping its TTL
+    fn update(&mut self, key: &str, value: String) -> Option<String> {
+        self.forget_tombstone(key);
+        self.versions.insert(key.to_string(), self.applied + 1);
+        self.data.insert(key.to_string(), value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        self.clear_expiry(key);
+        self.versions.remove(key);
+        self.data.remove(key)
+    }
+
+    // removes the key, leaving a tombstone if it was set and the delete has a time
+    fn delete(&mut self, key: &str, deleted_at_ms: Option<u64>) -> Option<String> {
+        let removed = self.remove(key);
+        if let (Some(_), Some(deleted_at_ms)) = (&removed, deleted_at_ms) {
+            self.forget_tombstone(key);
+            self.tombstone_order.insert((deleted_at_ms, key.to_string()));
+            self.tombstones.insert(key.to_string(), Tombstone { deleted_at_ms, index: self.applied + 1 });
+        }
+        removed
+    }
+
+    fn forget_tombstone(&mut self, key: &str) {
+        if let Some(tombstone) = self.tombstones.remove(key) {
+            self.tombstone_order.remove(&(tombstone.deleted_at_ms, key.to_string()));
+        }
+    }
+
+    fn purge_tombstones(&mut self, before_ms: u64) -> usize {
+        let purged: Vec<String> = self.tombstone_order.iter()
+            .take_while(|(deleted_at_ms, _)| *deleted_at_ms < before_ms)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &purged {
+            self.forget_tombstone(key);
+        }
+        purged.len()
+    }
+
+    fn set_expiry(&mut self, key: &str, expires_at_ms: u64, owner: Option<u32>) {
+        self.clear_expiry(key);
+        self.expiries.insert(key.to_string(), expires_at_ms);
+        self.expiry_order.insert((expires_at_ms, key.to_string()));
+        if let Some(owner) = owner {
+            self.expiry_owners.insert(key.to_string(), owner);
+        }
+    }
+
+    fn clear_expiry(&mut self, key: &str) {
+        if let Some(expires_at_ms) = self.expiries.remove(key) {
+            self.expiry_order.remove(&(expires_at_ms, key.to_string()));
+            self.expiry_owners.remove(key);
+        }
+    }
+
+    fn json_set(&mut self, key: &str, path: &str, value: &str) -> Option<()> {
+        let mut doc = match self.data.get(key) {
+            Some(doc) => serde_json::from_str(doc).ok()?,
+            None => serde_json::Value::Null,
+        };
+        let path = json_path::parse(path)?;
+        let value = serde_json::from_str(value).ok()?;
+        if !json_path::set(&mut doc, &path, value) {
+            return None;
+        }
+        // objects keep their keys sorted, so every replica writes the same text
+        self.update(key, doc.to_string());
+        Some(())
+    }
+
+    fn schedule(&mut self, name: &str, schedule: Schedule) {
+        self.unschedule(name);
+        self.schedule_order.insert((schedule.next_at_ms, name.to_string()));
+        self.schedules.insert(name.to_string(), schedule);
+    }
+
+    fn unschedule(&mut self, name: &str) -> bool {
+        match self.schedules.remove(name) {
+            Some(schedule) => self.schedule_order.remove(&(schedule.next_at_ms, name.to_string())),
+            None => false,
+        }
+    }
+
+    fn run_schedule(&mut self, name: &str, due_at_ms: u64, now_ms: u64) -> CommandResult {
+        let schedule = match self.schedules.get(name) {
+            Some(schedule) if schedule.next_at_ms == due_at_ms => schedule.clone(),
+            _ => return CommandResult::Failed,
+        };
+        self.unschedule(name);
+        if schedule.interval_ms > 0 {
+            let missed = now_ms.saturating_sub(due_at_ms) / schedule.interval_ms;
+            let next_at_ms = due_at_ms.saturating_add(schedule.interval_ms.saturating_mul(missed + 1));
+            self.schedule(name, Schedule { next_at_ms, ..schedule.clone() });
+        }
+        self.apply_op(&schedule.op)
+    }
+
+    fn expire_keys(&mut self, now_ms: u64) -> usize {
+        let expired: Vec<String> = self.expiry_order.iter()
+            .take_while(|(expires_at_ms, _)| *expires_at_ms <= now_ms)
+            .map(|(_, key)| key.clone())
+            .collect();
+        let mut notices = vec![];
+        for key in &expired {
+            if let Some(owner) = self.expiry_owners.get(key) {
+                notices.push((*owner, key.clone()));
+            }
+            self.remove(key);
+        }
+
+        if !notices.is_empty() {
+            self.expired_notices.push_back((self.applied + 1, notices));
+            if self.expired_notices.len() > MAX_EXPIRED_NOTICES {
+                self.expired_notices.pop_front();
+            }
+        }
+        expired.len()
+    }
+
+    fn apply_op(&mut self, op: &KvOp) -> CommandResult {
+        match op {
+            KvOp::Set(SetValueCommand { key, value }) => {
+                self.set(key, value.clone());
+                CommandResult::Ok
+            }
+            KvOp::Delete(DeleteValueCommand { key, deleted_at_ms }) => {
+                self.delete(key, *deleted_at_ms);
+                CommandResult::Ok
+            }
+            KvOp::Cas(CasCommand { key, expected, value }) => {
+                if self.data.get(key) == expected.as_ref() {
+                    self.set(key, value.clone());
+                    CommandResult::Ok
+                } else {
+                    CommandResult::Failed
+                }
+            }
+            KvOp::Batch(BatchSetCommand(pairs)) => {
+                for (key, value) in pairs {
+                    self.set(key, value.clone());
+                }
+                CommandResult::Ok
+            }
+            KvOp::GetSet(SetValueCommand { key, value }) =>
+                CommandResult::Value(self.set(key, value.clone())),
+            KvOp::GetDelete(DeleteValueCommand { key, deleted_at_ms }) =>
+                CommandResult::Value(self.delete(key, *deleted_at_ms)),
+            KvOp::Defrag => {
+                self.versions.shrink_to_fit();
+                self.expiries.shrink_to_fit();
+                self.expiry_owners.shrink_to_fit();
+                self.commands.shrink_to_fit();
+                self.results.by_mid.shrink_to_fit();
+                self.results.order.shrink_to_fit();
+                CommandResult::Ok
+            }
+            KvOp::Script(ScriptCommand { key, script }) => self.run_script(key, script, ""),
+            KvOp::Register(RegisterCommand { name, script }) => {
+                if script::validate(script).is_ok() {
+                    self.commands.insert(name.clone(), script.clone());
+                    CommandResult::Ok
+                } else {
+                    CommandResult::Failed
+                }
+            }
+            KvOp::Call(CallCommand { name, key, arg }) => match self.commands.get(name).cloned() {
+                Some(script) => self.run_script(key, &script, arg),
+                None => CommandResult::Failed,
+            },
+            KvOp::Rename(MoveCommand { from, to, deleted_at_ms }) => match self.delete(from, *deleted_at_ms) {
+                Some(value) => {
+                    self.set(to, value);
+                    CommandResult::Ok
+                }
+                None => CommandResult::Failed,
+            },
+            KvOp::Copy(MoveCommand { from, to, .. }) => match self.data.get(from).cloned() {
+                Some(value) => {
+                    self.set(to, value);
+                    CommandResult::Ok
+                }
+                None => CommandResult::Failed,
+            },
+            KvOp::DeleteIf(DeleteIfCommand { key, expected, deleted_at_ms }) => {
+                let matches = match expected {
+                    Expected::Value(value) => self.data.get(key) == Some(value),
+                    Expected::Version(version) => self.data.contains_key(key) && self.version(key) == Some(*version),
+                };
+                if matches {
+                    self.delete(key, *deleted_at_ms);
+                    CommandResult::Ok
+                } else {
+                    CommandResult::Failed
+                }
+            }
+            KvOp::SetWithTtl(SetWithTtlCommand { key, value, expires_at_ms, notify }) => {
+                self.set(key, value.clone());
+                self.set_expiry(key, *expires_at_ms, *notify);
+                CommandResult::Ok
+            }
+            KvOp::ExpireKeys(ExpireKeysCommand { now_ms }) =>
+                CommandResult::Value(Some(self.expire_keys(*now_ms).to_string())),
+            KvOp::PurgeTombstones(PurgeTombstonesCommand { before_ms }) =>
+                CommandResult::Value(Some(self.purge_tombstones(*before_ms).to_string())),
+            KvOp::Schedule(ScheduleCommand { op, .. }) if matches!(**op, KvOp::Schedule(_) | KvOp::Unschedule(_) | KvOp::RunSchedule(_)) =>
+                CommandResult::Failed,
+            KvOp::Schedule(ScheduleCommand { name, at_ms, interval_ms, op }) => {
+                self.schedule(name, Schedule { op: (**op).clone(), next_at_ms: *at_ms, interval_ms: *interval_ms });
+                CommandResult::Ok
+            }
+            KvOp::Unschedule(UnscheduleCommand { name }) =>
+                if self.unschedule(name) { CommandResult::Ok } else { CommandResult::Failed },
+            KvOp::RunSchedule(RunScheduleCommand { name, due_at_ms, now_ms }) => self.run_schedule(name, *due_at_ms, *now_ms),
+            KvOp::Append(AppendCommand { key, value }) => {
+                let appended = self.data.get(key).cloned().unwrap_or_default() + value;
+                let len = appended.len();
+                self.update(key, appended);
+                CommandResult::Value(Some(len.to_string()))
+            }
+            KvOp::JsonSet(JsonSetCommand { key, path, value }) => match self.json_set(key, path, value) {
+                Some(()) => CommandResult::Ok,
+                None => CommandResult::Failed,
+            },
+            KvOp::Incr(IncrCommand { key, by }) => {
+                let current = match self.data.get(key) {
+                    Some(value) => value.parse::<i64>().ok(),
+                    None => Some(0),
+                };
+                match current.and_then(|current| current.checked_add(*by)) {
+                    Some(sum) => {
+                        self.update(key, sum.to_string());
+                        CommandResult::Value(Some(sum.to_string()))
+                    }
+                    None => CommandResult::Failed,
+                }
+            }
+            KvOp::Unknown { .. } => CommandResult::Failed,
+        }
+    }
+
+    fn run_script(&mut self, key: &str, script: &str, arg: &str) -> CommandResult {
+        let current = self.data.get(key).map(|v| v.as_str()).unwrap_or("");
+        match script::run(script, current, arg) {
+            Ok(value) => {
+                self.set(key, value.clone());
+                CommandResult::Value(Some(value))
+            }
+            Err(_) => CommandResult::Failed,
+        }
+    }
+}
 
 impl StateMachine for KvStateMachine {
-    type Command = SetValueCommand;
+    type Command = KvCommand;
 
     fn apply_command(&mut self, command: &Self::Command) {
-        self.0.insert(command.key.clone(), command.value.clone());
+        let _subsystem = alloc_stats::enter(Subsystem::StateMachine);
+        let started = Instant::now();
+
+        let result = self.apply_op(&command.op);
+
+        self.applied += 1;
+        self.results.record(&command.mid, result, self.applied);
+        self.apply_stats.record(&command.mid, started.elapsed(), APPLY_BUDGET_US.load(Ordering::Relaxed), APPLY_BREAKER_AFTER.load(Ordering::Relaxed));
+    }
+}
+
+// The state machine as it's written in snapshot formats other than the native one. Schedules are ScheduleCommands in the
+// native encoding, with when they're next due, so the ops in them don't need to be anything but bytes.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PortableState {
+    data: BTreeMap<String, String>,
+    commands: BTreeMap<String, String>,
+    versions: BTreeMap<String, u32>,
+    expiries: BTreeMap<String, u64>,
+    expiry_owners: BTreeMap<String, u32>,
+    schedules: Vec<Vec<u8>>,
+    // oldest first, along with the applied index
+    results: Vec<(String, CommandResult, u32)>,
+    applied: u32,
+    // snapshots in JSON or CBOR from before there were tombstones don't have them, bincode ones are read as
+    // PortableStateWithoutTombstones
+    #[serde(default)]
+    tombstones: BTreeMap<String, Tombstone>,
+}
+
+// PortableState as bincode snapshots were written before there were tombstones. Bincode has no field names to go by, so
+// those are tagged as a format of their own.
+#[derive(Serialize, Deserialize)]
+pub struct PortableStateWithoutTombstones {
+    data: BTreeMap<String, String>,
+    commands: BTreeMap<String, String>,
+    versions: BTreeMap<String, u32>,
+    expiries: BTreeMap<String, u64>,
+    expiry_owners: BTreeMap<String, u32>,
+    schedules: Vec<Vec<u8>>,
+    results: Vec<(String, CommandResult, u32)>,
+    applied: u32,
+}
+
+impl From<PortableStateWithoutTombstones> for PortableState {
+    fn from(state: PortableStateWithoutTombstones) -> PortableState {
+        PortableState {
+            data: state.data,
+            commands: state.commands,
+            versions: state.versions,
+            expiries: state.expiries,
+            expiry_owners: state.expiry_owners,
+            schedules: state.schedules,
+            results: state.results,
+            applied: state.applied,
+            tombstones: BTreeMap::new(),
+        }
+    }
+}
+
+impl KvStateMachine {
+    fn to_portable(&self) -> io::Result<PortableState> {
+        let mut schedules = vec![];
+        for (name, schedule) in &self.schedules {
+            let command = ScheduleCommand { name: name.clone(), at_ms: schedule.next_at_ms, interval_ms: schedule.interval_ms, op: Box::new(schedule.op.clone()) };
+            let mut bytes = vec![];
+            command.write_bytes_with_writer(&mut bytes)?;
+            schedules.push(bytes);
+        }
+        Ok(PortableState {
+            data: self.data.clone(),
+            commands: self.commands.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            versions: self.versions.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            expiries: self.expiries.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            expiry_owners: self.expiry_owners.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            schedules,
+            results: self.results.order.iter().map(|mid| {
+                let (result, index) = &self.results.by_mid[mid];
+                (mid.clone(), result.clone(), *index)
+            }).collect(),
+            applied: self.applied,
+            tombstones: self.tombstones.clone(),
+        })
+    }
+
+    fn from_portable(state: PortableState) -> Option<KvStateMachine> {
+        let mut schedules = BTreeMap::new();
+        let mut schedule_order = BTreeSet::new();
+        for bytes in &state.schedules {
+            let command = ScheduleCommand::try_from_slice(bytes)?;
+            schedule_order.insert((command.at_ms, command.name.clone()));
+            schedules.insert(command.name, Schedule { op: *command.op, next_at_ms: command.at_ms, interval_ms: command.interval_ms });
+        }
+        let mut results = CommandResults::default();
+        for (mid, result, index) in state.results {
+            results.record(&mid, result, index);
+        }
+        Some(KvStateMachine {
+            data: state.data,
+            commands: state.commands.into_iter().collect(),
+            versions: state.versions.into_iter().collect(),
+            expiry_order: state.expiries.iter().map(|(key, at_ms)| (*at_ms, key.clone())).collect(),
+            expiries: state.expiries.into_iter().collect(),
+            expiry_owners: state.expiry_owners.into_iter().collect(),
+            expired_notices: VecDeque::new(),
+            schedules,
+            schedule_order,
+            tombstone_order: state.tombstones.iter().map(|(key, tombstone)| (tombstone.deleted_at_ms, key.clone())).collect(),
+            tombstones: state.tombstones,
+            applied: state.applied,
+            results,
+            apply_stats: ApplyStats::default(),
+        })
     }
 }
 
 impl TryFromBytes for KvStateMachine {
     fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
-        let mut map = HashMap::new();
+        let mut data = BTreeMap::new();
+
+        let mut len = bytes.next_u32()?;
+        if len == FORMAT_MARKER {
+            let format = snapshot_format::by_tag(bytes.next_u32()?)?;
+            let encoded_len = bytes.next_u32()?;
+            return KvStateMachine::from_portable(format.decode(bytes.next_bytes(encoded_len as usize)?)?);
+        }
+
+        let mut tombstones = BTreeMap::new();
+        let mut tombstone_order = BTreeSet::new();
+        if len == TOMBSTONES_MARKER {
+            let tombstones_len = bytes.next_u32()?;
+            for _ in 0..tombstones_len {
+                let key = read_string(&mut bytes)?;
+                let deleted_at_ms = read_u64(&mut bytes)?;
+                let index = bytes.next_u32()?;
+                tombstone_order.insert((deleted_at_ms, key.clone()));
+                tombstones.insert(key, Tombstone { deleted_at_ms, index });
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut expiry_owners = HashMap::new();
+        if len == EXPIRY_OWNERS_MARKER {
+            let owners_len = bytes.next_u32()?;
+            for _ in 0..owners_len {
+                let key = read_string(&mut bytes)?;
+                expiry_owners.insert(key, bytes.next_u32()?);
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut schedules = BTreeMap::new();
+        let mut schedule_order = BTreeSet::new();
+        if len == SCHEDULES_MARKER {
+            let schedules_len = bytes.next_u32()?;
+            for _ in 0..schedules_len {
+                let name = read_string(&mut bytes)?;
+                let next_at_ms = read_u64(&mut bytes)?;
+                let interval_ms = read_u64(&mut bytes)?;
+                let op = read_op(&mut bytes)?;
+                schedule_order.insert((next_at_ms, name.clone()));
+                schedules.insert(name, Schedule { op, next_at_ms, interval_ms });
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut expiries = HashMap::new();
+        let mut expiry_order = BTreeSet::new();
+        if len == EXPIRIES_MARKER {
+            let expiries_len = bytes.next_u32()?;
+            for _ in 0..expiries_len {
+                let key = read_string(&mut bytes)?;
+                let expires_at_ms = read_u64(&mut bytes)?;
+                expiry_order.insert((expires_at_ms, key.clone()));
+                expiries.insert(key, expires_at_ms);
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut results = CommandResults::default();
+        if len == RESULTS_MARKER {
+            let results_len = bytes.next_u32()?;
+            for _ in 0..results_len {
+                let mid = read_string(&mut bytes)?;
+                let result = read_result(&mut bytes)?;
+                results.record(&mid, result, bytes.next_u32()?);
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut versions = HashMap::new();
+        if len == VERSIONS_MARKER {
+            let versions_len = bytes.next_u32()?;
+            for _ in 0..versions_len {
+                let key = read_string(&mut bytes)?;
+                versions.insert(key, bytes.next_u32()?);
+            }
+            len = bytes.next_u32()?;
+        }
+
+        let mut dictionary = vec![];
+        if len == DEDUP_MARKER {
+            let dictionary_len = bytes.next_u32()?;
+            for _ in 0..dictionary_len {
+                dictionary.push(read_string(&mut bytes)?);
+            }
+            len = bytes.next_u32()?;
+        }
 
-        let len = bytes.next_u32()?;
         for _ in 0..len {
-            let key_len = bytes.next_u32()?;
-            let key = String::from_utf8(bytes.next_bytes(key_len as usize)?.to_vec()).unwrap();
+            let key = read_string(&mut bytes)?;
             let value_len = bytes.next_u32()?;
-            let value = String::from_utf8(bytes.next_bytes(value_len as usize)?.to_vec()).unwrap();
-            map.insert(key, value);
+            let value = if value_len & VALUE_REF_BIT != 0 {
+                dictionary.get((value_len & !VALUE_REF_BIT) as usize)?.clone()
+            } else {
+                String::from_utf8(bytes.next_bytes(value_len as usize)?.to_vec()).ok()?
+            };
+            data.insert(key, value);
+        }
+
+        let mut commands = HashMap::new();
+        let len = bytes.next_u32()?;
+        for _ in 0..len {
+            let name = read_string(&mut bytes)?;
+            let script = read_string(&mut bytes)?;
+            commands.insert(name, script);
         }
-        Some(KvStateMachine(map))
+        let applied = bytes.next_u32()?;
+        Some(KvStateMachine {
+            data,
+            commands,
+            versions,
+            expiries,
+            expiry_order,
+            expiry_owners,
+            expired_notices: VecDeque::new(),
+            schedules,
+            schedule_order,
+            tombstones,
+            tombstone_order,
+            applied,
+            results,
+            apply_stats: ApplyStats::default(),
+        })
     }
 }
 
 impl WriteBytes for KvStateMachine {
     fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
-        writer.write_u32(self.0.len() as u32)?;
-        for (key, value) in &self.0 {
-            writer.write_u32(key.len() as u32)?;
-            writer.write(key.as_bytes())?;
-            writer.write_u32(value.len() as u32)?;
-            writer.write(value.as_bytes())?;
+        if let Some(format) = snapshot_format::writing_with().format {
+            let encoded = format.encode(&self.to_portable()?).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            writer.write_u32(FORMAT_MARKER)?;
+            writer.write_u32(format.tag())?;
+            writer.write_u32(encoded.len() as u32)?;
+            return writer.write(&encoded);
+        }
+
+        // these are left out when there are none, so the snapshots can still be read by older versions
+        if !self.tombstones.is_empty() {
+            writer.write_u32(TOMBSTONES_MARKER)?;
+            writer.write_u32(self.tombstones.len() as u32)?;
+            for (key, tombstone) in &self.tombstones {
+                write_string(writer, key)?;
+                write_u64(writer, tombstone.deleted_at_ms)?;
+                writer.write_u32(tombstone.index)?;
+            }
+        }
+        if !self.expiry_owners.is_empty() {
+            writer.write_u32(EXPIRY_OWNERS_MARKER)?;
+            writer.write_u32(self.expiry_owners.len() as u32)?;
+            for (key, owner) in &self.expiry_owners {
+                write_string(writer, key)?;
+                writer.write_u32(*owner)?;
+            }
+        }
+        if !self.schedules.is_empty() {
+            writer.write_u32(SCHEDULES_MARKER)?;
+            writer.write_u32(self.schedules.len() as u32)?;
+            for (name, schedule) in &self.schedules {
+                write_string(writer, name)?;
+                write_u64(writer, schedule.next_at_ms)?;
+                write_u64(writer, schedule.interval_ms)?;
+                write_op(writer, &schedule.op)?;
+            }
+        }
+        if !self.expiry_order.is_empty() {
+            writer.write_u32(EXPIRIES_MARKER)?;
+            writer.write_u32(self.expiry_order.len() as u32)?;
+            for (expires_at_ms, key) in &self.expiry_order {
+                write_string(writer, key)?;
+                write_u64(writer, *expires_at_ms)?;
+            }
+        }
+
+        writer.write_u32(RESULTS_MARKER)?;
+        writer.write_u32(self.results.order.len() as u32)?;
+        for mid in &self.results.order {
+            let (result, index) = &self.results.by_mid[mid];
+            write_string(writer, mid)?;
+            write_result(writer, result)?;
+            writer.write_u32(*index)?;
+        }
+
+        writer.write_u32(VERSIONS_MARKER)?;
+        writer.write_u32(self.versions.len() as u32)?;
+        for (key, version) in &self.versions {
+            write_string(writer, key)?;
+            writer.write_u32(*version)?;
+        }
+
+        if snapshot_format::writing_with().dedup {
+            self.write_deduped_data(writer)?;
+        } else {
+            writer.write_u32(self.data.len() as u32)?;
+            for (key, value) in &self.data {
+                write_string(writer, key)?;
+                write_string(writer, value)?;
+            }
+        }
+        self.write_commands_and_applied(writer)
+    }
+}
+
+impl KvStateMachine {
+    fn write_deduped_data<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for value in self.data.values() {
+            *counts.entry(value.as_str()).or_insert(0) += 1;
+        }
+
+        // a reference takes 4 bytes, so shorter values are cheaper to repeat
+        let mut dictionary: HashMap<&str, u32> = HashMap::new();
+        let mut interned = vec![];
+        for (value, count) in counts {
+            if count > 1 && value.len() > 4 {
+                dictionary.insert(value, interned.len() as u32);
+                interned.push(value);
+            }
+        }
+
+        writer.write_u32(DEDUP_MARKER)?;
+        writer.write_u32(interned.len() as u32)?;
+        for value in interned {
+            write_string(writer, value)?;
+        }
+
+        writer.write_u32(self.data.len() as u32)?;
+        for (key, value) in &self.data {
+            write_string(writer, key)?;
+            match dictionary.get(value.as_str()) {
+                Some(i) => writer.write_u32(i | VALUE_REF_BIT)?,
+                None => write_string(writer, value)?,
+            }
         }
         Ok(())
     }
+
+    fn write_commands_and_applied<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        writer.write_u32(self.commands.len() as u32)?;
+        for (name, script) in &self.commands {
+            write_string(writer, name)?;
+            write_string(writer, script)?;
+        }
+        writer.write_u32(self.applied)
+    }
 }
 
-impl TryFromBytes for SetValueCommand {
+// Commands are written as the MID, a tag, and a length prefixed payload, so entries with tags this version doesn't
+// know about can still be read (and replicated) without understanding the payload.
+impl TryFromBytes for KvCommand {
     fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
-        let key_len = bytes.next_u32()?;
-        let key = String::from_utf8(bytes.next_bytes(key_len as usize)?.to_vec()).unwrap();
-        let value_len = bytes.next_u32()?;
-        let value = String::from_utf8(bytes.next_bytes(value_len as usize)?.to_vec()).unwrap();
-        let mid_len = bytes.next_u32()?;
-        let mid = String::from_utf8(bytes.next_bytes(mid_len as usize)?.to_vec()).unwrap();
-        Some(SetValueCommand { key, value, mid })
+        let mid = read_string(&mut bytes)?;
+        let op = read_op(&mut bytes)?;
+        Some(KvCommand { mid, op })
     }
 }
 
-impl WriteBytes for SetValueCommand {
+impl WriteBytes for KvCommand {
     fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
-        writer.write_u32(self.key.len() as u32)?;
-        writer.write(self.key.as_bytes())?;
-        writer.write_u32(self.value.len() as u32)?;
-        writer.write(self.value.as_bytes())?;
-        writer.write_u32(self.mid.len() as u32)?;
-        writer.write(self.mid.as_bytes())
+        write_string(writer, &self.mid)?;
+        write_op(writer, &self.op)
     }
 }
 
-pub fn clone_state_machine<S: StateMachine + Clone>(state_machine: &RaftStateMachine<S>) -> RaftStateMachine<S> {
-    RaftStateMachine {
-        inner: state_machine.inner.clone(),
-        config: state_machine.config.clone(),
-        client_last_command_ids: state_machine.client_last_command_ids.clone(),
-    }
-}
\ No newline at end of file
+fn read_op(bytes: &mut impl ReadBytes) -> Option<KvOp> {
+    let tag = bytes.next_u32()?;
+    let payload_len = bytes.next_u32()?;
+    let payload = bytes.next_bytes(payload_len as usize)?.to_vec();
+
+    Some(match tag {
+        SET_TAG => KvOp::Set(SetValueCommand::try_from_slice(&payload)?),
+        DELETE_TAG => KvOp::Delete(DeleteValueCommand::try_from_slice(&payload)?),
+        CAS_TAG => KvOp::Cas(CasCommand::try_from_slice(&payload)?),
+        BATCH_TAG => KvOp::Batch(BatchSetCommand::try_from_slice(&payload)?),
+        GET_SET_TAG => KvOp::GetSet(SetValueCommand::try_from_slice(&payload)?),
+        GET_DELETE_TAG => KvOp::GetDelete(DeleteValueCommand::try_from_slice(&payload)?),
+        DEFRAG_TAG => KvOp::Defrag,
+        SCRIPT_TAG => KvOp::Script(ScriptCommand::try_from_slice(&payload)?),
+        REGISTER_TAG => KvOp::Register(RegisterCommand::try_from_slice(&payload)?),
+        CALL_TAG => KvOp::Call(CallCommand::try_from_slice(&payload)?),
+        RENAME_TAG => KvOp::Rename(MoveCommand::try_from_slice(&payload)?),
+        COPY_TAG => KvOp::Copy(MoveCommand::try_from_slice(&payload)?),
+        DELETE_IF_TAG => KvOp::DeleteIf(DeleteIfCommand::try_from_slice(&payload)?),
+        SET_WITH_TTL_TAG => KvOp::SetWithTtl(SetWithTtlCommand::try_from_slice(&payload)?),
+        EXPIRE_KEYS_TAG => KvOp::ExpireKeys(ExpireKeysCommand::try_from_slice(&payload)?),
+        SCHEDULE_TAG => KvOp::Schedule(ScheduleCommand::try_from_slice(&payload)?),
+        UNSCHEDULE_TAG => KvOp::Unschedule(UnscheduleCommand::try_from_slice(&payload)?),
+        RUN_SCHEDULE_TAG => KvOp::RunSchedule(RunScheduleCommand::try_from_slice(&payload)?),
+        APPEND_TAG => KvOp::Append(AppendCommand::try_from_slice(&payload)?),
+        INCR_TAG => KvOp::Incr(IncrCommand::try_from_slice(&payload)?),
+        JSON_SET_TAG => KvOp::JsonSet(JsonSetCommand::try_from_slice(&payload)?),
+        PURGE_TOMBSTONES_TAG => KvOp::PurgeTombstones(PurgeTombstonesCommand::try_from_slice(&payload)?),
+        _ => KvOp::Unknown { tag, payload },
+    })
+}
+
+fn write_op<W: Write>(writer: &mut BytesWriter<W>, op: &KvOp) -> io::Result<()> {
+    let mut payload = vec![];
+    let tag = match op {
+        KvOp::Set(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            SET_TAG
+        }
+        KvOp::Delete(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            DELETE_TAG
+        }
+        KvOp::Cas(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            CAS_TAG
+        }
+        KvOp::Batch(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            BATCH_TAG
+        }
+        KvOp::GetSet(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            GET_SET_TAG
+        }
+        KvOp::GetDelete(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            GET_DELETE_TAG
+        }
+        KvOp::Defrag => DEFRAG_TAG,
+        KvOp::Script(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            SCRIPT_TAG
+        }
+        KvOp::Register(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            REGISTER_TAG
+        }
+        KvOp::Call(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            CALL_TAG
+        }
+        KvOp::Rename(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            RENAME_TAG
+        }
+        KvOp::Copy(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            COPY_TAG
+        }
+        KvOp::DeleteIf(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            DELETE_IF_TAG
+        }
+        KvOp::SetWithTtl(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            SET_WITH_TTL_TAG
+        }
+        KvOp::ExpireKeys(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            EXPIRE_KEYS_TAG
+        }
+        KvOp::Schedule(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            SCHEDULE_TAG
+        }
+        KvOp::Unschedule(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            UNSCHEDULE_TAG
+        }
+        KvOp::RunSchedule(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            RUN_SCHEDULE_TAG
+        }
+        KvOp::Append(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            APPEND_TAG
+        }
+        KvOp::Incr(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            INCR_TAG
+        }
+        KvOp::JsonSet(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            JSON_SET_TAG
+        }
+        KvOp::PurgeTombstones(c) => {
+            c.write_bytes_with_writer(&mut payload)?;
+            PURGE_TOMBSTONES_TAG
+        }
+        KvOp::Unknown { tag, payload: unknown } => {
+            payload.extend_from_slice(unknown);
+            *tag
+        }
+    };
+
+    writer.write_u32(tag)?;
+    writer.write_u32(payload.len() as u32)?;
+    writer.write(&payload)
+}
+
+impl TryFromBytes for SetValueCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let value = read_string(&mut bytes)?;
+        Some(SetValueCommand { key, value })
+    }
+}
+
+impl WriteBytes for SetValueCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.value)
+    }
+}
+
+// the time is left off when there isn't one, so deletes are written as they always were unless tombstones are kept
+impl TryFromBytes for DeleteValueCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        Some(DeleteValueCommand { key, deleted_at_ms: read_u64(&mut bytes) })
+    }
+}
+
+impl WriteBytes for DeleteValueCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        match self.deleted_at_ms {
+            Some(deleted_at_ms) => write_u64(writer, deleted_at_ms),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFromBytes for CasCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let expected = match bytes.next_u32()? {
+            0 => None,
+            _ => Some(read_string(&mut bytes)?),
+        };
+        let value = read_string(&mut bytes)?;
+        Some(CasCommand { key, expected, value })
+    }
+}
+
+impl WriteBytes for CasCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        match &self.expected {
+            Some(expected) => {
+                writer.write_u32(1)?;
+                write_string(writer, expected)?;
+            }
+            None => writer.write_u32(0)?,
+        }
+        write_string(writer, &self.value)
+    }
+}
+
+impl TryFromBytes for ScriptCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let script = read_string(&mut bytes)?;
+        Some(ScriptCommand { key, script })
+    }
+}
+
+impl WriteBytes for ScriptCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.script)
+    }
+}
+
+impl TryFromBytes for RegisterCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let name = read_string(&mut bytes)?;
+        let script = read_string(&mut bytes)?;
+        Some(RegisterCommand { name, script })
+    }
+}
+
+impl WriteBytes for RegisterCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.name)?;
+        write_string(writer, &self.script)
+    }
+}
+
+impl TryFromBytes for CallCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let name = read_string(&mut bytes)?;
+        let key = read_string(&mut bytes)?;
+        let arg = read_string(&mut bytes)?;
+        Some(CallCommand { name, key, arg })
+    }
+}
+
+impl WriteBytes for CallCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.name)?;
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.arg)
+    }
+}
+
+// the time is left off when there isn't one, like a delete's
+impl TryFromBytes for MoveCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let from = read_string(&mut bytes)?;
+        let to = read_string(&mut bytes)?;
+        Some(MoveCommand { from, to, deleted_at_ms: read_u64(&mut bytes) })
+    }
+}
+
+impl WriteBytes for MoveCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.from)?;
+        write_string(writer, &self.to)?;
+        match self.deleted_at_ms {
+            Some(deleted_at_ms) => write_u64(writer, deleted_at_ms),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFromBytes for DeleteIfCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let expected = match bytes.next_u32()? {
+            0 => Expected::Value(read_string(&mut bytes)?),
+            1 => Expected::Version(bytes.next_u32()?),
+            _ => return None,
+        };
+        Some(DeleteIfCommand { key, expected, deleted_at_ms: read_u64(&mut bytes) })
+    }
+}
+
+impl WriteBytes for DeleteIfCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        match &self.expected {
+            Expected::Value(value) => {
+                writer.write_u32(0)?;
+                write_string(writer, value)?;
+            }
+            Expected::Version(version) => {
+                writer.write_u32(1)?;
+                writer.write_u32(*version)?;
+            }
+        }
+        match self.deleted_at_ms {
+            Some(deleted_at_ms) => write_u64(writer, deleted_at_ms),
+            None => Ok(()),
+        }
+    }
+}
+
+// the client to notify is left off when there isn't one, like the time of a delete, so entries written before there
+// was one still read
+impl TryFromBytes for SetWithTtlCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let value = read_string(&mut bytes)?;
+        let expires_at_ms = read_u64(&mut bytes)?;
+        let notify = match bytes.next_u32() {
+            None | Some(0) => None,
+            Some(_) => Some(bytes.next_u32()?),
+        };
+        Some(SetWithTtlCommand { key, value, expires_at_ms, notify })
+    }
+}
+
+impl WriteBytes for SetWithTtlCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.value)?;
+        write_u64(writer, self.expires_at_ms)?;
+        match self.notify {
+            Some(client) => {
+                writer.write_u32(1)?;
+                writer.write_u32(client)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFromBytes for ExpireKeysCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        Some(ExpireKeysCommand { now_ms: read_u64(&mut bytes)? })
+    }
+}
+
+impl WriteBytes for ExpireKeysCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_u64(writer, self.now_ms)
+    }
+}
+
+impl TryFromBytes for PurgeTombstonesCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        Some(PurgeTombstonesCommand { before_ms: read_u64(&mut bytes)? })
+    }
+}
+
+impl WriteBytes for PurgeTombstonesCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_u64(writer, self.before_ms)
+    }
+}
+
+impl TryFromBytes for AppendCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let value = read_string(&mut bytes)?;
+        Some(AppendCommand { key, value })
+    }
+}
+
+impl WriteBytes for AppendCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.value)
+    }
+}
+
+impl TryFromBytes for IncrCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let by = read_u64(&mut bytes)? as i64;
+        Some(IncrCommand { key, by })
+    }
+}
+
+impl WriteBytes for IncrCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_u64(writer, self.by as u64)
+    }
+}
+
+impl TryFromBytes for JsonSetCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let key = read_string(&mut bytes)?;
+        let path = read_string(&mut bytes)?;
+        let value = read_string(&mut bytes)?;
+        Some(JsonSetCommand { key, path, value })
+    }
+}
+
+impl WriteBytes for JsonSetCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.key)?;
+        write_string(writer, &self.path)?;
+        write_string(writer, &self.value)
+    }
+}
+
+impl TryFromBytes for ScheduleCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let name = read_string(&mut bytes)?;
+        let at_ms = read_u64(&mut bytes)?;
+        let interval_ms = read_u64(&mut bytes)?;
+        let op = Box::new(read_op(&mut bytes)?);
+        Some(ScheduleCommand { name, at_ms, interval_ms, op })
+    }
+}
+
+impl WriteBytes for ScheduleCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.name)?;
+        write_u64(writer, self.at_ms)?;
+        write_u64(writer, self.interval_ms)?;
+        write_op(writer, &self.op)
+    }
+}
+
+impl TryFromBytes for UnscheduleCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        Some(UnscheduleCommand { name: read_string(&mut bytes)? })
+    }
+}
+
+impl WriteBytes for UnscheduleCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.name)
+    }
+}
+
+impl TryFromBytes for RunScheduleCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let name = read_string(&mut bytes)?;
+        let due_at_ms = read_u64(&mut bytes)?;
+        let now_ms = read_u64(&mut bytes)?;
+        Some(RunScheduleCommand { name, due_at_ms, now_ms })
+    }
+}
+
+impl WriteBytes for RunScheduleCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        write_string(writer, &self.name)?;
+        write_u64(writer, self.due_at_ms)?;
+        write_u64(writer, self.now_ms)
+    }
+}
+
+impl TryFromBytes for BatchSetCommand {
+    fn try_from_bytes(mut bytes: impl ReadBytes) -> Option<Self> {
+        let len = bytes.next_u32()?;
+        let mut pairs = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let key = read_string(&mut bytes)?;
+            let value = read_string(&mut bytes)?;
+            pairs.push((key, value));
+        }
+        Some(BatchSetCommand(pairs))
+    }
+}
+
+impl WriteBytes for BatchSetCommand {
+    fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+        writer.write_u32(self.0.len() as u32)?;
+        for (key, value) in &self.0 {
+            write_string(writer, key)?;
+            write_string(writer, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_result(bytes: &mut impl ReadBytes) -> Option<CommandResult> {
+    Some(match bytes.next_u32()? {
+        0 => CommandResult::Ok,
+        1 => CommandResult::Failed,
+        2 => CommandResult::Value(None),
+        3 => CommandResult::Value(Some(read_string(bytes)?)),
+        _ => return None,
+    })
+}
+
+fn write_result<W: Write>(writer: &mut BytesWriter<W>, result: &CommandResult) -> io::Result<()> {
+    match result {
+        CommandResult::Ok => writer.write_u32(0),
+        CommandResult::Failed => writer.write_u32(1),
+        CommandResult::Value(None) => writer.write_u32(2),
+        CommandResult::Value(Some(value)) => {
+            writer.write_u32(3)?;
+            write_string(writer, value)
+        }
+    }
+}
+
+fn read_string(bytes: &mut impl ReadBytes) -> Option<String> {
+    let len = bytes.next_u32()?;
+    String::from_utf8(bytes.next_bytes(len as usize)?.to_vec()).ok()
+}
+
+fn write_string<W: Write>(writer: &mut BytesWriter<W>, s: &str) -> io::Result<()> {
+    writer.write_u32(s.len() as u32)?;
+    writer.write(s.as_bytes())
+}
+
+// as two u32s, high half first
+fn read_u64(bytes: &mut impl ReadBytes) -> Option<u64> {
+    let high = bytes.next_u32()? as u64;
+    Some((high << 32) | bytes.next_u32()? as u64)
+}
+
+fn write_u64<W: Write>(writer: &mut BytesWriter<W>, n: u64) -> io::Result<()> {
+    writer.write_u32((n >> 32) as u32)?;
+    writer.write_u32(n as u32)
+}
+
+pub fn clone_state_machine<S: StateMachine + Clone>(state_machine: &RaftStateMachine<S>) -> RaftStateMachine<S> {
+    RaftStateMachine {
+        inner: state_machine.inner.clone(),
+        config: state_machine.config.clone(),
+        client_last_command_ids: state_machine.client_last_command_ids.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Write;
+    use std::time::Duration;
+
+    use my_raft::bytes::{BytesWriter, TryFromBytes, WriteBytes};
+    use my_raft::state_machine::StateMachine;
+
+    use crate::snapshot_format;
+    use crate::snapshot_format::SnapshotEncoding;
+    use crate::state_machine::{AppendCommand, ApplyStats, BatchSetCommand, CallCommand, CasCommand, CommandResult, DeleteIfCommand, DeleteValueCommand, Expected, ExpireKeysCommand, FORMAT_MARKER, IncrCommand, JsonSetCommand, KvCommand, KvOp, KvStateMachine, MoveCommand, PortableStateWithoutTombstones, PurgeTombstonesCommand, RegisterCommand, RunScheduleCommand, ScheduleCommand, ScriptCommand, SetValueCommand, SetWithTtlCommand, UnscheduleCommand};
+
+    fn round_trip(command: KvCommand) {
+        let mut bytes = vec![];
+        command.write_bytes_with_writer(&mut bytes).unwrap();
+        assert_eq!(KvCommand::try_from_slice(&bytes), Some(command));
+    }
+
+    #[test]
+    fn command_serialization() {
+        round_trip(KvCommand { mid: "a".to_string(), op: KvOp::Set(SetValueCommand { key: "k".to_string(), value: "v".to_string() }) });
+        round_trip(KvCommand { mid: "b".to_string(), op: KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None }) });
+        round_trip(KvCommand { mid: "c".to_string(), op: KvOp::Cas(CasCommand { key: "k".to_string(), expected: None, value: "v".to_string() }) });
+        round_trip(KvCommand { mid: "d".to_string(), op: KvOp::Cas(CasCommand { key: "k".to_string(), expected: Some("old".to_string()), value: "v".to_string() }) });
+        round_trip(KvCommand { mid: "e".to_string(), op: KvOp::Batch(BatchSetCommand(vec![("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())])) });
+        round_trip(KvCommand { mid: "g".to_string(), op: KvOp::GetSet(SetValueCommand { key: "k".to_string(), value: "v".to_string() }) });
+        round_trip(KvCommand { mid: "h".to_string(), op: KvOp::GetDelete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None }) });
+        round_trip(KvCommand { mid: "i".to_string(), op: KvOp::Defrag });
+        round_trip(KvCommand { mid: "j".to_string(), op: KvOp::Script(ScriptCommand { key: "k".to_string(), script: "add(value, 1)".to_string() }) });
+        round_trip(KvCommand { mid: "k".to_string(), op: KvOp::Register(RegisterCommand { name: "incr".to_string(), script: "add(value, arg)".to_string() }) });
+        round_trip(KvCommand { mid: "l".to_string(), op: KvOp::Call(CallCommand { name: "incr".to_string(), key: "k".to_string(), arg: "2".to_string() }) });
+        round_trip(KvCommand { mid: "m".to_string(), op: KvOp::Rename(MoveCommand { from: "a".to_string(), to: "b".to_string(), deleted_at_ms: Some(1 << 40) }) });
+        round_trip(KvCommand { mid: "n".to_string(), op: KvOp::Copy(MoveCommand { from: "a".to_string(), to: "b".to_string(), deleted_at_ms: None }) });
+        round_trip(KvCommand { mid: "o".to_string(), op: KvOp::DeleteIf(DeleteIfCommand { key: "k".to_string(), expected: Expected::Value("v".to_string()), deleted_at_ms: None }) });
+        round_trip(KvCommand { mid: "p".to_string(), op: KvOp::DeleteIf(DeleteIfCommand { key: "k".to_string(), expected: Expected::Version(7), deleted_at_ms: Some(12) }) });
+        round_trip(KvCommand { mid: "q".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "k".to_string(), value: "v".to_string(), expires_at_ms: 1 << 40, notify: Some(3) }) });
+        round_trip(KvCommand { mid: "qq".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "k".to_string(), value: "v".to_string(), expires_at_ms: 1 << 40, notify: None }) });
+        round_trip(KvCommand { mid: "r".to_string(), op: KvOp::ExpireKeys(ExpireKeysCommand { now_ms: u64::MAX }) });
+        round_trip(KvCommand { mid: "s".to_string(), op: KvOp::Schedule(ScheduleCommand { name: "n".to_string(), at_ms: 5, interval_ms: 10, op: Box::new(KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None })) }) });
+        round_trip(KvCommand { mid: "t".to_string(), op: KvOp::Unschedule(UnscheduleCommand { name: "n".to_string() }) });
+        round_trip(KvCommand { mid: "u".to_string(), op: KvOp::RunSchedule(RunScheduleCommand { name: "n".to_string(), due_at_ms: 5, now_ms: 6 }) });
+        round_trip(KvCommand { mid: "v".to_string(), op: KvOp::Append(AppendCommand { key: "k".to_string(), value: "v".to_string() }) });
+        round_trip(KvCommand { mid: "w".to_string(), op: KvOp::Incr(IncrCommand { key: "k".to_string(), by: -3 }) });
+        round_trip(KvCommand { mid: "y".to_string(), op: KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: Some(1 << 40) }) });
+        round_trip(KvCommand { mid: "z".to_string(), op: KvOp::PurgeTombstones(PurgeTombstonesCommand { before_ms: 1 << 40 }) });
+        round_trip(KvCommand { mid: "x".to_string(), op: KvOp::JsonSet(JsonSetCommand { key: "k".to_string(), path: "a.b".to_string(), value: "[1]".to_string() }) });
+        round_trip(KvCommand { mid: "f".to_string(), op: KvOp::Unknown { tag: 9999, payload: vec![1, 2, 3] } });
+    }
+
+    #[test]
+    fn unknown_command_is_failed_no_op() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::Unknown { tag: 9999, payload: vec![1, 2, 3] } });
+        assert!(sm.data.is_empty());
+        assert_eq!(sm.result("a"), Some(&CommandResult::Failed));
+    }
+
+    #[test]
+    fn cas() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::Cas(CasCommand { key: "k".to_string(), expected: None, value: "1".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::Cas(CasCommand { key: "k".to_string(), expected: None, value: "2".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::Cas(CasCommand { key: "k".to_string(), expected: Some("1".to_string()), value: "3".to_string() }) });
+
+        assert_eq!(sm.result("a"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("b"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Ok));
+        assert_eq!(sm.data.get("k").map(|s| s.as_str()), Some("3"));
+        // failed commands are applied too
+        assert_eq!(sm.applied_index(), 3);
+        assert_eq!(sm.applied_at("b"), Some(2));
+        assert_eq!(sm.applied_at("d"), None);
+    }
+
+    #[test]
+    fn get_set_and_get_delete() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::GetSet(SetValueCommand { key: "k".to_string(), value: "1".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::GetSet(SetValueCommand { key: "k".to_string(), value: "2".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::GetDelete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None }) });
+
+        assert_eq!(sm.result("a"), Some(&CommandResult::Value(None)));
+        assert_eq!(sm.result("b"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Value(Some("2".to_string()))));
+        assert!(sm.data.is_empty());
+
+        // results are part of snapshots, so retries can still be answered after a restart
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        assert_eq!(restored.result("b"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(restored.applied_at("c"), Some(3));
+    }
+
+    #[test]
+    fn rename_and_copy() {
+        let mut sm = KvStateMachine::default();
+        let op = |mid: &str, kind: fn(MoveCommand) -> KvOp, from: &str, to: &str| KvCommand { mid: mid.to_string(), op: kind(MoveCommand { from: from.to_string(), to: to.to_string(), deleted_at_ms: None }) };
+        sm.data.insert("a".to_string(), "1".to_string());
+        sm.data.insert("c".to_string(), "3".to_string());
+        sm.apply_command(&op("a", KvOp::Copy, "a", "b"));
+        sm.apply_command(&op("b", KvOp::Rename, "a", "c"));
+        sm.apply_command(&op("c", KvOp::Rename, "a", "d"));
+        sm.apply_command(&op("d", KvOp::Copy, "missing", "b"));
+
+        assert_eq!(sm.result("a"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("b"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("d"), Some(&CommandResult::Failed));
+        let mut data: Vec<(&str, &str)> = sm.data.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        data.sort_unstable();
+        assert_eq!(data, vec![("b", "1"), ("c", "1")]);
+        assert_eq!(sm.tombstones("", 0).count(), 0);
+
+        // a rename stamped with a time leaves a tombstone for the key it moved from, like a delete
+        sm.apply_command(&KvCommand { mid: "e".to_string(), op: KvOp::Rename(MoveCommand { from: "c".to_string(), to: "e".to_string(), deleted_at_ms: Some(50) }) });
+        let tombstones: Vec<(&str, u64)> = sm.tombstones("", 0).map(|(key, tombstone)| (key.as_str(), tombstone.deleted_at_ms)).collect();
+        assert_eq!(tombstones, vec![("c", 50)]);
+    }
+
+    #[test]
+    fn delete_if() {
+        let mut sm = KvStateMachine::default();
+        let set = |mid: &str, key: &str| KvCommand { mid: mid.to_string(), op: KvOp::Set(SetValueCommand { key: key.to_string(), value: "v".to_string() }) };
+        let delete_if = |mid: &str, key: &str, expected: Expected| KvCommand { mid: mid.to_string(), op: KvOp::DeleteIf(DeleteIfCommand { key: key.to_string(), expected, deleted_at_ms: None }) };
+        sm.apply_command(&set("a", "lock"));
+        sm.apply_command(&set("b", "lease"));
+        assert_eq!(sm.version("lock"), Some(1));
+        assert_eq!(sm.version("lease"), Some(2));
+
+        sm.apply_command(&delete_if("c", "lock", Expected::Value("other".to_string())));
+        sm.apply_command(&delete_if("d", "lease", Expected::Version(1)));
+        sm.apply_command(&delete_if("e", "lock", Expected::Value("v".to_string())));
+        sm.apply_command(&delete_if("f", "lock", Expected::Value("v".to_string())));
+
+        // versions are part of snapshots
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let mut restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        restored.apply_command(&delete_if("g", "lease", Expected::Version(2)));
+
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("d"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("e"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("f"), Some(&CommandResult::Failed));
+        assert_eq!(restored.result("g"), Some(&CommandResult::Ok));
+        assert!(restored.data.is_empty());
+        assert_eq!(restored.version("lease"), None);
+    }
+
+    #[test]
+    fn range() {
+        let mut sm = KvStateMachine::default();
+        for key in &["a", "ab", "abc", "b", "ba"] {
+            sm.data.insert(key.to_string(), key.to_uppercase());
+        }
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "abd".to_string(), value: "gone".to_string(), expires_at_ms: 10, notify: None }) });
+        let keys = |start: &str, end: Option<&str>| sm.range(start, end, 10).map(|(k, _)| k.as_str()).collect::<Vec<&str>>();
+
+        assert_eq!(keys("ab", Some("b")), vec!["ab", "abc"]);
+        assert_eq!(keys("ab", None), vec!["ab", "abc", "b", "ba"]);
+        assert_eq!(keys("b", Some("a")), Vec::<&str>::new());
+        assert_eq!(keys("b", Some("b")), Vec::<&str>::new());
+        assert_eq!(sm.range("abd", None, 9).next(), Some((&"abd".to_string(), &"gone".to_string())));
+    }
+
+    #[test]
+    fn ttl() {
+        let mut sm = KvStateMachine::default();
+        let set_with_ttl = |mid: &str, key: &str, expires_at_ms: u64| KvCommand { mid: mid.to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: key.to_string(), value: "v".to_string(), expires_at_ms, notify: Some(7) }) };
+        sm.apply_command(&set_with_ttl("a", "a", 1000));
+        sm.apply_command(&set_with_ttl("b", "b", 2000));
+        sm.apply_command(&set_with_ttl("c", "c", 3000));
+        // setting it again without a TTL keeps it for good
+        sm.apply_command(&KvCommand { mid: "d".to_string(), op: KvOp::Set(SetValueCommand { key: "c".to_string(), value: "w".to_string() }) });
+        assert_eq!(sm.next_expiry(), Some(1000));
+        assert_eq!(sm.expires_at("c"), None);
+
+        // expired keys can't be read, but are still there until they're expired by a command
+        assert_eq!(sm.get("a", 999).map(|s| s.as_str()), Some("v"));
+        assert_eq!(sm.get("a", 1000), None);
+        assert!(sm.data.contains_key("a"));
+
+        // expiries are part of snapshots
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let mut restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        assert_eq!(restored.expires_at("b"), Some(2000));
+
+        restored.apply_command(&KvCommand { mid: "e".to_string(), op: KvOp::ExpireKeys(ExpireKeysCommand { now_ms: 1500 }) });
+        assert_eq!(restored.result("e"), Some(&CommandResult::Value(Some("1".to_string()))));
+        // owners are part of snapshots
+        let index = restored.applied_at("e").unwrap();
+        assert_eq!(restored.expired_notices(index), &[(7, "a".to_string())]);
+        assert!(restored.expired_notices(index - 1).is_empty());
+        let mut keys: Vec<&str> = restored.data.keys().map(|k| k.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["b", "c"]);
+        assert_eq!(restored.next_expiry(), Some(2000));
+        assert_eq!(restored.version("a"), None);
+    }
+
+    #[test]
+    fn commands_after_expiry() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "n".to_string(), value: "5".to_string(), expires_at_ms: 10, notify: None }) });
+        assert!(KvOp::Incr(IncrCommand { key: "n".to_string(), by: 1 }).reads_current());
+        assert!(!KvOp::ExpireKeys(ExpireKeysCommand { now_ms: 10 }).reads_current());
+
+        // the leader puts an ExpireKeys ahead of the incr, which starts over rather than reviving the value or its TTL
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::ExpireKeys(ExpireKeysCommand { now_ms: 10 }) });
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::Incr(IncrCommand { key: "n".to_string(), by: 1 }) });
+        assert_eq!(sm.result("c"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(sm.expires_at("n"), None);
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut sm = KvStateMachine::default();
+        let set = |mid: &str, key: &str| KvCommand { mid: mid.to_string(), op: KvOp::Set(SetValueCommand { key: key.to_string(), value: "v".to_string() }) };
+        let delete = |mid: &str, key: &str, deleted_at_ms: Option<u64>| KvCommand { mid: mid.to_string(), op: KvOp::Delete(DeleteValueCommand { key: key.to_string(), deleted_at_ms }) };
+        for (mid, key) in &[("a", "a/1"), ("b", "a/2"), ("c", "b/1"), ("d", "c/1")] {
+            sm.apply_command(&set(mid, key));
+        }
+        sm.apply_command(&delete("e", "a/1", Some(1000)));
+        sm.apply_command(&KvCommand { mid: "f".to_string(), op: KvOp::GetDelete(DeleteValueCommand { key: "a/2".to_string(), deleted_at_ms: Some(2000) }) });
+        sm.apply_command(&KvCommand { mid: "g".to_string(), op: KvOp::DeleteIf(DeleteIfCommand { key: "b/1".to_string(), expected: Expected::Value("v".to_string()), deleted_at_ms: Some(3000) }) });
+        // without a time, or of a key that wasn't set, there's nothing to keep
+        sm.apply_command(&delete("h", "c/1", None));
+        sm.apply_command(&delete("i", "d/1", Some(4000)));
+
+        let tombstones = |sm: &KvStateMachine, prefix: &str, since_ms: u64| sm.tombstones(prefix, since_ms)
+            .map(|(key, tombstone)| (key.clone(), tombstone.deleted_at_ms, tombstone.index))
+            .collect::<Vec<_>>();
+        assert_eq!(tombstones(&sm, "", 0), vec![("a/1".to_string(), 1000, 5), ("a/2".to_string(), 2000, 6), ("b/1".to_string(), 3000, 7)]);
+        assert_eq!(tombstones(&sm, "a/", 1500), vec![("a/2".to_string(), 2000, 6)]);
+
+        // setting a key again means it's no longer deleted
+        sm.apply_command(&set("j", "a/2"));
+        assert_eq!(tombstones(&sm, "a/", 0), vec![("a/1".to_string(), 1000, 5)]);
+
+        // tombstones are part of snapshots
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let mut restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        assert_eq!(tombstones(&restored, "", 0), tombstones(&sm, "", 0));
+
+        restored.apply_command(&KvCommand { mid: "k".to_string(), op: KvOp::PurgeTombstones(PurgeTombstonesCommand { before_ms: 3000 }) });
+        assert_eq!(restored.result("k"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(restored.oldest_tombstone(), Some(3000));
+    }
+
+    #[test]
+    fn schedules() {
+        let mut sm = KvStateMachine::default();
+        let schedule = |mid: &str, name: &str, at_ms: u64, interval_ms: u64, op: KvOp| KvCommand { mid: mid.to_string(), op: KvOp::Schedule(ScheduleCommand { name: name.to_string(), at_ms, interval_ms, op: Box::new(op) }) };
+        let run = |mid: &str, name: &str, due_at_ms: u64, now_ms: u64| KvCommand { mid: mid.to_string(), op: KvOp::RunSchedule(RunScheduleCommand { name: name.to_string(), due_at_ms, now_ms }) };
+        sm.apply_command(&schedule("a", "tick", 100, 10, KvOp::Script(ScriptCommand { key: "ticks".to_string(), script: "add(default(value, 0), 1)".to_string() })));
+        sm.apply_command(&schedule("b", "once", 50, 0, KvOp::Set(SetValueCommand { key: "k".to_string(), value: "v".to_string() })));
+        sm.apply_command(&schedule("c", "nested", 50, 0, KvOp::Unschedule(UnscheduleCommand { name: "tick".to_string() })));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.upcoming_schedules(10), vec![(50, "once".to_string()), (100, "tick".to_string())]);
+
+        sm.apply_command(&run("d", "once", 50, 51));
+        sm.apply_command(&run("e", "once", 50, 51));
+        assert_eq!(sm.result("d"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("e"), Some(&CommandResult::Failed));
+        assert_eq!(sm.data.get("k").map(|s| s.as_str()), Some("v"));
+
+        // runs missed before 135 are skipped
+        sm.apply_command(&run("f", "tick", 100, 135));
+        assert_eq!(sm.result("f"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(sm.upcoming_schedules(10), vec![(140, "tick".to_string())]);
+
+        // schedules are part of snapshots
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let mut restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        restored.apply_command(&run("g", "tick", 140, 140));
+        assert_eq!(restored.data.get("ticks").map(|s| s.as_str()), Some("2"));
+        restored.apply_command(&KvCommand { mid: "h".to_string(), op: KvOp::Unschedule(UnscheduleCommand { name: "tick".to_string() }) });
+        assert!(restored.upcoming_schedules(10).is_empty());
+    }
+
+    #[test]
+    fn append_and_incr() {
+        let mut sm = KvStateMachine::default();
+        let append = |mid: &str, value: &str| KvCommand { mid: mid.to_string(), op: KvOp::Append(AppendCommand { key: "s".to_string(), value: value.to_string() }) };
+        let incr = |mid: &str, key: &str, by: i64| KvCommand { mid: mid.to_string(), op: KvOp::Incr(IncrCommand { key: key.to_string(), by }) };
+        sm.apply_command(&append("a", "ab"));
+        sm.apply_command(&append("b", "cde"));
+        sm.apply_command(&incr("c", "n", 5));
+        sm.apply_command(&incr("d", "n", -7));
+        sm.apply_command(&incr("e", "s", 1));
+        sm.apply_command(&incr("f", "n", i64::MAX));
+        sm.apply_command(&incr("g", "n", i64::MIN));
+
+        assert_eq!(sm.result("b"), Some(&CommandResult::Value(Some("5".to_string()))));
+        assert_eq!(sm.data.get("s").map(|s| s.as_str()), Some("abcde"));
+        assert_eq!(sm.result("d"), Some(&CommandResult::Value(Some("-2".to_string()))));
+        assert_eq!(sm.result("e"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("f"), Some(&CommandResult::Value(Some((i64::MAX - 2).to_string()))));
+        assert_eq!(sm.result("g"), Some(&CommandResult::Value(Some("-2".to_string()))));
+
+        // they keep the key's TTL
+        sm.apply_command(&KvCommand { mid: "h".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "t".to_string(), value: "1".to_string(), expires_at_ms: 10, notify: None }) });
+        sm.apply_command(&incr("i", "t", 1));
+        assert_eq!(sm.expires_at("t"), Some(10));
+    }
+
+    #[test]
+    fn json_set() {
+        let mut sm = KvStateMachine::default();
+        let json_set = |mid: &str, path: &str, value: &str| KvCommand { mid: mid.to_string(), op: KvOp::JsonSet(JsonSetCommand { key: "doc".to_string(), path: path.to_string(), value: value.to_string() }) };
+        sm.apply_command(&json_set("a", "user.name", "\"ann\""));
+        sm.apply_command(&json_set("b", "user.age", "30"));
+        sm.apply_command(&json_set("c", "user.name.first", "\"ann\""));
+        sm.apply_command(&json_set("d", "user.age", "not json"));
+
+        assert_eq!(sm.result("b"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("d"), Some(&CommandResult::Failed));
+        assert_eq!(sm.data.get("doc").map(|s| s.as_str()), Some(r#"{"user":{"age":30,"name":"ann"}}"#));
+
+        sm.data.insert("plain".to_string(), "text".to_string());
+        sm.apply_command(&KvCommand { mid: "e".to_string(), op: KvOp::JsonSet(JsonSetCommand { key: "plain".to_string(), path: "a".to_string(), value: "1".to_string() }) });
+        assert_eq!(sm.result("e"), Some(&CommandResult::Failed));
+    }
+
+    #[test]
+    fn script() {
+        let mut sm = KvStateMachine::default();
+        let incr = |mid: &str| KvCommand { mid: mid.to_string(), op: KvOp::Script(ScriptCommand { key: "k".to_string(), script: "add(default(value, 0), 1)".to_string() }) };
+        sm.apply_command(&incr("a"));
+        sm.apply_command(&incr("b"));
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::Script(ScriptCommand { key: "k".to_string(), script: "div(value, 0)".to_string() }) });
+
+        assert_eq!(sm.result("a"), Some(&CommandResult::Value(Some("1".to_string()))));
+        assert_eq!(sm.result("b"), Some(&CommandResult::Value(Some("2".to_string()))));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.data.get("k").map(|s| s.as_str()), Some("2"));
+    }
+
+    #[test]
+    fn registered_commands() {
+        let mut sm = KvStateMachine::default();
+        let call = |mid: &str, name: &str| KvCommand { mid: mid.to_string(), op: KvOp::Call(CallCommand { name: name.to_string(), key: "k".to_string(), arg: "5".to_string() }) };
+        sm.apply_command(&call("a", "incr"));
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::Register(RegisterCommand { name: "incr".to_string(), script: "add(default(value, 0), arg)".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::Register(RegisterCommand { name: "bad".to_string(), script: "add(".to_string() }) });
+        sm.apply_command(&call("d", "incr"));
+        sm.apply_command(&call("e", "incr"));
+
+        assert_eq!(sm.result("a"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("b"), Some(&CommandResult::Ok));
+        assert_eq!(sm.result("c"), Some(&CommandResult::Failed));
+        assert_eq!(sm.result("e"), Some(&CommandResult::Value(Some("10".to_string()))));
+
+        // registered commands are part of snapshots
+        let mut bytes = vec![];
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        let restored = KvStateMachine::try_from_slice(&bytes).unwrap();
+        assert_eq!(restored.commands, sm.commands);
+        assert_eq!(restored.applied_index(), 5);
+    }
+
+    #[test]
+    fn deduped_snapshots() {
+        let mut sm = KvStateMachine::default();
+        for i in 0..100 {
+            sm.data.insert(format!("key{}", i), if i % 2 == 0 { "a fairly long repeated value".to_string() } else { i.to_string() });
+        }
+        sm.data.insert("empty".to_string(), String::new());
+
+        let mut plain = vec![];
+        sm.write_bytes_with_writer(&mut plain).unwrap();
+        let mut deduped = vec![];
+        snapshot_format::write_with(SnapshotEncoding { dedup: true, ..SnapshotEncoding::default() }, || sm.write_bytes_with_writer(&mut deduped)).unwrap();
+
+        assert!(deduped.len() < plain.len() / 2);
+        assert_eq!(KvStateMachine::try_from_slice(&plain).unwrap().data, sm.data);
+        assert_eq!(KvStateMachine::try_from_slice(&deduped).unwrap().data, sm.data);
+    }
+
+    #[test]
+    fn portable_snapshots() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::Set(SetValueCommand { key: "k".to_string(), value: "v".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "t".to_string(), value: "v".to_string(), expires_at_ms: 500, notify: Some(3) }) });
+        sm.apply_command(&KvCommand { mid: "c".to_string(), op: KvOp::Schedule(ScheduleCommand { name: "s".to_string(), at_ms: 100, interval_ms: 10, op: Box::new(KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: None })) }) });
+        sm.apply_command(&KvCommand { mid: "d".to_string(), op: KvOp::Register(RegisterCommand { name: "r".to_string(), script: "value".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "e".to_string(), op: KvOp::Delete(DeleteValueCommand { key: "k".to_string(), deleted_at_ms: Some(50) }) });
+
+        let portable = sm.to_portable().unwrap();
+        for format in snapshot_format::FORMATS {
+            let encoded = format.encode(&portable).unwrap();
+            let restored = KvStateMachine::from_portable(format.decode(&encoded).unwrap()).unwrap();
+            assert_eq!(restored.to_portable().unwrap(), portable, "{}", format.name());
+            assert_eq!(restored.next_expiry(), Some(500));
+            assert_eq!(restored.upcoming_schedules(10), vec![(100, "s".to_string())]);
+            assert_eq!(restored.result("b"), Some(&CommandResult::Ok));
+            assert_eq!(restored.oldest_tombstone(), Some(50));
+        }
+    }
+
+    struct OldBincodeSnapshot(Vec<u8>);
+
+    impl WriteBytes for OldBincodeSnapshot {
+        fn write_bytes<W: Write>(&self, writer: &mut BytesWriter<W>) -> io::Result<()> {
+            writer.write_u32(FORMAT_MARKER)?;
+            writer.write_u32(3)?;
+            writer.write_u32(self.0.len() as u32)?;
+            writer.write(&self.0)
+        }
+    }
+
+    #[test]
+    fn bincode_snapshots_without_tombstones() {
+        let mut sm = KvStateMachine::default();
+        sm.apply_command(&KvCommand { mid: "a".to_string(), op: KvOp::Set(SetValueCommand { key: "k".to_string(), value: "v".to_string() }) });
+        sm.apply_command(&KvCommand { mid: "b".to_string(), op: KvOp::SetWithTtl(SetWithTtlCommand { key: "t".to_string(), value: "v".to_string(), expires_at_ms: 500, notify: Some(3) }) });
+        let portable = sm.to_portable().unwrap();
+
+        // laid out the way bincode snapshots were written before there were tombstones
+        let old = PortableStateWithoutTombstones {
+            data: portable.data.clone(),
+            commands: portable.commands.clone(),
+            versions: portable.versions.clone(),
+            expiries: portable.expiries.clone(),
+            expiry_owners: portable.expiry_owners.clone(),
+            schedules: portable.schedules.clone(),
+            results: portable.results.clone(),
+            applied: portable.applied,
+        };
+        let encoded = bincode::serialize(&old).unwrap();
+        let mut snapshot = vec![];
+        OldBincodeSnapshot(encoded.clone()).write_bytes_with_writer(&mut snapshot).unwrap();
+
+        let restored = KvStateMachine::try_from_slice(&snapshot).unwrap();
+        assert_eq!(restored.to_portable().unwrap(), portable);
+        assert_eq!(restored.next_expiry(), Some(500));
+        assert!(snapshot_format::by_name("bincode").unwrap().decode(&encoded).is_none());
+    }
+
+    #[test]
+    fn apply_breaker() {
+        let mut stats = ApplyStats::default();
+        let slow = Duration::from_millis(50);
+        stats.record("a", slow, 0, 2);
+        assert_eq!(stats.over_budget, 0);
+
+        stats.record("b", slow, 10_000, 2);
+        stats.record("c", Duration::from_millis(1), 10_000, 2);
+        stats.record("d", slow, 10_000, 2);
+        assert_eq!(stats.trips, 0);
+        stats.record("e", slow, 10_000, 2);
+        stats.record("f", slow, 10_000, 2);
+
+        assert_eq!(stats.applies, 6);
+        assert_eq!(stats.over_budget, 4);
+        assert_eq!(stats.slowest_us, 50_000);
+        assert_eq!(stats.trips, 1);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Transfer {
+    // the leader keeps the entries the node is missing until it has them, costing bandwidth
+    Log,
+    // the leader carries on compacting, so the node gets a snapshot once its entries are gone, costing leader CPU
+    Snapshot,
+}
+
+impl FromStr for Transfer {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(Transfer::Log),
+            "snapshot" => Ok(Transfer::Snapshot),
+            _ => Err(()),
+        }
+    }
+}
+
+// How a node that reconnects behind the leader catches up. Any gap up to threshold entries is caught up from the log,
+// past that it's whatever prefer says. The leader holds back compaction for nodes catching up from the log for at most
+// max_hold, after which they get a snapshot anyway.
+#[derive(Copy, Clone, Debug)]
+pub struct StateTransferPolicy {
+    pub prefer: Transfer,
+    pub threshold: u32,
+    pub max_hold: Duration,
+}
+
+impl StateTransferPolicy {
+    pub fn decide(&self, gap: u32) -> Transfer {
+        if gap <= self.threshold {
+            Transfer::Log
+        } else {
+            self.prefer
+        }
+    }
+}
+
+// The nodes the leader is holding compaction back for, with the index each has to reach and since when.
+#[derive(Default)]
+pub struct CatchUps {
+    holding: HashMap<u32, (u32, Instant)>,
+}
+
+impl CatchUps {
+    pub fn hold(&mut self, node: u32, until_index: u32) {
+        self.holding.insert(node, (until_index, Instant::now()));
+    }
+
+    // stops holding for the node once it's reached its index
+    pub fn progress(&mut self, node: u32, last_index: u32) {
+        if self.holding.get(&node).map_or(false, |(until_index, _)| last_index >= *until_index) {
+            self.holding.remove(&node);
+        }
+    }
+
+    // gives back the nodes that have been held for too long
+    pub fn expire(&mut self, max_hold: Duration) -> Vec<u32> {
+        let expired: Vec<u32> = self.holding.iter().filter(|(_, (_, since))| since.elapsed() >= max_hold).map(|(node, _)| *node).collect();
+        for node in &expired {
+            self.holding.remove(node);
+        }
+        expired
+    }
+
+    pub fn clear(&mut self) {
+        self.holding.clear();
+    }
+
+    pub fn is_holding(&self) -> bool {
+        !self.holding.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::state_transfer::{CatchUps, StateTransferPolicy, Transfer};
+
+    #[test]
+    fn decides_by_gap() {
+        let policy = StateTransferPolicy { prefer: Transfer::Snapshot, threshold: 100, max_hold: Duration::from_secs(60) };
+        assert_eq!(policy.decide(100), Transfer::Log);
+        assert_eq!(policy.decide(101), Transfer::Snapshot);
+        assert_eq!(StateTransferPolicy { prefer: Transfer::Log, ..policy }.decide(1_000_000), Transfer::Log);
+        assert_eq!("snapshot".parse(), Ok(Transfer::Snapshot));
+        assert!("rsync".parse::<Transfer>().is_err());
+
+        let mut catch_ups = CatchUps::default();
+        catch_ups.hold(1, 50);
+        catch_ups.hold(2, 50);
+        catch_ups.progress(1, 49);
+        assert!(catch_ups.is_holding());
+        catch_ups.progress(1, 50);
+        assert_eq!(catch_ups.expire(Duration::from_secs(60)), vec![]);
+        assert_eq!(catch_ups.expire(Duration::from_secs(0)), vec![2]);
+        assert!(!catch_ups.is_holding());
+    }
+}
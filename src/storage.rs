@@ -1,57 +1,642 @@
-use my_raft::bytes::{BytesRef, TryFromBytes, WriteBytes};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::borrow::Cow;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use flate2::Compression;
+use flate2::CrcWriter;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use my_raft::bytes::{TryFromBytes, WriteBytes};
 use my_raft::state_machine::{RaftStateMachine, StateMachine};
 use my_raft::storage::log::{LogEntry, LogEntryType};
 use my_raft::storage::Storage;
+use serde_json::json;
 
+use crate::{alloc_stats, num_to_network_name};
+use crate::alloc_stats::Subsystem;
+use crate::backup;
+use crate::backup::{BackupTarget, Manifest, RestorePoint, Upload};
+use crate::cluster::ClusterId;
+use crate::snapshot_chunks::{Rejected, SnapshotChunks};
+use crate::snapshot_file::SnapshotBytes;
+use crate::snapshot_format;
+use crate::snapshot_format::SnapshotEncoding;
+use crate::snapshot_pause::SnapshotPause;
+use crate::shutdown::Shutdown;
+use crate::snapshot_pull::SnapshotExchange;
 use crate::state_machine::clone_state_machine;
+use crate::storage_metrics::{LogGauges, StorageMetrics, StoredBytes};
+use crate::wal;
+use crate::wal::Wal;
+
+// every snapshot starts with the id of the cluster that produced it
+pub const SNAPSHOT_HEADER_LEN: usize = 16;
+// A compressed snapshot follows the cluster id with this and a byte for the codec, an uncompressed one is written as it
+// always was so older versions can still read it.
+const COMPRESSED_MAGIC: [u8; 4] = *b"\xffKVZ";
+// Snapshots written now follow the cluster id with this and end with the checksum of everything between the two, which
+// is checked when one is loaded and when one sent in chunks has been pieced back together. Older ones without it are
+// still read, unchecked.
+const CHECKSUMMED_MAGIC: [u8; 4] = *b"\xffKVC";
+pub const DEFAULT_APPEND_ENTRIES_BYTES: usize = 3072;
+// Raft messages go out as JSON arrays with up to four characters a byte, which have to fit in a packet along with the
+// rest of the message. Commands bigger than an AppendEntries can be are refused before they're proposed, so an entry
+// that's over a lower budget on its own, which is still sent so the follower isn't stuck, fits too.
+pub const MAX_APPEND_ENTRIES_BYTES: usize = 12 * 1024;
+
+// what to do on startup when the log doesn't line up with the snapshot it follows
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LogRepair {
+    Refuse,
+    // drops the log from the first entry that doesn't line up, which a leader sends again
+    Truncate,
+    // drops the snapshot and the whole log, leaving the node to catch up from the leader
+    Discard,
+}
+
+impl FromStr for LogRepair {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(LogRepair::Refuse),
+            "truncate" => Ok(LogRepair::Truncate),
+            "discard" => Ok(LogRepair::Discard),
+            _ => Err(()),
+        }
+    }
+}
+
+// how snapshots are compressed, both on disk and in the InstallSnapshot chunks sent to followers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SnapshotCodec {
+    Uncompressed = 0,
+    Gzip = 1,
+    Lz4 = 2,
+}
+
+impl FromStr for SnapshotCodec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SnapshotCodec::Uncompressed),
+            "gzip" => Ok(SnapshotCodec::Gzip),
+            "lz4" => Ok(SnapshotCodec::Lz4),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SnapshotCodec {
+    fn from_tag(tag: u8) -> Option<SnapshotCodec> {
+        match tag {
+            1 => Some(SnapshotCodec::Gzip),
+            2 => Some(SnapshotCodec::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SnapshotCodec::Uncompressed => Ok(body.to_vec()),
+            SnapshotCodec::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::fast());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            SnapshotCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(body)),
+        }
+    }
+
+    fn decompress(self, bytes: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            SnapshotCodec::Uncompressed => Some(bytes.to_vec()),
+            SnapshotCodec::Gzip => {
+                let mut body = vec![];
+                GzDecoder::new(bytes).read_to_end(&mut body).ok()?;
+                Some(body)
+            }
+            SnapshotCodec::Lz4 => lz4_flex::decompress_size_prepended(bytes).ok(),
+        }
+    }
+}
 
 pub struct RamStorage<S: StateMachine> {
+    cluster_id: ClusterId,
     log: Vec<LogEntry<S::Command>>,
+    // serialized size of each entry in the log, taken when it's added
+    entry_sizes: Vec<usize>,
+    // log_entries gives back no more than this many bytes of entries, or a single entry if that's bigger
+    append_entries_bytes: usize,
     current_term: u32,
     voted_for: Option<u32>,
-    snapshot_bytes: Vec<u8>,
+    // term voted_for was last set in, for catching double votes with debug-invariants
+    voted_in_term: u32,
+    // mapped from the write-ahead log's snapshot file when there is one
+    snapshot_bytes: SnapshotBytes,
+    // what snapshots the core takes are compressed with, those from elsewhere are kept as they came
+    snapshot_codec: SnapshotCodec,
+    snapshot_encoding: SnapshotEncoding,
+    // snapshot_bytes deserialized, the first time it's asked for after it changes
+    snapshot_cache: RefCell<Option<RaftStateMachine<S>>>,
     snapshot_last_index: u32,
     snapshot_last_term: u32,
-    snapshot_chunk_bytes: Vec<u8>,
+    // the snapshot being installed
+    snapshot_chunks: SnapshotChunks,
     init_state_machine: RaftStateMachine<S>,
+    backup: Option<BackupSchedule>,
+    checkpoint: Option<CheckpointSchedule>,
+    // lets followers pull parts of snapshots, from this node and into it
+    snapshot_exchange: Option<SnapshotExchange>,
+    snapshot_pause: Option<SnapshotPause>,
+    // a last checkpoint is written when it's requested, whether or not one is due
+    shutdown: Option<Shutdown>,
+    // set when a snapshot the core took was skipped, so the compaction that follows it is skipped too
+    skip_compaction: bool,
+    // when set, everything is written through to it as it changes
+    wal: Option<Wal>,
+    metrics: Option<StorageMetrics>,
+}
+
+struct BackupSchedule {
+    uploads: Sender<Upload>,
+    interval: Duration,
+    last_run: Instant,
+    uploaded_snapshot_index: u32,
+    uploaded_log_index: u32,
+}
+
+struct CheckpointSchedule {
+    path: PathBuf,
+    interval: Duration,
+    last_run: Instant,
+    metrics: StorageMetrics,
 }
 
 impl<S: StateMachine> RamStorage<S> {
-    pub fn new(init_state_machine: RaftStateMachine<S>) -> RamStorage<S> {
+    pub fn new(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId) -> RamStorage<S> {
         RamStorage {
+            cluster_id,
             log: vec![],
+            entry_sizes: vec![],
+            append_entries_bytes: DEFAULT_APPEND_ENTRIES_BYTES,
             current_term: 0,
             voted_for: None,
-            snapshot_bytes: vec![],
+            voted_in_term: 0,
+            snapshot_bytes: SnapshotBytes::default(),
+            snapshot_codec: SnapshotCodec::Uncompressed,
+            snapshot_encoding: SnapshotEncoding::default(),
+            snapshot_cache: RefCell::new(None),
             snapshot_last_index: 0,
             snapshot_last_term: 0,
-            snapshot_chunk_bytes: vec![],
+            snapshot_chunks: SnapshotChunks::default(),
             init_state_machine,
+            backup: None,
+            checkpoint: None,
+            snapshot_exchange: None,
+            snapshot_pause: None,
+            shutdown: None,
+            skip_compaction: false,
+            wal: None,
+            metrics: None,
+        }
+    }
+
+    pub fn set_append_entries_bytes(&mut self, bytes: usize) {
+        self.append_entries_bytes = bytes;
+    }
+
+    pub fn set_snapshot_codec(&mut self, codec: SnapshotCodec) {
+        self.snapshot_codec = codec;
+    }
+
+    pub fn set_snapshot_encoding(&mut self, encoding: SnapshotEncoding) {
+        self.snapshot_encoding = encoding;
+    }
+
+    fn measure_log(&mut self) {
+        self.entry_sizes = self.log.iter().map(entry_size).collect();
+    }
+
+    // writes everything in storage to the file every interval, from then on, to be picked up by load_checkpoint
+    pub fn start_checkpoints(&mut self, path: PathBuf, interval: Duration, metrics: StorageMetrics) {
+        self.checkpoint = Some(CheckpointSchedule { path, interval, last_run: Instant::now(), metrics });
+    }
+
+    pub fn share_snapshot_pause(&mut self, pause: SnapshotPause) {
+        self.snapshot_pause = Some(pause);
+    }
+
+    // called after start_checkpoints, without them there's nothing to write that the write-ahead log doesn't have
+    pub fn share_shutdown(&mut self, shutdown: Shutdown) {
+        if self.checkpoint.is_some() {
+            shutdown.expect_flush();
+        }
+        self.shutdown = Some(shutdown);
+    }
+
+    pub fn share_metrics(&mut self, metrics: StorageMetrics) {
+        self.metrics = Some(metrics);
+        self.report_log();
+    }
+
+    fn report_log(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_log(LogGauges {
+                current_term: self.current_term,
+                last_index: self.snapshot_last_index + self.log.len() as u32,
+                entries: self.log.len(),
+            }, StoredBytes {
+                log: self.entry_sizes.iter().sum::<usize>() as u64,
+                snapshot: self.snapshot_bytes.len() as u64,
+            });
+        }
+    }
+
+    pub fn share_snapshots(&mut self, exchange: SnapshotExchange) {
+        exchange.publish(self.snapshot_last_index, &self.snapshot_bytes);
+        self.snapshot_exchange = Some(exchange);
+    }
+
+    fn checkpoint_if_due(&mut self) {
+        let shutting_down = self.shutdown.as_ref().map_or(false, Shutdown::take_flush);
+        let (path, metrics) = match &mut self.checkpoint {
+            Some(checkpoint) if shutting_down || checkpoint.last_run.elapsed() >= checkpoint.interval => {
+                checkpoint.last_run = Instant::now();
+                (checkpoint.path.clone(), checkpoint.metrics.clone())
+            }
+            _ => return,
+        };
+        match self.write_checkpoint(&path) {
+            Ok((bytes, sync)) => metrics.record_write(bytes, sync),
+            Err(e) => eprintln!("failed to write checkpoint to {}: {}", path.display(), e),
+        }
+    }
+
+    // Checkpoint files are the cluster id, the current term, the vote (0 for none, otherwise the node id + 1), the
+    // snapshot's last index and term, the length prefixed snapshot, then the log as a backup segment. They're replaced
+    // by renaming over them, so a crash mid-write leaves the previous checkpoint. Gives back the bytes written and how
+    // long syncing them took.
+    fn write_checkpoint(&self, path: &Path) -> io::Result<(usize, Duration)> {
+        let mut bytes = self.cluster_id.to_bytes().to_vec();
+        for n in &[
+            self.current_term,
+            self.voted_for.map_or(0, |id| id + 1),
+            self.snapshot_last_index,
+            self.snapshot_last_term,
+            self.snapshot_bytes.len() as u32,
+        ] {
+            bytes.extend_from_slice(&n.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.snapshot_bytes);
+        bytes.extend_from_slice(&backup::encode_segment(&self.log));
+
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        let sync_started = Instant::now();
+        file.sync_all()?;
+        let sync = sync_started.elapsed();
+        fs::rename(&tmp, path)?;
+        Ok((bytes.len(), sync))
+    }
+
+    // ships the snapshot and new log entries to the target every interval, from then on
+    pub fn start_backups(&mut self, uploads: Sender<Upload>, interval: Duration) {
+        self.backup = Some(BackupSchedule {
+            uploads,
+            interval,
+            last_run: Instant::now(),
+            uploaded_snapshot_index: 0,
+            uploaded_log_index: 0,
+        });
+    }
+
+    fn backup_if_due(&mut self) {
+        let backup = match &mut self.backup {
+            Some(backup) if backup.last_run.elapsed() >= backup.interval => backup,
+            _ => return,
+        };
+        backup.last_run = Instant::now();
+
+        if self.snapshot_last_index > backup.uploaded_snapshot_index && !self.snapshot_bytes.is_empty() {
+            backup.uploaded_snapshot_index = self.snapshot_last_index;
+            let _ = backup.uploads.send(Upload::Snapshot {
+                last_index: self.snapshot_last_index,
+                last_term: self.snapshot_last_term,
+                bytes: self.snapshot_bytes.to_vec(),
+            });
+        }
+
+        let first_index = self.snapshot_last_index + 1;
+        let last_index = self.snapshot_last_index + self.log.len() as u32;
+        let from = first_index.max(backup.uploaded_log_index + 1);
+        if from <= last_index {
+            let entries = &self.log[(from - first_index) as usize..];
+            backup.uploaded_log_index = last_index;
+            let _ = backup.uploads.send(Upload::Segment {
+                first_index: from,
+                last_index,
+                last_term: self.log.last().map_or(0, |e| e.term),
+                bytes: backup::encode_segment(entries),
+            });
+        }
+    }
+
+    // Checks the Raft safety invariants that are visible from storage, aborting with a dump of the stored state if
+    // any are broken. Only does anything when built with the debug-invariants feature.
+    fn check_invariants(&self, op: &str) {
+        if !cfg!(feature = "debug-invariants") {
+            return;
+        }
+
+        let mut violations = vec![];
+
+        let terms: Vec<u32> = self.log.iter().map(|e| e.term).collect();
+        if let Some(i) = terms.windows(2).position(|w| w[0] > w[1]) {
+            violations.push(format!("log term decreases from {} to {} at entry {}", terms[i], terms[i + 1], i + 1));
+        }
+        if let Some(first) = terms.first() {
+            if *first < self.snapshot_last_term {
+                violations.push(format!("first log entry term {} is before snapshot term {}", first, self.snapshot_last_term));
+            }
+        }
+        if let Some(last) = terms.last() {
+            if *last > self.current_term {
+                violations.push(format!("last log entry term {} is after current term {}", last, self.current_term));
+            }
+        }
+        if self.snapshot_last_term > self.current_term {
+            violations.push(format!("snapshot term {} is after current term {}", self.snapshot_last_term, self.current_term));
+        }
+
+        if !violations.is_empty() {
+            self.abort_with_dump(op, &violations);
+        }
+    }
+
+    // Checks that the log picks up where the snapshot leaves off, as loaded from a checkpoint or backups, and repairs it
+    // with the policy if it doesn't. Gives back what was wrong and what was done about it, or an error if the policy
+    // can't fix it.
+    pub fn verify_log_prefix(&mut self, repair: LogRepair) -> Result<Option<String>, String> {
+        let snapshot_problem = if self.snapshot_last_index == 0 && (self.snapshot_last_term != 0 || !self.snapshot_bytes.is_empty()) {
+            Some(format!("snapshot at index 0 has term {} and {} bytes", self.snapshot_last_term, self.snapshot_bytes.len()))
+        } else if self.snapshot_last_index > 0 && self.snapshot_bytes.is_empty() {
+            Some(format!("snapshot through {} is missing", self.snapshot_last_index))
+        } else {
+            None
+        };
+
+        let terms: Vec<u32> = self.log.iter().map(|e| e.term).collect();
+        let bad_entry = if terms.first().map_or(false, |first| *first < self.snapshot_last_term) {
+            Some(0)
+        } else {
+            terms.windows(2).position(|w| w[0] > w[1]).map(|i| i + 1)
+        };
+        let log_problem = bad_entry.map(|i| format!("log entry {} has term {}, after term {}", self.snapshot_last_index as usize + i + 1, terms[i],
+                                                    if i == 0 { self.snapshot_last_term } else { terms[i - 1] }));
+
+        let snapshot_intact = snapshot_problem.is_none();
+        let problem = match (snapshot_problem, log_problem) {
+            (None, None) => return Ok(None),
+            (Some(problem), None) | (None, Some(problem)) => problem,
+            (Some(snapshot), Some(log)) => format!("{}, {}", snapshot, log),
+        };
+        match repair {
+            LogRepair::Refuse => Err(problem),
+            LogRepair::Truncate => match bad_entry {
+                Some(i) if snapshot_intact => {
+                    let dropped = self.log.len() - i;
+                    self.log.truncate(i);
+                    self.entry_sizes.truncate(i);
+                    let removed_from = self.snapshot_last_index + 1 + i as u32;
+                    write_wal(&mut self.wal, |wal| wal.truncate_from(removed_from));
+                    Ok(Some(format!("{}, dropped the last {} log entries", problem, dropped)))
+                }
+                _ => Err(format!("{}, which truncating the log can't fix", problem)),
+            },
+            LogRepair::Discard => {
+                self.log.clear();
+                self.entry_sizes.clear();
+                self.snapshot_bytes = SnapshotBytes::default();
+                *self.snapshot_cache.get_mut() = None;
+                self.snapshot_last_index = 0;
+                self.snapshot_last_term = 0;
+                write_wal(&mut self.wal, |wal| {
+                    wal.save_snapshot(0, 0, &[])?;
+                    wal.truncate_from(1)
+                });
+                Ok(Some(format!("{}, discarded the snapshot and log", problem)))
+            }
         }
     }
+
+    fn abort_with_dump(&self, op: &str, violations: &[String]) -> ! {
+        eprintln!("invariant violated after {}:", op);
+        for violation in violations {
+            eprintln!("  {}", violation);
+        }
+        eprintln!("current_term={} voted_for={:?} snapshot_last_index={} snapshot_last_term={} snapshot_bytes={}",
+                  self.current_term, self.voted_for, self.snapshot_last_index, self.snapshot_last_term, self.snapshot_bytes.len());
+        eprintln!("log terms ({} entries): {:?}", self.log.len(), self.log.iter().map(|e| e.term).collect::<Vec<u32>>());
+        std::process::abort();
+    }
+}
+
+impl<S: StateMachine + Clone> RamStorage<S> {
+    // rebuilds storage from the backups in the target as they were at the restore point: the last snapshot before it,
+    // then the log after that from the uploaded segments
+    pub fn restore(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId, target: &mut dyn BackupTarget, point: RestorePoint) -> io::Result<RamStorage<S>> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        let manifest: Manifest = serde_json::from_slice(&target.get(backup::MANIFEST)?)
+            .map_err(|e| invalid(format!("bad manifest: {}", e)))?;
+        let plan = backup::plan_restore(&manifest, &point).map_err(invalid)?;
+
+        let mut storage = RamStorage::new(init_state_machine, cluster_id);
+
+        if let Some(snapshot) = &plan.snapshot {
+            let bytes = target.get(&snapshot.name)?;
+            if bytes.get(..SNAPSHOT_HEADER_LEN) != Some(&cluster_id.to_bytes()[..]) {
+                return Err(invalid(format!("{} is from a different cluster", snapshot.name)));
+            }
+            if let Err(e) = parse_snapshot::<S>(&bytes) {
+                return Err(invalid(format!("{} {}", snapshot.name, e)));
+            }
+            storage.snapshot_bytes = SnapshotBytes::Memory(bytes);
+            storage.snapshot_last_index = snapshot.last_index;
+            storage.snapshot_last_term = snapshot.last_term;
+        }
+
+        let first_index = storage.snapshot_last_index + 1;
+        for segment in &plan.segments {
+            let entries = backup::decode_segment(&target.get(&segment.name)?)
+                .ok_or_else(|| invalid(format!("{} is corrupt", segment.name)))?;
+            let start = segment.first_index.max(first_index);
+            storage.log.truncate((start - first_index) as usize);
+            storage.log.extend(entries.into_iter().skip((start - segment.first_index) as usize));
+        }
+        storage.log.truncate((plan.last_index + 1 - first_index) as usize);
+
+        storage.measure_log();
+        storage.current_term = storage.log.last().map_or(storage.snapshot_last_term, |e| e.term);
+        storage.check_invariants("restore");
+        Ok(storage)
+    }
+
+    // storage as it was in the last checkpoint written to the file, None if there isn't one
+    pub fn load_checkpoint(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId, path: &Path) -> io::Result<Option<RamStorage<S>>> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("checkpoint {} {}", path.display(), what));
+
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if file.get(..SNAPSHOT_HEADER_LEN) != Some(&cluster_id.to_bytes()[..]) {
+            return Err(invalid("is from a different cluster"));
+        }
+
+        let mut bytes = &file[SNAPSHOT_HEADER_LEN..];
+        let mut next = || backup::take_u32(&mut bytes).ok_or_else(|| invalid("is truncated"));
+        let current_term = next()?;
+        let voted_for = next()?.checked_sub(1);
+        let snapshot_last_index = next()?;
+        let snapshot_last_term = next()?;
+        let snapshot_len = next()? as usize;
+
+        if bytes.len() < snapshot_len {
+            return Err(invalid("is truncated"));
+        }
+        let (snapshot, log) = bytes.split_at(snapshot_len);
+        if !snapshot.is_empty() {
+            if let Err(e) = parse_snapshot::<S>(snapshot) {
+                return Err(invalid(&format!("has a snapshot that {}", e)));
+            }
+        }
+
+        let mut storage = RamStorage::new(init_state_machine, cluster_id);
+        storage.log = backup::decode_segment(log).ok_or_else(|| invalid("has a corrupt log"))?;
+        storage.measure_log();
+        storage.current_term = current_term;
+        storage.voted_for = voted_for;
+        storage.voted_in_term = current_term;
+        storage.snapshot_bytes = SnapshotBytes::Memory(snapshot.to_vec());
+        storage.snapshot_last_index = snapshot_last_index;
+        storage.snapshot_last_term = snapshot_last_term;
+        storage.check_invariants("load_checkpoint");
+        Ok(Some(storage))
+    }
+
+    // storage as it was left in the write-ahead log in the directory, which it carries on writing to
+    pub fn open_wal(init_state_machine: RaftStateMachine<S>, cluster_id: ClusterId, dir: &Path, segment_bytes: u64, metrics: StorageMetrics) -> io::Result<RamStorage<S>> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("write-ahead log in {} {}", dir.display(), what));
+
+        let (wal, recovered) = Wal::open(dir, segment_bytes, metrics)?;
+        if !recovered.snapshot.is_empty() {
+            if recovered.snapshot.get(..SNAPSHOT_HEADER_LEN) != Some(&cluster_id.to_bytes()[..]) {
+                return Err(invalid("is from a different cluster".to_string()));
+            }
+            if let Err(e) = parse_snapshot::<S>(&recovered.snapshot) {
+                return Err(invalid(format!("has a snapshot that {}", e)));
+            }
+        }
+
+        let mut storage = RamStorage::new(init_state_machine, cluster_id);
+        for (i, bytes) in recovered.entries.iter().enumerate() {
+            let index = recovered.snapshot_last_index as usize + i + 1;
+            storage.log.push(LogEntry::try_from_slice(bytes).ok_or_else(|| invalid(format!("has a corrupt entry at {}", index)))?);
+        }
+        storage.measure_log();
+        storage.current_term = recovered.current_term;
+        storage.voted_for = recovered.voted_for;
+        storage.voted_in_term = recovered.current_term;
+        storage.snapshot_bytes = recovered.snapshot;
+        storage.snapshot_last_index = recovered.snapshot_last_index;
+        storage.snapshot_last_term = recovered.snapshot_last_term;
+        storage.wal = Some(wal);
+        storage.check_invariants("open_wal");
+        Ok(storage)
+    }
 }
 
 impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     fn add_log_entry(&mut self, entry: LogEntry<<S as StateMachine>::Command>) {
-        self.log.push(entry)
+        let _subsystem = alloc_stats::enter(Subsystem::Log);
+        let mut bytes = vec![];
+        entry.write_bytes_with_writer(&mut bytes).unwrap();
+        let index = self.snapshot_last_index + 1 + self.log.len() as u32;
+        write_wal(&mut self.wal, |wal| wal.append(index, &bytes));
+        if let Some(metrics) = &self.metrics {
+            metrics.record_append(bytes.len());
+        }
+        self.entry_sizes.push(bytes.len());
+        self.log.push(entry);
+        self.check_invariants("add_log_entry");
     }
 
     fn remove_log_entries_before(&mut self, index: usize) {
+        if std::mem::take(&mut self.skip_compaction) {
+            return;
+        }
         self.log.drain(..index);
+        let compacted: usize = self.entry_sizes.drain(..index).sum();
+        if let Some(metrics) = &self.metrics {
+            metrics.record_compaction(index, compacted);
+        }
+        // compaction can leave the log far smaller than what it was allocated for
+        if self.log.capacity() > 4 * self.log.len().max(64) {
+            self.log.shrink_to_fit();
+            self.entry_sizes.shrink_to_fit();
+        }
+        let snapshot_last_index = self.snapshot_last_index;
+        write_wal(&mut self.wal, |wal| wal.compact_through(snapshot_last_index));
+        self.check_invariants("remove_log_entries_before");
     }
 
     fn remove_log_entries_starting_at(&mut self, index: usize) {
         self.log.drain(index..);
+        self.entry_sizes.drain(index..);
+        // the replacement entries need to be uploaded over the removed ones
+        let removed_from = self.snapshot_last_index + 1 + index as u32;
+        if let Some(backup) = &mut self.backup {
+            backup.uploaded_log_index = backup.uploaded_log_index.min(removed_from - 1);
+        }
+        write_wal(&mut self.wal, |wal| wal.truncate_from(removed_from));
+        self.check_invariants("remove_log_entries_starting_at");
     }
 
-    fn save_log(&mut self) {}
+    fn save_log(&mut self) {
+        write_wal(&mut self.wal, Wal::sync);
+        self.report_log();
+        self.backup_if_due();
+        self.checkpoint_if_due();
+    }
 
     fn log_entry(&self, index: usize) -> Option<&LogEntry<<S as StateMachine>::Command>> {
         self.log.get(index)
     }
 
+    // the core sends what this gives back as one AppendEntries, so it's cut off at the byte budget, see
+    // MAX_APPEND_ENTRIES_BYTES for an entry that's over it on its own
     fn log_entries(&self, start_index: usize) -> &[LogEntry<<S as StateMachine>::Command>] {
-        &self.log[start_index..]
+        let entries = &self.log[start_index..];
+        let mut bytes = 0;
+        let fits = self.entry_sizes[start_index..].iter()
+            .take_while(|size| {
+                bytes += **size;
+                bytes <= self.append_entries_bytes
+            })
+            .count();
+        &entries[..fits.max(1).min(entries.len())]
     }
 
     fn get_index_of_last_config_in_log(&self) -> Option<usize> {
@@ -72,16 +657,53 @@ impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     }
 
     fn set_snapshot(&mut self, last_index: u32, last_term: u32, snapshot: &RaftStateMachine<S>) {
+        if cfg!(feature = "debug-invariants") && last_index < self.snapshot_last_index {
+            self.abort_with_dump("set_snapshot", &[format!("snapshot index moves back from {} to {}", self.snapshot_last_index, last_index)]);
+        }
+        if let Some(pause) = self.snapshot_pause.as_ref().filter(|pause| pause.skips_snapshots()) {
+            pause.record_skipped();
+            self.skip_compaction = true;
+            return;
+        }
+
         self.snapshot_last_index = last_index;
         self.snapshot_last_term = last_term;
 
-        self.snapshot_bytes.clear();
-        snapshot.write_bytes_with_writer(&mut self.snapshot_bytes).unwrap();
+        let (header, codec, encoding) = (self.cluster_id.to_bytes(), self.snapshot_codec, self.snapshot_encoding);
+        let written = write_wal(&mut self.wal, |wal| {
+            let bytes = wal.write_snapshot(last_index, last_term, |out| {
+                out.write_all(&header)?;
+                write_snapshot_body(out, snapshot, codec, encoding)
+            })?;
+            wal.compact_through(last_index)?;
+            Ok(bytes)
+        });
+        self.snapshot_bytes = match written {
+            Some(bytes) => bytes,
+            None => {
+                let mut bytes = header.to_vec();
+                write_snapshot_body(&mut bytes, snapshot, codec, encoding).unwrap();
+                SnapshotBytes::Memory(bytes)
+            }
+        };
+        *self.snapshot_cache.get_mut() = None;
+        if let Some(metrics) = &self.metrics {
+            metrics.record_snapshot(self.snapshot_bytes.len());
+        }
+        if let Some(exchange) = &self.snapshot_exchange {
+            exchange.publish(last_index, &self.snapshot_bytes);
+        }
+        self.check_invariants("set_snapshot");
+        self.backup_if_due();
+        self.checkpoint_if_due();
     }
 
+    // the core asks for it every time it sends a follower the snapshot, which shouldn't mean parsing it every time
     fn snapshot(&self) -> RaftStateMachine<S> {
-        let bytes = BytesRef::new(&self.snapshot_bytes);
-        RaftStateMachine::try_from_bytes(bytes).unwrap_or_else(|| clone_state_machine(&self.init_state_machine))
+        let mut cache = self.snapshot_cache.borrow_mut();
+        let snapshot = cache.get_or_insert_with(|| parse_snapshot(&self.snapshot_bytes)
+            .unwrap_or_else(|_| clone_state_machine(&self.init_state_machine)));
+        clone_state_machine(snapshot)
     }
 
     fn snapshot_last_index(&self) -> u32 {
@@ -93,20 +715,72 @@ impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     }
 
     fn add_new_snapshot_chunk(&mut self, offset: u32, data: &[u8]) {
-        let start = offset as usize;
-        let end = start + data.len();
-        self.snapshot_chunk_bytes.resize_with(end, || 0u8);
-        self.snapshot_chunk_bytes.splice(start..end, data.iter().map(|n| *n));
+        match self.snapshot_chunks.add(offset, data) {
+            Ok(()) => {}
+            Err(Rejected::Duplicate) => return,
+            Err(rejected) => {
+                eprintln!("ignoring snapshot chunk of {} bytes at {}: {:?}", data.len(), offset, rejected);
+                return;
+            }
+        }
+        if let Some(exchange) = &self.snapshot_exchange {
+            exchange.record_pushed(offset, data.len() as u32);
+        }
     }
 
     fn try_use_chunks_as_new_snapshot(&mut self, last_index: u32, last_term: u32) -> Option<RaftStateMachine<S>> {
-        if let Some(snapshot) = RaftStateMachine::<S>::try_from_slice(&self.snapshot_chunk_bytes) {
-            self.snapshot_bytes = std::mem::take(&mut self.snapshot_chunk_bytes);
-            self.snapshot_last_index = last_index;
-            self.snapshot_last_term = last_term;
-            return Some(snapshot);
+        // whatever was pulled fills the gaps the pushes left
+        let (gaps, total) = match &self.snapshot_exchange {
+            Some(exchange) => (exchange.gaps(last_index), exchange.total(last_index)),
+            None => (vec![], None),
+        };
+        for (offset, data) in gaps {
+            // parts pulled twice overlap, the first is as good as the second
+            let _ = self.snapshot_chunks.add(offset, &data);
         }
-        None
+
+        let bytes = self.snapshot_chunks.complete(total)?;
+        if bytes.len() < SNAPSHOT_HEADER_LEN {
+            return None;
+        }
+
+        if bytes[..SNAPSHOT_HEADER_LEN] != self.cluster_id.to_bytes()[..] {
+            eprintln!("refusing to install snapshot from a different cluster");
+            return None;
+        }
+
+        let snapshot = match parse_snapshot::<S>(bytes) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                // every chunk has arrived, so waiting won't fix it, the leader sends it again from the start
+                eprintln!("refusing to install snapshot through {}: it {}", last_index, e);
+                self.snapshot_chunks.take();
+                if let Some(exchange) = &self.snapshot_exchange {
+                    exchange.finish();
+                }
+                return None;
+            }
+        };
+        let chunks = self.snapshot_chunks.take();
+        let written = write_wal(&mut self.wal, |wal| {
+            let bytes = wal.write_snapshot(last_index, last_term, |out| out.write_all(&chunks))?;
+            wal.compact_through(last_index)?;
+            Ok(bytes)
+        });
+        self.snapshot_bytes = match written {
+            Some(bytes) => bytes,
+            None => SnapshotBytes::Memory(chunks),
+        };
+        *self.snapshot_cache.get_mut() = None;
+        self.skip_compaction = false;
+        self.snapshot_last_index = last_index;
+        self.snapshot_last_term = last_term;
+        if let Some(exchange) = &self.snapshot_exchange {
+            exchange.finish();
+            exchange.publish(last_index, &self.snapshot_bytes);
+        }
+        self.check_invariants("try_use_chunks_as_new_snapshot");
+        Some(snapshot)
     }
 
     fn snapshot_chunk(&self, offset: u32, amt: u32) -> &[u8] {
@@ -120,7 +794,20 @@ impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     }
 
     fn set_voted_for(&mut self, voted_for: Option<u32>) {
+        if cfg!(feature = "debug-invariants") && self.voted_in_term == self.current_term {
+            if let (Some(old), Some(new)) = (self.voted_for, voted_for) {
+                if old != new {
+                    self.abort_with_dump("set_voted_for", &[format!("vote changes from {} to {} in term {}", old, new, self.current_term)]);
+                }
+            }
+        }
+
+        if voted_for != self.voted_for {
+            let current_term = self.current_term;
+            write_wal(&mut self.wal, |wal| wal.save_hard_state(current_term, voted_for));
+        }
         self.voted_for = voted_for;
+        self.voted_in_term = self.current_term;
     }
 
     fn voted_for(&self) -> Option<u32> {
@@ -128,7 +815,19 @@ impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     }
 
     fn set_current_term(&mut self, current_term: u32) {
+        if cfg!(feature = "debug-invariants") && current_term < self.current_term {
+            self.abort_with_dump("set_current_term", &[format!("term moves back from {} to {}", self.current_term, current_term)]);
+        }
+
+        if current_term != self.current_term {
+            let voted_for = self.voted_for;
+            write_wal(&mut self.wal, |wal| wal.save_hard_state(current_term, voted_for));
+        }
         self.current_term = current_term;
+        self.report_log();
+        // a node shutting down sees the next term when its peers start the election it told them to
+        self.checkpoint_if_due();
+        self.check_invariants("set_current_term");
     }
 
     fn current_term(&self) -> u32 {
@@ -136,21 +835,222 @@ impl<S: StateMachine + Clone> Storage<S> for RamStorage<S> {
     }
 }
 
+// What's in the write-ahead log a stopped node left in the directory, as JSON, without checking whose cluster it's from.
+pub fn inspect_wal<S: StateMachine>(dir: &Path) -> io::Result<serde_json::Value> {
+    let (_, recovered) = Wal::open(dir, wal::DEFAULT_SEGMENT_BYTES, StorageMetrics::default())?;
+
+    let entries: Vec<serde_json::Value> = recovered.entries.iter().enumerate().map(|(i, bytes)| {
+        let index = recovered.snapshot_last_index as usize + i + 1;
+        match LogEntry::<S::Command>::try_from_slice(bytes) {
+            Some(entry) => json!({
+                "index": index,
+                "term": entry.term,
+                "config": matches!(entry.entry_type, LogEntryType::Config(_)),
+                "bytes": bytes.len(),
+            }),
+            None => json!({ "index": index, "corrupt": true, "bytes": bytes.len() }),
+        }
+    }).collect();
+
+    let snapshot_problem = parse_snapshot::<S>(&recovered.snapshot).err();
+    let body = recovered.snapshot.get(SNAPSHOT_HEADER_LEN..).unwrap_or_default();
+    let unchecked = body.strip_prefix(&CHECKSUMMED_MAGIC[..]);
+    Ok(json!({
+        "current_term": recovered.current_term,
+        "voted_for": recovered.voted_for,
+        "snapshot": {
+            "last_index": recovered.snapshot_last_index,
+            "last_term": recovered.snapshot_last_term,
+            "bytes": recovered.snapshot.len(),
+            "checksummed": unchecked.is_some(),
+            "compressed": unchecked.unwrap_or(body).starts_with(&COMPRESSED_MAGIC),
+            "readable": snapshot_problem.is_none(),
+            "problem": snapshot_problem,
+        },
+        "entries": entries,
+    }))
+}
+
+// Rewrites the snapshot in a stopped node's write-ahead log with the encoding, compressed with the codec, and deletes the
+// segments it covers. Entries after the snapshot stay, only the core knows which of them are committed, and it
+// snapshots them once there are KV_SNAPSHOT_MIN_LOG_SIZE.
+pub fn compact_wal<S: StateMachine>(dir: &Path, codec: SnapshotCodec, encoding: SnapshotEncoding) -> io::Result<serde_json::Value> {
+    let corrupt = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("write-ahead log in {} has a snapshot that {}", dir.display(), what));
+
+    let (mut wal, recovered) = Wal::open(dir, wal::DEFAULT_SEGMENT_BYTES, StorageMetrics::default())?;
+    let segments_before = wal.num_segments();
+    let mut bytes_after = 0;
+    if !recovered.snapshot.is_empty() {
+        let header = recovered.snapshot.get(..SNAPSHOT_HEADER_LEN).ok_or_else(|| corrupt("is truncated"))?;
+        let state_machine = parse_snapshot::<S>(&recovered.snapshot).map_err(corrupt)?;
+        bytes_after = wal.write_snapshot(recovered.snapshot_last_index, recovered.snapshot_last_term, |out| {
+            out.write_all(header)?;
+            write_snapshot_body(out, &state_machine, codec, encoding)
+        })?.len();
+    }
+    wal.compact_through(recovered.snapshot_last_index)?;
+
+    Ok(json!({
+        "snapshot_last_index": recovered.snapshot_last_index,
+        "snapshot_bytes": { "before": recovered.snapshot.len(), "after": bytes_after },
+        "segments": { "before": segments_before, "after": wal.num_segments() },
+        "entries_after_snapshot": recovered.entries.len(),
+    }))
+}
+
+// Makes this node the only member of a new cluster, for when too many members are lost for good to ever make a quorum
+// again. Every config in the snapshot and the log is rewritten to have just this node, keeping the data, and the
+// snapshot is stamped with the new cluster's id. The snapshot may be from any cluster, so an interrupted run can be
+// run again.
+pub fn force_new_cluster<S: StateMachine>(dir: &Path, our_id: u32, cluster_id: ClusterId, codec: SnapshotCodec, encoding: SnapshotEncoding) -> io::Result<serde_json::Value> {
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("write-ahead log in {} {}", dir.display(), what));
+
+    let (mut wal, recovered) = Wal::open(dir, wal::DEFAULT_SEGMENT_BYTES, StorageMetrics::default())?;
+    let mut state_machine = if recovered.snapshot.is_empty() {
+        None
+    } else {
+        Some(parse_snapshot::<S>(&recovered.snapshot).map_err(|e| invalid(format!("has a snapshot that {}", e)))?)
+    };
+    let mut entries = vec![];
+    for (i, bytes) in recovered.entries.iter().enumerate() {
+        let index = recovered.snapshot_last_index as usize + i + 1;
+        entries.push(LogEntry::<S::Command>::try_from_slice(bytes).ok_or_else(|| invalid(format!("has a corrupt entry at {}", index)))?);
+    }
+
+    // nothing is written unless this node is in every config there is
+    let mut members = BTreeSet::new();
+    let mut configs = state_machine.iter_mut().map(|sm| &mut sm.config)
+        .chain(entries.iter_mut().filter_map(|entry| match &mut entry.entry_type {
+            LogEntryType::Config(config) => Some(config),
+            _ => None,
+        }))
+        .collect::<Vec<_>>();
+    for config in &configs {
+        if !config.nodes.contains_key(&our_id) {
+            return Err(invalid(format!("has a config without {} in it", num_to_network_name(our_id))));
+        }
+        members.extend(config.nodes.keys().copied());
+    }
+    for config in &mut configs {
+        config.nodes.retain(|id, _| *id == our_id);
+    }
+    let configs_rewritten = configs.len();
+
+    if let Some(state_machine) = &state_machine {
+        wal.write_snapshot(recovered.snapshot_last_index, recovered.snapshot_last_term, |out| {
+            out.write_all(&cluster_id.to_bytes())?;
+            write_snapshot_body(out, state_machine, codec, encoding)
+        })?;
+    }
+    let first_config = entries.iter().position(|entry| matches!(entry.entry_type, LogEntryType::Config(_)));
+    if let Some(first_config) = first_config {
+        let first_index = recovered.snapshot_last_index + 1 + first_config as u32;
+        wal.truncate_from(first_index)?;
+        for (i, entry) in entries[first_config..].iter().enumerate() {
+            let mut bytes = vec![];
+            entry.write_bytes_with_writer(&mut bytes).unwrap();
+            wal.append(first_index + i as u32, &bytes)?;
+        }
+        wal.sync()?;
+    }
+
+    members.remove(&our_id);
+    Ok(json!({
+        "cluster": cluster_id.to_string(),
+        "removed_members": members.into_iter().map(num_to_network_name).collect::<Vec<String>>(),
+        "snapshot_last_index": recovered.snapshot_last_index,
+        "configs_rewritten": configs_rewritten,
+        "entries_kept": entries.len(),
+    }))
+}
+
+// the state machine in a snapshot, which has to have a header and may be compressed, or why it can't be read
+pub fn parse_snapshot<S: StateMachine>(snapshot: &[u8]) -> Result<RaftStateMachine<S>, &'static str> {
+    let mut body = snapshot.get(SNAPSHOT_HEADER_LEN..).ok_or("is truncated")?;
+    if let Some(checksummed) = body.strip_prefix(&CHECKSUMMED_MAGIC[..]) {
+        if checksummed.len() < 4 {
+            return Err("is truncated");
+        }
+        let (checksummed, mut sum) = checksummed.split_at(checksummed.len() - 4);
+        if backup::take_u32(&mut sum) != Some(backup::checksum(checksummed)) {
+            return Err("fails its checksum");
+        }
+        body = checksummed;
+    }
+    let body = match body.strip_prefix(&COMPRESSED_MAGIC[..]) {
+        Some(compressed) => {
+            let codec = compressed.first().and_then(|tag| SnapshotCodec::from_tag(*tag)).ok_or("has an unknown codec")?;
+            Cow::Owned(codec.decompress(&compressed[1..]).ok_or("can't be decompressed")?)
+        }
+        None => Cow::Borrowed(body),
+    };
+    RaftStateMachine::try_from_slice(&body).ok_or("can't be deserialized")
+}
+
+// everything in a snapshot after its header
+pub fn write_snapshot_body<S: StateMachine, W: Write>(out: &mut W, state_machine: &RaftStateMachine<S>, codec: SnapshotCodec, encoding: SnapshotEncoding) -> io::Result<()> {
+    out.write_all(&CHECKSUMMED_MAGIC)?;
+    let mut checksummed = CrcWriter::new(out);
+    snapshot_format::write_with(encoding, || write_state_machine(&mut checksummed, state_machine, codec))?;
+    let sum = checksummed.crc().sum();
+    checksummed.into_inner().write_all(&sum.to_be_bytes())
+}
+
+fn write_state_machine<S: StateMachine, W: Write>(out: &mut W, state_machine: &RaftStateMachine<S>, codec: SnapshotCodec) -> io::Result<()> {
+    if codec == SnapshotCodec::Uncompressed {
+        state_machine.write_bytes_with_writer(out)?;
+        return Ok(());
+    }
+    out.write_all(&COMPRESSED_MAGIC)?;
+    out.write_all(&[codec as u8])?;
+    // gzip compresses as it's written, an lz4 block needs the whole body first
+    if codec == SnapshotCodec::Gzip {
+        let mut encoder = GzEncoder::new(out, Compression::fast());
+        state_machine.write_bytes_with_writer(&mut encoder)?;
+        encoder.finish()?;
+        return Ok(());
+    }
+    let mut body = vec![];
+    state_machine.write_bytes_with_writer(&mut body)?;
+    out.write_all(&codec.compress(&body)?)
+}
+
+// the node can't go on promising what it can't make durable, so it stops and recovers from what was written
+fn write_wal<T>(wal: &mut Option<Wal>, op: impl FnOnce(&mut Wal) -> io::Result<T>) -> Option<T> {
+    match op(wal.as_mut()?) {
+        Ok(written) => Some(written),
+        Err(e) => panic!("failed to write the write-ahead log: {}", e),
+    }
+}
+
+pub fn entry_size(entry: &impl WriteBytes) -> usize {
+    let mut bytes = vec![];
+    entry.write_bytes_with_writer(&mut bytes).unwrap();
+    bytes.len()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::time::Duration;
 
     use my_raft::bytes::WriteBytes;
-    use my_raft::config::Config;
+    use my_raft::config::{Config, NodeAddress};
     use my_raft::state_machine::RaftStateMachine;
     use my_raft::storage::Storage;
 
+    use crate::cluster::ClusterId;
+    use crate::shutdown::Shutdown;
+    use crate::snapshot_file::SnapshotBytes;
+    use crate::snapshot_format;
+    use crate::snapshot_format::SnapshotEncoding;
+    use crate::snapshot_pause::SnapshotPause;
     use crate::state_machine::KvStateMachine;
-    use crate::storage::RamStorage;
+    use crate::storage::{CHECKSUMMED_MAGIC, compact_wal, force_new_cluster, inspect_wal, LogRepair, parse_snapshot, RamStorage, SNAPSHOT_HEADER_LEN, SnapshotCodec};
+    use crate::storage_metrics::{LogGauges, StorageMetrics};
 
     fn get_empty_storage() -> RamStorage<KvStateMachine> {
         RamStorage::new(RaftStateMachine {
-            inner: KvStateMachine(HashMap::new()),
+            inner: KvStateMachine::default(),
             config: Config {
                 election_timeout_min: 0,
                 election_timeout_range: 0,
@@ -164,15 +1064,15 @@ mod tests {
                 nodes: Default::default(),
             },
             client_last_command_ids: Default::default(),
-        })
+        }, ClusterId(0))
     }
 
     #[test]
     fn snapshot_chunks() {
-        let mut sm = KvStateMachine(HashMap::new());
-        sm.0.insert("hello".to_string(), "goodbye".to_string());
-        sm.0.insert("blue".to_string(), "red".to_string());
-        sm.0.insert("hot".to_string(), "cold".to_string());
+        let mut sm = KvStateMachine::default();
+        sm.data.insert("hello".to_string(), "goodbye".to_string());
+        sm.data.insert("blue".to_string(), "red".to_string());
+        sm.data.insert("hot".to_string(), "cold".to_string());
 
         let sm = RaftStateMachine {
             inner: sm,
@@ -191,21 +1091,309 @@ mod tests {
             client_last_command_ids: Default::default(),
         };
 
-        let mut bytes = vec![];
+        let mut bytes = ClusterId(0).to_bytes().to_vec();
         sm.write_bytes_with_writer(&mut bytes).unwrap();
 
         let mut storage = get_empty_storage();
 
         let chunk_size = 10;
 
-        for i in 0..((bytes.len() / chunk_size) + 1) {
+        // the second chunk comes last, and nothing's installed without it
+        for i in (0..((bytes.len() / chunk_size) + 1)).filter(|i| *i != 1).chain(vec![1]) {
             let offset = i * chunk_size;
             let amt = chunk_size.min(bytes.len() - offset);
             storage.add_new_snapshot_chunk(offset as u32, &bytes[offset..(offset + amt)]);
+            if i != 1 {
+                assert!(storage.try_use_chunks_as_new_snapshot(5, 5).is_none());
+            }
         }
 
-        assert_eq!(storage.snapshot_chunk_bytes, bytes);
+        assert_eq!(storage.snapshot_chunks.complete(None), Some(&bytes[..]));
 
         storage.try_use_chunks_as_new_snapshot(5, 5).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn snapshot_from_other_cluster_rejected() {
+        let mut bytes = ClusterId(1).to_bytes().to_vec();
+        get_empty_storage().snapshot().write_bytes_with_writer(&mut bytes).unwrap();
+
+        let mut storage = get_empty_storage();
+        storage.add_new_snapshot_chunk(0, &bytes);
+
+        assert!(storage.try_use_chunks_as_new_snapshot(5, 5).is_none());
+    }
+
+    #[test]
+    fn paused_snapshots_skipped() {
+        let mut storage = get_empty_storage();
+        let pause = SnapshotPause::default();
+        storage.share_snapshot_pause(pause.clone());
+        let sm = storage.snapshot();
+
+        pause.set_paused(true);
+        storage.set_snapshot(7, 2, &sm);
+        assert_eq!(storage.snapshot_last_index(), 0);
+        assert!(storage.skip_compaction);
+        storage.remove_log_entries_before(0);
+        assert!(!storage.skip_compaction);
+        assert_eq!(pause.to_json()["skipped_snapshots"], 1);
+
+        // held for a node catching up, which resuming doesn't undo
+        pause.set_held(true);
+        pause.set_paused(false);
+        storage.set_snapshot(7, 2, &sm);
+        assert_eq!(storage.snapshot_last_index(), 0);
+        storage.remove_log_entries_before(0);
+
+        pause.set_held(false);
+        storage.set_snapshot(7, 2, &sm);
+        assert_eq!(storage.snapshot_last_index(), 7);
+    }
+
+    #[test]
+    fn log_reported() {
+        let mut storage = get_empty_storage();
+        let metrics = StorageMetrics::default();
+        storage.share_metrics(metrics.clone());
+        assert_eq!(metrics.log(), LogGauges::default());
+
+        let sm = storage.snapshot();
+        storage.set_current_term(4);
+        storage.set_snapshot(7, 2, &sm);
+        storage.save_log();
+        assert_eq!(metrics.log(), LogGauges { current_term: 4, last_index: 7, entries: 0 });
+        assert_eq!(metrics.stored().snapshot, storage.total_snapshot_bytes() as u64);
+        assert_eq!(metrics.growth()["snapshots"]["taken"], 1);
+    }
+
+    #[test]
+    fn snapshot_cache_invalidated() {
+        let mut storage = get_empty_storage();
+        let mut sm = storage.snapshot();
+        assert!(sm.inner.data.is_empty());
+
+        sm.inner.data.insert("hello".to_string(), "goodbye".to_string());
+        storage.set_snapshot(3, 1, &sm);
+        assert_eq!(storage.snapshot().inner.data.get("hello").map(String::as_str), Some("goodbye"));
+        assert_eq!(storage.snapshot().inner.data.get("hello").map(String::as_str), Some("goodbye"));
+
+        sm.inner.data.insert("hello".to_string(), "again".to_string());
+        let mut bytes = ClusterId(0).to_bytes().to_vec();
+        sm.write_bytes_with_writer(&mut bytes).unwrap();
+        storage.add_new_snapshot_chunk(0, &bytes);
+        storage.try_use_chunks_as_new_snapshot(5, 1).unwrap();
+        assert_eq!(storage.snapshot().inner.data.get("hello").map(String::as_str), Some("again"));
+    }
+
+    #[test]
+    fn log_prefix_verified() {
+        let mut storage = get_empty_storage();
+        assert_eq!(storage.verify_log_prefix(LogRepair::Refuse), Ok(None));
+
+        let sm = storage.snapshot();
+        storage.set_snapshot(7, 2, &sm);
+        assert_eq!(storage.verify_log_prefix(LogRepair::Refuse), Ok(None));
+
+        storage.snapshot_bytes = SnapshotBytes::default();
+        assert!(storage.verify_log_prefix(LogRepair::Refuse).is_err());
+        assert!(storage.verify_log_prefix(LogRepair::Truncate).is_err());
+        assert!(storage.verify_log_prefix(LogRepair::Discard).unwrap().is_some());
+        assert_eq!(storage.snapshot_last_index(), 0);
+        assert_eq!(storage.snapshot_last_term(), 0);
+        assert_eq!(storage.verify_log_prefix(LogRepair::Refuse), Ok(None));
+    }
+
+    #[test]
+    fn wal_recovery() {
+        let dir = std::env::temp_dir().join(format!("storage_wal_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = |cluster_id| RamStorage::open_wal(get_empty_storage().init_state_machine, cluster_id, &dir, 1024, StorageMetrics::default());
+
+        let mut storage = open(ClusterId(0)).unwrap();
+        let mut sm = storage.snapshot();
+        sm.inner.data.insert("k".to_string(), "v".to_string());
+        storage.set_current_term(3);
+        storage.set_voted_for(Some(1));
+        storage.set_snapshot(7, 2, &sm);
+        storage.save_log();
+        assert!(matches!(storage.snapshot_bytes, SnapshotBytes::Mapped { .. }));
+        let chunk = storage.snapshot_chunk(0, storage.total_snapshot_bytes()).to_vec();
+        drop(storage);
+
+        let recovered = open(ClusterId(0)).unwrap();
+        assert_eq!(recovered.current_term(), 3);
+        assert_eq!(recovered.voted_for(), Some(1));
+        assert_eq!(recovered.snapshot_last_index(), 7);
+        assert_eq!(recovered.snapshot_last_term(), 2);
+        assert_eq!(recovered.snapshot().inner.data, sm.inner.data);
+        assert_eq!(recovered.snapshot_chunk(0, recovered.total_snapshot_bytes()), &chunk[..]);
+
+        assert!(open(ClusterId(1)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_snapshots() {
+        let mut sm = KvStateMachine::default();
+        for i in 0..1000 {
+            sm.data.insert(format!("key{}", i), "value".repeat(10));
+        }
+
+        let mut plain = get_empty_storage();
+        let mut snapshot = plain.snapshot();
+        snapshot.inner = sm;
+        plain.set_snapshot(5, 1, &snapshot);
+
+        for &codec in &[SnapshotCodec::Gzip, SnapshotCodec::Lz4] {
+            let mut compressed = get_empty_storage();
+            compressed.set_snapshot_codec(codec);
+            compressed.set_snapshot(5, 1, &snapshot);
+            assert!(compressed.total_snapshot_bytes() < plain.total_snapshot_bytes() / 4);
+
+            // sent to a follower in chunks and pieced back together
+            let mut follower = get_empty_storage();
+            let total = compressed.total_snapshot_bytes();
+            let mut offset = 0;
+            while offset < total {
+                let amt = (total - offset).min(100);
+                follower.add_new_snapshot_chunk(offset, compressed.snapshot_chunk(offset, amt));
+                offset += amt;
+            }
+            let installed = follower.try_use_chunks_as_new_snapshot(5, 1).unwrap();
+            assert_eq!(installed.inner.data, snapshot.inner.data);
+            assert_eq!(follower.snapshot().inner.data, snapshot.inner.data);
+        }
+        assert_eq!(plain.snapshot().inner.data, snapshot.inner.data);
+    }
+
+    #[test]
+    fn encoded_snapshots() {
+        let mut sm = KvStateMachine::default();
+        for i in 0..1000 {
+            sm.data.insert(format!("key{}", i), "value".repeat(10));
+        }
+
+        let mut plain = get_empty_storage();
+        let mut snapshot = plain.snapshot();
+        snapshot.inner = sm;
+        plain.set_snapshot(5, 1, &snapshot);
+
+        // the encoding is the storage's own, so another one writing at the same time isn't affected
+        let mut deduped = get_empty_storage();
+        deduped.set_snapshot_encoding(SnapshotEncoding { dedup: true, ..SnapshotEncoding::default() });
+        deduped.set_snapshot(5, 1, &snapshot);
+        plain.set_snapshot(6, 1, &snapshot);
+        assert!(deduped.total_snapshot_bytes() < plain.total_snapshot_bytes() / 2);
+        assert_eq!(deduped.snapshot().inner.data, snapshot.inner.data);
+
+        let mut json = get_empty_storage();
+        json.set_snapshot_encoding(SnapshotEncoding { format: snapshot_format::by_name("json"), ..SnapshotEncoding::default() });
+        json.set_snapshot(5, 1, &snapshot);
+        plain.set_snapshot(7, 1, &snapshot);
+        assert!(json.total_snapshot_bytes() > plain.total_snapshot_bytes());
+        assert_eq!(json.snapshot().inner.data, snapshot.inner.data);
+        assert_eq!(plain.snapshot().inner.data, snapshot.inner.data);
+    }
+
+    #[test]
+    fn corrupt_snapshots_rejected() {
+        let mut leader = get_empty_storage();
+        let mut snapshot = leader.snapshot();
+        snapshot.inner.data.insert("hello".to_string(), "goodbye".to_string());
+        leader.set_snapshot(5, 1, &snapshot);
+        let bytes = leader.snapshot_chunk(0, leader.total_snapshot_bytes()).to_vec();
+        assert!(parse_snapshot::<KvStateMachine>(&bytes).is_ok());
+
+        let mut corrupt = bytes.clone();
+        corrupt[SNAPSHOT_HEADER_LEN + CHECKSUMMED_MAGIC.len() + 2] ^= 1;
+        assert_eq!(parse_snapshot::<KvStateMachine>(&corrupt).err(), Some("fails its checksum"));
+        assert_eq!(parse_snapshot::<KvStateMachine>(&bytes[..bytes.len() - 1]).err(), Some("fails its checksum"));
+
+        let mut follower = get_empty_storage();
+        follower.add_new_snapshot_chunk(0, &corrupt);
+        assert!(follower.try_use_chunks_as_new_snapshot(5, 1).is_none());
+        assert_eq!(follower.snapshot_last_index(), 0);
+        // sent again from the start, intact this time
+        follower.add_new_snapshot_chunk(0, &bytes);
+        assert_eq!(follower.try_use_chunks_as_new_snapshot(5, 1).unwrap().inner.data, snapshot.inner.data);
+    }
+
+    #[test]
+    fn offline_wal_tools() {
+        let dir = std::env::temp_dir().join(format!("storage_wal_tools_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || RamStorage::open_wal(get_empty_storage().init_state_machine, ClusterId(0), &dir, 1024, StorageMetrics::default());
+
+        let mut storage = open().unwrap();
+        let mut sm = storage.snapshot();
+        sm.inner.data.insert("k".to_string(), "v".to_string());
+        for id in 0..3 {
+            sm.config.nodes.insert(id, NodeAddress::String(format!("000{}", id)));
+        }
+        storage.set_current_term(3);
+        storage.set_snapshot(7, 2, &sm);
+        storage.save_log();
+        drop(storage);
+
+        let inspected = inspect_wal::<KvStateMachine>(&dir).unwrap();
+        assert_eq!(inspected["current_term"], 3);
+        assert_eq!(inspected["snapshot"]["last_index"], 7);
+        assert_eq!(inspected["snapshot"]["readable"], true);
+        assert_eq!(inspected["snapshot"]["checksummed"], true);
+        assert_eq!(inspected["entries"], serde_json::json!([]));
+
+        let compacted = compact_wal::<KvStateMachine>(&dir, SnapshotCodec::Lz4, SnapshotEncoding::default()).unwrap();
+        assert_eq!(compacted["snapshot_last_index"], 7);
+        assert_eq!(open().unwrap().snapshot().inner.data, sm.inner.data);
+        assert_eq!(inspect_wal::<KvStateMachine>(&dir).unwrap()["snapshot"]["compressed"], true);
+
+        assert!(force_new_cluster::<KvStateMachine>(&dir, 5, ClusterId(1), SnapshotCodec::Uncompressed, SnapshotEncoding::default()).is_err());
+        let forced = force_new_cluster::<KvStateMachine>(&dir, 0, ClusterId(1), SnapshotCodec::Uncompressed, SnapshotEncoding::default()).unwrap();
+        assert_eq!(forced["removed_members"], serde_json::json!(["0001", "0002"]));
+        assert!(open().is_err());
+        let storage = RamStorage::open_wal(get_empty_storage().init_state_machine, ClusterId(1), &dir, 1024, StorageMetrics::default()).unwrap();
+        assert_eq!(storage.snapshot().config.nodes.keys().copied().collect::<Vec<u32>>(), vec![0]);
+        assert_eq!(storage.snapshot().inner.data, sm.inner.data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checkpoints() {
+        let path = std::env::temp_dir().join(format!("checkpoint_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(RamStorage::load_checkpoint(get_empty_storage().init_state_machine, ClusterId(0), &path).unwrap().is_none());
+
+        let mut storage = get_empty_storage();
+        let mut sm = storage.snapshot();
+        sm.inner.data.insert("k".to_string(), "v".to_string());
+        storage.set_current_term(3);
+        storage.set_voted_for(Some(0));
+        storage.set_snapshot(7, 2, &sm);
+        storage.write_checkpoint(&path).unwrap();
+
+        let loaded = RamStorage::load_checkpoint(get_empty_storage().init_state_machine, ClusterId(0), &path).unwrap().unwrap();
+        assert_eq!(loaded.current_term(), 3);
+        assert_eq!(loaded.voted_for(), Some(0));
+        assert_eq!(loaded.snapshot_last_index(), 7);
+        assert_eq!(loaded.snapshot_last_term(), 2);
+        assert_eq!(loaded.num_log_entries(), 0);
+        assert_eq!(loaded.snapshot().inner.data, sm.inner.data);
+
+        assert!(RamStorage::load_checkpoint(get_empty_storage().init_state_machine, ClusterId(1), &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        // shutting down writes one before it's due
+        storage.start_checkpoints(path.clone(), Duration::from_secs(3600), StorageMetrics::default());
+        let shutdown = Shutdown::default();
+        storage.share_shutdown(shutdown.clone());
+        storage.save_log();
+        assert!(!path.exists());
+        shutdown.request();
+        storage.set_current_term(4);
+        assert!(!shutdown.is_flush_pending());
+        let loaded = RamStorage::load_checkpoint(get_empty_storage().init_state_machine, ClusterId(0), &path).unwrap().unwrap();
+        assert_eq!(loaded.current_term(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use nix::sys::statvfs;
+use serde_json::json;
+
+use crate::metrics::Histogram;
+
+const MAX_SYNC_SAMPLES: usize = 256;
+// bytes written per second is averaged over this long
+const WRITE_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Metrics {
+    sync_us: VecDeque<u64>,
+    // every fsync since the metrics were last reset
+    sync_histogram: Histogram,
+    bytes_written: u64,
+    recent_writes: VecDeque<(Instant, u64)>,
+    log: LogGauges,
+    growth: Growth,
+    stored: StoredBytes,
+}
+
+// What the log and snapshots cost compared to what they hold, for tuning KV_SNAPSHOT_MIN_LOG_SIZE and batching.
+#[derive(Default)]
+struct Growth {
+    // serialized entries added to the log, what clients and the core asked to be stored
+    appended_entries: u64,
+    appended_bytes: u64,
+    snapshots_taken: u64,
+    snapshot_bytes_written: u64,
+    last_snapshot: Option<Instant>,
+    snapshot_interval_ms: Histogram,
+    // entries dropped from the log once a snapshot covered them
+    compacted_entries: u64,
+    compacted_bytes: u64,
+}
+
+// what's kept now, as of the last time storage saved the log
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StoredBytes {
+    pub log: u64,
+    pub snapshot: u64,
+}
+
+// What storage has been doing to the disk, shared between storage, which records it, and the network, which reports
+// it in stats.
+#[derive(Clone, Default)]
+pub struct StorageMetrics {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+// where the log is at, as of the last time storage saved it
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LogGauges {
+    pub current_term: u32,
+    pub last_index: u32,
+    // entries kept since the last snapshot
+    pub entries: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+impl StorageMetrics {
+    pub fn record_write(&self, bytes: usize, sync: Duration) {
+        let now = Instant::now();
+        let mut metrics = self.metrics.lock().unwrap();
+        if metrics.sync_us.len() == MAX_SYNC_SAMPLES {
+            metrics.sync_us.pop_front();
+        }
+        metrics.sync_us.push_back(sync.as_micros() as u64);
+        metrics.sync_histogram.record(sync.as_micros() as u64);
+        metrics.bytes_written += bytes as u64;
+        metrics.recent_writes.push_back((now, bytes as u64));
+        while metrics.recent_writes.front().map_or(false, |(t, _)| now.duration_since(*t) > WRITE_RATE_WINDOW) {
+            metrics.recent_writes.pop_front();
+        }
+    }
+
+    pub fn record_log(&self, log: LogGauges, stored: StoredBytes) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.log = log;
+        metrics.stored = stored;
+    }
+
+    pub fn record_append(&self, bytes: usize) {
+        let growth = &mut self.metrics.lock().unwrap().growth;
+        growth.appended_entries += 1;
+        growth.appended_bytes += bytes as u64;
+    }
+
+    pub fn record_snapshot(&self, bytes: usize) {
+        let growth = &mut self.metrics.lock().unwrap().growth;
+        growth.snapshots_taken += 1;
+        growth.snapshot_bytes_written += bytes as u64;
+        let now = Instant::now();
+        if let Some(last) = growth.last_snapshot.replace(now) {
+            growth.snapshot_interval_ms.record(now.duration_since(last).as_millis() as u64);
+        }
+    }
+
+    pub fn record_compaction(&self, entries: usize, bytes: usize) {
+        let growth = &mut self.metrics.lock().unwrap().growth;
+        growth.compacted_entries += entries as u64;
+        growth.compacted_bytes += bytes as u64;
+    }
+
+    pub fn log(&self) -> LogGauges {
+        self.metrics.lock().unwrap().log
+    }
+
+    pub fn fsync_histogram(&self) -> serde_json::Value {
+        self.metrics.lock().unwrap().sync_histogram.to_json()
+    }
+
+    // the log gauges and stored bytes aren't counted since anything, so they stay
+    pub fn reset(&self) {
+        let mut metrics = self.metrics.lock().unwrap();
+        *metrics = Metrics { log: metrics.log, stored: metrics.stored, ..Metrics::default() };
+    }
+
+    pub fn stored(&self) -> StoredBytes {
+        self.metrics.lock().unwrap().stored
+    }
+
+    // Bytes written to disk for every byte appended to the log, counting snapshots, the term and vote, and record
+    // headers, and how much snapshots have saved, since the metrics were last reset. Fewer snapshots of a large state
+    // machine write less, more of them keep less log.
+    pub fn growth(&self) -> serde_json::Value {
+        let metrics = self.metrics.lock().unwrap();
+        let growth = &metrics.growth;
+        let ratio = |a: u64, b: u64| if b == 0 { None } else { Some(a as f64 / b as f64) };
+        json!({
+            "appended_entries": growth.appended_entries,
+            "appended_bytes": growth.appended_bytes,
+            "bytes_written": metrics.bytes_written,
+            "write_amplification": ratio(metrics.bytes_written, growth.appended_bytes),
+            "stored_bytes": { "log": metrics.stored.log, "snapshot": metrics.stored.snapshot },
+            "snapshots": {
+                "taken": growth.snapshots_taken,
+                "bytes_written": growth.snapshot_bytes_written,
+                "interval_ms": growth.snapshot_interval_ms.to_json(),
+                "entries_per_snapshot": ratio(growth.compacted_entries, growth.snapshots_taken),
+            },
+            "compaction": {
+                "entries": growth.compacted_entries,
+                "bytes": growth.compacted_bytes,
+                // log dropped for each byte of snapshot written in its place, below 1 snapshots cost more than they save
+                "bytes_per_snapshot_byte": ratio(growth.compacted_bytes, growth.snapshot_bytes_written),
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let metrics = self.metrics.lock().unwrap();
+        let mut sync_us: Vec<u64> = metrics.sync_us.iter().copied().collect();
+        sync_us.sort_unstable();
+        let percentile = |p: usize| sync_us.get((sync_us.len() * p / 100).min(sync_us.len().saturating_sub(1))).copied();
+
+        let now = Instant::now();
+        let recent: u64 = metrics.recent_writes.iter()
+            .filter(|(t, _)| now.duration_since(*t) <= WRITE_RATE_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        json!({
+            "fsync_us": { "p50": percentile(50), "p99": percentile(99), "max": sync_us.last() },
+            "bytes_written": metrics.bytes_written,
+            "bytes_written_per_sec": recent as f64 / WRITE_RATE_WINDOW.as_secs_f64(),
+        })
+    }
+}
+
+pub fn disk_usage(path: &Path) -> nix::Result<DiskUsage> {
+    let stats = statvfs::statvfs(path)?;
+    let block = stats.fragment_size() as u64;
+    Ok(DiskUsage { total_bytes: stats.blocks() as u64 * block, free_bytes: stats.blocks_available() as u64 * block })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::storage_metrics::{disk_usage, LogGauges, StorageMetrics, StoredBytes};
+
+    #[test]
+    fn records_writes() {
+        let metrics = StorageMetrics::default();
+        for ms in 1..=100 {
+            metrics.record_write(1000, Duration::from_millis(ms));
+        }
+
+        let json = metrics.to_json();
+        assert_eq!(json["bytes_written"], 100_000);
+        assert_eq!(json["bytes_written_per_sec"], 10_000.0);
+        assert_eq!(json["fsync_us"]["p50"], 51_000);
+        assert_eq!(json["fsync_us"]["p99"], 100_000);
+        assert_eq!(json["fsync_us"]["max"], 100_000);
+
+        let log = LogGauges { current_term: 2, last_index: 10, entries: 4 };
+        let stored = StoredBytes { log: 400, snapshot: 1000 };
+        metrics.record_log(log, stored);
+        metrics.reset();
+        assert_eq!(metrics.to_json()["bytes_written"], 0);
+        assert_eq!(metrics.log(), log);
+        assert_eq!(metrics.stored(), stored);
+
+        let usage = disk_usage(Path::new("/")).unwrap();
+        assert!(usage.free_bytes <= usage.total_bytes);
+    }
+
+    #[test]
+    fn reports_growth() {
+        let metrics = StorageMetrics::default();
+        assert_eq!(metrics.growth()["write_amplification"], serde_json::Value::Null);
+
+        for _ in 0..10 {
+            metrics.record_append(100);
+            metrics.record_write(108, Duration::from_millis(1));
+        }
+        metrics.record_snapshot(250);
+        metrics.record_write(258, Duration::from_millis(1));
+        metrics.record_compaction(10, 1000);
+
+        let growth = metrics.growth();
+        assert_eq!(growth["appended_bytes"], 1000);
+        assert_eq!(growth["write_amplification"], 1.338);
+        assert_eq!(growth["snapshots"]["taken"], 1);
+        assert_eq!(growth["snapshots"]["entries_per_snapshot"], 10.0);
+        assert_eq!(growth["compaction"]["bytes_per_snapshot_byte"], 4.0);
+    }
+}
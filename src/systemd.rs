@@ -0,0 +1,126 @@
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use nix::sys::socket;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::unistd;
+
+// Tells systemd how the node is doing over $NOTIFY_SOCKET: READY=1 the first time it's ready for clients, a STATUS
+// line whenever its role changes, and WATCHDOG=1 keepalives from the main loop when the unit has WatchdogSec set, so
+// a node that stops looping gets restarted.
+pub struct Notifier {
+    socket_fd: RawFd,
+    addr: SockAddr,
+    watchdog_interval: Option<Duration>,
+    last_keepalive: Option<Instant>,
+    sent_ready: bool,
+    status: String,
+}
+
+impl Notifier {
+    // None when not started by systemd with Type=notify
+    pub fn from_env() -> Option<Notifier> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        // the watchdog is meant for whichever process WATCHDOG_PID names, and is ours when it's not set
+        let ours = std::env::var("WATCHDOG_PID").ok()
+            .map_or(true, |pid| pid.parse::<i32>().ok() == Some(unistd::getpid().as_raw()));
+        let watchdog_interval = std::env::var("WATCHDOG_USEC").ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| ours)
+            .map(Duration::from_micros);
+
+        match Notifier::new(&path, watchdog_interval) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                eprintln!("can't notify systemd at {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn new(path: &str, watchdog_interval: Option<Duration>) -> nix::Result<Notifier> {
+        // a leading @ is an abstract socket
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SockAddr::Unix(UnixAddr::new_abstract(name.as_bytes())?),
+            None => SockAddr::new_unix(path)?,
+        };
+        let socket_fd = socket::socket(AddressFamily::Unix, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+        Ok(Notifier { socket_fd, addr, watchdog_interval, last_keepalive: None, sent_ready: false, status: String::new() })
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = socket::sendto(self.socket_fd, state.as_bytes(), &self.addr, MsgFlags::empty()) {
+            eprintln!("failed to notify systemd: {}", e);
+        }
+    }
+
+    pub fn ready(&mut self) {
+        if !self.sent_ready {
+            self.sent_ready = true;
+            self.send("READY=1");
+        }
+    }
+
+    pub fn stopping(&mut self) {
+        self.send("STOPPING=1");
+    }
+
+    pub fn status(&mut self, status: String) {
+        if status != self.status {
+            self.send(&format!("STATUS={}", status));
+            self.status = status;
+        }
+    }
+
+    // at twice the rate systemd asks for, so one late loop doesn't get the node killed
+    pub fn keepalive_if_due(&mut self) {
+        let interval = match self.watchdog_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if self.last_keepalive.map_or(true, |t| t.elapsed() >= interval / 2) {
+            self.last_keepalive = Some(Instant::now());
+            self.send("WATCHDOG=1");
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.socket_fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+
+    use crate::systemd::Notifier;
+
+    #[test]
+    fn notifies() {
+        let path = std::env::temp_dir().join(format!("notify_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        let mut notifier = Notifier::new(path.to_str().unwrap(), Some(Duration::from_secs(60))).unwrap();
+        notifier.status("follower".to_string());
+        notifier.status("follower".to_string());
+        notifier.ready();
+        notifier.ready();
+        notifier.keepalive_if_due();
+        notifier.keepalive_if_due();
+        notifier.status("leader".to_string());
+
+        systemd.set_nonblocking(true).unwrap();
+        let mut received = vec![];
+        let mut buffer = [0u8; 64];
+        while let Ok(amt) = systemd.recv(&mut buffer) {
+            received.push(String::from_utf8(buffer[..amt].to_vec()).unwrap());
+        }
+        assert_eq!(received, vec!["STATUS=follower", "READY=1", "WATCHDOG=1", "STATUS=leader"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use my_raft::config::NodeAddress;
+use nix::sys::socket;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockFlag, SockType};
+
+use crate::network::{Transport, member_addresses, routed_to};
+
+const PACKET_SIZE: usize = 65527;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+// after failing to connect to a peer, its messages are dropped for this long rather than held up behind reconnecting
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Connection {
+    // what the stream was opened to, it's reopened if the member's address changes
+    address: String,
+    stream: Option<TcpStream>,
+    last_attempt: Option<Instant>,
+}
+
+// Carries the messages a node sends on its SeqPacket socket to the other members over TCP, framed by a length prefix,
+// so a node built for the simulator can run across machines. The node talks to one end of a socketpair, a thread
+// sends whatever comes out of it to the member named by dst over a connection that's kept open and reopened as
+// needed, and every connection accepted on the listen address gets a thread writing the messages it carries back in.
+// Addresses are resolved again on every reconnect, so a member can move to a new IP under the same hostname. Raft
+// retries anything lost, so messages for a member that can't be reached are dropped.
+#[derive(Clone)]
+pub struct TcpTransport {
+    addresses: Arc<Mutex<HashMap<u32, String>>>,
+}
+
+impl Transport for TcpTransport {
+    fn start(listen: &str) -> io::Result<(RawFd, TcpTransport)> {
+        let (node_fd, transport_fd) = socket::socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let listener = TcpListener::bind(listen)?;
+        let transport = TcpTransport { addresses: Arc::new(Mutex::new(HashMap::new())) };
+
+        thread::Builder::new()
+            .name("tcp-accept".to_string())
+            .spawn(move || accept(listener, transport_fd))?;
+        let sending = transport.clone();
+        thread::Builder::new()
+            .name("tcp-send".to_string())
+            .spawn(move || sending.send_loop(transport_fd))?;
+        Ok((node_fd, transport))
+    }
+
+    fn set_members(&self, nodes: &HashMap<u32, NodeAddress>) {
+        *self.addresses.lock().unwrap() = member_addresses(nodes);
+    }
+}
+
+impl TcpTransport {
+    fn send_loop(&self, fd: RawFd) {
+        let mut connections: HashMap<u32, Connection> = HashMap::new();
+        let mut buffer = vec![0u8; PACKET_SIZE];
+        loop {
+            let amt = match socket::recv(fd, &mut buffer, MsgFlags::empty()) {
+                Ok(amt) if amt > 0 => amt,
+                // the node has gone away
+                _ => return,
+            };
+            let dst = match routed_to(&buffer[..amt]) {
+                Some(dst) => dst,
+                None => continue,
+            };
+            let address = match self.addresses.lock().unwrap().get(&dst).cloned() {
+                Some(address) => address,
+                None => continue,
+            };
+
+            let connection = connections.entry(dst).or_insert(Connection { address: address.clone(), stream: None, last_attempt: None });
+            if connection.address != address {
+                *connection = Connection { address: address.clone(), stream: None, last_attempt: None };
+            }
+            if connection.stream.is_none() && connection.last_attempt.map_or(true, |t| t.elapsed() >= RECONNECT_INTERVAL) {
+                connection.last_attempt = Some(Instant::now());
+                connection.stream = connect(&address).ok();
+            }
+            if let Some(stream) = &mut connection.stream {
+                if write_frame(stream, &buffer[..amt]).is_err() {
+                    connection.stream = None;
+                }
+            }
+        }
+    }
+}
+
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let addr = address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} didn't resolve", address)))?;
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_nodelay(true)?;
+    // a member that stops reading shouldn't hold up the others for long
+    stream.set_write_timeout(Some(RECONNECT_INTERVAL))?;
+    Ok(stream)
+}
+
+fn accept(listener: TcpListener, fd: RawFd) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let _ = thread::Builder::new()
+            .name("tcp-recv".to_string())
+            .spawn(move || receive(stream, fd));
+    }
+}
+
+// passes on messages until the connection closes or sends something that isn't a frame
+fn receive(mut stream: TcpStream, fd: RawFd) {
+    let mut buffer = vec![0u8; PACKET_SIZE];
+    // the node drops anything that isn't from a member itself
+    while let Ok(amt) = read_frame(&mut stream, &mut buffer) {
+        if socket::send(fd, &buffer[..amt], MsgFlags::empty()).is_err() {
+            return;
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    stream.write_all(&frame)
+}
+
+fn read_frame(stream: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > buffer.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too big", len)));
+    }
+    stream.read_exact(&mut buffer[..len])?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    use my_raft::config::NodeAddress;
+    use nix::sys::socket;
+    use nix::sys::socket::MsgFlags;
+
+    use crate::network::Transport;
+    use crate::tcp_transport::TcpTransport;
+
+    #[test]
+    fn carries_messages_between_nodes() {
+        // find two free ports
+        let ports: Vec<u16> = (0..2).map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()).collect();
+        let addresses: HashMap<u32, NodeAddress> = ports.iter().enumerate()
+            .map(|(id, port)| (id as u32, NodeAddress::String(format!("127.0.0.1:{}", port))))
+            .collect();
+
+        let (fd_a, transport_a) = TcpTransport::start(&format!("127.0.0.1:{}", ports[0])).unwrap();
+        let (fd_b, transport_b) = TcpTransport::start(&format!("127.0.0.1:{}", ports[1])).unwrap();
+        transport_a.set_members(&addresses);
+        transport_b.set_members(&addresses);
+
+        let message = br#"{"src":"0000","dst":"0001","leader":"FFFF","type":"pong","sent_us":1}"#;
+        socket::send(fd_a, message, MsgFlags::empty()).unwrap();
+        let mut buffer = [0u8; 256];
+        let amt = socket::recv(fd_b, &mut buffer, MsgFlags::empty()).unwrap();
+        assert_eq!(&buffer[..amt], &message[..]);
+    }
+}
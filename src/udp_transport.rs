@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use my_raft::config::NodeAddress;
+use nix::sys::socket;
+use nix::sys::socket::{AddressFamily, MsgFlags, SockFlag, SockType};
+
+use crate::network::{Transport, member_addresses, routed_to};
+
+const PACKET_SIZE: usize = 65527;
+// fragments stay under the usual MTU so IP doesn't fragment them again, where losing any piece loses the datagram
+const FRAGMENT_BYTES: usize = 1200;
+// kind, sequence number, length of the whole message, fragment index
+const HEADER_LEN: usize = 1 + 4 + 4 + 2;
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(30);
+// a message still unacknowledged after this many sends is given up on, raft sends it again if it still matters
+const MAX_SENDS: u32 = 4;
+// a message missing fragments for this long is dropped
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+// how many delivered messages are remembered to recognise them being sent again after their ack was lost
+const DELIVERED_HISTORY: usize = 4096;
+
+struct Unacked {
+    address: SocketAddr,
+    datagrams: Vec<Vec<u8>>,
+    last_sent: Instant,
+    sends: u32,
+}
+
+// Carries the messages a node sends on its SeqPacket socket to the other members over UDP, for LANs where a lost
+// packet holding up everything behind it on a TCP connection hurts election timing. Every message gets a sequence
+// number and is split into fragments that fit in a datagram, each carrying the length of the whole message. The
+// receiver acknowledges a message once it has all of it and the sender sends it again until it's acknowledged or
+// it's been tried MAX_SENDS times. Messages can arrive out of order, which raft copes with anyway.
+#[derive(Clone)]
+pub struct UdpTransport {
+    addresses: Arc<Mutex<HashMap<u32, String>>>,
+    unacked: Arc<Mutex<HashMap<u32, Unacked>>>,
+}
+
+impl Transport for UdpTransport {
+    fn start(listen: &str) -> io::Result<(RawFd, UdpTransport)> {
+        let (node_fd, transport_fd) = socket::socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::empty())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let udp = UdpSocket::bind(listen)?;
+        // retransmits are checked for whenever nothing arrives for this long
+        udp.set_read_timeout(Some(RETRANSMIT_INTERVAL))?;
+        let transport = UdpTransport { addresses: Arc::new(Mutex::new(HashMap::new())), unacked: Arc::new(Mutex::new(HashMap::new())) };
+
+        let (receiving, receiving_udp) = (transport.clone(), udp.try_clone()?);
+        thread::Builder::new()
+            .name("udp-recv".to_string())
+            .spawn(move || receiving.receive_loop(&receiving_udp, transport_fd))?;
+        let sending = transport.clone();
+        thread::Builder::new()
+            .name("udp-send".to_string())
+            .spawn(move || sending.send_loop(&udp, transport_fd))?;
+        Ok((node_fd, transport))
+    }
+
+    fn set_members(&self, nodes: &HashMap<u32, NodeAddress>) {
+        *self.addresses.lock().unwrap() = member_addresses(nodes);
+    }
+}
+
+impl UdpTransport {
+    fn send_loop(&self, udp: &UdpSocket, fd: RawFd) {
+        // an address is only resolved again if the member's address changes
+        let mut resolved: HashMap<u32, (String, SocketAddr)> = HashMap::new();
+        // a restarted node mustn't reuse sequence numbers the others remember delivering
+        let mut seq = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u32);
+        let mut buffer = vec![0u8; PACKET_SIZE];
+        loop {
+            let amt = match socket::recv(fd, &mut buffer, MsgFlags::empty()) {
+                Ok(amt) if amt > 0 => amt,
+                // the node has gone away
+                _ => return,
+            };
+            let dst = match routed_to(&buffer[..amt]) {
+                Some(dst) => dst,
+                None => continue,
+            };
+            let address = match self.addresses.lock().unwrap().get(&dst).cloned() {
+                Some(address) => address,
+                None => continue,
+            };
+            if resolved.get(&dst).map(|(resolved_from, _)| resolved_from) != Some(&address) {
+                match address.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+                    Some(addr) => resolved.insert(dst, (address, addr)),
+                    None => continue,
+                };
+            }
+            let addr = resolved[&dst].1;
+
+            seq = seq.wrapping_add(1);
+            let datagrams = fragment(seq, &buffer[..amt]);
+            // registered before it's sent, so the ack can't arrive first
+            let mut unacked = self.unacked.lock().unwrap();
+            for datagram in &datagrams {
+                let _ = udp.send_to(datagram, addr);
+            }
+            unacked.insert(seq, Unacked { address: addr, datagrams, last_sent: Instant::now(), sends: 1 });
+        }
+    }
+
+    fn receive_loop(&self, udp: &UdpSocket, fd: RawFd) {
+        let mut reassembly = Reassembly::default();
+        let mut datagram = vec![0u8; HEADER_LEN + FRAGMENT_BYTES];
+        loop {
+            // errors include ICMP port unreachable from a member that's down, which is no reason to stop
+            if let Ok((amt, from)) = udp.recv_from(&mut datagram) {
+                let datagram = &datagram[..amt];
+                match datagram.first() {
+                    Some(&ACK) if amt >= 5 => {
+                        self.unacked.lock().unwrap().remove(&read_u32(&datagram[1..5]));
+                    }
+                    Some(&DATA) if amt >= HEADER_LEN => {
+                        let seq = read_u32(&datagram[1..5]);
+                        let len = read_u32(&datagram[5..9]) as usize;
+                        let index = u16::from_be_bytes([datagram[9], datagram[10]]) as usize;
+                        match reassembly.add(from, seq, len, index, &datagram[HEADER_LEN..]) {
+                            Received::Incomplete => {}
+                            Received::Duplicate => ack(udp, from, seq),
+                            Received::Complete(message) => {
+                                // the node has gone away
+                                if socket::send(fd, &message, MsgFlags::empty()).is_err() {
+                                    return;
+                                }
+                                ack(udp, from, seq);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            self.retransmit(udp);
+        }
+    }
+
+    fn retransmit(&self, udp: &UdpSocket) {
+        self.unacked.lock().unwrap().retain(|_, message| {
+            if message.last_sent.elapsed() < RETRANSMIT_INTERVAL {
+                return true;
+            }
+            if message.sends >= MAX_SENDS {
+                return false;
+            }
+            for datagram in &message.datagrams {
+                let _ = udp.send_to(datagram, message.address);
+            }
+            message.last_sent = Instant::now();
+            message.sends += 1;
+            true
+        });
+    }
+}
+
+fn ack(udp: &UdpSocket, to: SocketAddr, seq: u32) {
+    let mut datagram = vec![ACK];
+    datagram.extend_from_slice(&seq.to_be_bytes());
+    let _ = udp.send_to(&datagram, to);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn num_fragments(len: usize) -> usize {
+    ((len + FRAGMENT_BYTES - 1) / FRAGMENT_BYTES).max(1)
+}
+
+fn fragment(seq: u32, message: &[u8]) -> Vec<Vec<u8>> {
+    (0..num_fragments(message.len()))
+        .map(|index| {
+            let start = index * FRAGMENT_BYTES;
+            let end = (start + FRAGMENT_BYTES).min(message.len());
+            let mut datagram = Vec::with_capacity(HEADER_LEN + end - start);
+            datagram.push(DATA);
+            datagram.extend_from_slice(&seq.to_be_bytes());
+            datagram.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            datagram.extend_from_slice(&(index as u16).to_be_bytes());
+            datagram.extend_from_slice(&message[start..end]);
+            datagram
+        })
+        .collect()
+}
+
+enum Received {
+    Incomplete,
+    // delivered already, its ack must have been lost
+    Duplicate,
+    Complete(Vec<u8>),
+}
+
+struct Partial {
+    data: Vec<u8>,
+    have: Vec<bool>,
+    missing: usize,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct Reassembly {
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    delivered: HashSet<(SocketAddr, u32)>,
+    delivered_order: VecDeque<(SocketAddr, u32)>,
+}
+
+impl Reassembly {
+    // fragments that don't fit the message they claim to be part of are ignored
+    fn add(&mut self, from: SocketAddr, seq: u32, len: usize, index: usize, payload: &[u8]) -> Received {
+        let key = (from, seq);
+        if self.delivered.contains(&key) {
+            return Received::Duplicate;
+        }
+        let count = num_fragments(len);
+        let start = index * FRAGMENT_BYTES;
+        if len > PACKET_SIZE || index >= count || payload.len() != (len - start).min(FRAGMENT_BYTES) {
+            return Received::Incomplete;
+        }
+
+        self.partial.retain(|_, partial| partial.started.elapsed() < REASSEMBLY_TIMEOUT);
+        let partial = self.partial.entry(key)
+            .or_insert_with(|| Partial { data: vec![0; len], have: vec![false; count], missing: count, started: Instant::now() });
+        if partial.data.len() != len {
+            return Received::Incomplete;
+        }
+        if !partial.have[index] {
+            partial.have[index] = true;
+            partial.missing -= 1;
+            partial.data[start..start + payload.len()].copy_from_slice(payload);
+        }
+        if partial.missing > 0 {
+            return Received::Incomplete;
+        }
+
+        let message = self.partial.remove(&key).unwrap().data;
+        self.delivered.insert(key);
+        self.delivered_order.push_back(key);
+        if self.delivered_order.len() > DELIVERED_HISTORY {
+            if let Some(oldest) = self.delivered_order.pop_front() {
+                self.delivered.remove(&oldest);
+            }
+        }
+        Received::Complete(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+
+    use my_raft::config::NodeAddress;
+    use nix::sys::socket;
+    use nix::sys::socket::MsgFlags;
+
+    use crate::network::Transport;
+    use crate::udp_transport::{FRAGMENT_BYTES, HEADER_LEN, Reassembly, Received, UdpTransport, fragment};
+
+    #[test]
+    fn reassembles_fragments() {
+        let from = "127.0.0.1:7100".parse().unwrap();
+        let message: Vec<u8> = (0..3 * FRAGMENT_BYTES + 10).map(|i| i as u8).collect();
+        let datagrams = fragment(9, &message);
+        assert_eq!(datagrams.len(), 4);
+
+        let mut reassembly = Reassembly::default();
+        let add = |reassembly: &mut Reassembly, datagram: &[u8]| {
+            let index = u16::from_be_bytes([datagram[9], datagram[10]]) as usize;
+            reassembly.add(from, 9, message.len(), index, &datagram[HEADER_LEN..])
+        };
+        // out of order, with a repeat and a fragment cut short
+        for datagram in datagrams.iter().rev().skip(1) {
+            assert!(matches!(add(&mut reassembly, datagram), Received::Incomplete));
+        }
+        assert!(matches!(add(&mut reassembly, &datagrams[1]), Received::Incomplete));
+        assert!(matches!(add(&mut reassembly, &datagrams[3][..HEADER_LEN + 5]), Received::Incomplete));
+        assert!(matches!(add(&mut reassembly, &datagrams[3]), Received::Complete(whole) if whole == message));
+        assert!(matches!(add(&mut reassembly, &datagrams[0]), Received::Duplicate));
+    }
+
+    #[test]
+    fn carries_messages_between_nodes() {
+        // find two free ports
+        let ports: Vec<u16> = (0..2).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()).collect();
+        let addresses: HashMap<u32, NodeAddress> = ports.iter().enumerate()
+            .map(|(id, port)| (id as u32, NodeAddress::String(format!("127.0.0.1:{}", port))))
+            .collect();
+
+        let (fd_a, transport_a) = UdpTransport::start(&format!("127.0.0.1:{}", ports[0])).unwrap();
+        let (fd_b, transport_b) = UdpTransport::start(&format!("127.0.0.1:{}", ports[1])).unwrap();
+        transport_a.set_members(&addresses);
+        transport_b.set_members(&addresses);
+
+        // bigger than a datagram, like an InstallSnapshot chunk
+        let message = format!(r#"{{"src":"0000","dst":"0001","leader":"FFFF","type":"pong","sent_us":1,"pad":"{}"}}"#, "x".repeat(5000));
+        socket::send(fd_a, message.as_bytes(), MsgFlags::empty()).unwrap();
+        let mut buffer = [0u8; 8192];
+        let amt = socket::recv(fd_b, &mut buffer, MsgFlags::empty()).unwrap();
+        assert_eq!(&buffer[..amt], message.as_bytes());
+    }
+}
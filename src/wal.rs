@@ -0,0 +1,339 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::backup::{checksum, take_u32};
+use crate::snapshot_file::SnapshotBytes;
+use crate::storage_metrics::StorageMetrics;
+
+pub const DEFAULT_SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+const SEGMENT_SUFFIX: &str = ".wal";
+const HARD_STATE_FILE: &str = "hard_state";
+const SNAPSHOT_FILE: &str = "snapshot";
+// the snapshot file starts with its last index and term
+const SNAPSHOT_PREFIX_LEN: usize = 8;
+// every record is its length and checksum, then the entry
+const RECORD_HEADER_LEN: u64 = 8;
+
+struct Segment {
+    first_index: u32,
+    path: PathBuf,
+    // where each record starts
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl Segment {
+    fn next_index(&self) -> u32 {
+        self.first_index + self.offsets.len() as u32
+    }
+}
+
+// What was in the directory when it was opened. Entries start right after the snapshot.
+pub struct Recovered {
+    pub current_term: u32,
+    pub voted_for: Option<u32>,
+    pub snapshot_last_index: u32,
+    pub snapshot_last_term: u32,
+    pub snapshot: SnapshotBytes,
+    pub entries: Vec<Vec<u8>>,
+}
+
+// A write-ahead log of serialized entries, appended to segment files named by the index of their first entry, with a
+// new one started once the last is segment_bytes long. Appends are only written out and synced by sync, so the core's
+// save_log costs what was added since the last one rather than the whole log. Segments whose entries are all in the
+// snapshot are deleted, and removing entries from the end truncates the segment they start in. The term, vote and
+// snapshot live in files of their own that are replaced by renaming over them.
+pub struct Wal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    segments: Vec<Segment>,
+    // the last segment, open for appending
+    file: Option<File>,
+    // records appended to the last segment that haven't been written yet
+    pending: Vec<u8>,
+    metrics: StorageMetrics,
+}
+
+impl Wal {
+    pub fn open(dir: &Path, segment_bytes: u64, metrics: StorageMetrics) -> io::Result<(Wal, Recovered)> {
+        fs::create_dir_all(dir)?;
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("write-ahead log in {} {}", dir.display(), what));
+
+        let mut recovered = Recovered {
+            current_term: 0,
+            voted_for: None,
+            snapshot_last_index: 0,
+            snapshot_last_term: 0,
+            snapshot: SnapshotBytes::default(),
+            entries: vec![],
+        };
+        if let Some(bytes) = read_if_exists(&dir.join(HARD_STATE_FILE))? {
+            let mut bytes = &bytes[..];
+            recovered.current_term = take_u32(&mut bytes).ok_or_else(|| invalid("has a truncated hard state".to_string()))?;
+            recovered.voted_for = take_u32(&mut bytes).ok_or_else(|| invalid("has a truncated hard state".to_string()))?.checked_sub(1);
+        }
+        match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(mut file) => {
+                let mut prefix = [0u8; SNAPSHOT_PREFIX_LEN];
+                file.read_exact(&mut prefix).map_err(|_| invalid("has a truncated snapshot".to_string()))?;
+                let mut bytes = &prefix[..];
+                recovered.snapshot_last_index = take_u32(&mut bytes).ok_or_else(|| invalid("has a truncated snapshot".to_string()))?;
+                recovered.snapshot_last_term = take_u32(&mut bytes).ok_or_else(|| invalid("has a truncated snapshot".to_string()))?;
+                recovered.snapshot = SnapshotBytes::map(&file, SNAPSHOT_PREFIX_LEN)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut segments = vec![];
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let first_index = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+                .and_then(|index| index.parse().ok());
+            if let Some(first_index) = first_index {
+                segments.push(Segment { first_index, path, offsets: vec![], len: 0 });
+            }
+        }
+        segments.sort_by_key(|segment| segment.first_index);
+
+        let num_segments = segments.len();
+        for (i, segment) in segments.iter_mut().enumerate() {
+            let bytes = fs::read(&segment.path)?;
+            let mut rest = &bytes[..];
+            while let Some(entry) = take_record(&mut rest) {
+                let index = segment.next_index();
+                segment.offsets.push(segment.len);
+                segment.len += RECORD_HEADER_LEN + entry.len() as u64;
+                if index > recovered.snapshot_last_index {
+                    if index != recovered.snapshot_last_index + 1 + recovered.entries.len() as u32 {
+                        return Err(invalid(format!("is missing entries before {}", index)));
+                    }
+                    recovered.entries.push(entry.to_vec());
+                }
+            }
+            if !rest.is_empty() {
+                // only a crash partway through the last write leaves a record cut short
+                if i + 1 != num_segments {
+                    return Err(invalid(format!("has a corrupt record in {}", segment.path.display())));
+                }
+                OpenOptions::new().write(true).open(&segment.path)?.set_len(segment.len)?;
+            }
+        }
+
+        let file = match segments.last() {
+            Some(segment) => Some(OpenOptions::new().append(true).open(&segment.path)?),
+            None => None,
+        };
+        let wal = Wal { dir: dir.to_path_buf(), segment_bytes, segments, file, pending: vec![], metrics };
+        Ok((wal, recovered))
+    }
+
+    pub fn append(&mut self, index: u32, entry: &[u8]) -> io::Result<()> {
+        let expected = self.segments.last().map(Segment::next_index);
+        if expected.map_or(false, |expected| index < expected) {
+            self.truncate_from(index)?;
+        }
+        // an installed snapshot can leave a gap, which recovery skips since the snapshot covers it
+        let full = self.segments.last().map_or(true, |segment| segment.len >= self.segment_bytes || segment.next_index() != index);
+        if full {
+            self.start_segment(index)?;
+        }
+
+        let segment = self.segments.last_mut().unwrap();
+        segment.offsets.push(segment.len);
+        segment.len += RECORD_HEADER_LEN + entry.len() as u64;
+        self.pending.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(&checksum(entry).to_be_bytes());
+        self.pending.extend_from_slice(entry);
+        Ok(())
+    }
+
+    fn start_segment(&mut self, first_index: u32) -> io::Result<()> {
+        self.sync()?;
+        if let Some(file) = &self.file {
+            file.sync_all()?;
+        }
+        let path = self.dir.join(format!("{:010}{}", first_index, SEGMENT_SUFFIX));
+        self.file = Some(OpenOptions::new().create(true).write(true).truncate(true).open(&path)?);
+        self.segments.push(Segment { first_index, path, offsets: vec![], len: 0 });
+        sync_dir(&self.dir)
+    }
+
+    // writes out everything appended since the last sync
+    pub fn sync(&mut self) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) if !self.pending.is_empty() => file,
+            _ => return Ok(()),
+        };
+        file.write_all(&self.pending)?;
+        let sync_started = Instant::now();
+        file.sync_data()?;
+        self.metrics.record_write(self.pending.len(), sync_started.elapsed());
+        self.pending.clear();
+        Ok(())
+    }
+
+    // removes the entry at index and everything after it
+    pub fn truncate_from(&mut self, index: u32) -> io::Result<()> {
+        self.sync()?;
+        while self.segments.last().map_or(false, |segment| segment.first_index >= index) {
+            let segment = self.segments.pop().unwrap();
+            self.file = None;
+            fs::remove_file(&segment.path)?;
+        }
+        if let Some(segment) = self.segments.last_mut() {
+            if let Some(offset) = segment.offsets.get((index - segment.first_index) as usize).copied() {
+                segment.offsets.truncate((index - segment.first_index) as usize);
+                segment.len = offset;
+            }
+            let file = OpenOptions::new().append(true).open(&segment.path)?;
+            file.set_len(segment.len)?;
+            file.sync_all()?;
+            self.file = Some(file);
+        }
+        sync_dir(&self.dir)
+    }
+
+    // deletes the segments with nothing after index in them, which the snapshot through index has replaced
+    pub fn compact_through(&mut self, index: u32) -> io::Result<()> {
+        let obsolete = self.segments.windows(2)
+            .take_while(|pair| pair[1].first_index <= index + 1)
+            .count();
+        for segment in self.segments.drain(..obsolete) {
+            fs::remove_file(&segment.path)?;
+        }
+        if obsolete > 0 {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_hard_state(&self, current_term: u32, voted_for: Option<u32>) -> io::Result<()> {
+        let mut bytes = current_term.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&voted_for.map_or(0, |id| id + 1).to_be_bytes());
+        self.replace(HARD_STATE_FILE, &bytes)
+    }
+
+    pub fn save_snapshot(&self, last_index: u32, last_term: u32, snapshot: &[u8]) -> io::Result<()> {
+        self.write_snapshot(last_index, last_term, |out| out.write_all(snapshot)).map(|_| ())
+    }
+
+    // Streams the snapshot write gives it into a new file that's renamed over the old one, and gives back the new one
+    // mapped, so a snapshot never has to be in memory all at once to be written or read.
+    pub fn write_snapshot(&self, last_index: u32, last_term: u32, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<SnapshotBytes> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(&last_index.to_be_bytes())?;
+        out.write_all(&last_term.to_be_bytes())?;
+        write(&mut out)?;
+        let file = out.into_inner()?;
+        let sync_started = Instant::now();
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+        self.metrics.record_write(file.metadata()?.len() as usize, sync_started.elapsed());
+        SnapshotBytes::map(&file, SNAPSHOT_PREFIX_LEN)
+    }
+
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        let sync_started = Instant::now();
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+        self.metrics.record_write(bytes.len(), sync_started.elapsed());
+        Ok(())
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+fn take_record<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let mut rest = *bytes;
+    let len = take_u32(&mut rest)? as usize;
+    let sum = take_u32(&mut rest)?;
+    if rest.len() < len {
+        return None;
+    }
+    let (entry, rest) = rest.split_at(len);
+    if checksum(entry) != sum {
+        return None;
+    }
+    *bytes = rest;
+    Some(entry)
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// makes creating, renaming and removing files in the directory durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage_metrics::StorageMetrics;
+    use crate::wal::Wal;
+
+    #[test]
+    fn segments() {
+        let dir = std::env::temp_dir().join(format!("wal_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (mut wal, recovered) = Wal::open(&dir, 64, StorageMetrics::default()).unwrap();
+        assert!(recovered.entries.is_empty());
+        for index in 1..=10 {
+            wal.append(index, &[index as u8; 20]).unwrap();
+        }
+        wal.sync().unwrap();
+        assert_eq!(wal.num_segments(), 4);
+
+        // replaces 8 onwards
+        wal.append(8, &[80; 20]).unwrap();
+        wal.sync().unwrap();
+        wal.save_hard_state(3, Some(1)).unwrap();
+        wal.save_snapshot(4, 2, b"snapshot").unwrap();
+        wal.compact_through(4).unwrap();
+        assert_eq!(wal.num_segments(), 2);
+        // appended but never synced, so lost in the crash
+        wal.append(9, &[90; 20]).unwrap();
+        drop(wal);
+
+        let (mut wal, recovered) = Wal::open(&dir, 64, StorageMetrics::default()).unwrap();
+        assert_eq!((recovered.current_term, recovered.voted_for), (3, Some(1)));
+        assert_eq!((recovered.snapshot_last_index, recovered.snapshot_last_term), (4, 2));
+        assert_eq!(&recovered.snapshot[..], b"snapshot");
+        assert_eq!(recovered.entries, vec![vec![5; 20], vec![6; 20], vec![7; 20], vec![80; 20]]);
+
+        // a snapshot installed past the end of the log leaves a gap
+        wal.save_snapshot(20, 3, b"later").unwrap();
+        wal.append(21, &[21; 20]).unwrap();
+        wal.sync().unwrap();
+        wal.compact_through(20).unwrap();
+        assert_eq!(wal.num_segments(), 1);
+        drop(wal);
+
+        let (_, recovered) = Wal::open(&dir, 64, StorageMetrics::default()).unwrap();
+        assert_eq!(recovered.snapshot_last_index, 20);
+        assert_eq!(recovered.entries, vec![vec![21; 20]]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// what the main loop was last seen doing
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage {
+    // in the raft core, between calls to wait_for_message
+    Core,
+    Receiving,
+    HandlingMessage,
+    // everything else the network does between receives, like flushing queues and serving HTTP
+    NetworkUpkeep,
+}
+
+const STAGES: [Stage; 4] = [Stage::Core, Stage::Receiving, Stage::HandlingMessage, Stage::NetworkUpkeep];
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Core => "raft core",
+            Stage::Receiving => "receiving",
+            Stage::HandlingMessage => "handling a message",
+            Stage::NetworkUpkeep => "network upkeep",
+        }
+    }
+}
+
+struct Progress {
+    started: Instant,
+    last_ms: AtomicU64,
+    stage: AtomicUsize,
+}
+
+impl Progress {
+    fn stalled_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    fn stage(&self) -> Stage {
+        STAGES[self.stage.load(Ordering::Relaxed)]
+    }
+}
+
+// Watches for the main loop going quiet for longer than the timeout, e.g. from a deadlock or a syscall that never
+// returns, and says what it was doing when it stopped. With abort set the process is aborted so a supervisor can
+// restart it, rather than leaving a node that's up but never answers.
+pub struct Watchdog {
+    progress: Arc<Progress>,
+}
+
+impl Watchdog {
+    pub fn spawn(timeout: Duration, abort: bool) -> Watchdog {
+        let progress = Arc::new(Progress { started: Instant::now(), last_ms: AtomicU64::new(0), stage: AtomicUsize::new(0) });
+
+        let watched = progress.clone();
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || {
+                let mut stalled = false;
+                loop {
+                    thread::sleep(timeout / 4);
+                    let stalled_for = watched.stalled_for();
+                    if stalled_for < timeout {
+                        if stalled {
+                            eprintln!("watchdog: main loop is making progress again");
+                            stalled = false;
+                        }
+                        continue;
+                    }
+                    if stalled {
+                        continue;
+                    }
+
+                    stalled = true;
+                    eprintln!("watchdog: main loop has made no progress for {:.1}s, last seen {}", stalled_for.as_secs_f64(), watched.stage().name());
+                    if abort {
+                        eprintln!("watchdog: aborting");
+                        std::process::abort();
+                    }
+                }
+            })
+            .expect("could not start watchdog thread");
+
+        Watchdog { progress }
+    }
+
+    pub fn progress(&self, stage: Stage) {
+        let progress = &self.progress;
+        progress.last_ms.store(progress.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        progress.stage.store(stage as usize, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::watchdog::{Stage, Watchdog};
+
+    #[test]
+    fn tracks_progress() {
+        let watchdog = Watchdog::spawn(Duration::from_secs(60), false);
+        watchdog.progress(Stage::HandlingMessage);
+        thread::sleep(Duration::from_millis(20));
+
+        assert!(watchdog.progress.stalled_for() >= Duration::from_millis(20));
+        assert_eq!(watchdog.progress.stage(), Stage::HandlingMessage);
+
+        watchdog.progress(Stage::Core);
+        assert!(watchdog.progress.stalled_for() < Duration::from_millis(20));
+        assert_eq!(watchdog.progress.stage(), Stage::Core);
+    }
+}